@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+fn main() {
+  // files read at compile time. Once any `rerun-if-changed` is printed, cargo tracks only the listed paths
+  // instead of the whole package, so every file read by the macros must be listed here
+  for path in [
+    // `embed_dir!("www")`
+    "www",
+    // `obj_params_structs!("res/ObjectParameters", ...)`
+    "res/ObjectParameters",
+    // `entries_from_json!("res/VTables/*.json")`, including `hierarchy.json`
+    "res/VTables",
+    // `include_str!("../../res/GameProfiles.json")`
+    "res/GameProfiles.json",
+  ] {
+    println!("cargo:rerun-if-changed={path}");
+  }
+}
@@ -56,26 +56,4 @@ impl std::fmt::Debug for Addr {
   }
 }
 
-#[derive(Debug, Clone)]
-pub struct AddrOffsets<T=u32>(pub T, pub Box<[T]>);
-impl std::ops::Add<&AddrOffsets> for &AddrOffsets {
-  type Output = AddrOffsets;
-  fn add(self, other: &AddrOffsets) -> AddrOffsets {
-    match self.1.split_last() {
-      Some((last, init)) => AddrOffsets(
-        self.0,
-        [init, &[last+other.0], &other.1].concat().into(),
-      ),
-      None => AddrOffsets(self.0+other.0, other.1.clone()),
-    }
-  }
-}
-impl std::fmt::Display for AddrOffsets {
-  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-    write!(fmt, "{:X}", self.0)?;
-    for off in self.1.iter() {
-      write!(fmt, ",{:X}", off)?;
-    }
-    Ok(())
-  }
-}
+pub use sup_smsac_obj_params::AddrOffsets;
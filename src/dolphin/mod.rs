@@ -108,11 +108,21 @@ impl From<DolphinProcessMemory> for DolphinMemory {
     Self::ProcessMemory(x)
   }
 }
+/// Whether the process name is one of Dolphin
+fn is_dolphin(name: &str) -> bool {
+  matches!(name, "Dolphin.exe" | "DolphinQt2.exe" | "DolphinWx.exe")
+}
+
 impl DolphinMemory {
+  /// Whether the Dolphin of the pid is still running
+  pub fn is_running(pid: PidType) -> bool {
+    Process32Iterator::new().any(|p| p.pid() == pid && p.get_name().to_str().is_some_and(is_dolphin))
+  }
+
   pub fn list() -> impl Iterator<Item = (PidType, Option<DolphinMemory>)> {
     Process32Iterator::new().filter_map(|p| p.get_name().to_str().and_then(|name|
       match name {
-        "Dolphin.exe" | "DolphinQt2.exe" | "DolphinWx.exe" => {
+        name if is_dolphin(name) => {
           let pid = p.pid();
           Some((pid, {
             DolphinSharedMemory::open_pid(pid).ok()
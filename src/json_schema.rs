@@ -0,0 +1,115 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{collections::BTreeMap, sync::Arc};
+use serde_json::{json, Map, Value as JsonValue};
+
+/// JSON Schema URI of the schemas
+pub const JSON_SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// A type with a JSON Schema (draft 2020-12) of its JSON representation
+pub trait JsonSchema {
+  /// The schema of the type. Named types are defined in `defs` and referred by `$ref`
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue;
+}
+
+macro_rules! impl_json_schema {
+  ($($type:ty),+ => $schema:tt) => {$(
+    impl JsonSchema for $type {
+      fn json_schema(_defs: &mut Map<String, JsonValue>) -> JsonValue {
+        json!($schema)
+      }
+    }
+  )+};
+}
+impl_json_schema!(bool => {"type": "boolean"});
+impl_json_schema!(u8 => {"type": "integer", "minimum": 0, "maximum": 0xff});
+impl_json_schema!(u16 => {"type": "integer", "minimum": 0, "maximum": 0xffff});
+impl_json_schema!(u32 => {"type": "integer", "minimum": 0, "maximum": 0xffffffffu32});
+impl_json_schema!(u64, usize => {"type": "integer", "minimum": 0});
+impl_json_schema!(i8, i16, i32, i64 => {"type": "integer"});
+impl_json_schema!(f32, f64 => {"type": "number"});
+impl_json_schema!(str, String, Box<str>, Arc<str> => {"type": "string"});
+impl_json_schema!(() => {"type": "null"});
+// any value
+impl_json_schema!(JsonValue => {});
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    json!({"anyOf": [T::json_schema(defs), {"type": "null"}]})
+  }
+}
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    json!({"type": "array", "items": T::json_schema(defs)})
+  }
+}
+impl<T: JsonSchema> JsonSchema for Box<[T]> {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    Vec::<T>::json_schema(defs)
+  }
+}
+impl<T: JsonSchema> JsonSchema for BTreeMap<String, T> {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    json!({"type": "object", "additionalProperties": T::json_schema(defs)})
+  }
+}
+impl JsonSchema for Map<String, JsonValue> {
+  fn json_schema(_defs: &mut Map<String, JsonValue>) -> JsonValue {
+    json!({"type": "object"})
+  }
+}
+
+macro_rules! impl_json_schema_for_tuple {
+  ($($name:ident),+) => {
+    impl<$($name: JsonSchema),+> JsonSchema for ($($name,)+) {
+      fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+        json!({"type": "array", "prefixItems": [$($name::json_schema(defs)),+], "items": false})
+      }
+    }
+  };
+}
+impl_json_schema_for_tuple!(A, B);
+impl_json_schema_for_tuple!(A, B, C);
+impl_json_schema_for_tuple!(A, B, C, D);
+
+// types of `sup-smsac-obj-params`, which cannot derive JsonSchema of this crate
+/// Define the schema of a named type in `defs` and refer to it
+fn named_schema(
+  defs: &mut Map<String, JsonValue>, name: &str,
+  schema: impl FnOnce(&mut Map<String, JsonValue>) -> JsonValue,
+) -> JsonValue {
+  if !defs.contains_key(name) {
+    defs.insert(name.into(), JsonValue::Null);
+    let schema = schema(defs);
+    defs.insert(name.into(), schema);
+  }
+  json!({"$ref": format!("#/$defs/{name}")})
+}
+impl JsonSchema for crate::sms::SMSVersion {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    named_schema(defs, "SMSVersion", |_| json!({
+      "enum": Self::ALL.map(|ver| ver.to_string()),
+    }))
+  }
+}
+impl JsonSchema for crate::obj_params::diagnostic::Severity {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    named_schema(defs, "Severity", |_| json!({"enum": ["error", "warning"]}))
+  }
+}
+impl JsonSchema for crate::obj_params::diagnostic::ObjParamsDiagnostic {
+  fn json_schema(defs: &mut Map<String, JsonValue>) -> JsonValue {
+    named_schema(defs, "ObjParamsDiagnostic", |defs| json!({
+      "type": "object",
+      "properties": {
+        "severity": crate::obj_params::diagnostic::Severity::json_schema(defs),
+        "file": {"type": "string", "description": "path of the file in which the problem is found"},
+        "path": {"type": "string", "description": "JSON path to the offending value (e.g. `$[\"TMario\"].offsets[3].type`)"},
+        "message": {"type": "string"},
+      },
+      "required": ["severity", "file", "path", "message"],
+      "description": "A problem found while loading ObjectParameters",
+    }))
+  }
+}
@@ -8,10 +8,15 @@ pub mod sys;
 pub mod sms;
 pub mod server;
 pub mod obj_params;
+pub mod json_schema;
 
 use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use clap::Parser;
+use addr::AddrOffsets;
+use server::auth::{Auth, generate_token, TOKEN_PARAM};
+use sms::{SMSVersion, SMSDolphin, vt, profile::{self, GameProfiles}};
+use obj_params::{ObjParams, ObjectType, dmw::{self, DmwFile}, bench, c_header, ghidra};
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -33,12 +38,280 @@ struct Args {
 
   #[arg(short='d', long)]
   root_dir: Option<PathBuf>,
+
+  /// Access token of the API (default: a random token generated at startup, included in the opened URL)
+  #[arg(long, value_name = "TOKEN")]
+  token: Option<String>,
+
+  /// Access token of the read-only API, which cannot write the memory of the game
+  /// (default: a random token generated at startup)
+  #[arg(long, value_name = "TOKEN")]
+  read_only_token: Option<String>,
+
+  /// Allow full access to the API without a token
+  #[arg(long)]
+  no_auth: bool,
+
+  /// Allow pages from ORIGIN (e.g. https://example.com, or * for any origin) to use the API.
+  /// Can be specified multiple times
+  #[arg(long = "allow-origin", value_name = "ORIGIN")]
+  allowed_origins: Vec<String>,
+
+  /// ObjectParameters directory (default: res/ObjectParameters in the root directory).
+  /// Can be specified multiple times; later directories take precedence
+  #[arg(long = "obj-params", value_name = "DIR")]
+  obj_params_dirs: Vec<PathBuf>,
+
+  /// vtable directory (default: res/VTables in the root directory), containing `<GameID>.json`
+  /// and additions `<GameID>.<name>.json`. Can be specified multiple times; later directories take precedence
+  #[arg(long = "vtables", value_name = "DIR")]
+  vtables_dirs: Vec<PathBuf>,
+
+  /// Game profiles file (default: res/GameProfiles.json in the root directory).
+  /// Can be specified multiple times; later files take precedence
+  #[arg(long = "game-profiles", value_name = "FILE")]
+  game_profiles_files: Vec<PathBuf>,
+
+  /// Treat the running game as VERSION regardless of its game ID
+  #[arg(long, value_name = "VERSION")]
+  force_version: Option<SMSVersion>,
+
+  /// Do not reload ObjectParameters when the files are modified
+  #[arg(long)]
+  no_watch: bool,
+
+  /// Do not cache the resolved ObjectParameters for fast startup
+  #[arg(long)]
+  no_obj_params_cache: bool,
+
+  /// Measure the load time of N synthetic ObjectParameters classes with and without the cache, and exit
+  #[arg(long, value_name = "N")]
+  bench_obj_params: Option<usize>,
+
+  /// Check ObjectParameters in DIR (or in all ObjectParameters directories) and exit
+  #[arg(long, value_name = "DIR")]
+  check_obj_params: Option<Option<PathBuf>>,
+
+  /// Convert a Dolphin Memory Engine watch list (.dmw) into ObjectParameters and exit
+  #[arg(long, value_name = "FILE")]
+  import_dmw: Option<PathBuf>,
+
+  /// Export the fields of CLASS as a Dolphin Memory Engine watch list (.dmw) and exit
+  #[arg(long, value_name = "CLASS")]
+  export_dmw: Option<String>,
+
+  /// Export CLASS (or all classes) as C structs and exit
+  #[arg(long, value_name = "CLASS")]
+  export_c_header: Option<Option<String>>,
+
+  /// Export CLASS (or all classes) as Ghidra XML data types and exit
+  #[arg(long, value_name = "CLASS")]
+  export_ghidra: Option<Option<String>>,
+
+  /// Convert the structs in a C header into ObjectParameters and exit
+  #[arg(long, value_name = "FILE")]
+  import_c_header: Option<PathBuf>,
+
+  /// Address of the exported object, with optional pointer offsets (e.g. 8040E108,0)
+  #[arg(long, value_name = "ADDR", default_value = "0")]
+  addr: AddrOffsets,
+
+  /// Game version used when exporting
+  #[arg(long, value_name = "VERSION", default_value = "GMSJ01")]
+  game_version: SMSVersion,
+}
+
+fn check_obj_params(dirs: &[Box<Path>]) -> i32 {
+  match obj_params::load_obj_params(dirs) {
+    Ok(db) => {
+      for e in db.diagnostics.iter() {
+        println!("{e}");
+      }
+      let n_error = db.diagnostics.iter().filter(|e| e.is_error()).count();
+      println!("{n_error} error(s), {} warning(s)", db.diagnostics.len() - n_error);
+      if db.has_error() {1} else {0}
+    },
+    Err(e) => {
+      println!("Fail to load ObjectParameters: {e}");
+      1
+    },
+  }
+}
+
+fn import_dmw(path: &Path) -> i32 {
+  let dmw = match std::fs::File::open(path) {
+    Ok(file) => serde_json::from_reader::<_, DmwFile>(std::io::BufReader::new(file)),
+    Err(e) => {
+      eprintln!("Fail to open {}: {e}", path.to_string_lossy());
+      return 1;
+    },
+  };
+  let dmw = match dmw {
+    Ok(dmw) => dmw,
+    Err(e) => {
+      eprintln!("Fail to parse {}: {e}", path.to_string_lossy());
+      return 1;
+    },
+  };
+  let name = path.file_stem().map_or("Watches".into(), |s| s.to_string_lossy());
+  match dmw::import_dmw(&dmw, &name) {
+    Ok(o) => {
+      println!("{}", serde_json::to_string_pretty(&o).unwrap());
+      0
+    },
+    Err(e) => {
+      eprintln!("Fail to import {}: {e}", path.to_string_lossy());
+      1
+    },
+  }
+}
+
+fn export_dmw(dirs: &[Box<Path>], class: &str, ver: SMSVersion, addr: &AddrOffsets) -> i32 {
+  let db = match obj_params::load_obj_params(dirs) {
+    Ok(db) => db,
+    Err(e) => {
+      eprintln!("Fail to load ObjectParameters: {e}");
+      return 1;
+    },
+  };
+  let Some(ObjectType::Class(class)) = db.get(ver).and_then(|o| o.get(class)) else {
+    eprintln!("unknown class: \"{class}\"");
+    return 1;
+  };
+  println!("{}", serde_json::to_string_pretty(&dmw::export_dmw(class, &class.tree, addr)).unwrap());
+  0
+}
+
+type ExportTypes = fn(&ObjParams<SMSDolphin>, Option<&str>) -> Result<String, String>;
+fn export_types(dirs: &[Box<Path>], export: ExportTypes, class: Option<&str>, ver: SMSVersion) -> i32 {
+  let db = match obj_params::load_obj_params(dirs) {
+    Ok(db) => db,
+    Err(e) => {
+      eprintln!("Fail to load ObjectParameters: {e}");
+      return 1;
+    },
+  };
+  let Some(db) = db.get(ver) else {
+    eprintln!("ObjectParameters of {ver} is not loaded");
+    return 1;
+  };
+  match export(db, class) {
+    Ok(s) => {
+      print!("{s}");
+      0
+    },
+    Err(e) => {
+      eprintln!("{e}");
+      1
+    },
+  }
+}
+
+fn import_c_header(path: &Path) -> i32 {
+  let src = match std::fs::read_to_string(path) {
+    Ok(src) => src,
+    Err(e) => {
+      eprintln!("Fail to open {}: {e}", path.to_string_lossy());
+      return 1;
+    },
+  };
+  match c_header::import_c_header(&src) {
+    Ok(o) => {
+      println!("{}", serde_json::to_string_pretty(&o).unwrap());
+      0
+    },
+    Err(e) => {
+      eprintln!("Fail to import {}: {e}", path.to_string_lossy());
+      1
+    },
+  }
+}
+
+/// Per-user configuration directory (`sup-smsac/` in `%APPDATA%`, `$XDG_CONFIG_HOME` or `~/.config`),
+/// whose ObjectParameters, vtables and game profiles are loaded after all others
+fn user_config_dir() -> Option<PathBuf> {
+  std::env::var_os("APPDATA").map(PathBuf::from)
+    .or_else(|| std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from))
+    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    .map(|dir| dir.join("sup-smsac"))
 }
 
 #[tokio::main]
 async fn main() {
   let args = Args::parse();
 
+  let root_dir = args.root_dir
+    .unwrap_or_else(|| {
+      let mut path = std::env::current_exe().unwrap();
+      path.pop();
+      path
+    }).canonicalize().unwrap().into_boxed_path();
+
+  let mut obj_params_dirs = match args.obj_params_dirs.is_empty() {
+    true => vec![root_dir.join(obj_params::OBJ_PARAMS_DIR)],
+    false => args.obj_params_dirs,
+  };
+  if let Some(dir) = user_config_dir().map(|dir| dir.join("ObjectParameters")).filter(|dir| dir.is_dir()) {
+    obj_params_dirs.push(dir);
+  }
+  let obj_params_dirs: Box<[Box<Path>]> = obj_params_dirs.into_iter()
+    .map(|dir| dir.into_boxed_path())
+    .collect();
+
+  let mut vtables_dirs = match args.vtables_dirs.is_empty() {
+    true => vec![root_dir.join(vt::VTABLES_DIR)],
+    false => args.vtables_dirs,
+  };
+  if let Some(dir) = user_config_dir().map(|dir| dir.join("VTables")).filter(|dir| dir.is_dir()) {
+    vtables_dirs.push(dir);
+  }
+  let vtables_dirs: Box<[Box<Path>]> = vtables_dirs.into_iter()
+    .map(|dir| dir.into_boxed_path())
+    .collect();
+
+  if let Some(path) = args.import_dmw {
+    std::process::exit(import_dmw(&path));
+  }
+  if let Some(class) = args.export_dmw {
+    std::process::exit(export_dmw(&obj_params_dirs, &class, args.game_version, &args.addr));
+  }
+  if let Some(class) = args.export_c_header {
+    std::process::exit(export_types(&obj_params_dirs, c_header::export_c_header, class.as_deref(), args.game_version));
+  }
+  if let Some(class) = args.export_ghidra {
+    std::process::exit(export_types(&obj_params_dirs, ghidra::export_ghidra_xml, class.as_deref(), args.game_version));
+  }
+  if let Some(path) = args.import_c_header {
+    std::process::exit(import_c_header(&path));
+  }
+  if let Some(n) = args.bench_obj_params {
+    std::process::exit(bench::bench_obj_params(n));
+  }
+  if let Some(dir) = args.check_obj_params {
+    std::process::exit(match dir {
+      Some(dir) => check_obj_params(&[dir.into_boxed_path()]),
+      None => check_obj_params(&obj_params_dirs),
+    });
+  }
+
+  let mut game_profiles_files = match args.game_profiles_files.is_empty() {
+    true => vec![root_dir.join(profile::GAME_PROFILES_FILE)],
+    false => args.game_profiles_files,
+  };
+  if let Some(file) = user_config_dir().map(|dir| dir.join("GameProfiles.json")).filter(|file| file.is_file()) {
+    game_profiles_files.push(file);
+  }
+  let game_profiles_files: Box<[Box<Path>]> = game_profiles_files.into_iter()
+    .map(|file| file.into_boxed_path())
+    .collect();
+  let mut profiles = GameProfiles::load(&game_profiles_files);
+  for e in profiles.diagnostics.iter() {
+    eprintln!("{e}");
+  }
+  if let Some(ver) = args.force_version {
+    profiles = profiles.force_version(ver);
+  }
+
   let listener = {
     let mut sock_addr = SocketAddr::new(args.host, args.port);
     match tokio::net::TcpListener::bind(&sock_addr).await {
@@ -53,18 +326,46 @@ async fn main() {
     }
   };
 
-  let url = format!("http://{}", listener.local_addr().unwrap());
-  println!("Listening on {url}");
+  let auth = Auth {
+    token: (!args.no_auth).then(|| args.token.unwrap_or_else(generate_token)),
+    read_only_token: Some(args.read_only_token.unwrap_or_else(generate_token)),
+    allowed_origins: args.allowed_origins.into_boxed_slice(),
+    listen_addr: listener.local_addr().unwrap(),
+  };
+  let base_url = format!("http://{}", auth.listen_addr);
+  println!("Listening on {base_url}");
+  let token_url = |token: &str| format!("{base_url}/?{TOKEN_PARAM}={}", urlencoding::encode(token));
+  let url = auth.token.as_deref().map_or_else(|| format!("{base_url}/"), token_url);
+  println!("Full access: {url}");
+  if let Some(token) = &auth.read_only_token {
+    println!("Read-only: {}", token_url(token));
+  }
   if !args.no_browser {
     let _ = open::that(url);
   }
 
-  let root_dir = args.root_dir
-    .unwrap_or_else(|| {
-      let mut path = std::env::current_exe().unwrap();
-      path.pop();
-      path
-    }).canonicalize().unwrap().into_boxed_path();
+  let obj_params_cache = match args.no_obj_params_cache {
+    true => None,
+    false => obj_params::obj_params_cache_path(),
+  };
+  server::http::serve(listener, root_dir, obj_params_dirs, obj_params_cache, vtables_dirs, profiles, auth, !args.no_watch).await.unwrap();
+}
 
-  server::http::serve(listener, root_dir).await.unwrap();
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_obj_params_exit_code() {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-test-check-{}", std::process::id()));
+    let (valid, invalid) = (dir.join("valid"), dir.join("invalid"));
+    std::fs::create_dir_all(&valid).unwrap();
+    std::fs::create_dir_all(&invalid).unwrap();
+    std::fs::write(valid.join("a.json"), r#"{"A": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]}}"#).unwrap();
+    std::fs::write(invalid.join("a.json"), r#"{"A": {"offsets": [{"offset": "0", "type": "TFoo", "name": "x"}]}}"#).unwrap();
+    let code = |dir: &Path| check_obj_params(&[Box::from(dir)]);
+    let codes = (code(&valid), code(&invalid), code(&dir.join("missing")));
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(codes, (0, 1, 1));
+  }
 }
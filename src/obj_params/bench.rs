@@ -0,0 +1,123 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value as JsonValue};
+use super::ObjParamsLoader;
+
+/// number of classes in a synthetic file
+const CLASSES_PER_FILE: usize = 100;
+/// length of the inheritance chains of the synthetic classes
+const CHAIN_LEN: usize = 10;
+/// number of fields defined in each synthetic class
+const FIELDS_PER_CLASS: usize = 16;
+/// number of times each case is measured
+const ROUNDS: usize = 5;
+
+/// A class inheriting the previous class in its chain
+fn synthetic_class(i: usize) -> JsonValue {
+  let depth = i % CHAIN_LEN;
+  let begin = depth * FIELDS_PER_CLASS * 4;
+  let mut offsets = Vec::new();
+  if depth > 0 {
+    offsets.push(json!({"offset": "0", "type": format!("Class{}", i-1), "name": "Inherited fields"}));
+  }
+  for j in 0..FIELDS_PER_CLASS {
+    let offset = format!("{:X}", begin + j*4);
+    offsets.push(match j % 4 {
+      0 => json!({"offset": offset, "type": "u32", "name": format!("State {i}.{j}"), "format": "hex"}),
+      1 => json!({"offset": offset, "type": "float", "name": format!("X {i}.{j}"), "notes": "synthetic"}),
+      2 => json!({
+        "offset": {"GMSJ01": offset, "GMSE01": offset, "GMSP01": offset, "GMSJ0A": offset},
+        "type": "float", "name": format!("Y {i}.{j}"),
+        "when": {"field": format!("State {i}.{}", j-2), "equals": "0x1"},
+      }),
+      _ => json!({"offset": offset, "type": format!("Class{}*", i/CHAIN_LEN*CHAIN_LEN), "name": format!("Ptr {i}.{j}")}),
+    });
+  }
+  offsets.push(json!({"expr": format!("sqrt({{X {i}.1}}**2 + {{Y {i}.2}}**2)"), "name": format!("Norm {i}")}));
+  json!({"offsets": offsets})
+}
+
+fn write_synthetic(dir: &Path, n: usize) -> std::io::Result<Vec<PathBuf>> {
+  std::fs::create_dir_all(dir)?;
+  (0..n.div_ceil(CLASSES_PER_FILE)).map(|k| {
+    let classes = (k*CLASSES_PER_FILE..n.min((k+1)*CLASSES_PER_FILE))
+      .map(|i| (format!("Class{i}"), synthetic_class(i)))
+      .collect::<serde_json::Map<_, _>>();
+    let path = dir.join(format!("{k:04}.json"));
+    std::fs::write(&path, serde_json::to_vec(&classes)?)?;
+    Ok(path)
+  }).collect()
+}
+
+/// Minimum and median duration of `f` in [`ROUNDS`] rounds
+fn measure(mut f: impl FnMut() -> Result<(), String>) -> Result<(Duration, Duration), String> {
+  let mut times = (0..ROUNDS).map(|_| {
+    let t0 = Instant::now();
+    f()?;
+    Ok(t0.elapsed())
+  }).collect::<Result<Vec<_>, String>>()?;
+  times.sort();
+  Ok((times[0], times[ROUNDS/2]))
+}
+
+fn run(dir: &Path, n: usize) -> Result<(), String> {
+  let files = write_synthetic(dir, n).map_err(|e| e.to_string())?;
+  let obj_params_dir = [dir];
+  let cache = dir.join("cache/ObjectParameters.cache");
+  let load = |loader: &mut ObjParamsLoader| -> Result<(), String> {
+    let db = loader.load().map_err(|e| e.to_string())?;
+    match db.diagnostics.iter().find(|e| e.is_error()) {
+      Some(e) => Err(e.to_string()),
+      None => Ok(()),
+    }
+  };
+  let report = |case: &str, (min, median): (Duration, Duration)| {
+    println!("{case:<32} min {:>9.2?}  median {:>9.2?}", min, median);
+  };
+  println!("{n} classes in {} files, {ROUNDS} rounds", files.len());
+
+  report("without cache", measure(|| load(&mut ObjParamsLoader::new(&obj_params_dir)))?);
+  report("write cache", measure(|| {
+    let _ = std::fs::remove_file(&cache);
+    load(&mut ObjParamsLoader::new(&obj_params_dir).with_cache(&cache))
+  })?);
+  report("read cache", measure(|| load(&mut ObjParamsLoader::new(&obj_params_dir).with_cache(&cache)))?);
+  // only the modified file is parsed again, and only the classes in it are resolved again
+  let mut touched = 0;
+  let mut touch = || -> Result<(), String> {
+    touched += 1;
+    let src = std::fs::read_to_string(&files[0]).map_err(|e| e.to_string())?;
+    std::fs::write(&files[0], format!("{src}{}", " ".repeat(touched))).map_err(|e| e.to_string())
+  };
+  report("one file modified", measure(|| {
+    touch()?;
+    load(&mut ObjParamsLoader::new(&obj_params_dir).with_cache(&cache))
+  })?);
+  // reloading with the same loader, as the server does when a file is modified
+  let mut loader = ObjParamsLoader::new(&obj_params_dir);
+  load(&mut loader)?;
+  report("reload one file modified", measure(|| {
+    touch()?;
+    load(&mut loader)
+  })?);
+  let size = std::fs::metadata(&cache).map_err(|e| e.to_string())?.len();
+  println!("cache size: {size} bytes");
+  Ok(())
+}
+
+/// Measure the load time of `n` synthetic classes with and without the cache
+pub fn bench_obj_params(n: usize) -> i32 {
+  let dir = std::env::temp_dir().join(format!("sup-smsac-bench-{}", std::process::id()));
+  let result = run(&dir, n);
+  let _ = std::fs::remove_dir_all(&dir);
+  match result {
+    Ok(()) => 0,
+    Err(e) => {
+      eprintln!("{e}");
+      1
+    },
+  }
+}
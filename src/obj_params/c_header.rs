@@ -0,0 +1,932 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use crate::{
+  dolphin::Dolphin,
+  obj_params::{
+    ObjParams, ObjectType, ClassType, ImportedClass, ImportedClasses, ImportedField, ImportedOffset,
+    tree::{FieldNode, FieldNodeKind, UNION_TYPE},
+    validate::{primitive_size, array_type},
+  },
+};
+
+/**** layout ****/
+pub enum MemberKind<'a> {
+  /// primitive type in ObjectParameters
+  Primitive(&'a str),
+  /// embedded class
+  Class(&'a str),
+}
+pub struct Member<'a> {
+  pub offset: u32,
+  pub size: u32,
+  pub kind: MemberKind<'a>,
+  pub name: &'a str,
+  /// valid C identifier, unique in the struct
+  pub ident: String,
+  pub notes: &'a str,
+}
+/// Fields which cannot be a member of a struct
+pub struct SkippedMember<'a> {
+  pub name: &'a str,
+  pub reason: String,
+}
+pub struct StructLayout<'a> {
+  pub name: &'a str,
+  /// size in ObjectParameters, or the end of the last member if unknown
+  pub size: u32,
+  pub size_known: bool,
+  /// sorted by offset, without overlapping
+  pub members: Vec<Member<'a>>,
+  pub skipped: Vec<SkippedMember<'a>>,
+}
+
+/// Convert a name into a valid C identifier
+pub fn c_ident(name: &str) -> String {
+  let mut ident: String = name.chars()
+    .map(|c| if c.is_ascii_alphanumeric() || c == '_' {c} else {'_'})
+    .collect();
+  if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+    ident.insert(0, '_');
+  }
+  ident
+}
+
+/// Size of a type, or the end of the last field of a class of unknown size
+fn type_size<D: Dolphin>(db: &ObjParams<D>, type_: &str) -> u32 {
+  primitive_size(type_).unwrap_or_else(|| match db.get(type_) {
+    Some(ObjectType::Class(class)) => class_extent(db, class),
+    // unknown types are read as addresses
+    _ => 4,
+  })
+}
+fn class_extent<D: Dolphin>(db: &ObjParams<D>, class: &ClassType<D>) -> u32 {
+  class.size.unwrap_or_else(|| {
+    class.fields.iter()
+      .filter(|field| field.offset.1.is_empty() && field.expr.is_none())
+      .map(|field| field.offset.0 + type_size(db, &field.type_))
+      .max()
+      .unwrap_or(0)
+  })
+}
+
+/// Nodes of the members of a class.
+/// The members of unions are laid out as the other members (all but the first one overlap)
+fn member_nodes<D: Dolphin>(class: &ClassType<D>) -> Vec<&FieldNode> {
+  let mut nodes = Vec::new();
+  for node in class.tree.iter() {
+    match &node.kind {
+      FieldNodeKind::Group {type_, children, ..} if &**type_ == UNION_TYPE => nodes.extend(children.iter()),
+      _ => nodes.push(node),
+    }
+  }
+  nodes
+}
+
+/// Layout of a class as a C struct.
+/// The embedded class at offset 0 named `Inherited fields` is named `base`
+pub fn struct_layout<'a, D: Dolphin>(
+  db: &'a ObjParams<D>, name: &'a str, class: &'a ClassType<D>,
+) -> StructLayout<'a> {
+  let mut members = Vec::<Member>::new();
+  let mut skipped = Vec::<SkippedMember>::new();
+  for node in member_nodes(class) {
+    let (offset, kind, notes) = match &node.kind {
+      FieldNodeKind::Field(i) => {
+        let field = &class.fields[*i];
+        if field.expr.is_some() {
+          skipped.push(SkippedMember {name: &node.name, reason: "computed field".into()});
+          continue;
+        }
+        (&field.offset, MemberKind::Primitive(&field.type_), &*field.notes)
+      },
+      FieldNodeKind::Group {offset, type_, notes, ..} => (offset, MemberKind::Class(type_), &**notes),
+    };
+    if !offset.1.is_empty() {
+      skipped.push(SkippedMember {name: &node.name, reason: format!("pointer offsets {offset}")});
+      continue;
+    }
+    let size = match kind {
+      MemberKind::Primitive(type_) | MemberKind::Class(type_) => type_size(db, type_),
+    };
+    let ident = match (&kind, offset.0, &*node.name) {
+      (MemberKind::Class(_), 0, "Inherited fields") => "base".into(),
+      (_, off, "") => format!("field_{off:x}"),
+      (_, _, name) => c_ident(name),
+    };
+    members.push(Member {offset: offset.0, size, kind, name: &node.name, ident, notes});
+  }
+  members.sort_by_key(|m| m.offset);
+
+  // remove overlapping members
+  let mut end = 0;
+  let mut idents = HashSet::<String>::new();
+  members.retain_mut(|m| {
+    if m.offset < end {
+      skipped.push(SkippedMember {
+        name: m.name,
+        reason: format!("overlaps at offset {:#x}", m.offset),
+      });
+      return false;
+    }
+    end = m.offset + m.size;
+    // make the identifier unique
+    let ident = m.ident.clone();
+    let mut i = 1;
+    while !idents.insert(m.ident.clone()) {
+      i += 1;
+      m.ident = format!("{ident}_{i}");
+    }
+    true
+  });
+
+  StructLayout {
+    name,
+    size: class.size.unwrap_or(end),
+    size_known: class.size.is_some(),
+    members,
+    skipped,
+  }
+}
+
+/// Classes to export (the classes embedded in a class come before it)
+pub fn export_order<'a, D: Dolphin>(
+  db: &'a ObjParams<D>, root: Option<&'a str>,
+) -> Result<Vec<(&'a str, &'a ClassType<D>)>, String> {
+  fn visit<'a, D: Dolphin>(
+    db: &'a ObjParams<D>, name: &'a str, class: &'a ClassType<D>,
+    visited: &mut HashSet<&'a str>, order: &mut Vec<(&'a str, &'a ClassType<D>)>,
+  ) {
+    if !visited.insert(name) {return}
+    for node in member_nodes(class) {
+      if let FieldNodeKind::Group {type_, ..} = &node.kind {
+        if let Some((name, ObjectType::Class(class))) = db.get_key_value(&**type_) {
+          visit(db, name, class, visited, order);
+        }
+      }
+    }
+    order.push((name, class));
+  }
+
+  let mut names = match root {
+    Some(root) => vec![root],
+    None => db.iter()
+      .filter(|(_, o)| matches!(o, ObjectType::Class(_)))
+      .map(|(name, _)| &**name)
+      .collect(),
+  };
+  names.sort();
+  let mut visited = HashSet::new();
+  let mut order = Vec::new();
+  for name in names {
+    match db.get_key_value(name) {
+      Some((name, ObjectType::Class(class))) => visit(db, name, class, &mut visited, &mut order),
+      Some(_) => return Err(format!("\"{name}\" is not a class")),
+      None => return Err(format!("unknown class: \"{name}\"")),
+    }
+  }
+  Ok(order)
+}
+
+/**** export ****/
+/// Declaration of a member of primitive type
+fn c_decl(type_: &str, ident: &str, classes: &HashMap<&str, String>) -> String {
+  let c_type = match type_ {
+    "u8" => "uint8_t",
+    "s8" => "int8_t",
+    "u16" => "uint16_t",
+    "s16" => "int16_t",
+    "u32" => "uint32_t",
+    "s32" => "int32_t",
+    "float" => "float",
+    "double" => "double",
+    "string" => "char*",
+    "void*" => "void*",
+    t => {
+      if let Some(class) = t.strip_suffix('*') {
+        return match classes.get(class) {
+          Some(class) => format!("{class}* {ident}"),
+          None => format!("void* {ident}"),
+        };
+      }
+      return match array_type(t) {
+        Some(("char", len)) => format!("char {ident}[{len}]"),
+        Some(("u8", len)) => format!("uint8_t {ident}[{len}]"),
+        _ => format!("uint32_t {ident}; /* unknown type \"{t}\" */"),
+      };
+    },
+  };
+  format!("{c_type} {ident}")
+}
+
+/// Export classes (`root` and the classes embedded in it, or all classes) as C structs
+pub fn export_c_header<D: Dolphin>(db: &ObjParams<D>, root: Option<&str>) -> Result<String, String> {
+  let order = export_order(db, root)?;
+  let classes: HashMap<&str, String> = order.iter()
+    .map(|(name, _)| (*name, c_ident(name)))
+    .collect();
+
+  let mut out = String::new();
+  out.push_str("/* Generated by sup-smsac from ObjectParameters (pointers are 4 bytes as in GameCube) */\n");
+  out.push_str("#pragma once\n#include <stdint.h>\n#include <assert.h>\n\n");
+  for (name, _) in order.iter() {
+    writeln!(out, "typedef struct {0} {0};", classes[name]).unwrap();
+  }
+  out.push_str("\n#pragma pack(push, 1)\n");
+  for (name, class) in order.iter() {
+    let layout = struct_layout(db, name, class);
+    let ident = &classes[name];
+    out.push('\n');
+    if *ident != **name {
+      writeln!(out, "/* {name} */").unwrap();
+    }
+    writeln!(out, "struct {ident} {{").unwrap();
+    let mut end = 0;
+    for m in layout.members.iter() {
+      if m.offset > end {
+        writeln!(out, "  uint8_t _pad_{end:x}[{:#x}];", m.offset - end).unwrap();
+      }
+      for line in m.notes.lines() {
+        writeln!(out, "  // {line}").unwrap();
+      }
+      let decl = match m.kind {
+        MemberKind::Primitive(type_) => c_decl(type_, &m.ident, &classes),
+        MemberKind::Class(type_) => format!("{} {}", classes[type_], m.ident),
+      };
+      let decl = match decl.ends_with("*/") {
+        true => decl,
+        false => decl + ";",
+      };
+      writeln!(out, "  {decl} // {:#x}", m.offset).unwrap();
+      end = m.offset + m.size;
+    }
+    if layout.size > end {
+      writeln!(out, "  uint8_t _pad_{end:x}[{:#x}];", layout.size - end).unwrap();
+    }
+    for m in layout.skipped.iter() {
+      writeln!(out, "  /* skipped \"{}\": {} */", m.name, m.reason).unwrap();
+    }
+    writeln!(out, "}};").unwrap();
+    if layout.size_known {
+      writeln!(out, "static_assert(sizeof({ident}) == {:#x}, \"sizeof({ident})\");", layout.size).unwrap();
+    }
+  }
+  out.push_str("\n#pragma pack(pop)\n");
+  Ok(out)
+}
+
+/**** import ****/
+/// Maximum size of an imported struct (the size of MEM1)
+const MAX_STRUCT_SIZE: u32 = 0x180_0000;
+/// Maximum number of imported fields, as arrays of non-byte elements are expanded into one field per element
+const MAX_IMPORTED_FIELDS: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Ident(String),
+  Num(u64),
+  Punct(char),
+  /// `#pragma pack`: `Some(n)` to push or set, `None` to pop or reset
+  Pack(Option<u32>),
+}
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, String> {
+  let mut tokens = Vec::new();
+  let mut chars = src.chars().peekable();
+  let mut line = 1;
+  let mut line_start = true;
+  while let Some(c) = chars.next() {
+    match c {
+      '\n' => {
+        line += 1;
+        line_start = true;
+        continue;
+      },
+      c if c.is_whitespace() => continue,
+      '#' if line_start => {
+        // preprocessor directive
+        let mut directive = String::new();
+        while let Some(&c) = chars.peek() {
+          if c == '\n' {break}
+          directive.push(c);
+          chars.next();
+        }
+        let directive = directive.split_whitespace().collect::<String>();
+        if let Some(args) = directive.strip_prefix("pragmapack(").and_then(|s| s.strip_suffix(')')) {
+          let n = args.rsplit(',').next().unwrap_or("");
+          tokens.push((line, Token::Pack(match n {
+            "" | "pop" => None,
+            n => match n.parse() {
+              Ok(n) => Some(n),
+              Err(_) => return Err(format!("line {line}: unsupported #pragma pack({args})")),
+            },
+          })));
+        }
+        continue;
+      },
+      '/' if chars.peek() == Some(&'/') => {
+        while chars.peek().is_some_and(|&c| c != '\n') {
+          chars.next();
+        }
+        continue;
+      },
+      '/' if chars.peek() == Some(&'*') => {
+        chars.next();
+        let mut prev = ' ';
+        loop {
+          match chars.next() {
+            None => return Err(format!("line {line}: unterminated comment")),
+            Some('/') if prev == '*' => break,
+            Some(c) => {
+              if c == '\n' {line += 1}
+              prev = c;
+            },
+          }
+        }
+        continue;
+      },
+      '"' => {
+        // strings only appear in static_assert, which is skipped
+        while let Some(c) = chars.next() {
+          match c {
+            '\\' => {chars.next();},
+            '"' => break,
+            _ => {},
+          }
+        }
+        tokens.push((line, Token::Punct('"')));
+      },
+      c if c.is_ascii_alphabetic() || c == '_' => {
+        let mut ident = String::from(c);
+        while let Some(&c) = chars.peek() {
+          if !(c.is_ascii_alphanumeric() || c == '_') {break}
+          ident.push(c);
+          chars.next();
+        }
+        tokens.push((line, Token::Ident(ident)));
+      },
+      c if c.is_ascii_digit() => {
+        let mut num = String::from(c);
+        while let Some(&c) = chars.peek() {
+          if !c.is_ascii_alphanumeric() {break}
+          num.push(c);
+          chars.next();
+        }
+        let digits = num.trim_end_matches(['u', 'U', 'l', 'L']);
+        let n = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+          Some(hex) => u64::from_str_radix(hex, 16),
+          None => digits.parse(),
+        }.map_err(|e| format!("line {line}: invalid number \"{num}\": {e}"))?;
+        tokens.push((line, Token::Num(n)));
+      },
+      c => tokens.push((line, Token::Punct(c))),
+    }
+    line_start = false;
+  }
+  Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum CType {
+  /// ObjectParameters type, size
+  Primitive(&'static str, u32),
+  Char,
+  Void,
+  /// name of the struct
+  Struct(String),
+  Pointer(Box<CType>),
+  Array(Box<CType>, u32),
+}
+
+struct CStruct {
+  size: u32,
+  align: u32,
+  /// offset, name, type
+  members: Vec<(u32, String, CType)>,
+}
+
+struct Parser {
+  tokens: Vec<(usize, Token)>,
+  pos: usize,
+  typedefs: HashMap<String, CType>,
+  structs: HashMap<String, CStruct>,
+  /// in the order of definition
+  struct_names: Vec<String>,
+  pack: Vec<u32>,
+  anonymous_count: usize,
+}
+
+macro_rules! parse_err {
+  ($parser: expr, $($msg: expr),+) => {
+    return Err(format!("line {}: {}", $parser.line(), format!($($msg),+)))
+  };
+}
+
+impl Parser {
+  fn line(&self) -> usize {
+    self.tokens.get(self.pos).or(self.tokens.last()).map_or(0, |t| t.0)
+  }
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos).map(|t| &t.1)
+  }
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).map(|t| t.1.clone());
+    self.pos += 1;
+    token
+  }
+  fn is_punct(&self, c: char) -> bool {
+    self.peek() == Some(&Token::Punct(c))
+  }
+  fn is_ident(&self, s: &str) -> bool {
+    matches!(self.peek(), Some(Token::Ident(t)) if t == s)
+  }
+  fn expect_punct(&mut self, c: char) -> Result<(), String> {
+    match self.next() {
+      Some(Token::Punct(t)) if t == c => Ok(()),
+      t => parse_err!(self, "expected '{c}', found {t:?}"),
+    }
+  }
+  fn expect_ident(&mut self) -> Result<String, String> {
+    match self.next() {
+      Some(Token::Ident(s)) => Ok(s),
+      t => parse_err!(self, "expected identifier, found {t:?}"),
+    }
+  }
+  /// Skip until `;` outside of brackets
+  fn skip_statement(&mut self) -> Result<(), String> {
+    let mut depth = 0;
+    loop {
+      match self.next() {
+        None => parse_err!(self, "unexpected end of file"),
+        Some(Token::Punct('(' | '{' | '[')) => depth += 1,
+        Some(Token::Punct(')' | '}' | ']')) => depth -= 1,
+        Some(Token::Punct(';')) if depth == 0 => return Ok(()),
+        _ => {},
+      }
+    }
+  }
+
+  fn size_align(&self, t: &CType) -> Result<(u32, u32), String> {
+    Ok(match t {
+      CType::Primitive(_, size) => (*size, *size),
+      CType::Char => (1, 1),
+      CType::Void => (0, 1),
+      CType::Struct(name) => self.structs.get(name).map_or((0, 1), |s| (s.size, s.align)),
+      CType::Pointer(_) => (4, 4),
+      CType::Array(t, n) => {
+        let (size, align) = self.size_align(t)?;
+        match size.checked_mul(*n) {
+          Some(size) if size <= MAX_STRUCT_SIZE => (size, align),
+          _ => parse_err!(self, "array is too large (> {MAX_STRUCT_SIZE:#x} bytes)"),
+        }
+      },
+    })
+  }
+
+  /// Parse the type specifiers (e.g. `unsigned int`, `struct X {...}`, `u32`)
+  fn parse_type_spec(&mut self) -> Result<CType, String> {
+    let mut words = Vec::<String>::new();
+    while let Some(Token::Ident(word)) = self.peek().cloned() {
+      match word.as_str() {
+        "const" | "volatile" => {self.pos += 1;},
+        "struct" | "class" => {
+          if !words.is_empty() {break}
+          self.pos += 1;
+          return self.parse_struct();
+        },
+        "union" => parse_err!(self, "unions are not supported"),
+        "enum" => {
+          if !words.is_empty() {break}
+          self.pos += 1;
+          if let Some(Token::Ident(_)) = self.peek() {self.pos += 1}
+          if self.is_punct('{') {
+            while !self.is_punct('}') {
+              if self.next().is_none() {parse_err!(self, "unexpected end of file")}
+            }
+            self.pos += 1;
+          }
+          return Ok(CType::Primitive("s32", 4));
+        },
+        "signed" | "unsigned" | "short" | "long" | "int" | "char" | "float" | "double" | "void" | "bool" | "_Bool" => {
+          words.push(word);
+          self.pos += 1;
+        },
+        _ => {
+          // typedef name
+          if !words.is_empty() {break}
+          self.pos += 1;
+          return self.named_type(&word);
+        },
+      }
+    }
+    let unsigned = words.iter().any(|w| w == "unsigned");
+    let longs = words.iter().filter(|w| *w == "long").count();
+    let has = |s: &str| words.iter().any(|w| w == s);
+    Ok(match () {
+      _ if words.is_empty() => parse_err!(self, "expected type, found {:?}", self.peek()),
+      _ if has("void") => CType::Void,
+      _ if has("float") => CType::Primitive("float", 4),
+      _ if has("double") => CType::Primitive("double", 8),
+      _ if has("bool") || has("_Bool") => CType::Primitive("u8", 1),
+      _ if has("char") => match (unsigned, has("signed")) {
+        (true, _) => CType::Primitive("u8", 1),
+        (false, true) => CType::Primitive("s8", 1),
+        (false, false) => CType::Char,
+      },
+      _ if has("short") => CType::Primitive(if unsigned {"u16"} else {"s16"}, 2),
+      _ if longs >= 2 => CType::Array(Box::new(CType::Primitive("u8", 1)), 8),
+      _ => CType::Primitive(if unsigned {"u32"} else {"s32"}, 4),
+    })
+  }
+
+  fn named_type(&self, name: &str) -> Result<CType, String> {
+    Ok(match name {
+      "uint8_t" | "u8" | "BOOL" => CType::Primitive("u8", 1),
+      "int8_t" | "s8" => CType::Primitive("s8", 1),
+      "uint16_t" | "u16" => CType::Primitive("u16", 2),
+      "int16_t" | "s16" => CType::Primitive("s16", 2),
+      "uint32_t" | "u32" | "uintptr_t" | "size_t" => CType::Primitive("u32", 4),
+      "int32_t" | "s32" => CType::Primitive("s32", 4),
+      "f32" => CType::Primitive("float", 4),
+      "f64" => CType::Primitive("double", 8),
+      "uint64_t" | "int64_t" | "u64" | "s64" => CType::Array(Box::new(CType::Primitive("u8", 1)), 8),
+      name => match self.typedefs.get(name) {
+        Some(t) => t.clone(),
+        None if self.structs.contains_key(name) => CType::Struct(name.into()),
+        None => parse_err!(self, "unknown type \"{name}\""),
+      },
+    })
+  }
+
+  /// Parse `struct [name] [: base] [{...}]` after `struct`
+  fn parse_struct(&mut self) -> Result<CType, String> {
+    let name = match self.peek() {
+      Some(Token::Ident(_)) => self.expect_ident()?,
+      _ => {
+        self.anonymous_count += 1;
+        format!("anonymous_{}", self.anonymous_count)
+      },
+    };
+    let mut bases = Vec::<String>::new();
+    if self.is_punct(':') {
+      self.pos += 1;
+      loop {
+        if self.is_ident("public") || self.is_ident("private") || self.is_ident("protected") {
+          self.pos += 1;
+        }
+        let base = self.expect_ident()?;
+        if !self.structs.contains_key(&base) {
+          parse_err!(self, "base class \"{base}\" is not defined");
+        }
+        bases.push(base);
+        if !self.is_punct(',') {break}
+        self.pos += 1;
+      }
+    }
+    if !self.is_punct('{') {
+      // declaration only
+      return Ok(CType::Struct(name));
+    }
+    self.pos += 1;
+
+    let pack = self.pack.last().copied().unwrap_or(u32::MAX);
+    let mut members = Vec::<(u32, String, CType)>::new();
+    let mut end = 0u32;
+    let mut align = 1u32;
+    let mut push = |parser: &Parser, name: String, t: CType| {
+      let (size, align1) = parser.size_align(&t)?;
+      let align1 = align1.min(pack).max(1);
+      let offset = match end.checked_next_multiple_of(align1) {
+        Some(offset) if offset.checked_add(size).is_some_and(|end| end <= MAX_STRUCT_SIZE) => offset,
+        _ => parse_err!(parser, "struct is too large (> {MAX_STRUCT_SIZE:#x} bytes)"),
+      };
+      end = offset + size;
+      align = align.max(align1);
+      members.push((offset, name, t));
+      Ok(())
+    };
+    for (i, base) in bases.into_iter().enumerate() {
+      let name = match i {
+        0 => "Inherited fields".into(),
+        i => format!("Inherited fields {}", i+1),
+      };
+      push(self, name, CType::Struct(base))?;
+    }
+    while !self.is_punct('}') {
+      if self.is_ident("static_assert") || self.is_ident("_Static_assert") {
+        self.skip_statement()?;
+        continue;
+      }
+      let t = self.parse_type_spec()?;
+      loop {
+        let (member_name, t) = self.parse_declarator(t.clone())?;
+        if self.is_punct(':') {
+          parse_err!(self, "bit fields are not supported");
+        }
+        if self.is_punct('(') {
+          parse_err!(self, "member functions are not supported");
+        }
+        if matches!(t, CType::Struct(ref s) if !self.structs.contains_key(s)) {
+          parse_err!(self, "incomplete type of \"{member_name}\"");
+        }
+        push(self, member_name, t)?;
+        if !self.is_punct(',') {break}
+        self.pos += 1;
+      }
+      self.expect_punct(';')?;
+    }
+    self.pos += 1;
+
+    let size = match end.checked_next_multiple_of(align) {
+      Some(size) if size <= MAX_STRUCT_SIZE => size,
+      _ => parse_err!(self, "struct is too large (> {MAX_STRUCT_SIZE:#x} bytes)"),
+    };
+    if self.structs.insert(name.clone(), CStruct {size, align, members}).is_some() {
+      parse_err!(self, "struct \"{name}\" is defined twice");
+    }
+    self.struct_names.push(name.clone());
+    Ok(CType::Struct(name))
+  }
+
+  /// Parse `*name[N]...`
+  fn parse_declarator(&mut self, mut t: CType) -> Result<(String, CType), String> {
+    while self.is_punct('*') || self.is_ident("const") {
+      if self.is_punct('*') {
+        t = CType::Pointer(Box::new(t));
+      }
+      self.pos += 1;
+    }
+    let name = self.expect_ident()?;
+    let mut dims = Vec::new();
+    while self.is_punct('[') {
+      self.pos += 1;
+      let n = match self.next() {
+        Some(Token::Num(n)) => match u32::try_from(n) {
+          Ok(n) => n,
+          Err(_) => parse_err!(self, "array size {n} is too large"),
+        },
+        t => parse_err!(self, "array size must be a number, found {t:?}"),
+      };
+      self.expect_punct(']')?;
+      dims.push(n);
+    }
+    for n in dims.into_iter().rev() {
+      t = CType::Array(Box::new(t), n);
+    }
+    Ok((name, t))
+  }
+
+  fn parse(&mut self) -> Result<(), String> {
+    while let Some(token) = self.peek().cloned() {
+      match token {
+        Token::Pack(Some(n)) => {
+          self.pack.push(n);
+          self.pos += 1;
+        },
+        Token::Pack(None) => {
+          self.pack.pop();
+          self.pos += 1;
+        },
+        Token::Punct(';') => self.pos += 1,
+        Token::Ident(s) if s == "static_assert" || s == "_Static_assert" => self.skip_statement()?,
+        Token::Ident(s) if s == "typedef" => {
+          self.pos += 1;
+          let mut base = self.parse_type_spec()?;
+          loop {
+            let (name, t) = self.parse_declarator(base.clone())?;
+            // `typedef struct {...} X;` names the anonymous struct
+            match &t {
+              CType::Struct(s) if s.starts_with("anonymous_") && self.structs.contains_key(s) => {
+                let o = self.structs.remove(s).unwrap();
+                self.structs.insert(name.clone(), o);
+                if let Some(e) = self.struct_names.iter_mut().find(|e| *e == s) {
+                  *e = name.clone();
+                }
+                self.typedefs.insert(name.clone(), CType::Struct(name.clone()));
+                // the following declarators (e.g. `*PX` in `typedef struct {...} X, *PX;`) refer to the named struct
+                base = CType::Struct(name);
+              },
+              _ => {self.typedefs.insert(name, t);},
+            }
+            if !self.is_punct(',') {break}
+            self.pos += 1;
+          }
+          self.expect_punct(';')?;
+        },
+        Token::Ident(_) => {
+          self.parse_type_spec()?;
+          // ignore variable declarations
+          if !self.is_punct(';') {
+            self.skip_statement()?;
+          }
+        },
+        t => parse_err!(self, "unexpected {t:?}"),
+      }
+    }
+    Ok(())
+  }
+
+  /// Convert a member into fields of ObjectParameters, failing if there are more than `limit` fields
+  fn fields(&self, offset: u32, name: &str, t: &CType, out: &mut Vec<ImportedField>, limit: usize) -> Result<(), String> {
+    let type_ = match t {
+      CType::Primitive(t, _) => t.to_string(),
+      CType::Char => "s8".into(),
+      CType::Void => return Ok(()),
+      CType::Struct(s) => s.clone(),
+      CType::Pointer(t) => match &**t {
+        CType::Char => "string".into(),
+        CType::Void => "void*".into(),
+        CType::Primitive(t, _) => format!("{t}*"),
+        CType::Struct(s) => format!("{s}*"),
+        _ => "void*".into(),
+      },
+      CType::Array(elem, n) => match &**elem {
+        CType::Char => format!("char[{n}]"),
+        CType::Primitive("u8", _) => format!("u8[{n}]"),
+        elem => {
+          let (size, _) = self.size_align(elem)?;
+          for i in 0..*n {
+            // the offsets do not overflow as the size of the array is checked in `size_align`
+            self.fields(offset + i*size, &format!("{name}[{i}]"), elem, out, limit)?;
+          }
+          return Ok(());
+        },
+      },
+    };
+    if out.len() >= limit {
+      return Err(format!("too many fields (> {MAX_IMPORTED_FIELDS}) are imported"));
+    }
+    out.push(ImportedField {
+      offset: ImportedOffset::Offset(format!("{offset:x}")),
+      type_,
+      name: name.into(),
+      notes: Some(String::new()),
+      format: None,
+    });
+    Ok(())
+  }
+}
+
+/// Convert C struct definitions into ObjectParameters.
+///
+/// Supports structs (with C++ base classes), fixed-size arrays, pointers, typedefs and `#pragma pack`.
+/// Members named `_pad*` are skipped, and the struct member `base` at offset 0 is treated as the base class
+pub fn import_c_header(src: &str) -> Result<ImportedClasses, String> {
+  let mut parser = Parser {
+    tokens: tokenize(src)?,
+    pos: 0,
+    typedefs: HashMap::new(),
+    structs: HashMap::new(),
+    struct_names: Vec::new(),
+    pack: Vec::new(),
+    anonymous_count: 0,
+  };
+  parser.parse()?;
+
+  let mut classes = ImportedClasses::new();
+  let mut count = 0;
+  for name in parser.struct_names.iter() {
+    let o = &parser.structs[name];
+    let mut offsets = Vec::new();
+    for (offset, member_name, t) in o.members.iter() {
+      if member_name.starts_with("_pad") {continue}
+      let member_name = match (*offset, &**member_name, t) {
+        (0, "base", CType::Struct(_)) => "Inherited fields",
+        (_, name, _) => name,
+      };
+      parser.fields(*offset, member_name, t, &mut offsets, MAX_IMPORTED_FIELDS - count)?;
+    }
+    count += offsets.len();
+    classes.insert(name.clone(), ImportedClass {size: Some(o.size), offsets});
+  }
+  Ok(classes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{obj_params::tests::load_str, sms::SMSVersion};
+
+  fn import(src: &str) -> ImportedClasses {
+    import_c_header(src).unwrap()
+  }
+
+  /// `(offset, type, name)` of the fields of an imported class
+  fn fields<'a>(classes: &'a ImportedClasses, name: &str) -> Vec<(&'a str, &'a str, &'a str)> {
+    classes[name].offsets.iter().map(|o| match &o.offset {
+      ImportedOffset::Offset(offset) => (&**offset, &*o.type_, &*o.name),
+      ImportedOffset::Pointer(_) => panic!("unexpected pointer offsets of \"{}\"", o.name),
+    }).collect()
+  }
+
+  #[test]
+  fn export_round_trip() {
+    let db = load_str(&[("a.json", r#"{
+      "A": {"size": 8, "offsets": [
+        {"offset": "0", "type": "u32", "name": "x"},
+        {"offset": "4", "type": "float", "name": "y"}
+      ]},
+      "B": {"size": 24, "offsets": [
+        {"offset": "0", "type": "A", "name": "Inherited fields"},
+        {"offset": "8", "type": "u16", "name": "n"},
+        {"offset": "c", "type": "char[4]", "name": "s"},
+        {"offset": "10", "type": "A*", "name": "p"},
+        {"offset": "14", "type": "string", "name": "name"},
+        {"name": "twice", "expr": "{n} * 2"}
+      ]}
+    }"#)]);
+    let header = export_c_header(db.get(SMSVersion::GMSJ01).unwrap(), Some("B")).unwrap();
+    assert!(header.contains("  uint8_t _pad_a[0x2];\n"), "{header}");
+    assert!(header.contains("  /* skipped \"twice\": computed field */\n"), "{header}");
+    assert!(header.contains("static_assert(sizeof(B) == 0x18, \"sizeof(B)\");\n"), "{header}");
+
+    let classes = import(&header);
+    assert_eq!(classes.keys().collect::<Vec<_>>(), ["A", "B"]);
+    assert_eq!(classes["A"].size, Some(8));
+    assert_eq!(fields(&classes, "A"), [("0", "u32", "x"), ("4", "float", "y")]);
+    assert_eq!(classes["B"].size, Some(0x18));
+    assert_eq!(fields(&classes, "B"), [
+      ("0", "A", "Inherited fields"),
+      ("8", "u16", "n"),
+      ("c", "char[4]", "s"),
+      ("10", "A*", "p"),
+      ("14", "string", "name"),
+    ]);
+  }
+
+  #[test]
+  fn pragma_pack() {
+    let classes = import("
+      struct P { char c; int i; };
+      #pragma pack(push, 1)
+      struct Q { char c; int i; };
+      #pragma pack(push, 2)
+      struct R { char c; int i; };
+      #pragma pack(pop)
+      struct S { char c; int i; };
+      #pragma pack(pop)
+      struct T { char c; short s; };
+    ");
+    let layout = |name: &str| (classes[name].size, fields(&classes, name).into_iter().map(|o| o.0).collect::<Vec<_>>());
+    assert_eq!(layout("P"), (Some(8), vec!["0", "4"]));
+    assert_eq!(layout("Q"), (Some(5), vec!["0", "1"]));
+    assert_eq!(layout("R"), (Some(6), vec!["0", "2"]));
+    assert_eq!(layout("S"), (Some(5), vec!["0", "1"]));
+    assert_eq!(layout("T"), (Some(4), vec!["0", "2"]));
+  }
+
+  #[test]
+  fn typedef_anonymous_struct() {
+    let classes = import("
+      typedef struct { uint32_t a; float b; } Vec, *PVec;
+      typedef Vec Vec2;
+      struct S { Vec v; PVec p; Vec2 w[2]; short h[2]; };
+    ");
+    assert_eq!(classes.keys().collect::<Vec<_>>(), ["S", "Vec"]);
+    assert_eq!(fields(&classes, "Vec"), [("0", "u32", "a"), ("4", "float", "b")]);
+    assert_eq!(classes["S"].size, Some(0x20));
+    assert_eq!(fields(&classes, "S"), [
+      ("0", "Vec", "v"),
+      ("8", "Vec*", "p"),
+      ("c", "Vec", "w[0]"),
+      ("14", "Vec", "w[1]"),
+      ("1c", "s16", "h[0]"),
+      ("1e", "s16", "h[1]"),
+    ]);
+  }
+
+  #[test]
+  fn base_classes() {
+    let classes = import("
+      struct A { int x; };
+      struct B { short y; };
+      class C : public A, B { char z; };
+      struct D { A base; B base2; };
+    ");
+    assert_eq!(classes["C"].size, Some(8));
+    assert_eq!(fields(&classes, "C"), [("0", "A", "Inherited fields"), ("4", "B", "Inherited fields 2"), ("6", "s8", "z")]);
+    // the member `base` at offset 0 is the base class as in the exported headers
+    assert_eq!(fields(&classes, "D"), [("0", "A", "Inherited fields"), ("4", "B", "base2")]);
+    assert_eq!(import_c_header("struct E : X { int x; };").unwrap_err(),
+      "line 1: base class \"X\" is not defined");
+  }
+
+  #[test]
+  fn too_large() {
+    let err = |src: &str| import_c_header(src).unwrap_err();
+    assert_eq!(err("struct A { int a[0x40000001]; };"), "line 1: array is too large (> 0x1800000 bytes)");
+    assert_eq!(err("struct A { char a[0x100000001]; };"), "line 1: array size 4294967297 is too large");
+    assert_eq!(err("struct A { int a[100000000]; };"), "line 1: array is too large (> 0x1800000 bytes)");
+    assert_eq!(err("struct A { int a[0x10000][0x10000]; };"), "line 1: array is too large (> 0x1800000 bytes)");
+    assert_eq!(err("struct A { char a[0x1000000]; char b[0x1000000]; };"),
+      "line 1: struct is too large (> 0x1800000 bytes)");
+    assert_eq!(err("struct A { char a[0x1800000]; }; struct B : A { char b; };"),
+      "line 1: struct is too large (> 0x1800000 bytes)");
+    // arrays of non-byte elements are expanded into one field per element
+    assert_eq!(err("struct A { short a[0x8000]; }; struct B { short b[0x8001]; };"),
+      format!("too many fields (> {MAX_IMPORTED_FIELDS}) are imported"));
+    assert_eq!(import("struct A { char a[0x100]; short b[0xffff]; };")["A"].offsets.len(), MAX_IMPORTED_FIELDS);
+  }
+}
@@ -0,0 +1,601 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use crate::{
+  addr::AddrOffsets,
+  sms::{SMSDolphin, SMSVersion},
+};
+use super::{
+  ClassField, ClassFieldReader, ClassType, ObjParamsDB, ObjParamsFileContent, ObjParamsFileKey, ObjParamsJson,
+  ObjParamsOffset, ObjParamsOffsetEntry, ObjParamsOffsetEntryFormat, ObjectType, ResolvedClass, ResolvedClasses,
+  ObjParamsCondition, condition::FieldCondition,
+  diagnostic::ObjParamsDiagnostic,
+  expr::{ComputedExpr, Expr},
+  tree::{FieldNode, FieldNodeKind},
+  formatted_reader, primitive_reader, unknown_reader,
+};
+
+type D = SMSDolphin;
+
+const MAGIC: [u8; 8] = *b"SMSACOPC";
+/// bumped whenever the layout of the cached types changes
+const FORMAT_VERSION: u32 = 3;
+
+#[inline]
+fn options() -> impl Options {
+  // variable-length integers keep the indices small
+  bincode::DefaultOptions::new()
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheHeader {
+  magic: [u8; 8],
+  format_version: u32,
+  app_version: String,
+  files: Vec<ObjParamsFileKey>,
+}
+
+/// index in [`CacheBody::strings`]
+type StrId = u32;
+
+/// The id of a field is the id of the node of the field in the tree
+#[derive(Serialize, Deserialize)]
+struct CachedField {
+  offset: AddrOffsets,
+  type_: StrId,
+  name: StrId,
+  notes: StrId,
+  format: Option<ObjParamsOffsetEntryFormat>,
+  /// source of the expression and the referenced fields
+  expr: Option<(StrId, Box<[usize]>)>,
+  when: Box<[FieldCondition]>,
+  class: StrId,
+  file: StrId,
+}
+
+/// The id of a node is the id of its parent followed by `suffix` (see [`FieldNode`]),
+/// which is mostly shared by the fields of the same name in different classes
+#[derive(Serialize, Deserialize)]
+struct CachedNode {
+  suffix: StrId,
+  name: StrId,
+  kind: CachedNodeKind,
+}
+#[derive(Serialize, Deserialize)]
+enum CachedNodeKind {
+  Field(usize),
+  Group {
+    offset: AddrOffsets,
+    type_: StrId,
+    notes: StrId,
+    file: StrId,
+    children: Box<[CachedNode]>,
+  },
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedClass {
+  fields: Box<[CachedField]>,
+  tree: Box<[CachedNode]>,
+  size: Option<u32>,
+}
+
+/// name and index in [`CacheBody::classes`] (`None` for primitive types) of the types of a version
+type CachedTypes = Vec<(StrId, Option<u32>)>;
+
+/// An offset entry of a parsed file.
+/// JSON values are stored as JSON text, as they cannot be deserialized from bincode
+#[derive(Serialize, Deserialize)]
+struct CachedEntry {
+  offset: Option<CachedOffset>,
+  type_: Option<StrId>,
+  name: Option<StrId>,
+  notes: Option<StrId>,
+  format: Option<ObjParamsOffsetEntryFormat>,
+  hidden: Option<bool>,
+  /// field and the JSON text of `equals`
+  when: Option<(StrId, String)>,
+  union: Option<StrId>,
+  expr: Option<StrId>,
+}
+#[derive(Serialize, Deserialize)]
+enum CachedOffset {
+  Common(AddrOffsets),
+  PerVersion(Vec<(SMSVersion, AddrOffsets)>),
+}
+
+/// A class of a parsed file
+#[derive(Serialize, Deserialize)]
+struct CachedJson {
+  name: StrId,
+  /// JSON text of the size
+  size: Option<String>,
+  versions: Option<Box<[SMSVersion]>>,
+  patch: bool,
+  offsets: Box<[CachedEntry]>,
+}
+
+/// A parsed file with the hash of its content, or the parse error
+#[derive(Serialize, Deserialize)]
+struct CachedFile {
+  key: ObjParamsFileKey,
+  hash: u64,
+  content: Result<Box<[CachedJson]>, String>,
+}
+
+/// An embedded class with its sources, or an unknown type (see [`ResolvedClass`])
+type CachedDep = (StrId, Option<Box<[(StrId, u64)]>>);
+
+/// A resolved class (see [`ResolvedClass`]) with the index of the class in [`CacheBody::classes`]
+#[derive(Serialize, Deserialize)]
+struct CachedResolved {
+  name: StrId,
+  class: u32,
+  deps: Box<[CachedDep]>,
+  height: usize,
+  clean: bool,
+  diagnostics: Box<[ObjParamsDiagnostic]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheBody {
+  diagnostics: Box<[ObjParamsDiagnostic]>,
+  /// all strings, which are shared by the fields and the versions
+  strings: Vec<Arc<str>>,
+  /// classes which are the same in multiple versions are stored once
+  classes: Vec<CachedClass>,
+  versions: Vec<(SMSVersion, CachedTypes)>,
+  files: Vec<CachedFile>,
+  resolved: Vec<(SMSVersion, Vec<CachedResolved>)>,
+}
+
+/// A parsed file with the hash of its content
+pub(super) type LoadedFile = (ObjParamsFileKey, u64, ObjParamsFileContent);
+
+/// The state of the last load restored from the cache
+pub(super) struct CachedState {
+  pub files: Vec<LoadedFile>,
+  pub resolved: ResolvedClasses,
+  /// the resolved ObjectParameters if no file is added, removed or modified
+  pub db: Option<ObjParamsDB<D>>,
+}
+
+/// Read the cache if it is created by the same version of the app.
+/// Only the parsed files and the resolved classes are restored if the files are not the same as `files`
+pub(super) fn read_cache(path: &Path, files: &[ObjParamsFileKey]) -> Option<CachedState> {
+  let file = File::open(path).ok()?;
+  // nothing is longer than the file even if it is corrupted
+  let limit = file.metadata().ok()?.len();
+  let mut reader = BufReader::new(file);
+  let header: CacheHeader = options().with_limit(limit).deserialize_from(&mut reader).ok()?;
+  if header.magic != MAGIC
+    || header.format_version != FORMAT_VERSION
+    || header.app_version != env!("CARGO_PKG_VERSION")
+  {
+    return None;
+  }
+  let body: CacheBody = options().with_limit(limit).deserialize_from(&mut reader).ok()?;
+  Restorer::new(&body).restore(header.files == files)
+}
+
+pub(super) fn write_cache(
+  path: &Path, files: &[ObjParamsFileKey], db: &ObjParamsDB<D>,
+  loaded: &[LoadedFile], resolved: &ResolvedClasses,
+) -> Result<(), String> {
+  let header = CacheHeader {
+    magic: MAGIC,
+    format_version: FORMAT_VERSION,
+    app_version: env!("CARGO_PKG_VERSION").into(),
+    files: files.to_vec(),
+  };
+  let body = Compactor::default().compact(db, loaded, resolved)?;
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  }
+  // write to a temporary file first so that a partially written cache is never read
+  let tmp_path = path.with_extension("tmp");
+  let mut writer = BufWriter::new(File::create(&tmp_path).map_err(|e| e.to_string())?);
+  options().serialize_into(&mut writer, &header).map_err(|e| e.to_string())?;
+  options().serialize_into(&mut writer, &body).map_err(|e| e.to_string())?;
+  writer.flush().map_err(|e| e.to_string())?;
+  drop(writer);
+  std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+/// Whether two resolved classes are the same, ignoring the readers which are restored from the types
+fn same_class(a: &ClassType<D>, b: &ClassType<D>) -> bool {
+  a.size == b.size && a.tree == b.tree && a.fields.len() == b.fields.len() &&
+    a.fields.iter().zip(b.fields.iter()).all(|(a, b)| {
+      a.id == b.id && a.offset == b.offset && a.type_ == b.type_ && a.name == b.name &&
+        a.notes == b.notes && a.format == b.format && a.when == b.when &&
+        a.class == b.class && a.file == b.file &&
+        match (&a.expr, &b.expr) {
+          (Some(a), Some(b)) => a.expr.src == b.expr.src && a.fields == b.fields,
+          (a, b) => a.is_none() && b.is_none(),
+        }
+    })
+}
+
+#[derive(Default)]
+struct Compactor<'a> {
+  strings: Vec<Arc<str>>,
+  string_ids: HashMap<Arc<str>, StrId>,
+  /// address of a string of the DB -> its index, as most strings are shared by many fields
+  shared_ids: HashMap<*const u8, StrId>,
+  classes: Vec<CachedClass>,
+  /// name -> classes of the name and their indices in `classes`
+  class_ids: HashMap<StrId, Vec<(&'a ClassType<D>, u32)>>,
+  /// address of a class -> its index, as the resolved classes are shared by the DB
+  class_ptrs: HashMap<*const ClassType<D>, u32>,
+}
+impl<'a> Compactor<'a> {
+  fn str(&mut self, s: &str) -> StrId {
+    if let Some(&id) = self.string_ids.get(s) {
+      return id;
+    }
+    let s: Arc<str> = Arc::from(s);
+    let id = self.strings.len() as StrId;
+    self.strings.push(s.clone());
+    self.string_ids.insert(s, id);
+    id
+  }
+
+  fn shared_str(&mut self, s: &'a Arc<str>) -> StrId {
+    let ptr = s.as_ptr();
+    if let Some(&id) = self.shared_ids.get(&ptr) {
+      return id;
+    }
+    let id = self.str(s);
+    self.shared_ids.insert(ptr, id);
+    id
+  }
+
+  fn node(&mut self, parent: &str, node: &'a FieldNode, ids: &mut [Option<&'a str>]) -> Result<CachedNode, String> {
+    let suffix = node.id.strip_prefix(parent).filter(|suffix| suffix.starts_with('/'))
+      .ok_or_else(|| format!("unexpected id \"{}\" of a child of \"{parent}\"", node.id))?;
+    Ok(CachedNode {
+      suffix: self.str(suffix),
+      name: self.shared_str(&node.name),
+      kind: match &node.kind {
+        FieldNodeKind::Field(i) => {
+          *ids.get_mut(*i).ok_or_else(|| format!("field #{i} of \"{}\" is not found", node.id))? = Some(&node.id);
+          CachedNodeKind::Field(*i)
+        },
+        FieldNodeKind::Group {offset, type_, notes, file, children} => CachedNodeKind::Group {
+          offset: offset.clone(),
+          type_: self.shared_str(type_),
+          notes: self.shared_str(notes),
+          file: self.shared_str(file),
+          children: children.iter().map(|child| self.node(&node.id, child, ids)).collect::<Result<_, _>>()?,
+        },
+      },
+    })
+  }
+
+  fn class(&mut self, name: StrId, resolved: &'a ClassType<D>) -> Result<u32, String> {
+    if let Some(&id) = self.class_ptrs.get(&(resolved as *const _)) {
+      return Ok(id);
+    }
+    // most classes are the same in all versions
+    if let Some(&(_, id)) = self.class_ids.get(&name)
+      .and_then(|ids| ids.iter().find(|(class, _)| same_class(class, resolved)))
+    {
+      self.class_ptrs.insert(resolved, id);
+      return Ok(id);
+    }
+    // id of the node of each field
+    let mut ids = vec![None; resolved.fields.len()];
+    let tree = resolved.tree.iter().map(|node| self.node("", node, &mut ids)).collect::<Result<_, _>>()?;
+    let class = CachedClass {
+      fields: resolved.fields.iter().zip(ids).map(|(field, id)| {
+        if id != Some(&field.id) {
+          return Err(format!("field \"{}\" is not in the tree", field.id));
+        }
+        Ok(CachedField {
+          offset: field.offset.clone(),
+          type_: self.shared_str(&field.type_),
+          name: self.shared_str(&field.name),
+          notes: self.shared_str(&field.notes),
+          format: field.format,
+          expr: field.expr.as_ref().map(|c| (self.shared_str(&c.expr.src), c.fields.clone())),
+          when: field.when.clone(),
+          class: self.shared_str(&field.class),
+          file: self.shared_str(&field.file),
+        })
+      }).collect::<Result<_, String>>()?,
+      tree,
+      size: resolved.size,
+    };
+    let id = self.classes.len() as u32;
+    self.classes.push(class);
+    self.class_ids.entry(name).or_default().push((resolved, id));
+    self.class_ptrs.insert(resolved, id);
+    Ok(id)
+  }
+
+  fn entry(&mut self, entry: &'a ObjParamsOffsetEntry) -> Result<CachedEntry, String> {
+    Ok(CachedEntry {
+      offset: entry.offset.as_ref().map(|offset| match offset {
+        ObjParamsOffset::Common(offset) => CachedOffset::Common(offset.clone()),
+        ObjParamsOffset::PerVersion(offsets) => CachedOffset::PerVersion(
+          offsets.iter().map(|(&ver, offset)| (ver, offset.clone())).collect()),
+      }),
+      type_: entry.type_.as_ref().map(|s| self.shared_str(s)),
+      name: entry.name.as_ref().map(|s| self.shared_str(s)),
+      notes: entry.notes.as_ref().map(|s| self.shared_str(s)),
+      format: entry.format,
+      hidden: entry.hidden,
+      when: match &entry.when {
+        Some(cond) => Some((self.shared_str(&cond.field), serde_json::to_string(&cond.equals).map_err(|e| e.to_string())?)),
+        None => None,
+      },
+      union: entry.union.as_ref().map(|s| self.shared_str(s)),
+      expr: entry.expr.as_ref().map(|s| self.shared_str(s)),
+    })
+  }
+
+  fn file(&mut self, (key, hash, content): &'a LoadedFile) -> Result<CachedFile, String> {
+    Ok(CachedFile {
+      key: key.clone(),
+      hash: *hash,
+      content: match content {
+        Ok(classes) => Ok(classes.iter().map(|(name, json)| Ok(CachedJson {
+          name: self.shared_str(name),
+          size: match &json.size {
+            Some(size) => Some(serde_json::to_string(size).map_err(|e| e.to_string())?),
+            None => None,
+          },
+          versions: json.versions.clone(),
+          patch: json.patch,
+          offsets: json.offsets.iter().map(|entry| self.entry(entry)).collect::<Result<_, String>>()?,
+        })).collect::<Result<_, String>>()?),
+        Err(e) => Err(e.to_string()),
+      },
+    })
+  }
+
+  fn resolved(&mut self, name: &'a Arc<str>, resolved: &'a ResolvedClass) -> Result<CachedResolved, String> {
+    let name = self.shared_str(name);
+    Ok(CachedResolved {
+      name,
+      class: self.class(name, &resolved.class)?,
+      deps: resolved.deps.iter().map(|(dep, sources)| (
+        self.shared_str(dep),
+        sources.as_ref().map(|sources| sources.iter().map(|(file, hash)| (self.shared_str(file), *hash)).collect()),
+      )).collect(),
+      height: resolved.height,
+      clean: resolved.clean,
+      diagnostics: resolved.diagnostics.clone(),
+    })
+  }
+
+  fn compact(
+    mut self, db: &'a ObjParamsDB<D>, loaded: &'a [LoadedFile], resolved: &'a ResolvedClasses,
+  ) -> Result<CacheBody, String> {
+    let versions = db.versions.iter().map(|(&ver, types)| {
+      let types = types.iter().map(|(name, type_)| {
+        let name = self.shared_str(name);
+        Ok((name, match type_ {
+          ObjectType::Primitive(_) => None,
+          ObjectType::Class(class) => Some(self.class(name, class)?),
+        }))
+      }).collect::<Result<_, String>>()?;
+      Ok((ver, types))
+    }).collect::<Result<_, String>>()?;
+    let files = loaded.iter().map(|file| self.file(file)).collect::<Result<_, String>>()?;
+    let resolved = resolved.iter().map(|(&ver, classes)| {
+      let classes = classes.iter().map(|(name, o)| self.resolved(name, o)).collect::<Result<_, String>>()?;
+      Ok((ver, classes))
+    }).collect::<Result<_, String>>()?;
+    Ok(CacheBody {
+      diagnostics: db.diagnostics.clone(),
+      strings: self.strings,
+      classes: self.classes,
+      versions,
+      files,
+      resolved,
+    })
+  }
+}
+
+/// Rebuilds the types from the cache, restoring the readers from the types of the fields.
+/// Returns `None` if the cache is corrupted
+struct Restorer<'a> {
+  body: &'a CacheBody,
+  reader_unk: ClassFieldReader<D>,
+  /// readers are shared by the fields of the same type
+  readers: HashMap<(StrId, Option<ObjParamsOffsetEntryFormat>), ClassFieldReader<D>>,
+  exprs: HashMap<StrId, Arc<Expr>>,
+  /// index in [`CacheBody::classes`] -> restored class
+  classes: HashMap<u32, Arc<ClassType<D>>>,
+}
+impl<'a> Restorer<'a> {
+  fn new(body: &'a CacheBody) -> Self {
+    Self {
+      body,
+      reader_unk: unknown_reader(),
+      readers: HashMap::new(),
+      exprs: HashMap::new(),
+      classes: HashMap::new(),
+    }
+  }
+
+  #[inline]
+  fn str(&self, id: StrId) -> Option<Arc<str>> {
+    self.body.strings.get(id as usize).cloned()
+  }
+
+  fn reader(&mut self, type_: StrId, format: Option<ObjParamsOffsetEntryFormat>) -> Option<ClassFieldReader<D>> {
+    if let Some(reader) = self.readers.get(&(type_, format)) {
+      return Some(reader.clone());
+    }
+    let name = self.str(type_)?;
+    let reader = format.and_then(|format| formatted_reader(&name, format))
+      .or_else(|| primitive_reader(&name))
+      .unwrap_or_else(|| self.reader_unk.clone());
+    self.readers.insert((type_, format), reader.clone());
+    Some(reader)
+  }
+
+  fn expr(&mut self, src: StrId) -> Option<Arc<Expr>> {
+    if let Some(expr) = self.exprs.get(&src) {
+      return Some(expr.clone());
+    }
+    let expr = Arc::new(Expr::parse(&self.str(src)?).ok()?);
+    self.exprs.insert(src, expr.clone());
+    Some(expr)
+  }
+
+  fn node(&mut self, parent: &str, node: &CachedNode, ids: &mut [Option<Arc<str>>]) -> Option<FieldNode> {
+    let id: Arc<str> = Arc::from(format!("{parent}{}", self.body.strings.get(node.suffix as usize)?));
+    Some(FieldNode {
+      id: id.clone(),
+      name: self.str(node.name)?,
+      kind: match &node.kind {
+        CachedNodeKind::Field(i) => {
+          *ids.get_mut(*i)? = Some(id);
+          FieldNodeKind::Field(*i)
+        },
+        CachedNodeKind::Group {offset, type_, notes, file, children} => FieldNodeKind::Group {
+          offset: offset.clone(),
+          type_: self.str(*type_)?,
+          notes: self.str(*notes)?,
+          file: self.str(*file)?,
+          children: children.iter().map(|child| self.node(&id, child, ids)).collect::<Option<_>>()?,
+        },
+      },
+    })
+  }
+
+  fn class(&mut self, class: &CachedClass) -> Option<ClassType<D>> {
+    let mut ids = vec![None; class.fields.len()];
+    let tree = class.tree.iter().map(|node| self.node("", node, &mut ids)).collect::<Option<_>>()?;
+    let fields = class.fields.iter().zip(ids).map(|(field, id)| Some(ClassField {
+      id: id?,
+      offset: field.offset.clone(),
+      type_: self.str(field.type_)?,
+      name: self.str(field.name)?,
+      notes: self.str(field.notes)?,
+      format: field.format,
+      expr: match &field.expr {
+        Some((src, fields)) => Some(ComputedExpr {expr: self.expr(*src)?, fields: fields.clone()}),
+        None => None,
+      },
+      when: field.when.clone(),
+      class: self.str(field.class)?,
+      file: self.str(field.file)?,
+      reader: match field.expr {
+        // computed fields are not read from memory
+        Some(_) => self.reader_unk.clone(),
+        None => self.reader(field.type_, field.format)?,
+      },
+    })).collect::<Option<_>>()?;
+    Some(ClassType {
+      fields,
+      tree,
+      size: class.size,
+    })
+  }
+
+  /// The class of the index, which is restored once and shared
+  fn shared_class(&mut self, i: u32) -> Option<Arc<ClassType<D>>> {
+    if let Some(class) = self.classes.get(&i) {
+      return Some(class.clone());
+    }
+    let class = Arc::new(self.class(self.body.classes.get(i as usize)?)?);
+    self.classes.insert(i, class.clone());
+    Some(class)
+  }
+
+  fn entry(&self, entry: &CachedEntry) -> Option<ObjParamsOffsetEntry> {
+    let str = |id: Option<StrId>| match id {
+      Some(id) => self.str(id).map(Some),
+      None => Some(None),
+    };
+    Some(ObjParamsOffsetEntry {
+      offset: entry.offset.as_ref().map(|offset| match offset {
+        CachedOffset::Common(offset) => ObjParamsOffset::Common(offset.clone()),
+        CachedOffset::PerVersion(offsets) => ObjParamsOffset::PerVersion(offsets.iter().cloned().collect()),
+      }),
+      type_: str(entry.type_)?,
+      name: str(entry.name)?,
+      notes: str(entry.notes)?,
+      format: entry.format,
+      hidden: entry.hidden,
+      when: match &entry.when {
+        Some((field, equals)) => Some(Arc::new(ObjParamsCondition {
+          field: self.str(*field)?,
+          equals: serde_json::from_str(equals).ok()?,
+        })),
+        None => None,
+      },
+      union: str(entry.union)?,
+      expr: str(entry.expr)?,
+    })
+  }
+
+  fn file(&self, file: &CachedFile) -> Option<LoadedFile> {
+    let content = match &file.content {
+      Ok(classes) => Ok(classes.iter().map(|json| Some((self.str(json.name)?, Arc::new(ObjParamsJson {
+        size: match &json.size {
+          Some(size) => Some(serde_json::from_str(size).ok()?),
+          None => None,
+        },
+        versions: json.versions.clone(),
+        patch: json.patch,
+        offsets: json.offsets.iter().map(|entry| self.entry(entry)).collect::<Option<_>>()?,
+      })))).collect::<Option<_>>()?),
+      Err(e) => Err(Arc::from(e.as_str())),
+    };
+    Some((file.key.clone(), file.hash, content))
+  }
+
+  fn resolved(&mut self, o: &CachedResolved) -> Option<(Arc<str>, ResolvedClass)> {
+    Some((self.str(o.name)?, ResolvedClass {
+      class: self.shared_class(o.class)?,
+      deps: o.deps.iter().map(|(dep, sources)| Some((self.str(*dep)?, match sources {
+        Some(sources) => Some(sources.iter()
+          .map(|&(file, hash)| Some((self.str(file)?, hash)))
+          .collect::<Option<_>>()?),
+        None => None,
+      }))).collect::<Option<_>>()?,
+      height: o.height,
+      clean: o.clean,
+      diagnostics: o.diagnostics.clone(),
+    }))
+  }
+
+  /// Restore the parsed files and the resolved classes, and the DB if `with_db`
+  fn restore(mut self, with_db: bool) -> Option<CachedState> {
+    let body = self.body;
+    let db = match with_db {
+      true => {
+        let versions = body.versions.iter().map(|(ver, types)| {
+          let types = types.iter().map(|&(name, class)| {
+            let name = self.str(name)?;
+            let type_ = match class {
+              Some(i) => ObjectType::Class(self.shared_class(i)?),
+              None => ObjectType::Primitive(primitive_reader(&name).unwrap_or_else(|| self.reader_unk.clone())),
+            };
+            Some((name, type_))
+          }).collect::<Option<_>>()?;
+          Some((*ver, types))
+        }).collect::<Option<_>>()?;
+        Some(ObjParamsDB {versions, profiles: HashMap::new(), diagnostics: body.diagnostics.clone()})
+      },
+      false => None,
+    };
+    let files = body.files.iter().map(|file| self.file(file)).collect::<Option<_>>()?;
+    let resolved = body.resolved.iter().map(|(ver, classes)| {
+      Some((*ver, classes.iter().map(|o| self.resolved(o)).collect::<Option<_>>()?))
+    }).collect::<Option<_>>()?;
+    Some(CachedState {files, resolved, db})
+  }
+}
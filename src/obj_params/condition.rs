@@ -0,0 +1,113 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use crate::dolphin::Dolphin;
+use super::{ClassField, ObjParamsCondition, find_field, tree::FieldNode};
+
+/// A condition on the value of an integer field of the same object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldCondition {
+  /// index of the field in the flattened field list
+  pub field: usize,
+  /// size of the field in bytes
+  pub size: u32,
+  /// expected value, truncated to `size`
+  pub equals: u32,
+}
+impl FieldCondition {
+  #[inline]
+  pub fn matches(&self, value: u32) -> bool {
+    value == self.equals
+  }
+}
+
+fn condition_value(value: &JsonValue) -> Result<i64, String> {
+  match value {
+    JsonValue::Number(n) => n.as_i64()
+      .or_else(|| n.as_u64().map(|x| x as i64))
+      .ok_or_else(|| format!("\"equals\" must be an integer, got {n}")),
+    JsonValue::String(s) => {
+      let (neg, abs) = match s.strip_prefix('-') {
+        Some(abs) => (true, abs),
+        None => (false, s.as_str()),
+      };
+      match abs.strip_prefix("0x").or_else(|| abs.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => abs.parse(),
+      }.map(|x| if neg {-x} else {x})
+        .map_err(|e| format!("invalid \"equals\" \"{s}\": {e}"))
+    },
+    v => Err(format!("\"equals\" must be a number or a string, got {v}")),
+  }
+}
+
+/// Resolve the field referenced by the condition.
+/// `direct` maps the names of the fields defined in the class itself to their indices
+pub fn resolve_condition<D: Dolphin>(
+  cond: &ObjParamsCondition,
+  fields: &[ClassField<D>],
+  tree: &[FieldNode],
+  direct: &HashMap<&str, usize>,
+) -> Result<FieldCondition, String> {
+  let index = find_field(tree, direct, &cond.field)
+    .ok_or_else(|| format!("field \"{}\" is not found", cond.field))?;
+  let size = match &*fields[index].type_ {
+    "u8" | "s8" => 1,
+    "u16" | "s16" => 2,
+    "u32" | "s32" => 4,
+    t => return Err(format!("field \"{}\" of type \"{t}\" cannot be used in conditions (integer expected)", cond.field)),
+  };
+  let mask = u32::MAX >> (32 - 8*size);
+  Ok(FieldCondition {
+    field: index,
+    size,
+    equals: condition_value(&cond.equals)? as u32 & mask,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use crate::obj_params::tests::{class, errors, load_str};
+
+  #[test]
+  fn condition_values() {
+    assert_eq!(condition_value(&json!(16)), Ok(16));
+    assert_eq!(condition_value(&json!(u64::MAX)), Ok(-1));
+    assert_eq!(condition_value(&json!("16")), Ok(16));
+    assert_eq!(condition_value(&json!("0x1f")), Ok(31));
+    assert_eq!(condition_value(&json!("0X1F")), Ok(31));
+    assert_eq!(condition_value(&json!("-0x10")), Ok(-16));
+    assert_eq!(condition_value(&json!(1.5)), Err("\"equals\" must be an integer, got 1.5".into()));
+    assert_eq!(condition_value(&json!("0xg")), Err("invalid \"equals\" \"0xg\": invalid digit found in string".into()));
+    assert_eq!(condition_value(&json!(true)), Err("\"equals\" must be a number or a string, got true".into()));
+  }
+
+  #[test]
+  fn conditions_are_truncated_to_the_field() {
+    let db = load_str(&[("a.json", r#"{"A": {"offsets": [
+      {"offset": "0", "type": "u8", "name": "kind"},
+      {"offset": "2", "type": "s16", "name": "mode"},
+      {"offset": "4", "type": "float", "name": "speed"},
+      {"offset": "8", "type": "u32", "name": "a", "when": {"field": "kind", "equals": "0x110"}},
+      {"offset": "8", "type": "u32", "name": "b", "when": {"field": "mode", "equals": -1}},
+      {"offset": "8", "type": "u32", "name": "c", "when": {"field": "speed", "equals": 1}}
+    ]}}"#)]);
+    assert_eq!(errors(&db), [(
+      "a.json", r#"$["A"].offsets[5].when"#,
+      r#"field "speed" of type "float" cannot be used in conditions (integer expected)"#,
+    )]);
+    let a = class(&db, "A");
+    let when = |id: &str| a.fields.iter().find(|o| &*o.id == id).unwrap().when.to_vec();
+    assert_eq!(when("/a"), [FieldCondition {field: 0, size: 1, equals: 0x10}]);
+    assert_eq!(when("/b"), [FieldCondition {field: 1, size: 2, equals: 0xffff}]);
+    // the field with an invalid condition is always applicable
+    assert_eq!(when("/c"), []);
+    assert!(when("/b")[0].matches(0xffff));
+    assert!(!when("/b")[0].matches(0xfffe));
+  }
+}
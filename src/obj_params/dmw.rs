@@ -0,0 +1,389 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use sup_smsac_derive::JsonSchema;
+use crate::{
+  addr::AddrOffsets,
+  json_schema::JsonSchema,
+  dolphin::Dolphin,
+  obj_params::{
+    ClassType, ClassField, ObjParamsOffsetEntryFormat,
+    ImportedClass, ImportedClasses, ImportedField, ImportedOffset,
+    tree::{FieldNode, FieldNodeKind},
+    validate::array_type,
+  },
+};
+
+/// Dolphin Memory Engine watch list (`.dmw`)
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DmwFile {
+  pub watch_list: Vec<DmwNode>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum DmwNode {
+  Group(DmwGroup),
+  Entry(DmwEntry),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DmwGroup {
+  pub group_name: String,
+  pub group_entries: Vec<DmwNode>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DmwEntry {
+  pub label: String,
+  /// hex without prefix
+  pub address: String,
+  /// byte, halfword, word, float, double, string, byte array
+  pub type_index: u8,
+  #[serde(default)]
+  pub unsigned: bool,
+  /// decimal, hexadecimal, octal, binary
+  #[serde(default)]
+  pub base_index: u8,
+  /// hex without prefix
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub pointer_offsets: Option<Vec<String>>,
+  /// length of string and byte array
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub length: Option<usize>,
+}
+
+const DMW_BYTE: u8 = 0;
+const DMW_HALFWORD: u8 = 1;
+const DMW_WORD: u8 = 2;
+const DMW_FLOAT: u8 = 3;
+const DMW_DOUBLE: u8 = 4;
+const DMW_STRING: u8 = 5;
+const DMW_BYTE_ARRAY: u8 = 6;
+const DMW_BASE_DECIMAL: u8 = 0;
+const DMW_BASE_HEX: u8 = 1;
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+  let s = s.trim();
+  let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+  u32::from_str_radix(s, 16).map_err(|e| format!("invalid hex \"{s}\": {e}"))
+}
+
+/// `"7c"` for a single offset, or `["7c", "10"]` for a pointer chain
+fn imported_offset(first: u32, rest: &[u32]) -> ImportedOffset {
+  match rest.is_empty() {
+    true => ImportedOffset::Offset(format!("{first:x}")),
+    false => ImportedOffset::Pointer(
+      std::iter::once(&first).chain(rest)
+        .map(|off| format!("{off:x}"))
+        .collect()
+    ),
+  }
+}
+
+/// ObjectParameters type and format of a watch entry
+fn import_type(entry: &DmwEntry) -> Result<(String, Option<&'static str>), String> {
+  let hex = entry.base_index == DMW_BASE_HEX;
+  let int_type = |bits: u8| match (entry.unsigned || hex, hex) {
+    (true, true) => (format!("u{bits}"), Some("hex")),
+    (true, false) => (format!("u{bits}"), None),
+    (false, _) => (format!("s{bits}"), None),
+  };
+  let length = || entry.length
+    .ok_or_else(|| format!("\"{}\": missing length", entry.label));
+  Ok(match entry.type_index {
+    DMW_BYTE => int_type(8),
+    DMW_HALFWORD => int_type(16),
+    DMW_WORD => int_type(32),
+    DMW_FLOAT => ("float".into(), None),
+    DMW_DOUBLE => ("double".into(), None),
+    DMW_STRING => (format!("char[{}]", length()?), None),
+    DMW_BYTE_ARRAY => (format!("u8[{}]", length()?), None),
+    t => return Err(format!("\"{}\": unknown typeIndex {t}", entry.label)),
+  })
+}
+
+struct Importer {
+  classes: ImportedClasses,
+  names: HashSet<String>,
+}
+impl Importer {
+  /// Reserve a unique class name
+  fn class_name(&mut self, name: &str) -> String {
+    let mut unique = name.to_string();
+    let mut i = 1;
+    while !self.names.insert(unique.clone()) {
+      i += 1;
+      unique = format!("{name} ({i})");
+    }
+    unique
+  }
+
+  /// Convert a group into a class. Returns the offset at which the class
+  /// should be embedded into an absolute class, and the fields of the groups
+  /// to embed into the absolute class instead if the class is relative.
+  ///
+  /// If all entries of the group are pointers with the same base address,
+  /// the class is relative to the pointed object (i.e. fields are at the pointer offsets).
+  /// Otherwise the fields are at the absolute addresses (i.e. the class is read at address 0).
+  /// The root class is always absolute, and the classes of its groups are not prefixed
+  fn import_group(
+    &mut self, name: String, nodes: &[DmwNode], is_root: bool,
+  ) -> Result<(ImportedOffset, Vec<ImportedField>), String> {
+    let entries = nodes.iter().filter_map(|node| match node {
+      DmwNode::Entry(entry) => Some(entry),
+      DmwNode::Group(_) => None,
+    }).collect::<Vec<_>>();
+    let base = match (is_root, entries.first()) {
+      (false, Some(entry0)) => entries.iter().all(|entry| {
+        entry.address == entry0.address &&
+          entry.pointer_offsets.as_ref().is_some_and(|offs| !offs.is_empty())
+      }).then_some(entry0.address.as_str()),
+      _ => None,
+    };
+
+    let mut offsets = Vec::new();
+    let mut groups = Vec::new();
+    for node in nodes {
+      match node {
+        DmwNode::Entry(entry) => {
+          let (type_, format) = import_type(entry)?;
+          let address = parse_hex(&entry.address)?;
+          let pointer_offsets = entry.pointer_offsets.as_deref().unwrap_or(&[])
+            .iter().map(|off| parse_hex(off))
+            .collect::<Result<Vec<_>, _>>()?;
+          let offset = match (base, pointer_offsets.split_first()) {
+            (Some(_), Some((first, rest))) => imported_offset(*first, rest),
+            _ => imported_offset(address, &pointer_offsets),
+          };
+          offsets.push(ImportedField {
+            offset,
+            type_,
+            name: entry.label.clone(),
+            notes: None,
+            format: format.map(String::from),
+          });
+        },
+        DmwNode::Group(group) => {
+          let child_name = self.class_name(&match is_root {
+            true => group.group_name.clone(),
+            false => format!("{name}/{}", group.group_name),
+          });
+          let (child_offset, child_groups) = self.import_group(child_name.clone(), &group.group_entries, false)?;
+          // relative classes cannot embed the other classes, which are passed to the absolute class
+          let fields = if base.is_none() {&mut offsets} else {&mut groups};
+          fields.push(ImportedField {
+            offset: child_offset,
+            type_: child_name,
+            name: group.group_name.clone(),
+            notes: None,
+            format: None,
+          });
+          fields.extend(child_groups);
+        },
+      }
+    }
+
+    self.classes.insert(name, ImportedClass {size: None, offsets});
+    Ok((match base {
+      Some(base) => imported_offset(parse_hex(base)?, &[0]),
+      None => ImportedOffset::Offset("0".into()),
+    }, groups))
+  }
+}
+
+/// Convert a watch list into ObjectParameters.
+///
+/// The whole watch list becomes the class `name`, which is read at address 0.
+/// Each group becomes a class embedded into its parent class
+/// (or the nearest absolute ancestor if the parent is relative),
+/// named `{parent}/{group}` (or `{group}` for the top-level groups)
+pub fn import_dmw(dmw: &DmwFile, name: &str) -> Result<ImportedClasses, String> {
+  let mut importer = Importer {
+    classes: ImportedClasses::new(),
+    names: HashSet::new(),
+  };
+  let name = importer.class_name(name);
+  importer.import_group(name, &dmw.watch_list, true)?;
+  Ok(importer.classes)
+}
+
+/// DME type of a field: `(typeIndex, unsigned, baseIndex, length)`
+fn export_type<D: Dolphin>(field: &ClassField<D>) -> (u8, bool, u8, Option<usize>) {
+  let base = match field.format {
+    Some(ObjParamsOffsetEntryFormat::Hex) => DMW_BASE_HEX,
+    None => DMW_BASE_DECIMAL,
+  };
+  match &*field.type_ {
+    "u8" => (DMW_BYTE, true, base, None),
+    "s8" => (DMW_BYTE, false, base, None),
+    "u16" => (DMW_HALFWORD, true, base, None),
+    "s16" => (DMW_HALFWORD, false, base, None),
+    "u32" => (DMW_WORD, true, base, None),
+    "s32" => (DMW_WORD, false, base, None),
+    "float" => (DMW_FLOAT, false, base, None),
+    "double" => (DMW_DOUBLE, false, base, None),
+    t => match array_type(t) {
+      Some(("char", len)) => (DMW_STRING, false, base, Some(len)),
+      Some(("u8", len)) => (DMW_BYTE_ARRAY, false, base, Some(len)),
+      // pointers and unknown types are shown as addresses
+      _ => (DMW_WORD, true, DMW_BASE_HEX, None),
+    },
+  }
+}
+
+fn export_nodes<D: Dolphin>(
+  class: &ClassType<D>, nodes: &[FieldNode], base: &AddrOffsets,
+) -> Vec<DmwNode> {
+  nodes.iter().filter_map(|node| Some(match &node.kind {
+    FieldNodeKind::Field(i) => {
+      let field = &class.fields[*i];
+      // computed fields cannot be watched
+      if field.expr.is_some() {return None}
+      let offset = base + &field.offset;
+      let (type_index, unsigned, base_index, length) = export_type(field);
+      DmwNode::Entry(DmwEntry {
+        label: field.name.to_string(),
+        address: format!("{:08X}", offset.0),
+        type_index,
+        unsigned,
+        base_index,
+        pointer_offsets: match offset.1.is_empty() {
+          true => None,
+          false => Some(offset.1.iter().map(|off| format!("{off:X}")).collect()),
+        },
+        length,
+      })
+    },
+    FieldNodeKind::Group {children, ..} => DmwNode::Group(DmwGroup {
+      group_name: node.name.to_string(),
+      group_entries: export_nodes(class, children, base),
+    }),
+  })).collect()
+}
+
+/// Convert the fields of a class at `base` into a watch list.
+/// Embedded classes become groups
+pub fn export_dmw<D: Dolphin>(
+  class: &ClassType<D>, nodes: &[FieldNode], base: &AddrOffsets,
+) -> DmwFile {
+  DmwFile {
+    watch_list: export_nodes(class, nodes, base),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use crate::obj_params::tests::{class, errors, load_str};
+
+  fn entry(
+    label: &str, address: &str, type_index: u8, unsigned: bool, base_index: u8,
+    pointer_offsets: &[&str], length: Option<usize>,
+  ) -> DmwNode {
+    DmwNode::Entry(DmwEntry {
+      label: label.into(),
+      address: address.into(),
+      type_index,
+      unsigned,
+      base_index,
+      pointer_offsets: (!pointer_offsets.is_empty()).then(|| pointer_offsets.iter().map(|e| e.to_string()).collect()),
+      length,
+    })
+  }
+
+  fn group(name: &str, entries: Vec<DmwNode>) -> DmwNode {
+    DmwNode::Group(DmwGroup {group_name: name.into(), group_entries: entries})
+  }
+
+  /// Import the watch list, and export the imported class `Watches`
+  fn round_trip(dmw: &DmwFile) -> (ImportedClasses, serde_json::Value) {
+    let classes = import_dmw(dmw, "Watches").unwrap();
+    let db = load_str(&[("w.json", &serde_json::to_string(&classes).unwrap())]);
+    assert_eq!(errors(&db), []);
+    let watches = class(&db, "Watches");
+    let exported = export_dmw(watches, &watches.tree, &AddrOffsets(0, [].into()));
+    (classes, serde_json::to_value(exported).unwrap())
+  }
+
+  #[test]
+  fn import_and_export() {
+    let dmw = DmwFile {watch_list: vec![
+      entry("Byte", "80400000", DMW_BYTE, true, DMW_BASE_DECIMAL, &[], None),
+      entry("Half", "80400002", DMW_HALFWORD, false, DMW_BASE_DECIMAL, &[], None),
+      entry("Flags", "80400004", DMW_WORD, true, DMW_BASE_HEX, &[], None),
+      entry("Name", "80400010", DMW_STRING, false, DMW_BASE_DECIMAL, &[], Some(12)),
+      entry("Bytes", "80400020", DMW_BYTE_ARRAY, false, DMW_BASE_DECIMAL, &[], Some(6)),
+      entry("Chain", "8040A000", DMW_DOUBLE, false, DMW_BASE_DECIMAL, &["10", "8"], None),
+      group("Mario", vec![
+        entry("X", "8040E108", DMW_FLOAT, false, DMW_BASE_DECIMAL, &["10"], None),
+        entry("HP", "8040E108", DMW_HALFWORD, false, DMW_BASE_DECIMAL, &["120", "4"], None),
+      ]),
+      group("Stage", vec![
+        entry("ID", "803E9700", DMW_BYTE, true, DMW_BASE_DECIMAL, &[], None),
+        group("Timer", vec![entry("Frames", "803E9710", DMW_WORD, true, DMW_BASE_DECIMAL, &[], None)]),
+      ]),
+      group("Stage", vec![entry("ID", "803E9800", DMW_BYTE, true, DMW_BASE_DECIMAL, &[], None)]),
+      group("Watches", vec![]),
+    ]};
+    let (classes, exported) = round_trip(&dmw);
+    // duplicate group names get unique class names
+    assert_eq!(
+      classes.keys().map(|e| &**e).collect::<Vec<_>>(),
+      ["Mario", "Stage", "Stage (2)", "Stage/Timer", "Watches", "Watches (2)"],
+    );
+    // the group of pointers with the same base address is relative to the pointed object
+    assert_eq!(serde_json::to_value(&classes["Mario"]).unwrap(), json!({"offsets": [
+      {"offset": "10", "type": "float", "name": "X"},
+      {"offset": ["120", "4"], "type": "s16", "name": "HP"},
+    ]}));
+    let watches = serde_json::to_value(&classes["Watches"]).unwrap();
+    assert_eq!(watches["offsets"].as_array().unwrap()[..7], [
+      json!({"offset": "80400000", "type": "u8", "name": "Byte"}),
+      json!({"offset": "80400002", "type": "s16", "name": "Half"}),
+      json!({"offset": "80400004", "type": "u32", "name": "Flags", "format": "hex"}),
+      json!({"offset": "80400010", "type": "char[12]", "name": "Name"}),
+      json!({"offset": "80400020", "type": "u8[6]", "name": "Bytes"}),
+      json!({"offset": ["8040a000", "10", "8"], "type": "double", "name": "Chain"}),
+      json!({"offset": ["8040e108", "0"], "type": "Mario", "name": "Mario"}),
+    ]);
+    assert_eq!(exported, serde_json::to_value(&dmw).unwrap());
+  }
+
+  #[test]
+  fn signed_hex_is_unsigned() {
+    let DmwNode::Entry(e) = entry("x", "0", DMW_HALFWORD, false, DMW_BASE_HEX, &[], None) else {unreachable!()};
+    assert_eq!(import_type(&e), Ok(("u16".into(), Some("hex"))));
+    let DmwNode::Entry(e) = entry("s", "0", DMW_STRING, false, DMW_BASE_DECIMAL, &[], None) else {unreachable!()};
+    assert_eq!(import_type(&e), Err("\"s\": missing length".into()));
+  }
+
+  #[test]
+  fn groups_of_relative_groups() {
+    let dmw = DmwFile {watch_list: vec![
+      group("Mario", vec![
+        entry("X", "8040E108", DMW_FLOAT, false, DMW_BASE_DECIMAL, &["10"], None),
+        group("Stats", vec![entry("HP", "8040E108", DMW_HALFWORD, false, DMW_BASE_DECIMAL, &["120"], None)]),
+        group("Pos", vec![entry("Y", "80400000", DMW_FLOAT, false, DMW_BASE_DECIMAL, &[], None)]),
+      ]),
+    ]};
+    let (classes, exported) = round_trip(&dmw);
+    assert_eq!(serde_json::to_value(&classes["Watches"]).unwrap(), json!({"offsets": [
+      {"offset": ["8040e108", "0"], "type": "Mario", "name": "Mario"},
+      {"offset": ["8040e108", "0"], "type": "Mario/Stats", "name": "Stats"},
+      {"offset": "0", "type": "Mario/Pos", "name": "Pos"},
+    ]}));
+    // the groups of the relative group are embedded into the absolute class instead of being dropped
+    assert_eq!(exported, serde_json::to_value(DmwFile {watch_list: vec![
+      group("Mario", vec![entry("X", "8040E108", DMW_FLOAT, false, DMW_BASE_DECIMAL, &["10"], None)]),
+      group("Stats", vec![entry("HP", "8040E108", DMW_HALFWORD, false, DMW_BASE_DECIMAL, &["120"], None)]),
+      group("Pos", vec![entry("Y", "80400000", DMW_FLOAT, false, DMW_BASE_DECIMAL, &[], None)]),
+    ]}).unwrap());
+  }
+}
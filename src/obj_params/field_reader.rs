@@ -5,6 +5,7 @@ use crate::{
   sms::SMSDolphin,
 };
 use std::marker::PhantomData;
+use encoding_rs::SHIFT_JIS;
 
 pub trait FieldReader<D: Dolphin, T> {
   fn read(&self, d: &D, addr: Addr) -> Option<T>;
@@ -59,6 +60,18 @@ impl<D: Dolphin> FieldReader<D, String> for StringFieldReader {
   }
 }
 
+/// Null-terminated string stored in a fixed-size `char[N]`
+pub struct InlineStringFieldReader(pub usize);
+impl<D: Dolphin> FieldReader<D, String> for InlineStringFieldReader {
+  fn read(&self, d: &D, addr: Addr) -> Option<String> {
+    d.read_memory(addr, self.0, |ptr| {
+      let bytes = unsafe {std::slice::from_raw_parts(ptr, self.0)};
+      let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+      SHIFT_JIS.decode_without_bom_handling(&bytes[..len]).0.into_owned()
+    })
+  }
+}
+
 pub struct ClassNameReader;
 impl FieldReader<SMSDolphin, String> for ClassNameReader {
   fn read(&self, d: &SMSDolphin, addr: Addr) -> Option<String> {
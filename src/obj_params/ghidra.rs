@@ -0,0 +1,87 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::fmt::Write;
+use crate::{
+  dolphin::Dolphin,
+  obj_params::{
+    ObjParams,
+    c_header::{MemberKind, export_order, struct_layout},
+    validate::array_type,
+  },
+};
+
+/// category of the exported data types
+const NAMESPACE: &str = "/sup-smsac";
+
+fn xml_escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Ghidra data type of a primitive type, and whether it is defined in [`NAMESPACE`]
+fn ghidra_type<D: Dolphin>(db: &ObjParams<D>, type_: &str) -> (String, bool) {
+  let builtin = match type_ {
+    "u8" => "uchar",
+    "s8" => "sbyte",
+    "u16" => "ushort",
+    "s16" => "short",
+    "u32" => "uint",
+    "s32" => "int",
+    "float" => "float",
+    "double" => "double",
+    "string" => "char *",
+    "void*" => "void *",
+    t => {
+      if let Some(class) = t.strip_suffix('*') {
+        return match db.contains_key(class) {
+          true => (format!("{class} *"), true),
+          false => ("void *".into(), false),
+        };
+      }
+      return match array_type(t) {
+        Some(("char", len)) => (format!("char[{len}]"), false),
+        Some(("u8", len)) => (format!("uchar[{len}]"), false),
+        _ => ("uint".into(), false),
+      };
+    },
+  };
+  (builtin.into(), false)
+}
+
+/// Export classes (`root` and the classes embedded in it, or all classes)
+/// as the data types of Ghidra XML (File > Import File... > XML)
+pub fn export_ghidra_xml<D: Dolphin>(db: &ObjParams<D>, root: Option<&str>) -> Result<String, String> {
+  let order = export_order(db, root)?;
+  let mut out = String::new();
+  out.push_str("<?xml version=\"1.0\" standalone=\"yes\"?>\n");
+  out.push_str("<PROGRAM NAME=\"sup-smsac\" IMAGE_BASE=\"80000000\">\n");
+  out.push_str("  <PROCESSOR NAME=\"PowerPC\" LANGUAGE_PROVIDER=\"PowerPC:BE:32:default\" ENDIAN=\"big\" />\n");
+  out.push_str("  <DATATYPES>\n");
+  for (name, class) in order.iter() {
+    let layout = struct_layout(db, name, class);
+    writeln!(out, "    <STRUCTURE NAME=\"{}\" NAMESPACE=\"{NAMESPACE}\" SIZE=\"{:#x}\">",
+      xml_escape(name), layout.size).unwrap();
+    for m in layout.members.iter() {
+      let (type_, in_namespace) = match m.kind {
+        MemberKind::Primitive(type_) => ghidra_type(db, type_),
+        MemberKind::Class(type_) => (type_.into(), true),
+      };
+      let namespace = match in_namespace {
+        true => NAMESPACE,
+        false => "/",
+      };
+      write!(out, "      <MEMBER OFFSET=\"{:#x}\" DATATYPE=\"{}\" DATATYPE_NAMESPACE=\"{namespace}\" NAME=\"{}\" SIZE=\"{:#x}\"",
+        m.offset, xml_escape(&type_), xml_escape(&m.ident), m.size).unwrap();
+      match m.notes.is_empty() {
+        true => out.push_str(" />\n"),
+        false => writeln!(out, ">\n        <REGULAR_CMT>{}</REGULAR_CMT>\n      </MEMBER>", xml_escape(m.notes)).unwrap(),
+      }
+    }
+    out.push_str("    </STRUCTURE>\n");
+  }
+  out.push_str("  </DATATYPES>\n</PROGRAM>\n");
+  Ok(out)
+}
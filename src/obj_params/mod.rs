@@ -1,134 +1,104 @@
 /// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
 /// SPDX-License-Identifier: MIT OR Apache-2.0
 
-use std::fs::{read_dir, File};
-use std::io::BufReader;
+use std::fs::read_dir;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use serde_json;
-use serde::{Deserialize, Deserializer, de::{self, Visitor}};
+use serde::{Deserialize, Serialize};
 use crate::{
   addr::{Addr, AddrOffsets},
+  json_schema::JsonSchema,
   big_endian::DecodeBE,
   dolphin::Dolphin,
-  sms::SMSDolphin,
+  sms::{SMSDolphin, SMSVersion},
 };
+use sup_smsac_derive::JsonSchema;
 
 mod field_reader;
 use field_reader::*;
+/// Binary cache of the parsed files and the resolved ObjectParameters.
+/// The resolved ObjectParameters are used as is if the path, modification time and size
+/// of every file to load are unchanged; otherwise only the modified files are parsed again,
+/// and only the classes depending on them are resolved again.
+/// Readers are not cached but restored from the types of the fields
+mod cache;
+pub use sup_smsac_obj_params::diagnostic;
+pub mod dmw;
+pub mod bench;
+pub mod c_header;
+pub mod condition;
+use condition::{FieldCondition, resolve_condition};
+pub use sup_smsac_obj_params::expr;
+use expr::{ComputedExpr, is_numeric_type};
+pub mod ghidra;
+pub mod tree;
+use tree::{FieldNode, FieldNodeKind, UNION_TYPE, find_node, id_segment};
+use diagnostic::{ObjParamsDiagnostic, class_path};
+use sup_smsac_obj_params::definition;
+pub use definition::ObjParamsOffsetEntryFormat;
+use definition::*;
+use sup_smsac_obj_params::validate;
+use validate::{validate, array_type, class_size};
 
-/**** original json ****/
-#[derive(Debug, Deserialize)]
-struct ObjParamsJson {
-  offsets: Box<[ObjParamsOffsetEntry]>,
+/// A class converted into ObjectParameters from the other formats (see [`dmw`] and [`c_header`])
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImportedClass {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<u32>,
+  pub offsets: Vec<ImportedField>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ObjParamsOffsetEntry {
-  #[serde(deserialize_with = "deserialize_obj_params_offset_entry")]
-  offset: AddrOffsets,
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImportedField {
+  pub offset: ImportedOffset,
   #[serde(rename = "type")]
-  type_: Arc<str>,
-  name: Arc<str>,
-  notes: Arc<str>,
-  #[serde(
-    default = "ObjParamsOffsetEntryFormat::none",
-    deserialize_with = "deserialize_obj_params_offset_entry_format",
-  )]
-  format: Option<ObjParamsOffsetEntryFormat>,
-  hidden: Option<bool>,
-}
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum ObjParamsOffsetEntryFormat {
-  Hex,
-}
-impl ObjParamsOffsetEntryFormat {
-  #[inline]
-  fn none() -> Option<ObjParamsOffsetEntryFormat> {
-    None
-  }
+  pub type_: String,
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub notes: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub format: Option<String>,
 }
 
-impl std::fmt::Display for ObjParamsOffsetEntryFormat {
-  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-    match self {
-      Self::Hex => write!(fmt, "hex"),
-    }
-  }
+/// Offset in hex, or `[offset, ...pointer offsets]`
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ImportedOffset {
+  Offset(String),
+  Pointer(Vec<String>),
 }
 
-fn deserialize_obj_params_offset_entry<'de, D>(deserializer: D) -> Result<AddrOffsets, D::Error>
-where
-  D: Deserializer<'de>,
-{
-  struct ValueVisitor;
-  impl<'de> Visitor<'de> for ValueVisitor {
-    type Value = AddrOffsets;
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-      formatter.write_str("a hex string or a non-empty array of hex string")
-    }
-    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
-    where
-      E: de::Error,
-    {
-      u32::from_str_radix(value, 16)
-        .map_err(|e| E::custom(e))
-        .map(|x| AddrOffsets(x, Box::from([])))
-    }
-    fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
-    where
-      S: de::SeqAccess<'de>,
-    {
-      let mut arr = match seq.size_hint() {
-        Some(size) => Vec::<u32>::with_capacity(size),
-        None => Vec::<u32>::new(),
-      };
-      while let Some(value) = seq.next_element::<Cow<'de, str>>()? {
-        arr.push(u32::from_str_radix(&value, 16).map_err(de::Error::custom)?);
-      }
-      arr.split_first()
-        .map(|p| AddrOffsets(*p.0, p.1.into()))
-        .ok_or_else(|| de::Error::custom("Offset array must not be empty"))
-    }
-  }
-
-  deserializer.deserialize_any(ValueVisitor)
-}
-
-fn deserialize_obj_params_offset_entry_format<'de, D>(deserializer: D)
-  -> Result<Option<ObjParamsOffsetEntryFormat>, D::Error>
-where
-  D: Deserializer<'de>,
-{
-  struct ValueVisitor;
-  impl<'de> Visitor<'de> for ValueVisitor {
-    type Value = Option<ObjParamsOffsetEntryFormat>;
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-      formatter.write_str("\"hex\" or omitted")
-    }
-    fn visit_none<E>(self) -> Result<Self::Value, E> {
-      Ok(None)
-    }
-    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
-    where
-      E: de::Error,
-    {
-      match value {
-        "hex" => Ok(Some(ObjParamsOffsetEntryFormat::Hex)),
-        _ => Err(E::unknown_variant(value, &["hex"])),
-      }
-    }
-  }
-
-  deserializer.deserialize_any(ValueVisitor)
-}
+/// Classes of an ObjectParameters file by name
+pub type ImportedClasses = BTreeMap<String, ImportedClass>;
 
 /**** parsed ****/
 pub enum ObjectType<D: Dolphin> {
   Primitive(ClassFieldReader<D>),
-  Class(Box<[ClassField<D>]>),
+  /// classes restored from the cache are shared by the versions in which they are the same
+  Class(Arc<ClassType<D>>),
+}
+pub struct ClassType<D: Dolphin> {
+  /// flattened fields
+  pub fields: Box<[ClassField<D>]>,
+  /// fields grouped by the embedded classes
+  pub tree: Box<[FieldNode]>,
+  /// `None` if unknown
+  pub size: Option<u32>,
+}
+impl<D: Dolphin> ClassType<D> {
+  /// Indices of the fields under the node with the given id
+  pub fn field_indices(&self, id: &str) -> Option<Vec<usize>> {
+    find_node(&self.tree, id).map(|node| {
+      let mut indices = Vec::new();
+      node.field_indices(&mut indices);
+      indices
+    })
+  }
 }
 impl<D: Dolphin> ObjectType<D> {
   fn new_primitive<T: DecodeBE + ToString + Send + Sync + 'static>() -> Self {
@@ -138,11 +108,20 @@ impl<D: Dolphin> ObjectType<D> {
 
 type ClassFieldReader<D> = Arc<dyn FieldReader<D, String> + Send + Sync>;
 pub struct ClassField<D: Dolphin> {
+  /// see [`FieldNode`]
+  pub id: Arc<str>,
   pub offset: AddrOffsets,
   pub type_: Arc<str>,
   pub name: Arc<str>,
   pub notes: Arc<str>,
+  pub format: Option<ObjParamsOffsetEntryFormat>,
+  /// `Some` if the field is computed from the other fields instead of being read
+  pub expr: Option<ComputedExpr>,
+  /// conditions which must all hold for the field to be applicable
+  pub when: Box<[FieldCondition]>,
   pub class: Arc<str>,
+  /// file in which the field is defined
+  pub file: Arc<str>,
   pub reader: ClassFieldReader<D>,
 }
 impl<D: Dolphin> FieldReader<D, String> for ClassField<D> {
@@ -152,95 +131,689 @@ impl<D: Dolphin> FieldReader<D, String> for ClassField<D> {
   }
 }
 
+/// type of the computed fields
+pub const EXPR_TYPE: &str = "expr";
+
+/// default location of ObjectParameters relative to the root directory
+pub const OBJ_PARAMS_DIR: &str = "res/ObjectParameters";
+
+/// Default location of the binary cache of ObjectParameters
+pub fn obj_params_cache_path() -> Option<PathBuf> {
+  std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    .or_else(|| std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from))
+    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+    .map(|dir| dir.join("sup-smsac/ObjectParameters.cache"))
+}
+
 pub type ObjParams<D> = HashMap<Arc<str>, ObjectType<D>>;
-pub type ObjParamsLoadResult<D> = Result<ObjParams<D>, std::io::Error>;
-pub fn load_obj_params(dir: &Path) -> ObjParamsLoadResult<SMSDolphin> {
-  type D = SMSDolphin; // TODO
-  let entry_reader = read_dir(dir)?;
-  let mut db_raw = HashMap::<Arc<str>, Arc<ObjParamsJson>>::new();
-  entry_reader.for_each(|entry| {
-    let Ok(entry) = entry.map_err(|e| eprintln!("Fail to get entry: {e}")) else {return};
-    let path = entry.path();
-    if Some(true) != path.extension().map(|e| e == "json") {return}
-    let Ok(file) = File::open(&path)
-      .map_err(|e| eprintln!("Fail to open file \"{}\": {e}", path.to_string_lossy())) else {return};
-    let reader = BufReader::new(file);
-    let Ok(o) = serde_json::from_reader::<_, HashMap<Arc<str>, Arc<ObjParamsJson>>>(reader)
-      .map_err(|e| eprintln!("Fail to parse {}: {e}", path.to_string_lossy())) else {return};
-    for e in o {
-      db_raw.insert(e.0.clone(), e.1.clone());
+pub struct ObjParamsDB<D: Dolphin> {
+  versions: HashMap<SMSVersion, ObjParams<D>>,
+  /// ObjectParameters of the game profiles with their own layer (see [`ObjParamsProfileLayer`])
+  profiles: HashMap<Arc<str>, ObjParams<D>>,
+  pub diagnostics: Box<[ObjParamsDiagnostic]>,
+}
+impl<D: Dolphin> ObjParamsDB<D> {
+  #[inline]
+  pub fn get(&self, ver: SMSVersion) -> Option<&ObjParams<D>> {
+    self.versions.get(&ver)
+  }
+  /// ObjectParameters of the game profile if it has its own layer, or those of the version
+  pub fn get_profile(&self, profile: Option<&str>, ver: SMSVersion) -> Option<&ObjParams<D>> {
+    profile.and_then(|name| self.profiles.get(name)).or_else(|| self.get(ver))
+  }
+  pub fn has_error(&self) -> bool {
+    self.diagnostics.iter().any(|e| e.is_error())
+  }
+}
+pub type ObjParamsLoadResult<D> = Result<ObjParamsDB<D>, std::io::Error>;
+/// Load ObjectParameters from the directories in order.
+/// Later layers can add or replace classes, or patch the classes defined in the previous layers
+pub fn load_obj_params<P: AsRef<Path>>(dirs: &[P]) -> ObjParamsLoadResult<SMSDolphin> {
+  ObjParamsLoader::new(dirs).load()
+}
+
+/// ObjectParameters directory of a game profile, e.g. to patch the classes changed by a ROM hack.
+/// Its files are loaded after the directories of all versions, and applied to the base version only
+#[derive(Debug, Clone)]
+pub struct ObjParamsProfileLayer {
+  pub profile: Arc<str>,
+  pub base: SMSVersion,
+  pub dir: Box<Path>,
+}
+
+/// An ObjectParameters file, which is parsed again when its modification time or size changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ObjParamsFileKey {
+  path: PathBuf,
+  mtime: SystemTime,
+  len: u64,
+}
+
+/// A parsed ObjectParameters file
+struct ObjParamsFile {
+  mtime: SystemTime,
+  len: u64,
+  /// hash of the content
+  hash: u64,
+  content: ObjParamsFileContent,
+}
+
+/// An embedded class with its sources, or an unknown type
+type ClassDep = (Arc<str>, Option<Box<[FileSource]>>);
+
+/// A resolved class and the definitions it is resolved from, which is reused
+/// by the next load while the definitions are unchanged
+#[derive(Clone)]
+struct ResolvedClass {
+  class: Arc<ClassType<SMSDolphin>>,
+  /// the class itself and the classes it embeds (recursively) with their sources,
+  /// or `None` for the unknown types
+  deps: Box<[ClassDep]>,
+  /// length of the longest chain of the embedded classes
+  height: usize,
+  /// whether the class is resolved without cycles or too deep nesting,
+  /// in which case the result does not depend on the order of resolution
+  clean: bool,
+  /// problems found while resolving the class itself
+  diagnostics: Box<[ObjParamsDiagnostic]>,
+}
+impl ResolvedClass {
+  /// Whether the class can be reused for `db_raw` when embedded at the depth
+  fn reusable(&self, db_raw: &HashMap<Arc<str>, Arc<ObjParamsClass>>, depth: usize) -> bool {
+    self.clean && depth + self.height < MAX_EMBED_DEPTH &&
+      self.deps.iter().all(|(name, sources)| db_raw.get(name).map(|o| &o.sources[..]) == sources.as_deref())
+  }
+}
+/// The resolved classes of each version
+type ResolvedClasses = HashMap<SMSVersion, HashMap<Arc<str>, ResolvedClass>>;
+
+/// Loads ObjectParameters, re-parsing only the files modified since the last load,
+/// and resolving only the classes depending on them
+pub struct ObjParamsLoader {
+  dirs: Box<[Box<Path>]>,
+  profiles: Box<[ObjParamsProfileLayer]>,
+  files: HashMap<PathBuf, ObjParamsFile>,
+  resolved: ResolvedClasses,
+  /// file to cache the parsed files and the resolved ObjectParameters in (see [`cache`])
+  cache: Option<Box<Path>>,
+}
+impl ObjParamsLoader {
+  pub fn new<P: AsRef<Path>>(dirs: &[P]) -> Self {
+    Self {
+      dirs: dirs.iter().map(|dir| Box::from(dir.as_ref())).collect(),
+      profiles: Box::new([]),
+      files: HashMap::new(),
+      resolved: HashMap::new(),
+      cache: None,
+    }
+  }
+
+  /// Cache the parsed files and the resolved ObjectParameters in `path` for the next run
+  pub fn with_cache<P: AsRef<Path>>(mut self, path: P) -> Self {
+    self.cache = Some(Box::from(path.as_ref()));
+    self
+  }
+
+  /// Resolve the ObjectParameters of the game profiles with their own layer too
+  pub fn with_profiles(mut self, profiles: Box<[ObjParamsProfileLayer]>) -> Self {
+    self.profiles = profiles;
+    self
+  }
+
+  #[inline]
+  pub fn dirs(&self) -> &[Box<Path>] {
+    &self.dirs
+  }
+
+  /// The directories of the game profiles (see [`ObjParamsLoader::with_profiles`])
+  pub fn profile_dirs(&self) -> impl Iterator<Item = &Box<Path>> {
+    self.profiles.iter().map(|o| &o.dir)
+  }
+
+  /// List the files to load in order
+  fn scan(&self, diagnostics: &mut Vec<ObjParamsDiagnostic>) -> Result<Vec<ObjParamsFileKey>, std::io::Error> {
+    let mut files = Vec::new();
+    for dir in self.dirs.iter() {
+      scan_dir(dir, &mut files, diagnostics)?;
+    }
+    Ok(files)
+  }
+
+  /// Parse the files to load, and return them with the key and the hash of every file read
+  fn parse_files(
+    &mut self, files: &[ObjParamsFileKey], diagnostics: &mut Vec<ObjParamsDiagnostic>,
+  ) -> (Vec<(FileSource, ObjParamsFileClasses)>, Vec<cache::LoadedFile>) {
+    let mut parsed = Vec::with_capacity(files.len());
+    let mut loaded = Vec::<cache::LoadedFile>::with_capacity(files.len());
+    for key in files.iter() {
+      let file_name: Arc<str> = Arc::from(key.path.to_string_lossy());
+      match self.parse_file(key, &file_name) {
+        Ok((hash, content)) => {
+          loaded.push((key.clone(), hash, content.clone()));
+          match content {
+            Ok(o) => parsed.push(((file_name, hash), o)),
+            Err(e) => diagnostics.push(ObjParamsDiagnostic::error(&file_name, "$".into(), e.to_string())),
+          }
+        },
+        Err(e) => diagnostics.push(ObjParamsDiagnostic::error(&file_name, "$".into(),
+          format!("Fail to open file: {e}"))),
+      }
+    }
+    (parsed, loaded)
+  }
+
+  /// The hash and the classes of the file, which is parsed only if it is modified
+  fn parse_file(
+    &mut self, key: &ObjParamsFileKey, file_name: &Arc<str>,
+  ) -> Result<(u64, ObjParamsFileContent), std::io::Error> {
+    let ObjParamsFileKey {path, mtime, len} = key;
+    if let Some(o) = self.files.get(path).filter(|o| o.mtime == *mtime && o.len == *len) {
+      return Ok((o.hash, o.content.clone()));
+    }
+    let src = std::fs::read(path)?;
+    let (hash, content) = (content_hash(&src), parse_file_content(&src, file_name));
+    self.files.insert(path.clone(), ObjParamsFile {mtime: *mtime, len: *len, hash, content: content.clone()});
+    Ok((hash, content))
+  }
+
+  pub fn load(&mut self) -> ObjParamsLoadResult<SMSDolphin> {
+    let mut diagnostics = Vec::<ObjParamsDiagnostic>::new();
+    let files = self.scan(&mut diagnostics)?;
+    // restore the parsed files and the resolved classes of the previous run
+    if self.files.is_empty() {
+      if let Some(cached) = self.cache.as_deref().and_then(|path| cache::read_cache(path, &files)) {
+        self.files = cached.files.into_iter()
+          .map(|(key, hash, content)| (key.path, ObjParamsFile {mtime: key.mtime, len: key.len, hash, content}))
+          .collect();
+        self.resolved = cached.resolved;
+        // nothing is modified (the layers of the game profiles are not cached)
+        if let (Some(db), true, true) = (cached.db, diagnostics.is_empty(), self.profiles.is_empty()) {
+          return Ok(db);
+        }
+      }
+    }
+
+    let (parsed, loaded) = self.parse_files(&files, &mut diagnostics);
+    let (mut db, resolved) = build_db(&parsed, diagnostics, &self.resolved);
+    self.resolved = resolved;
+    let mut loaded_paths = loaded.iter().map(|(key, _, _)| key.path.clone()).collect::<HashSet<_>>();
+    let profiles = self.profiles.clone();
+    let mut diagnostics = db.diagnostics.to_vec();
+    for layer in profiles.iter() {
+      // a missing layer is reported instead of failing the whole load, since it only affects the profile
+      let mut profile_files = Vec::new();
+      if let Err(e) = scan_dir(&layer.dir, &mut profile_files, &mut diagnostics) {
+        diagnostics.push(ObjParamsDiagnostic::warning(&Arc::from(layer.dir.to_string_lossy()), "$".into(),
+          format!("Fail to open ObjectParameters of profile \"{}\": {e}", layer.profile)));
+        continue;
+      }
+      let (profile_parsed, profile_loaded) = self.parse_files(&profile_files, &mut diagnostics);
+      loaded_paths.extend(profile_loaded.into_iter().map(|(key, _, _)| key.path));
+      // the classes unchanged by the layer are reused from the base
+      let (mut profile_db, _) = build_db(&[&parsed[..], &profile_parsed[..]].concat(), Vec::new(), &self.resolved);
+      diagnostics.extend(profile_db.diagnostics.iter().cloned());
+      if let Some(types) = profile_db.versions.remove(&layer.base) {
+        db.profiles.insert(layer.profile.clone(), types);
+      }
+    }
+    // the same problem is reported once even if found by the profiles too
+    let mut seen = HashSet::new();
+    diagnostics.retain(|e| seen.insert(e.clone()));
+    db.diagnostics = diagnostics.into();
+    // forget the removed files
+    self.files.retain(|path, _| loaded_paths.contains(path));
+
+    if let Some(path) = &self.cache {
+      if let Err(e) = cache::write_cache(path, &files, &db, &loaded, &self.resolved) {
+        eprintln!("Fail to write ObjectParameters cache {}: {e}", path.to_string_lossy());
+      }
     }
+    Ok(db)
+  }
+}
+
+/// Append the JSON files in the directory (sorted by name, as later files take precedence)
+fn scan_dir(
+  dir: &Path, files: &mut Vec<ObjParamsFileKey>, diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) -> Result<(), std::io::Error> {
+  let dir_name: Arc<str> = Arc::from(dir.to_string_lossy());
+  let entry_reader = read_dir(dir)
+    .map_err(|e| std::io::Error::new(e.kind(), format!("{dir_name}: {e}")))?;
+  let mut paths = entry_reader.filter_map(|entry| match entry {
+    Ok(entry) => Some(entry.path()),
+    Err(e) => {
+      diagnostics.push(ObjParamsDiagnostic::error(&dir_name, "$".into(),
+        format!("Fail to get entry: {e}")));
+      None
+    },
+  }).filter(|path| Some(true) == path.extension().map(|e| e == "json"))
+    .collect::<Vec<_>>();
+  // later files take precedence
+  paths.sort();
+
+  for path in paths {
+    match std::fs::metadata(&path).and_then(|meta| Ok((meta.modified()?, meta.len()))) {
+      Ok((mtime, len)) => files.push(ObjParamsFileKey {path, mtime, len}),
+      Err(e) => diagnostics.push(ObjParamsDiagnostic::error(&Arc::from(path.to_string_lossy()), "$".into(),
+        format!("Fail to open file: {e}"))),
+    }
+  }
+  Ok(())
+}
+
+/// Hash of the content of a file, which identifies the version of the file
+fn content_hash(src: &[u8]) -> u64 {
+  let mut hasher = std::collections::hash_map::DefaultHasher::new();
+  src.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// Resolve the classes of the parsed files (later files take precedence) for every version.
+/// The classes resolved by the previous load (`prev`) are reused if their sources are unchanged
+fn build_db(
+  files: &[(FileSource, ObjParamsFileClasses)],
+  mut diagnostics: Vec<ObjParamsDiagnostic>,
+  prev: &ResolvedClasses,
+) -> (ObjParamsDB<SMSDolphin>, ResolvedClasses) {
+  let (db_raw, patches) = collect_classes(files, &mut diagnostics);
+  let mut versions = HashMap::new();
+  let mut resolved = HashMap::new();
+  for ver in SMSVersion::ALL {
+    let db_ver = classes_of_version(&db_raw, &patches, ver, &mut diagnostics);
+    validate(&db_ver, ver, &mut diagnostics);
+    let (types, resolved_ver) = build_obj_params(&db_ver, ver, &mut diagnostics, prev.get(&ver));
+    versions.insert(ver, types);
+    resolved.insert(ver, resolved_ver);
+  }
+
+  // the same problem is reported once for each version
+  let mut seen = HashSet::new();
+  diagnostics.retain(|e| seen.insert(e.clone()));
+  (ObjParamsDB {versions, profiles: HashMap::new(), diagnostics: diagnostics.into()}, resolved)
+}
+
+/// The id of a node of a class, with `#2`, `#3`, ... appended if it is already used
+/// (e.g. by the fields without a name), so that the ids of a class are unique
+fn unique_id(ids: &mut HashSet<Arc<str>>, id: String) -> Arc<str> {
+  let id: Arc<str> = match ids.contains(&*id) {
+    false => Arc::from(id),
+    true => (2..).map(|n| Arc::from(format!("{id}#{n}"))).find(|o| !ids.contains(o)).unwrap(),
+  };
+  ids.insert(id.clone());
+  id
+}
+
+/// The unions of a class by name, as the index of the group in the tree, its id and its members
+type Unions = HashMap<Arc<str>, (usize, Arc<str>, Vec<FieldNode>)>;
+
+/// The id of the node of a field, which is under the group of its union if any
+fn field_id(
+  ids: &mut HashSet<Arc<str>>,
+  tree: &mut Vec<FieldNode>,
+  unions: &mut Unions,
+  field: &ObjParamsField,
+  offset: &AddrOffsets,
+) -> Arc<str> {
+  let Some(union) = &field.union else {
+    return unique_id(ids, id_segment(&field.name));
+  };
+  let (_, prefix, _) = unions.entry(union.clone()).or_insert_with(|| {
+    let id = unique_id(ids, id_segment(union));
+    tree.push(FieldNode {
+      id: id.clone(),
+      name: union.clone(),
+      kind: FieldNodeKind::Group {
+        offset: offset.clone(),
+        type_: Arc::from(UNION_TYPE),
+        notes: Arc::from(""),
+        file: field.file.clone(),
+        children: [].into(),
+      },
+    });
+    (tree.len()-1, id, Vec::new())
   });
+  let prefix = prefix.clone();
+  unique_id(ids, format!("{prefix}{}", id_segment(&field.name)))
+}
+
+/// Add the node of a field to the tree, or to the group of its union
+fn push_node(tree: &mut Vec<FieldNode>, unions: &mut Unions, field: &ObjParamsField, node: FieldNode) {
+  match field.union.as_ref().and_then(|union| unions.get_mut(union)) {
+    Some((_, _, members)) => members.push(node),
+    None => tree.push(node),
+  }
+}
+
+/// Find a field by the name of a field defined in the class itself (see `direct`),
+/// or by the id of a (nested) field
+fn find_field(tree: &[FieldNode], direct: &HashMap<&str, usize>, name: &str) -> Option<usize> {
+  match name.starts_with('/') {
+    true => match find_node(tree, name).map(|node| &node.kind) {
+      Some(FieldNodeKind::Field(i)) => Some(*i),
+      _ => None,
+    },
+    false => direct.get(name).copied(),
+  }
+}
 
+/// types which are always defined
+const PRIMITIVE_TYPES: [&str; 10] = [
+  "u8", "u16", "u32", "s8", "s16", "s32", "float", "double", "string", "void*",
+];
+
+/// Reader of a primitive type (including pointers and arrays), or `None` if the type is not primitive
+fn primitive_reader(type_: &str) -> Option<ClassFieldReader<SMSDolphin>> {
+  Some(match type_ {
+    "u8" => Arc::new(PrimitiveFieldReader::<u8>::new()),
+    "u16" => Arc::new(PrimitiveFieldReader::<u16>::new()),
+    "u32" => Arc::new(PrimitiveFieldReader::<u32>::new()),
+    "s8" => Arc::new(PrimitiveFieldReader::<i8>::new()),
+    "s16" => Arc::new(PrimitiveFieldReader::<i16>::new()),
+    "s32" => Arc::new(PrimitiveFieldReader::<i32>::new()),
+    "float" => Arc::new(F32FieldReader),
+    "double" => Arc::new(PrimitiveFieldReader::<f64>::new()),
+    "string" => Arc::new(StringFieldReader),
+    "void*" => Arc::new(ClassNameReader),
+    t if t.ends_with('*') => Arc::new(PrimitiveFieldReader::<Addr>::new()),
+    t => match array_type(t)? {
+      ("char", len) => Arc::new(InlineStringFieldReader(len)),
+      ("u8", len) => Arc::new(HexFieldReader(len)),
+      _ => return None,
+    },
+  })
+}
+
+/// Reader of the fields of unknown types
+fn unknown_reader<D: Dolphin>() -> ClassFieldReader<D> {
+  Arc::new(PrimitiveFieldReader::<Addr>::new())
+}
+
+/// Reader of a formatted primitive type, or `None` if the format is not supported
+fn formatted_reader<D: Dolphin>(type_: &str, format: ObjParamsOffsetEntryFormat) -> Option<ClassFieldReader<D>> {
+  match (type_, format) {
+    ("u8", ObjParamsOffsetEntryFormat::Hex) => Some(Arc::new(HexFieldReader(1))),
+    ("u16", ObjParamsOffsetEntryFormat::Hex) => Some(Arc::new(HexFieldReader(2))),
+    ("u32", ObjParamsOffsetEntryFormat::Hex) => Some(Arc::new(HexFieldReader(4))),
+    _ => None,
+  }
+}
+
+/// maximum depth of nested embedded classes
+const MAX_EMBED_DEPTH: usize = 32;
+
+/// Resolve the classes of a version, reusing the classes resolved by the previous load (`prev`)
+/// whose definitions and embedded classes are unchanged.
+/// Returns the types and the resolved classes to reuse in the next load
+fn build_obj_params(
+  db_raw: &HashMap<Arc<str>, Arc<ObjParamsClass>>,
+  ver: SMSVersion,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+  prev: Option<&HashMap<Arc<str>, ResolvedClass>>,
+) -> (ObjParams<SMSDolphin>, HashMap<Arc<str>, ResolvedClass>) {
+  type D = SMSDolphin; // TODO
   struct Env<'a, D: Dolphin> {
-    db_raw: &'a HashMap::<Arc<str>, Arc<ObjParamsJson>>,
+    db_raw: &'a HashMap::<Arc<str>, Arc<ObjParamsClass>>,
+    ver: SMSVersion,
+    diagnostics: &'a mut Vec<ObjParamsDiagnostic>,
+    prev: Option<&'a HashMap<Arc<str>, ResolvedClass>>,
+    /// classes being resolved
+    stack: Vec<Arc<str>>,
     db_types: HashMap::<Arc<str>, ObjectType<D>>,
-    db_formatted: HashMap::<(&'a str, ObjParamsOffsetEntryFormat), ClassFieldReader<D>>,
+    resolved: HashMap<Arc<str>, ResolvedClass>,
+    /// types which are neither primitive nor defined
+    unknown: HashSet<Arc<str>>,
     reader_unk: ClassFieldReader<D>,
     type_addr: ObjectType<D>,
   }
-  fn resolve_type<'a, D: Dolphin>(env: &'a mut Env<D>, type_: Arc<str>) -> &'a ObjectType<D> {
+  /// Report a problem of the class being resolved
+  fn report(env: &mut Env<D>, own: &mut Vec<ObjParamsDiagnostic>, e: ObjParamsDiagnostic) {
+    own.push(e.clone());
+    env.diagnostics.push(e);
+  }
+  fn resolve_type<'a>(env: &'a mut Env<D>, type_: Arc<str>) -> &'a ObjectType<D> {
     if !env.db_types.contains_key(&type_) {
       if type_.ends_with('*') {
         return &env.type_addr;
       }
+      if let Some(reader) = primitive_reader(&type_) {
+        env.db_types.insert(type_.clone(), ObjectType::<D>::Primitive(reader));
+        return env.db_types.get(&type_).unwrap();
+      }
+      let prev = env.prev.and_then(|o| o.get(&type_)).filter(|o| o.reusable(env.db_raw, env.stack.len()));
+      if let Some(prev) = prev {
+        // resolve the embedded classes in the same order as resolving the class again
+        for (dep, _) in prev.deps.iter().skip(1) {
+          resolve_type(env, dep.clone());
+        }
+        env.diagnostics.extend(prev.diagnostics.iter().cloned());
+        env.resolved.insert(type_.clone(), prev.clone());
+        env.db_types.insert(type_.clone(), ObjectType::<D>::Class(prev.class.clone()));
+        return env.db_types.get(&type_).unwrap();
+      }
       let new_type = match env.db_raw.get(&type_) {
         Some(o) => {
+          // problems of this class, which are reported again when the class is reused
+          let mut own = Vec::<ObjParamsDiagnostic>::new();
+          // this class followed by the embedded classes in the order of resolution
+          let mut deps = vec![(type_.clone(), Some(Box::from(&o.sources[..])))];
+          let mut height = 0;
+          let mut clean = true;
           let mut class_fields = Vec::<ClassField<D>>::new();
-          for field in o.offsets.iter() {
+          let mut tree = Vec::<FieldNode>::new();
+          let mut unions = Unions::new();
+          // ids of the nodes of this class
+          let mut ids = HashSet::<Arc<str>>::new();
+          // name -> index of the fields defined in this class, referenced by conditions
+          let mut direct = HashMap::<&str, usize>::new();
+          // fields with a condition and the range of their flattened fields
+          let mut conditional = Vec::<(&ObjParamsField, &ObjParamsCondition, Range<usize>)>::new();
+          env.stack.push(type_.clone());
+          for field in o.fields.iter() {
             // skip hidden fields
-            if let Some(true) = field.hidden {continue}
+            if field.hidden {continue}
+            // skip fields absent in this version
+            let Some(offset) = field.offset.get(env.ver) else {continue};
+            let id = field_id(&mut ids, &mut tree, &mut unions, field, offset);
+            let begin = class_fields.len();
             // format
             if let Some(format) = field.format {
-              if let Some(reader) = env.db_formatted.get(&(&field.type_, format)) {
+              if let Some(reader) = formatted_reader(&field.type_, format) {
+                push_node(&mut tree, &mut unions, field, FieldNode {
+                  id: id.clone(),
+                  name: field.name.clone(),
+                  kind: FieldNodeKind::Field(begin),
+                });
+                direct.entry(&field.name).or_insert(begin);
                 class_fields.push(
                   ClassField {
-                    reader: reader.clone(),
-                    offset: field.offset.clone(),
+                    id,
+                    reader,
+                    offset: offset.clone(),
                     name: field.name.clone(),
                     notes: field.notes.clone(),
+                    format: Some(format),
+                    expr: None,
+                    when: [].into(),
                     type_: field.type_.clone(),
                     class: type_.clone(),
+                    file: field.file.clone(),
                   },
                 );
+                if let Some(when) = &field.when {
+                  conditional.push((field, when, begin..class_fields.len()));
+                }
                 continue;
-              } else {
-                eprintln!("format \"{format}\" cannot be used for type \"{}\" (in class \"{type_}\")", field.type_);
               }
             }
+            // the class must not embed itself
+            if let Some(pos) = env.stack.iter().position(|e| *e == field.type_) {
+              let message = format!("inheritance cycle: {} -> {}", env.stack[pos..].join(" -> "), field.type_);
+              report(env, &mut own, ObjParamsDiagnostic::error(&field.file,
+                class_path(&type_, format_args!(".offsets[{}].type", field.index)), message));
+              clean = false;
+              continue;
+            }
+            if env.stack.len() >= MAX_EMBED_DEPTH && env.db_raw.contains_key(&field.type_) {
+              let message = format!("classes are nested too deeply (> {MAX_EMBED_DEPTH}): {} -> {}",
+                env.stack.join(" -> "), field.type_);
+              report(env, &mut own, ObjParamsDiagnostic::error(&field.file,
+                class_path(&type_, format_args!(".offsets[{}].type", field.index)), message));
+              clean = false;
+              continue;
+            }
             // resolve
             match resolve_type(env, field.type_.clone()) {
-              ObjectType::<D>::Primitive(reader) => class_fields.push(
-                ClassField {
+              ObjectType::<D>::Primitive(reader) => {
+                push_node(&mut tree, &mut unions, field, FieldNode {
+                  id: id.clone(),
+                  name: field.name.clone(),
+                  kind: FieldNodeKind::Field(begin),
+                });
+                direct.entry(&field.name).or_insert(begin);
+                class_fields.push(ClassField {
+                  id,
                   reader: reader.clone(),
-                  offset: field.offset.clone(),
+                  offset: offset.clone(),
                   name: field.name.clone(),
                   notes: field.notes.clone(),
+                  format: None,
+                  expr: None,
+                  when: [].into(),
                   type_: field.type_.clone(),
                   class: type_.clone(),
-                },
-              ),
-              ObjectType::<D>::Class(subfields) => {
+                  file: field.file.clone(),
+                });
+              },
+              ObjectType::<D>::Class(subclass) => {
+                push_node(&mut tree, &mut unions, field, FieldNode {
+                  id: id.clone(),
+                  name: field.name.clone(),
+                  kind: FieldNodeKind::Group {
+                    offset: offset.clone(),
+                    type_: field.type_.clone(),
+                    notes: field.notes.clone(),
+                    file: field.file.clone(),
+                    children: subclass.tree.iter()
+                      .map(|e| e.embed(&id, offset, begin))
+                      .collect(),
+                  },
+                });
                 let is_name_template = field.name.contains('*');
-                for subfield in subfields.iter() {
+                for subfield in subclass.fields.iter() {
                   class_fields.push(ClassField {
+                    id: Arc::from(format!("{id}{}", subfield.id)),
                     reader: subfield.reader.clone(),
-                    offset: &field.offset + &subfield.offset,
+                    offset: offset + &subfield.offset,
                     name: match is_name_template {
                       true => Arc::from(field.name.replace('*', &subfield.name)),
                       false => subfield.name.clone(),
                     },
                     notes: subfield.notes.clone(),
+                    format: subfield.format,
+                    expr: subfield.expr.as_ref().map(|c| c.embed(begin)),
+                    when: subfield.when.iter()
+                      .map(|c| FieldCondition {field: begin + c.field, ..c.clone()})
+                      .collect(),
                     type_: subfield.type_.clone(),
                     class: subfield.class.clone(),
+                    file: subfield.file.clone(),
                   })
                 }
               },
             };
+            // the class depends on the embedded classes and the unknown types
+            if let Some(sub) = env.resolved.get(&field.type_) {
+              height = height.max(sub.height + 1);
+              clean &= sub.clean;
+              for dep in sub.deps.iter() {
+                if !deps.iter().any(|(name, _)| *name == dep.0) {
+                  deps.push(dep.clone());
+                }
+              }
+            } else if env.unknown.contains(&field.type_) && !deps.iter().any(|(name, _)| *name == field.type_) {
+              deps.push((field.type_.clone(), None));
+            }
+            if let Some(when) = &field.when {
+              conditional.push((field, when, begin..class_fields.len()));
+            }
           }
-          ObjectType::<D>::Class(class_fields.into())
+          env.stack.pop();
+          for (pos, _, members) in unions.into_values() {
+            if let FieldNodeKind::Group {children, ..} = &mut tree[pos].kind {
+              *children = members.into();
+            }
+          }
+          // computed fields follow the fields read from memory
+          for c in o.computed.iter().filter(|c| !c.hidden) {
+            let fields = c.expr.fields.iter().map(|name| {
+              let i = find_field(&tree, &direct, name)
+                .ok_or_else(|| format!("field \"{name}\" is not found"))?;
+              let field = &class_fields[i];
+              match field.expr.is_none() && is_numeric_type(&field.type_) {
+                true => Ok(i),
+                false => Err(format!("field \"{name}\" of type \"{}\" cannot be used in expressions", field.type_)),
+              }
+            }).collect::<Result<Box<[_]>, _>>();
+            let fields = match fields {
+              Ok(fields) => fields,
+              Err(e) => {
+                report(env, &mut own, ObjParamsDiagnostic::error(&c.file,
+                  class_path(&type_, format_args!(".offsets[{}].expr", c.index)), e));
+                continue;
+              },
+            };
+            let id = unique_id(&mut ids, id_segment(&c.name));
+            tree.push(FieldNode {
+              id: id.clone(),
+              name: c.name.clone(),
+              kind: FieldNodeKind::Field(class_fields.len()),
+            });
+            class_fields.push(ClassField {
+              id,
+              // computed fields are not read from memory
+              reader: env.reader_unk.clone(),
+              offset: AddrOffsets(0, [].into()),
+              name: c.name.clone(),
+              notes: c.notes.clone(),
+              format: None,
+              expr: Some(ComputedExpr {expr: c.expr.clone(), fields}),
+              when: [].into(),
+              type_: Arc::from(EXPR_TYPE),
+              class: type_.clone(),
+              file: c.file.clone(),
+            });
+          }
+          // the conditions of a field apply to all of its flattened fields
+          for (field, when, range) in conditional {
+            match resolve_condition(when, &class_fields, &tree, &direct) {
+              Ok(cond) => for f in class_fields[range].iter_mut() {
+                let mut conds = std::mem::take(&mut f.when).into_vec();
+                conds.push(cond.clone());
+                f.when = conds.into();
+              },
+              Err(e) => report(env, &mut own, ObjParamsDiagnostic::error(&field.file,
+                class_path(&type_, format_args!(".offsets[{}].when", field.index)), e)),
+            }
+          }
+          let n = own.len();
+          let size = class_size(&type_, o, &mut own);
+          env.diagnostics.extend_from_slice(&own[n..]);
+          let class = Arc::new(ClassType {
+            fields: class_fields.into(),
+            tree: tree.into(),
+            size,
+          });
+          env.resolved.insert(type_.clone(), ResolvedClass {
+            class: class.clone(),
+            deps: deps.into(),
+            height,
+            clean,
+            diagnostics: own.into(),
+          });
+          ObjectType::<D>::Class(class)
         },
         None => {
+          env.unknown.insert(type_.clone());
           ObjectType::<D>::Primitive(env.reader_unk.clone())
         },
       };
@@ -250,37 +823,517 @@ pub fn load_obj_params(dir: &Path) -> ObjParamsLoadResult<SMSDolphin> {
   }
 
   let mut env = Env {
-    db_raw: &db_raw,
-    db_types: HashMap::<Arc<str>, ObjectType<D>>::from([
-      (Arc::from("u8"), ObjectType::<D>::new_primitive::<u8>()),
-      (Arc::from("u16"), ObjectType::<D>::new_primitive::<u16>()),
-      (Arc::from("u32"), ObjectType::<D>::new_primitive::<u32>()),
-      (Arc::from("s8"), ObjectType::<D>::new_primitive::<i8>()),
-      (Arc::from("s16"), ObjectType::<D>::new_primitive::<i16>()),
-      (Arc::from("s32"), ObjectType::<D>::new_primitive::<i32>()),
-      (Arc::from("float"), ObjectType::<D>::Primitive(Arc::new(F32FieldReader))),
-      (Arc::from("string"), ObjectType::<D>::Primitive(Arc::new(StringFieldReader))),
-      (Arc::from("void*"), ObjectType::<D>::Primitive(Arc::new(ClassNameReader))),
-    ]),
-    db_formatted: HashMap::from([
-      (
-        ("u8", ObjParamsOffsetEntryFormat::Hex),
-        Arc::new(HexFieldReader(1)) as ClassFieldReader<D>,
-      ),
-      (
-        ("u16", ObjParamsOffsetEntryFormat::Hex),
-        Arc::new(HexFieldReader(2)) as ClassFieldReader<D>,
-      ),
-      (
-        ("u32", ObjParamsOffsetEntryFormat::Hex),
-        Arc::new(HexFieldReader(4)) as ClassFieldReader<D>,
-      ),
-    ]),
-    reader_unk: Arc::new(PrimitiveFieldReader::<Addr>::new()), // TODO
+    db_raw,
+    ver,
+    diagnostics,
+    prev,
+    stack: Vec::new(),
+    db_types: PRIMITIVE_TYPES.iter().map(|&type_| (
+      Arc::from(type_),
+      ObjectType::<D>::Primitive(primitive_reader(type_).unwrap()),
+    )).collect(),
+    resolved: HashMap::new(),
+    unknown: HashSet::new(),
+    reader_unk: unknown_reader(), // TODO
     type_addr: ObjectType::<D>::new_primitive::<Addr>(),
   };
-  for type_ in db_raw.keys() {
+  // resolve in a fixed order so that the same cycle is always reported in the same way
+  let mut types = db_raw.keys().collect::<Vec<_>>();
+  types.sort();
+  for type_ in types {
     resolve_type(&mut env, type_.clone());
   }
-  Ok(env.db_types)
+  (env.db_types, env.resolved)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// Resolve ObjectParameters files given as `(name, json)`
+  pub(crate) fn load_str(files: &[(&str, &str)]) -> ObjParamsDB<SMSDolphin> {
+    let files = files.iter()
+      .map(|&(name, src)| ((Arc::from(name), content_hash(src.as_bytes())), parse_file_content(src.as_bytes(), name).unwrap()))
+      .collect::<Vec<_>>();
+    build_db(&files, Vec::new(), &HashMap::new()).0
+  }
+
+  pub(crate) fn errors(db: &ObjParamsDB<SMSDolphin>) -> Vec<(&str, &str, &str)> {
+    db.diagnostics.iter()
+      .filter(|e| e.is_error())
+      .map(|e| (&*e.file, &*e.path, &*e.message))
+      .collect()
+  }
+
+  pub(crate) fn class<'a>(db: &'a ObjParamsDB<SMSDolphin>, name: &str) -> &'a ClassType<SMSDolphin> {
+    match db.get(SMSVersion::GMSJ01).and_then(|o| o.get(name)) {
+      Some(ObjectType::Class(class)) => class,
+      _ => panic!("class \"{name}\" is not resolved"),
+    }
+  }
+
+  pub(crate) fn field_ids(class: &ClassType<SMSDolphin>) -> Vec<&str> {
+    class.fields.iter().map(|o| &*o.id).collect()
+  }
+
+  #[test]
+  fn class_embedding_itself() {
+    let db = load_str(&[("a.json", r#"{
+      "A": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "x"},
+        {"offset": "4", "type": "A", "name": "self"}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), [("a.json", r#"$["A"].offsets[1].type"#, "inheritance cycle: A -> A")]);
+    // the offending field is skipped
+    assert_eq!(field_ids(class(&db, "A")), ["/x"]);
+  }
+
+  #[test]
+  fn mutual_embedding() {
+    let db = load_str(&[
+      ("a.json", r#"{"A": {"offsets": [{"offset": "0", "type": "B", "name": "b"}]}}"#),
+      ("b.json", r#"{"B": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "x"},
+        {"offset": "4", "type": "A", "name": "a"}
+      ]}}"#),
+    ]);
+    assert_eq!(errors(&db), [("b.json", r#"$["B"].offsets[1].type"#, "inheritance cycle: A -> B -> A")]);
+    assert_eq!(field_ids(class(&db, "A")), ["/b/x"]);
+    assert_eq!(field_ids(class(&db, "B")), ["/x"]);
+  }
+
+  #[test]
+  fn nesting_too_deep() {
+    // C00 -> C01 -> ... -> C39
+    let depth = MAX_EMBED_DEPTH + 8;
+    let classes = (0..depth).map(|i| {
+      let field = match i+1 < depth {
+        true => format!(r#"{{"offset": "0", "type": "C{:02}", "name": "c"}}"#, i+1),
+        false => r#"{"offset": "0", "type": "u32", "name": "x"}"#.to_string(),
+      };
+      format!(r#""C{i:02}": {{"offsets": [{field}]}}"#)
+    }).collect::<Vec<_>>();
+    let db = load_str(&[("c.json", &format!("{{{}}}", classes.join(",")))]);
+    let chain = (0..=MAX_EMBED_DEPTH).map(|i| format!("C{i:02}")).collect::<Vec<_>>().join(" -> ");
+    let message = format!("classes are nested too deeply (> {MAX_EMBED_DEPTH}): {chain}");
+    let path = format!(r#"$["C{:02}"].offsets[0].type"#, MAX_EMBED_DEPTH-1);
+    assert_eq!(errors(&db), [("c.json", &*path, &*message)]);
+    // the classes below the limit are still resolved on their own
+    assert_eq!(field_ids(class(&db, &format!("C{MAX_EMBED_DEPTH:02}"))).len(), 1);
+  }
+
+  #[test]
+  fn diamond_is_not_a_cycle() {
+    let db = load_str(&[("d.json", r#"{
+      "A": {"size": 4, "offsets": [{"offset": "0", "type": "u32", "name": "x"}]},
+      "B": {"size": 4, "offsets": [{"offset": "0", "type": "A", "name": "a"}]},
+      "C": {"size": 4, "offsets": [{"offset": "0", "type": "A", "name": "a"}]},
+      "D": {"size": 8, "offsets": [
+        {"offset": "0", "type": "B", "name": "b"},
+        {"offset": "4", "type": "C", "name": "c"}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), []);
+    let d = class(&db, "D");
+    assert_eq!(field_ids(d), ["/b/a/x", "/c/a/x"]);
+    assert_eq!(d.fields.iter().map(|o| o.offset.to_string()).collect::<Vec<_>>(), ["0", "4"]);
+  }
+
+  #[test]
+  fn duplicate_names_have_unique_ids() {
+    let db = load_str(&[("e.json", r#"{
+      "V": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]},
+      "E": {"offsets": [
+        {"offset": "0", "type": "u32"},
+        {"offset": "4", "type": "u32"},
+        {"offset": "8", "type": "u16", "name": "n"},
+        {"offset": "a", "type": "u16", "name": "n"},
+        {"offset": "c", "type": "u8", "name": "u", "union": "u"},
+        {"offset": "c", "type": "u8", "name": "u", "union": "u"},
+        {"offset": "10", "type": "V", "name": "u"},
+        {"offset": "14", "type": "V"},
+        {"name": "n", "expr": "{n} + 1"}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), []);
+    let e = class(&db, "E");
+    assert_eq!(field_ids(e), ["/", "/#2", "/n", "/n#2", "/u/u", "/u/u#2", "/u#2/x", "/#3/x", "/n#3"]);
+    let nodes = e.tree.iter().map(|o| &*o.id).collect::<Vec<_>>();
+    assert_eq!(nodes, ["/", "/#2", "/n", "/n#2", "/u", "/u#2", "/#3", "/n#3"]);
+    assert_eq!(e.field_indices("/u#2"), Some(vec![6]));
+    assert_eq!(e.field_indices("/u/u#2"), Some(vec![5]));
+  }
+
+  #[test]
+  fn union_members_are_grouped() {
+    let db = load_str(&[("u.json", r#"{
+      "U": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "kind"},
+        {"offset": "4", "type": "float", "name": "speed", "union": "data"},
+        {"offset": "8", "type": "u32", "name": "other"},
+        {"offset": "4", "type": "u32", "name": "count", "union": "data"}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), []);
+    let u = class(&db, "U");
+    // the fields keep the order of definition, while the members are grouped in the tree
+    assert_eq!(field_ids(u), ["/kind", "/data/speed", "/other", "/data/count"]);
+    assert_eq!(u.tree.iter().map(|o| &*o.id).collect::<Vec<_>>(), ["/kind", "/data", "/other"]);
+    let FieldNodeKind::Group {offset, type_, children, ..} = &u.tree[1].kind else {
+      panic!("the union is not a group");
+    };
+    assert_eq!((offset.to_string(), &**type_), ("4".into(), UNION_TYPE));
+    let children = children.iter().map(|o| (&*o.id, &o.kind)).collect::<Vec<_>>();
+    assert!(matches!(children[..], [("/data/speed", FieldNodeKind::Field(1)), ("/data/count", FieldNodeKind::Field(3))]));
+    assert_eq!(u.field_indices("/data"), Some(vec![1, 3]));
+  }
+
+  #[test]
+  fn patches_match_fields_by_offset() {
+    let db = load_str(&[
+      ("base.json", r#"{"A": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "x", "notes": "base"},
+        {"offset": "4", "type": "u32", "name": "y"},
+        {"offset": "8", "type": "float", "name": "z"}
+      ]}}"#),
+      ("mod.json", r#"{"A": {"patch": true, "offsets": [
+        {"offset": "0", "name": "X"},
+        {"offset": "4", "hidden": true},
+        {"offset": "c", "type": "u8", "name": "w"},
+        {"offset": "10", "name": "missing"}
+      ]}}"#),
+    ]);
+    assert_eq!(errors(&db), [(
+      "mod.json", r#"$["A"].offsets[3]"#, "field at offset 10 to patch is not found, and \"type\" is not specified",
+    )]);
+    let a = class(&db, "A");
+    // the hidden field is not resolved, and the added field follows the others
+    assert_eq!(field_ids(a), ["/X", "/z", "/w"]);
+    let field = |id: &str| a.fields.iter().find(|o| &*o.id == id).unwrap();
+    // only the specified keys are changed
+    assert_eq!((&*field("/X").type_, &*field("/X").notes), ("u32", "base"));
+    // the file of the last definition of each field
+    assert_eq!(a.fields.iter().map(|o| &*o.file).collect::<Vec<_>>(), ["mod.json", "base.json", "mod.json"]);
+  }
+
+  #[test]
+  fn patches_of_fields_sharing_the_offset() {
+    let db = load_str(&[
+      ("base.json", r#"{"A": {"offsets": [
+        {"offset": "0", "type": "u8", "name": "kind"},
+        {"offset": "4", "type": "float", "name": "speed", "union": "data"},
+        {"offset": "4", "type": "u32", "name": "count", "union": "data"},
+        {"offset": "4", "type": "u32", "name": "timer", "when": {"field": "kind", "equals": 1}}
+      ]}}"#),
+      ("mod.json", r#"{"A": {"patch": true, "offsets": [
+        {"offset": "4", "name": "count", "hidden": true},
+        {"offset": "4", "name": "speed", "notes": "patched"},
+        {"offset": "4", "type": "s16", "name": "angle", "union": "data"},
+        {"offset": "4", "notes": "ambiguous"}
+      ]}}"#),
+    ]);
+    assert_eq!(errors(&db), [(
+      "mod.json", r#"$["A"].offsets[3]"#,
+      r#"4 fields at offset 4 match the patch; specify "name" (or "union") of the field to patch"#,
+    )]);
+    let a = class(&db, "A");
+    assert_eq!(field_ids(a), ["/kind", "/data/speed", "/timer", "/data/angle"]);
+    let notes = a.fields.iter().map(|o| &*o.notes).collect::<Vec<_>>();
+    assert_eq!(notes, ["", "patched", "", ""]);
+  }
+
+  #[test]
+  fn conditions_of_embedded_classes_are_rebased() {
+    let db = load_str(&[("c.json", r#"{
+      "A": {"offsets": [
+        {"offset": "0", "type": "u8", "name": "kind"},
+        {"offset": "4", "type": "u32", "name": "x", "when": {"field": "kind", "equals": 1}}
+      ]},
+      "B": {"offsets": [
+        {"offset": "0", "type": "u16", "name": "mode"},
+        {"offset": "4", "type": "A", "name": "a", "when": {"field": "mode", "equals": 2}},
+        {"offset": "10", "type": "u32", "name": "y", "when": {"field": "/a/kind", "equals": 3}}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), []);
+    let b = class(&db, "B");
+    assert_eq!(field_ids(b), ["/mode", "/a/kind", "/a/x", "/y"]);
+    let when = |i: usize| b.fields[i].when.iter().map(|c| (&*b.fields[c.field].id, c.equals)).collect::<Vec<_>>();
+    // the conditions of the embedded class refer to its fields in the embedding class,
+    // followed by the condition of the embedding field
+    assert_eq!(when(1), [("/mode", 2)]);
+    assert_eq!(when(2), [("/a/kind", 1), ("/mode", 2)]);
+    assert_eq!(when(3), [("/a/kind", 3)]);
+  }
+
+  fn warnings(db: &ObjParamsDB<SMSDolphin>) -> Vec<(&str, &str, &str)> {
+    db.diagnostics.iter()
+      .filter(|e| !e.is_error())
+      .map(|e| (&*e.file, &*e.path, &*e.message))
+      .collect()
+  }
+
+  #[test]
+  fn unknown_type() {
+    let db = load_str(&[("a.json", r#"{"A": {"offsets": [{"offset": "0", "type": "TFoo", "name": "x"}]}}"#)]);
+    assert_eq!(errors(&db), [("a.json", r#"$["A"].offsets[0].type"#, r#"unknown type "TFoo""#)]);
+  }
+
+  #[test]
+  fn field_exceeding_size() {
+    let db = load_str(&[("a.json", r#"{
+      "A": {"size": 4, "offsets": [{"offset": "2", "type": "u32", "name": "x"}]},
+      "B": {"size": 8, "offsets": [{"offset": {"GMSJ01": "4", "GMSE01": "6"}, "type": "u32", "name": "y"}]},
+      "C": {"size": 8, "offsets": [{"offset": "0", "type": "A", "name": "a"}]}
+    }"#)]);
+    assert_eq!(errors(&db), [
+      ("a.json", r#"$["A"].offsets[0].offset"#, r#"field "x" [0x2, 0x6) exceeds the size of the class (0x4)"#),
+      ("a.json", r#"$["B"].offsets[0].offset"#, r#"field "y" [0x6, 0xA) exceeds the size of the class (0x8) (in GMSE01)"#),
+    ]);
+  }
+
+  #[test]
+  fn overlapping_fields() {
+    let db = load_str(&[("a.json", r#"{"A": {"offsets": [
+      {"offset": "0", "type": "u32", "name": "a"},
+      {"offset": "2", "type": "u16", "name": "b"},
+      {"offset": "8", "type": "u32", "name": "u0", "union": "u"},
+      {"offset": "8", "type": "float", "name": "u1", "union": "u"},
+      {"offset": "c", "type": "u8", "name": "kind"},
+      {"offset": "c", "type": "u32", "name": "c", "when": {"field": "kind", "equals": 1}}
+    ]}}"#)]);
+    assert_eq!(errors(&db), []);
+    // union members and conditional fields are meant to overlap
+    assert_eq!(warnings(&db), [
+      ("a.json", r#"$["A"].offsets[1].offset"#, r#"field "b" overlaps with "a" (offsets[0] in "a.json")"#),
+    ]);
+  }
+
+  #[test]
+  fn string_size() {
+    let db = load_str(&[("a.json", r#"{
+      "A": {"size": "4", "offsets": [{"offset": "2", "type": "u32", "name": "x"}]},
+      "B": {"size": "0x4", "offsets": []},
+      "C": {"size": true, "offsets": []}
+    }"#)]);
+    assert_eq!(warnings(&db), [
+      ("a.json", r#"$["A"].size"#, r#"size should be a number instead of a string: "4""#),
+    ]);
+    // the size given as a string still applies (the sizes are checked in any order)
+    let mut errors = errors(&db);
+    errors.sort();
+    assert_eq!(errors, [
+      ("a.json", r#"$["A"].offsets[0].offset"#, r#"field "x" [0x2, 0x6) exceeds the size of the class (0x4)"#),
+      ("a.json", r#"$["B"].size"#, r#"invalid size "0x4": invalid digit found in string"#),
+      ("a.json", r#"$["C"].size"#, "size must be a number, got true"),
+    ]);
+  }
+
+  #[test]
+  fn duplicate_class() {
+    let db = load_str(&[
+      ("a.json", r#"{"A": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]}}"#),
+      ("b.json", r#"{"A": {"offsets": [{"offset": "0", "type": "u32", "name": "y"}]}}"#),
+    ]);
+    assert_eq!(errors(&db), []);
+    assert_eq!(warnings(&db), [
+      ("b.json", r#"$["A"]"#, r#"class "A" is also defined in "a.json", which is overridden"#),
+    ]);
+  }
+
+  #[test]
+  fn invalid_format() {
+    let db = load_str(&[("a.json", r#"{"A": {"offsets": [
+      {"offset": "0", "type": "u32", "name": "x", "format": "hex"},
+      {"offset": "4", "type": "float", "name": "y", "format": "hex"}
+    ]}}"#)]);
+    assert_eq!(errors(&db), [("a.json", r#"$["A"].offsets[1].format"#, r#"format "hex" cannot be used for type "float""#)]);
+    // unknown formats fail to parse the file
+    let e = parse_file_content(br#"{"A": {"offsets": [{"offset": "0", "type": "u32", "format": "dec"}]}}"#, "b.json")
+      .unwrap_err();
+    assert!(e.starts_with("Fail to parse b.json: unknown variant `dec`, expected `hex`"), "{e}");
+  }
+
+  /// `(id, offset)` of the fields of a class in the version
+  fn offsets(db: &ObjParamsDB<SMSDolphin>, ver: SMSVersion, name: &str) -> Vec<(String, String)> {
+    match db.get(ver).and_then(|o| o.get(name)) {
+      Some(ObjectType::Class(class)) => class.fields.iter().map(|o| (o.id.to_string(), o.offset.to_string())).collect(),
+      _ => panic!("class \"{name}\" is not resolved in {ver}"),
+    }
+  }
+
+  #[test]
+  fn per_version_offsets() {
+    let db = load_str(&[("v.json", r#"{
+      "A": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "x"},
+        {"offset": {"GMSJ01": "7c", "GMSE01": "80", "GMSP01": "84", "GMSJ0A": "80"}, "type": "u32", "name": "y"},
+        {"offset": {"GMSJ01": "90", "GMSP01": "94"}, "type": "u16", "name": "z"}
+      ]}
+    }"#)]);
+    assert_eq!(errors(&db), []);
+    let field = |id: &str, offset: &str| (id.to_string(), offset.to_string());
+    assert_eq!(offsets(&db, SMSVersion::GMSJ01, "A"), [field("/x", "0"), field("/y", "7C"), field("/z", "90")]);
+    assert_eq!(offsets(&db, SMSVersion::GMSP01, "A"), [field("/x", "0"), field("/y", "84"), field("/z", "94")]);
+    // the field is absent in the versions without its offset
+    assert_eq!(offsets(&db, SMSVersion::GMSE01, "A"), [field("/x", "0"), field("/y", "80")]);
+    assert_eq!(offsets(&db, SMSVersion::GMSJ0A, "A"), [field("/x", "0"), field("/y", "80")]);
+  }
+
+  #[test]
+  fn version_tagged_class_takes_precedence() {
+    // the untagged definition is loaded later, but the tagged one still applies to its versions
+    let db = load_str(&[
+      ("a.json", r#"{"A": {"versions": ["GMSJ01", "GMSJ0A"], "offsets": [{"offset": "4", "type": "u32", "name": "j"}]}}"#),
+      ("b.json", r#"{"A": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]}}"#),
+    ]);
+    // and the untagged one is not reported as overriding it
+    assert!(db.diagnostics.is_empty(), "{:?}", db.diagnostics);
+    let field = |id: &str, offset: &str| vec![(id.to_string(), offset.to_string())];
+    assert_eq!(offsets(&db, SMSVersion::GMSJ01, "A"), field("/j", "4"));
+    assert_eq!(offsets(&db, SMSVersion::GMSJ0A, "A"), field("/j", "4"));
+    assert_eq!(offsets(&db, SMSVersion::GMSE01, "A"), field("/x", "0"));
+    assert_eq!(offsets(&db, SMSVersion::GMSP01, "A"), field("/x", "0"));
+  }
+
+  #[test]
+  fn find_matches_whole_segments() {
+    let db = load_str(&[("f.json", r#"{
+      "V": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]},
+      "F": {"offsets": [
+        {"offset": "0", "type": "V", "name": "a"},
+        {"offset": "4", "type": "V", "name": "ab"}
+      ]}
+    }"#)]);
+    let f = class(&db, "F");
+    assert_eq!(f.field_indices("/ab"), Some(vec![1]));
+    assert_eq!(f.field_indices("/ab/x"), Some(vec![1]));
+    assert_eq!(f.field_indices("/a/x"), Some(vec![0]));
+    assert_eq!(f.field_indices("/a/xy"), None);
+    assert_eq!(f.field_indices("/abc"), None);
+  }
+
+  /// Field ids of every class of each version
+  fn summary(db: &ObjParamsDB<SMSDolphin>) -> Vec<(String, String, Vec<&str>)> {
+    let mut classes = db.versions.iter().flat_map(|(ver, types)| types.iter().filter_map(move |(name, o)| match o {
+      ObjectType::Class(class) => Some((ver.to_string(), name.to_string(), field_ids(class))),
+      ObjectType::Primitive(_) => None,
+    })).collect::<Vec<_>>();
+    classes.sort();
+    classes
+  }
+
+  const FILE_A: &str = r#"{
+    "A": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}]},
+    "B": {"offsets": [{"offset": "0", "type": "A", "name": "a"}, {"offset": "4", "type": "Unknown", "name": "u"}]}
+  }"#;
+  const FILE_A_MODIFIED: &str = r#"{
+    "A": {"offsets": [{"offset": "0", "type": "u32", "name": "x"}, {"offset": "4", "type": "u32", "name": "y"}]},
+    "B": {"offsets": [{"offset": "0", "type": "A", "name": "a"}, {"offset": "8", "type": "Unknown", "name": "u"}]}
+  }"#;
+  const FILE_C: &str = r#"{
+    "C": {"offsets": [{"offset": "0", "type": "u32", "name": "x", "when": {"field": "z", "equals": 1}}]},
+    "D": {"offsets": [{"offset": "0", "type": "C", "name": "c"}]},
+    "E": {"offsets": [{"offset": "0", "type": "B", "name": "b"}]}
+  }"#;
+
+  #[test]
+  fn modified_file_resolves_dependents_only() {
+    let parse = |name: &str, src: &str| {
+      ((Arc::from(name), content_hash(src.as_bytes())), parse_file_content(src.as_bytes(), name).unwrap())
+    };
+    let (db0, resolved) = build_db(&[parse("a.json", FILE_A), parse("c.json", FILE_C)], Vec::new(), &HashMap::new());
+    let files = [parse("a.json", FILE_A_MODIFIED), parse("c.json", FILE_C)];
+    let (db1, _) = build_db(&files, Vec::new(), &resolved);
+    let (fresh, _) = build_db(&files, Vec::new(), &HashMap::new());
+    assert_eq!(summary(&db1), summary(&fresh));
+    assert_eq!(db1.diagnostics, fresh.diagnostics);
+    assert_eq!(errors(&db1), [
+      ("a.json", r#"$["B"].offsets[1].type"#, "unknown type \"Unknown\""),
+      ("c.json", r#"$["C"].offsets[0].when"#, "field \"z\" is not found"),
+    ]);
+
+    let class_arc = |db: &ObjParamsDB<SMSDolphin>, name: &str| match db.get(SMSVersion::GMSJ01).and_then(|o| o.get(name)) {
+      Some(ObjectType::Class(class)) => class.clone(),
+      _ => panic!("class \"{name}\" is not resolved"),
+    };
+    // the classes not embedding the modified classes are reused
+    for name in ["C", "D"] {
+      assert!(Arc::ptr_eq(&class_arc(&db0, name), &class_arc(&db1, name)), "{name} is resolved again");
+    }
+    for name in ["A", "B", "E"] {
+      assert!(!Arc::ptr_eq(&class_arc(&db0, name), &class_arc(&db1, name)), "{name} is reused");
+    }
+    assert_eq!(field_ids(class(&db1, "E")), ["/b/a/x", "/b/a/y", "/b/u"]);
+  }
+
+  #[test]
+  fn defining_unknown_type_resolves_dependents() {
+    let parse = |name: &str, src: &str| {
+      ((Arc::from(name), content_hash(src.as_bytes())), parse_file_content(src.as_bytes(), name).unwrap())
+    };
+    let (_, resolved) = build_db(&[parse("a.json", FILE_A)], Vec::new(), &HashMap::new());
+    let unknown = r#"{"Unknown": {"offsets": [{"offset": "0", "type": "u8", "name": "v"}]}}"#;
+    let (db, _) = build_db(&[parse("a.json", FILE_A), parse("u.json", unknown)], Vec::new(), &resolved);
+    assert_eq!(field_ids(class(&db, "B")), ["/a/x", "/u/v"]);
+  }
+
+  #[test]
+  fn cache_restores_unmodified_files() {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-test-cache-{}", std::process::id()));
+    let cache = dir.join("ObjectParameters.cache");
+    let obj_params_dir = dir.join("ObjectParameters");
+    std::fs::create_dir_all(&obj_params_dir).unwrap();
+    std::fs::write(obj_params_dir.join("a.json"), FILE_A).unwrap();
+    std::fs::write(obj_params_dir.join("c.json"), FILE_C).unwrap();
+    let dirs = [&obj_params_dir];
+    let db0 = ObjParamsLoader::new(&dirs).with_cache(&cache).load().unwrap();
+    let db1 = ObjParamsLoader::new(&dirs).with_cache(&cache).load().unwrap();
+    assert_eq!(summary(&db0), summary(&db1));
+    assert_eq!(db0.diagnostics, db1.diagnostics);
+
+    std::fs::write(obj_params_dir.join("a.json"), FILE_A_MODIFIED).unwrap();
+    let mut loader = ObjParamsLoader::new(&dirs).with_cache(&cache);
+    let db2 = loader.load().unwrap();
+    let fresh = ObjParamsLoader::new(&dirs).load().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(summary(&db2), summary(&fresh));
+    assert_eq!(db2.diagnostics, fresh.diagnostics);
+    // only the modified file is parsed again
+    assert_eq!(loader.files.len(), 2);
+  }
+
+  #[test]
+  fn profile_layer_patches_its_profile_only() {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-test-profile-{}", std::process::id()));
+    let (base_dir, profile_dir) = (dir.join("ObjectParameters"), dir.join("Hack"));
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::create_dir_all(&profile_dir).unwrap();
+    std::fs::write(base_dir.join("a.json"), FILE_A).unwrap();
+    std::fs::write(profile_dir.join("a.json"),
+      r#"{"A": {"patch": true, "offsets": [{"offset": "4", "type": "u32", "name": "y"}]}}"#).unwrap();
+    let layer = |profile: &str, dir: &Path| ObjParamsProfileLayer {
+      profile: Arc::from(profile), base: SMSVersion::GMSE01, dir: Box::from(dir),
+    };
+    let db = ObjParamsLoader::new(&[&base_dir])
+      .with_profiles(Box::new([layer("Hack", &profile_dir), layer("Missing", &dir.join("Missing"))]))
+      .load().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let fields = |profile: Option<&str>, name: &str| match db.get_profile(profile, SMSVersion::GMSE01)
+      .and_then(|o| o.get(name)) {
+      Some(ObjectType::Class(class)) => class.fields.iter().map(|o| o.id.to_string()).collect::<Vec<_>>(),
+      _ => panic!("class \"{name}\" is not resolved"),
+    };
+    // the classes embedding the patched class are resolved for the profile too
+    assert_eq!(fields(Some("Hack"), "A"), ["/x", "/y"]);
+    assert_eq!(fields(Some("Hack"), "B"), ["/a/x", "/a/y", "/u"]);
+    // the base version and the profiles without the layer are unchanged
+    assert_eq!(fields(None, "A"), ["/x"]);
+    assert_eq!(fields(Some("GMSE01"), "B"), ["/a/x", "/u"]);
+    // the missing layer is reported as a warning without failing the load
+    assert_eq!(fields(Some("Missing"), "A"), ["/x"]);
+    assert!(db.diagnostics.iter().any(|e| !e.is_error() && e.message.contains("profile \"Missing\"")));
+  }
 }
@@ -0,0 +1,87 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::sync::Arc;
+use crate::addr::AddrOffsets;
+
+/// A node of the field tree of a class, which mirrors the embedding hierarchy.
+///
+/// The id of a node is the path of the names of the fields from the root class
+/// in the form of JSON pointer (e.g. `/Inherited fields/Position`),
+/// so it does not change when other fields are added or removed.
+/// The fields of the same name in a class (including the fields without a name)
+/// are distinguished by `#2`, `#3`, ... in the order of definition (e.g. `/#2`)
+#[derive(PartialEq)]
+pub struct FieldNode {
+  pub id: Arc<str>,
+  pub name: Arc<str>,
+  pub kind: FieldNodeKind,
+}
+#[derive(PartialEq)]
+pub enum FieldNodeKind {
+  /// index of the field in the flattened field list
+  Field(usize),
+  /// an embedded class
+  Group {
+    offset: AddrOffsets,
+    type_: Arc<str>,
+    notes: Arc<str>,
+    file: Arc<str>,
+    children: Box<[FieldNode]>,
+  },
+}
+
+/// type of the group of the members of a union
+pub const UNION_TYPE: &str = "union";
+
+/// Escape the name of a field as a segment of JSON pointer
+pub fn id_segment(name: &str) -> String {
+  format!("/{}", name.replace('~', "~0").replace('/', "~1"))
+}
+
+impl FieldNode {
+  /// Copy the node of an embedded class into the class embedding it
+  pub fn embed(&self, prefix: &str, offset: &AddrOffsets, base: usize) -> FieldNode {
+    FieldNode {
+      id: Arc::from(format!("{prefix}{}", self.id)),
+      name: self.name.clone(),
+      kind: match &self.kind {
+        FieldNodeKind::Field(i) => FieldNodeKind::Field(base + i),
+        FieldNodeKind::Group {offset: offset1, type_, notes, file, children} => FieldNodeKind::Group {
+          offset: offset + offset1,
+          type_: type_.clone(),
+          notes: notes.clone(),
+          file: file.clone(),
+          children: children.iter().map(|e| e.embed(prefix, offset, base)).collect(),
+        },
+      },
+    }
+  }
+
+  /// Find the node with the given id in this subtree
+  pub fn find(&self, id: &str) -> Option<&FieldNode> {
+    if *self.id == *id {
+      return Some(self);
+    }
+    match &self.kind {
+      // only the ids under this node, i.e. `/a/...` but not `/ab`
+      FieldNodeKind::Group {children, ..} if id.strip_prefix(&*self.id).is_some_and(|o| o.starts_with('/')) =>
+        find_node(children, id),
+      _ => None,
+    }
+  }
+
+  /// Indices of the fields in this subtree
+  pub fn field_indices(&self, out: &mut Vec<usize>) {
+    match &self.kind {
+      FieldNodeKind::Field(i) => out.push(*i),
+      FieldNodeKind::Group {children, ..} => for e in children.iter() {
+        e.field_indices(out);
+      },
+    }
+  }
+}
+
+pub fn find_node<'a>(nodes: &'a [FieldNode], id: &str) -> Option<&'a FieldNode> {
+  nodes.iter().find_map(|e| e.find(id))
+}
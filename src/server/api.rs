@@ -4,13 +4,33 @@
 use crate::{
   addr::{Addr, AddrOffsets},
   dolphin::Dolphin,
-  sms::{SMSDolphin, SMSVersion},
+  sms::{SMSDolphin, SMSVersion, profile::{GameProfileInfo, GameProfilesInfo}},
   big_endian::DecodeBE,
-  server::http::HttpEnv,
-  obj_params::{load_obj_params, ObjectType},
+  server::{
+    http::HttpEnv, binary::binary_info, auth::Role,
+    subscription::{Subscriptions, SubscribeRequest},
+    session::{Session, AttachRequest, InstanceInfo},
+    freeze::{Freeze, FreezeRequest, FrozenInfo, UnfreezeRequest, UnfreezeOptions, encode_value},
+    commands::{
+      self, CommandHandler, find_command, Description, ManagerRow, ManageeRow,
+      ReqAddr, ReadRequest, ReadResponse, FieldValue, FieldApplicable, TaggedValues, AddrRequest, WriteRequest,
+      GetFieldsRequest, GetFieldsResponse, FieldsQuery, FieldRow, FieldInfo, FieldGroupInfo, FieldTreeNode,
+      FieldConditionInfo, ImportDmwRequest, ExportDmwRequest, ExportTypesRequest, TypesFormat, BatchRequest,
+    },
+  },
+  obj_params::{
+    ObjParams, ObjectType, ClassType, ClassField, ImportedClasses,
+    tree::{FieldNode, FieldNodeKind, find_node},
+    diagnostic::ObjParamsDiagnostic,
+    dmw::{self, DmwFile},
+    c_header::{self, export_c_header},
+    ghidra::export_ghidra_xml,
+  },
 };
 use sup_smsac_derive::DecodeBE;
+use serde::de::DeserializeOwned;
 use serde_json::{self, json, Value as JsonValue};
+use std::sync::Arc;
 
 #[derive(Debug, DecodeBE)]
 struct ConductorNode {
@@ -25,25 +45,23 @@ struct ChildInfo {
 }
 
 trait DolphinMemoryJsExt {
-  fn resolve_addr(&self, addr: &JsonValue) -> Result<Option<Addr>, ()>;
+  fn resolve_addr(&self, addr: &ReqAddr) -> Result<Option<Addr>, ()>;
   fn resolve_addr_offsets(&self, base: Addr, offsets: &AddrOffsets) -> Option<Addr>;
 }
 impl<T: Dolphin> DolphinMemoryJsExt for T {
-  fn resolve_addr(&self, addr: &JsonValue) -> Result<Option<Addr>, ()> {
-    // single addr
-    if let Some(addr) = addr.as_u64() {
-      return Ok(Some(Addr(addr as u32)));
-    }
-    // addr + offsets
-    let Some((Some(mut addr), offs)) = addr.as_array()
-      .and_then(|x| x.split_first())
-      .map(|e| (e.0.as_u64().map(|x| Addr(x as u32)), e.1))
+  fn resolve_addr(&self, addr: &ReqAddr) -> Result<Option<Addr>, ()> {
+    let offs = match addr {
+      // single addr
+      ReqAddr::Addr(addr) => return Ok(Some(Addr(*addr))),
+      // addr + offsets
+      ReqAddr::Pointer(offs) => offs,
+    };
+    let Some((Ok(mut addr), offs)) = offs.split_first()
+      .map(|e| (u32::try_from(*e.0).map(Addr), e.1))
     else {return Err(())};
     // resolve
-    for off in offs {
-      let Some(off) = off.as_i64().map(|x| x as u32) else {
-        return Err(());
-      };
+    for &off in offs {
+      let off = off as u32;
       match self.read::<Addr>(addr) {
         None => return Ok(None),
         Some(_addr) => addr = _addr+off,
@@ -63,222 +81,714 @@ impl<T: Dolphin> DolphinMemoryJsExt for T {
   }
 }
 
+/// Bytes from hex
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+  (0..s.len()).step_by(2)
+    .map(|i| s.get(i..i+2).and_then(|s| u8::from_str_radix(s, 16).ok()))
+    .collect()
+}
+
+/// The address in the same format as `read`, without resolving the pointers
+fn addr_offsets(addr: &ReqAddr) -> Option<AddrOffsets> {
+  match addr {
+    ReqAddr::Addr(addr) => Some(AddrOffsets(*addr, [].into())),
+    ReqAddr::Pointer(offs) => {
+      let (first, offs) = offs.split_first()?;
+      Some(AddrOffsets(
+        u32::try_from(*first).ok()?,
+        offs.iter().map(|&off| off as u32).collect(),
+      ))
+    },
+  }
+}
+
+/// The field tree, with the leaves and the groups converted by `leaf` and `group`
+fn fields_tree<D: Dolphin, T>(
+  class: &ClassType<D>,
+  nodes: &[FieldNode],
+  leaf: &dyn Fn(&ClassField<D>) -> T,
+  group: &dyn Fn(&FieldNode, Vec<T>) -> T,
+) -> Vec<T> {
+  nodes.iter().map(|node| match &node.kind {
+    FieldNodeKind::Field(i) => leaf(&class.fields[*i]),
+    FieldNodeKind::Group {children, ..} => group(node, fields_tree(class, children, leaf, group)),
+  }).collect()
+}
+
+/// Read a numeric field (see [`crate::obj_params::expr::is_numeric_type`]) of the object at `base`
+fn read_number<D: Dolphin>(d: &D, base: Addr, field: &ClassField<D>) -> Option<f64> {
+  let addr = d.resolve_addr_offsets(base, &field.offset)?;
+  match &*field.type_ {
+    "u8" => d.read::<u8>(addr).map(f64::from),
+    "s8" => d.read::<i8>(addr).map(f64::from),
+    "u16" => d.read::<u16>(addr).map(f64::from),
+    "s16" => d.read::<i16>(addr).map(f64::from),
+    "s32" => d.read::<i32>(addr).map(f64::from),
+    "float" => d.read::<f32>(addr).map(f64::from),
+    "double" => d.read::<f64>(addr),
+    // u32 and pointers
+    _ => d.read::<u32>(addr).map(f64::from),
+  }
+}
+
+/// maximum depth of the class hierarchy to walk up
+const MAX_HIERARCHY_DEPTH: usize = 32;
+
+/// The class of the object at `addr` (by its vtable), and the nearest class
+/// in its class hierarchy which has ObjectParameters (`_default` if none)
+fn auto_type<'a>(
+  d: &SMSDolphin, addr: Addr, obj_params: &'a ObjParams<SMSDolphin>,
+) -> (Option<Arc<str>>, &'a str) {
+  let class = d.read::<Addr>(addr).and_then(|vt| d.get_class(vt));
+  let used = std::iter::successors(class.clone(), |name| d.base_class(name))
+    .take(MAX_HIERARCHY_DEPTH)
+    .find_map(|name| obj_params.get_key_value(&*name))
+    .map_or("_default", |(name, _)| &**name);
+  (class, used)
+}
+
+/// `[{field, equals}]` where `field` is the id of the referenced field
+fn conditions<D: Dolphin>(class: &ClassType<D>, field: &ClassField<D>) -> Vec<FieldConditionInfo> {
+  field.when.iter().map(|c| FieldConditionInfo {
+    field: class.fields[c.field].id.clone(),
+    equals: c.equals,
+  }).collect()
+}
+
+/// Deserialize the body of the command (see [`crate::server::commands`] for the types).
+/// Empty named params (`{}`) are accepted as no body (`null`) too
+pub fn parse_body<T: DeserializeOwned>(command: &str, body: &JsonValue) -> Result<T, JsonValue> {
+  T::deserialize(body)
+    .or_else(|e| match body {
+      JsonValue::Object(o) if o.is_empty() => T::deserialize(&JsonValue::Null).map_err(|_| e),
+      _ => Err(e),
+    })
+    .map_err(|e| json!(format!("invalid body of \"{command}\": {e}")))
+}
+
+/// Error of a command, which is sent to the client as the message
+#[derive(Debug)]
+pub enum CommandError {
+  /// caused by the request, e.g. an invalid body or an unknown type
+  Request(JsonValue),
+  /// not caused by the request, e.g. Dolphin is not running or ObjectParameters cannot be loaded
+  Unavailable(JsonValue),
+}
+impl From<JsonValue> for CommandError {
+  fn from(e: JsonValue) -> Self {
+    CommandError::Request(e)
+  }
+}
+impl From<CommandError> for JsonValue {
+  fn from(e: CommandError) -> Self {
+    match e {
+      CommandError::Request(e) | CommandError::Unavailable(e) => e,
+    }
+  }
+}
+
+/// State of a websocket connection
+#[derive(Default)]
+pub struct Connection {
+  pub session: Session,
+  pub subscriptions: Subscriptions,
+  /// whether binary frames are accepted
+  pub binary: bool,
+  pub role: Role,
+}
+
+/// Handle a command of a connection, including the commands depending on the state of the connection
+pub async fn handle_connection_command(
+  env: &HttpEnv,
+  conn: &mut Connection,
+  command: &str,
+  body: &JsonValue,
+) -> Result<JsonValue, JsonValue> {
+  conn.role.check_command(command)?;
+  run_connection_command(env, conn, command, body).await.map_err(JsonValue::from)
+}
+
+/// [`handle_connection_command`] without checking the role, telling whether the error is caused by the request
+pub async fn run_connection_command(
+  env: &HttpEnv,
+  conn: &mut Connection,
+  command: &str,
+  body: &JsonValue,
+) -> Result<JsonValue, CommandError> {
+  match find_command(command).map(|o| o.handler) {
+    Some(CommandHandler::Connection(handler)) => handler(env, conn, body).await,
+    _ => run_command(env, &mut conn.session, command, body).await,
+  }
+}
+
+/// Handle a command which does not depend on the state of a connection
 pub async fn handle_command(
   env: &HttpEnv,
-  dolphin: &mut Option<SMSDolphin>,
+  session: &mut Session,
   command: &str,
   body: &JsonValue,
 ) -> Result<JsonValue, JsonValue> {
-  macro_rules! return_err {
-    ($($msg:expr),+) => {
-      return Err(json!(format!($($msg),+)))
-    }
+  run_command(env, session, command, body).await.map_err(JsonValue::from)
+}
+
+/// [`handle_command`], telling whether the error is caused by the request
+pub async fn run_command(
+  env: &HttpEnv,
+  session: &mut Session,
+  command: &str,
+  body: &JsonValue,
+) -> Result<JsonValue, CommandError> {
+  match find_command(command).map(|o| o.handler) {
+    Some(CommandHandler::Session(handler)) => handler(env, session, body).await,
+    Some(CommandHandler::Connection(_)) => Err(json!(format!("{command} needs a websocket connection")).into()),
+    None => Err(json!(format!("Unknown command: {command} (see `describe` for the commands)")).into()),
   }
-  macro_rules! let_dolphin {
-    ($d: ident) => {
-      let $d = match &dolphin {
-        Some(d) => d,
-        None => match SMSDolphin::find_one() {
-          Ok(d) => {
-            *dolphin = Some(d);
-            match &dolphin {
-              Some(d) => d,
-              None => unreachable!(),
-            }
-          },
-          Err(e) => return_err!("{}", e),
-        },
-      };
+}
+
+macro_rules! return_err {
+  ($($msg:expr),+) => {
+    return Err(json!(format!($($msg),+)).into())
+  }
+}
+/// Return the error which is not caused by the request (see [`CommandError::Unavailable`])
+macro_rules! return_unavailable {
+  ($($msg:expr),+) => {
+    return Err(CommandError::Unavailable(json!(format!($($msg),+))))
+  }
+}
+macro_rules! let_dolphin {
+  ($d:ident, $env:expr, $session:expr) => {
+    let $d = match $session.dolphin_or_attach($env) {
+      Ok(d) => d,
+      Err(e) => return_unavailable!("{}", e),
+    };
+  };
+}
+/// ObjectParameters of the game profile (`Option<&str>`) if it has its own layer, or those of the version
+macro_rules! let_obj_params {
+  ($obj_params:ident, $env:expr, $profile:expr, $ver:expr) => {
+    let lock_obj_params = $env.obj_params_result.lock().await;
+    let $obj_params = match &*lock_obj_params {
+      Ok(v) => v,
+      Err(e) => return_unavailable!("Fail to get ObjectParameters: {e}"),
+    };
+    let Some($obj_params) = $obj_params.get_profile($profile, $ver) else {
+      return_unavailable!("ObjectParameters of {} is not loaded", $ver);
     };
+  };
+}
+
+/// The nodes of the field (group) `root`, or all nodes of the class
+fn field_nodes<'a, D: Dolphin>(class: &'a ClassType<D>, root: Option<&str>) -> Result<&'a [FieldNode], JsonValue> {
+  match root {
+    None => Ok(&class.tree[..]),
+    Some(id) => match find_node(&class.tree, id) {
+      Some(node) => Ok(std::slice::from_ref(node)),
+      None => return_err!("field \"{id}\" not found"),
+    },
   }
+}
 
-  macro_rules! let_obj_params_fields {
-    ($fields:ident, $type: ident) => {
-      let lock_obj_params = env.obj_params_result.lock().await;
-      let obj_params = match &*lock_obj_params {
-        Ok(v) => v,
-        Err(e) => return_err!("Fail to get ObjectParameters: {e}"),
-      };
-      let Some($fields) = obj_params.get($type).or_else(|| obj_params.get("_default")) else {
-        return_err!("unknown type: \"{}\". Please defined \"_default\" type in ObjectParameters/*.json", $type);
-      };
-    };
+/// The type, or `_default` if the type is unknown
+fn type_fields<'a>(obj_params: &'a ObjParams<SMSDolphin>, type_: &str) -> Result<&'a ObjectType<SMSDolphin>, JsonValue> {
+  match obj_params.get(type_).or_else(|| obj_params.get("_default")) {
+    Some(fields) => Ok(fields),
+    None => return_err!("unknown type: \"{type_}\". Please defined \"_default\" type in ObjectParameters/*.json"),
   }
+}
 
-  match command {
-    "init" => {
-      let_dolphin!(d);
-      Ok(json!(d.pid()))
-    },
+// The handlers of the commands (see [`crate::server::commands::COMMAND_SPECS`])
+
+pub async fn init(env: &HttpEnv, session: &mut Session, _: ()) -> Result<u32, CommandError> {
+  let_dolphin!(d, env, session);
+  Ok(d.pid())
+}
+
+pub async fn list_instances(env: &HttpEnv, session: &mut Session, _: ()) -> Result<Vec<InstanceInfo>, CommandError> {
+  Ok(session.list_instances(env))
+}
+
+pub async fn attach(env: &HttpEnv, session: &mut Session, req: Option<AttachRequest>) -> Result<u32, CommandError> {
+  match session.attach(env, req.and_then(|o| o.pid)) {
+    Ok(d) => Ok(d.pid()),
+    Err(e) => return_err!("{}", e),
+  }
+}
+
+pub async fn detach(_: &HttpEnv, session: &mut Session, _: ()) -> Result<(), CommandError> {
+  session.detach();
+  Ok(())
+}
 
-    "getManagers" => {
-      let_dolphin!(d);
-      Ok(d.read::<Addr>(Addr(match d.ver() {
-        // TODO put addr in external file
-        SMSVersion::GMSJ01 => 0x8040A6E8,
-        SMSVersion::GMSE01 => 0x8040D110,
-        SMSVersion::GMSP01 => 0x80404870,
-        SMSVersion::GMSJ0A => 0x803FE048,
-      }))
-        .and_then(|a| d.read::<ChildInfo>(a+0x14))
-        .and_then(|o| {
-          let mut next = o.addr;
-          let mut arr: Vec<JsonValue> = Vec::with_capacity(o.count as usize);
-          for _i in 0..o.count {
-            let Some(node) = d.read::<ConductorNode>(next) else {return None};
-            arr.push(json!([
-              node.obj.0,
-              d.read::<Addr>(node.obj)
-                .map(|a| d.get_class_string(a))
-                .unwrap_or_else(|| "({a})".to_string()),
-              d.read::<Addr>(node.obj+4).and_then(|a| d.read_str(a)).unwrap_or_else(|| "�".to_string()),
-              d.read::<i32>(node.obj+0x14).unwrap_or(-1),
-            ]));
-            next = node.next;
-          }
-          Some(JsonValue::Array(arr))
-        }).unwrap_or_else(|| json!(null))
-      )
+pub async fn get_managers(
+  env: &HttpEnv, session: &mut Session, _: (),
+) -> Result<Option<Vec<ManagerRow>>, CommandError> {
+  let_dolphin!(d, env, session);
+  let Some(managers) = d.profile().global(d, "managers") else {
+    return_unavailable!("the address of \"managers\" is not defined in game profile \"{}\"", d.profile().name);
+  };
+  Ok(managers.and_then(|managers| d.read::<Addr>(managers))
+    .and_then(|a| d.read::<ChildInfo>(a+0x14))
+    .and_then(|o| {
+      let mut next = o.addr;
+      let mut arr = Vec::with_capacity(o.count as usize);
+      for _i in 0..o.count {
+        let node = d.read::<ConductorNode>(next)?;
+        arr.push((
+          node.obj.0,
+          d.read::<Addr>(node.obj)
+            .map(|a| d.get_class_string(a))
+            .unwrap_or_else(|| "({a})".to_string()),
+          d.read::<Addr>(node.obj+4).and_then(|a| d.read_str(a)).unwrap_or_else(|| "�".to_string()),
+          d.read::<i32>(node.obj+0x14).unwrap_or(-1),
+        ));
+        next = node.next;
+      }
+      Some(arr)
+    }))
+}
+
+pub async fn get_managees(
+  env: &HttpEnv, session: &mut Session, addr: u32,
+) -> Result<Option<Vec<ManageeRow>>, CommandError> {
+  let_dolphin!(d, env, session);
+  Ok(d.read::<ChildInfo>(Addr(addr)+0x14).and_then(|o| {
+    let mut arr = Vec::with_capacity(o.count as usize);
+    for a in (0..o.count).map(|i| d.read::<Addr>(o.addr+4*i)) {
+      let a = a?;
+      arr.push((
+        a.0,
+        d.read::<Addr>(a).map(|a| d.get_class_string(a)),
+        d.read::<Addr>(a+4).and_then(|a| d.read_str(a)).unwrap_or_else(|| "�".to_string()),
+      ));
+    }
+    Some(arr)
+  }))
+}
+
+pub async fn read(env: &HttpEnv, session: &mut Session, req: ReadRequest) -> Result<Option<ReadResponse>, CommandError> {
+  let ReadRequest {addr, size, type_, root, tree, applicable} = req;
+  let_dolphin!(d, env, session);
+  let Ok(addr) = d.resolve_addr(&addr) else {
+    return_err!("invalid addr: {addr:?}");
+  };
+  let Some(addr) = addr else {
+    return Ok(None);
+  };
+
+  if let Some(size) = size {
+    if type_.is_some() {
+      return_err!("\"size\" and \"type\" cannot be specified at the same time");
+    }
+    return Ok(Some(ReadResponse::Values(FieldValue::Value(d.dump_hex(addr, size)))));
+  }
+  let Some(type_) = type_.as_deref() else {
+    return_err!("either \"size\" and \"type\" must be specified");
+  };
+  let (root, tree) = (root.as_deref(), tree.unwrap_or(false));
+  // whether to read the inapplicable fields too, and tag the fields with the applicability
+  let tag = applicable.unwrap_or(false);
+  let_obj_params!(obj_params, env, Some(&d.profile().name), d.ver());
+  // `auto`: the class of the object and the class whose ObjectParameters are used
+  let auto = (type_ == "auto").then(|| auto_type(d, addr, obj_params));
+  let type_ = match auto {
+    Some((_, used)) => used,
+    None => type_,
+  };
+  let (values, applicable) = match type_fields(obj_params, type_)? {
+    ObjectType::Primitive(p) => (FieldValue::Value(p.read(d, addr)), None),
+    ObjectType::Class(class) => {
+      let nodes = field_nodes(class, root)?;
+      let applicable = |field: &ClassField<SMSDolphin>| field.when.iter().all(|c| {
+        d.resolve_addr_offsets(addr, &class.fields[c.field].offset)
+          .and_then(|addr| match c.size {
+            1 => d.read::<u8>(addr).map(u32::from),
+            2 => d.read::<u16>(addr).map(u32::from),
+            _ => d.read::<u32>(addr),
+          })
+          .is_some_and(|value| c.matches(value))
+      });
+      // the inapplicable fields are read as null unless tagged
+      let read_field = |field: &ClassField<SMSDolphin>| FieldValue::Value(match (tag || applicable(field), &field.expr) {
+        (true, None) => d.resolve_addr_offsets(addr, &field.offset)
+          .and_then(|addr| field.reader.read(d, addr)),
+        (true, Some(c)) => c.fields.iter()
+          .map(|&i| read_number(d, addr, &class.fields[i]))
+          .collect::<Option<Vec<_>>>()
+          .map(|values| c.expr.eval(&values).to_string()),
+        (false, _) => None,
+      });
+      let values = FieldValue::Group(collect_fields(class, nodes, tree, root, &read_field, &FieldValue::Group));
+      let applicable = tag.then(|| FieldApplicable::Group(collect_fields(
+        class, nodes, tree, root, &|field| FieldApplicable::Value(applicable(field)), &FieldApplicable::Group,
+      )));
+      (values, applicable)
     },
+  };
+  Ok(Some(match (auto, applicable) {
+    (None, None) => ReadResponse::Values(values),
+    (auto, applicable) => ReadResponse::Tagged(TaggedValues {
+      values,
+      applicable,
+      class: auto.as_ref().map(|(class, _)| class.clone()),
+      type_: auto.map(|(_, used)| used.into()),
+    }),
+  }))
+}
 
-    "getManagees" => {
-      let_dolphin!(d);
-      let Some(addr) = body.as_u64().map(|x| Addr(x as u32)) else {
-        return_err!("\"body\" must be a string");
-      };
-      Ok(d.read::<ChildInfo>(addr+0x14).and_then(|o| {
-        let mut arr: Vec<JsonValue> = Vec::with_capacity(o.count as usize);
-        for a in (0..o.count).map(|i| d.read::<Addr>(o.addr+4*i)) {
-          let Some(a) = a else {return None};
-          arr.push(json!([
-            a.0,
-            d.read::<Addr>(a).map(|a| d.get_class_string(a)),
-            d.read::<Addr>(a+4).and_then(|a| d.read_str(a)).unwrap_or_else(|| "�".to_string()),
-          ]));
-        }
-        Some(JsonValue::Array(arr))
-      }).unwrap_or_else(|| json!(null)))
+/// The values of the fields as nested arrays with `tree`, or flattened otherwise
+fn collect_fields<D: Dolphin, T>(
+  class: &ClassType<D>,
+  nodes: &[FieldNode],
+  tree: bool,
+  root: Option<&str>,
+  leaf: &dyn Fn(&ClassField<D>) -> T,
+  group: &dyn Fn(Vec<T>) -> T,
+) -> Vec<T> {
+  match (tree, root) {
+    (true, _) => fields_tree(class, nodes, leaf, &|_, children| group(children)),
+    (false, None) => class.fields.iter().map(leaf).collect(),
+    (false, Some(_)) => {
+      let mut indices = Vec::new();
+      nodes.iter().for_each(|e| e.field_indices(&mut indices));
+      indices.into_iter().map(|i| leaf(&class.fields[i])).collect()
     },
+  }
+}
 
-    "read" => {
-      let_dolphin!(d);
-      let Some(addr) = body.get("addr") else {
-        return_err!("addr must be specified");
-      };
-      let Ok(addr) = d.resolve_addr(addr) else {
-        return_err!("invalid addr: {addr:?}");
+pub async fn read_string(env: &HttpEnv, session: &mut Session, req: AddrRequest) -> Result<Option<String>, CommandError> {
+  let AddrRequest {addr} = req;
+  let_dolphin!(d, env, session);
+  let Ok(addr) = d.resolve_addr(&addr) else {
+    return_err!("invalid addr: {addr:?}");
+  };
+  Ok(addr.and_then(|addr| d.read_str(addr)))
+}
+
+pub async fn write(env: &HttpEnv, session: &mut Session, req: WriteRequest) -> Result<bool, CommandError> {
+  let_dolphin!(d, env, session);
+  let (Ok(addr), Some(payload)) = (d.resolve_addr(&req.addr), parse_hex(&req.payload)) else {
+    return_err!("Invalid body: {req:?}");
+  };
+  let ok = addr.and_then(|addr| d.write_bytes(addr, &payload)).is_some();
+  session.invalidate_cache();
+  Ok(ok)
+}
+
+pub async fn freeze(env: &HttpEnv, session: &mut Session, req: FreezeRequest) -> Result<u64, CommandError> {
+  let owner = session.id();
+  let_dolphin!(d, env, session);
+  let Some(target) = addr_offsets(&req.addr) else {
+    return_err!("invalid addr: {:?}", req.addr);
+  };
+  let (target, payload) = match (&req.payload, &req.type_, req.value) {
+    (Some(payload), None, None) => match parse_hex(payload) {
+      Some(payload) if !payload.is_empty() => (target, payload.into_boxed_slice()),
+      _ => return_err!("invalid payload: {payload}"),
+    },
+    (None, Some(type_), Some(value)) => {
+      let_obj_params!(obj_params, env, Some(&d.profile().name), d.ver());
+      let (target, value_type) = match (obj_params.get(type_.as_str()), &req.field) {
+        (None, _) => return_err!("unknown type: \"{type_}\""),
+        (Some(ObjectType::Primitive(_)), None) => (target, &**type_),
+        (Some(ObjectType::Primitive(_)), Some(_)) => return_err!("\"{type_}\" is not a class"),
+        (Some(ObjectType::Class(_)), None) => return_err!("\"field\" of \"{type_}\" must be specified"),
+        (Some(ObjectType::Class(class)), Some(id)) => match class.fields.iter().find(|o| &*o.id == id) {
+          None => return_err!("field \"{id}\" not found in \"{type_}\""),
+          Some(field) if field.expr.is_some() => return_err!("computed field \"{id}\" cannot be frozen"),
+          Some(field) => (&target + &field.offset, &*field.type_),
+        },
       };
-      let Some(addr) = addr else {
-        return Ok(json!(null));
+      let Some(payload) = encode_value(value_type, value) else {
+        return_err!("{value} cannot be written as {value_type}");
       };
+      (target, payload)
+    },
+    _ => return_err!("either \"payload\", or \"type\" and \"value\" must be specified"),
+  };
+  let freeze = Freeze::new(d, owner, req, target, payload)?;
+  match session.freeze(freeze) {
+    Ok(id) => Ok(id),
+    Err(e) => return_unavailable!("{}", e),
+  }
+}
 
-      Ok(match body.get("size") {
-        Some(size) => {
-          if body.get("type").is_some() {
-            return_err!("\"size\" and \"type\" cannot be specified at the same time");
-          }
-          let Some(size) = size.as_u64().map(|x| x as usize) else {
-            return_err!("\"size\" must be a positive integer");
-          };
-          d.dump_hex(addr, size)
-            .map(|s| json!(s))
-            .unwrap_or_else(|| json!(null))
-        },
-        None => {
-          let Some(type_) = body.get("type") else {
-            return_err!("either \"size\" and \"type\" must be specified");
-          };
-          let Some(type_) = type_.as_str() else {
-            return_err!("\"type\" must be a string");
-          };
-          let_obj_params_fields!(fields, type_);
-          match fields {
-            ObjectType::Primitive(p) => p.read(d, addr)
-              .map(|x| json!(x))
-              .unwrap_or_else(|| json!(null)),
-            ObjectType::Class(fields) => JsonValue::Array(fields.iter().map(|field| {
-              d.resolve_addr_offsets(addr, &field.offset)
-                .map(|addr| field.reader.read(d, addr))
-                .map(|x| json!(x))
-                .unwrap_or_else(|| json!(null))
-            }).collect()),
-          }
+pub async fn unfreeze(_: &HttpEnv, session: &mut Session, req: UnfreezeRequest) -> Result<(), CommandError> {
+  let (id, force) = match req {
+    UnfreezeRequest::Id(id) => (id, false),
+    UnfreezeRequest::Options(UnfreezeOptions {id, force}) => (id, force.unwrap_or(false)),
+  };
+  match session.unfreeze(id, force) {
+    Ok(()) => Ok(()),
+    Err(e) => return_err!("{}", e),
+  }
+}
+
+pub async fn list_frozen(_: &HttpEnv, session: &mut Session, _: ()) -> Result<Vec<FrozenInfo>, CommandError> {
+  Ok(session.list_frozen())
+}
+
+pub async fn get_class(env: &HttpEnv, session: &mut Session, req: AddrRequest) -> Result<Option<String>, CommandError> {
+  let AddrRequest {addr} = req;
+  let_dolphin!(d, env, session);
+  let Ok(addr) = d.resolve_addr(&addr) else {
+    return_err!("invalid addr: {addr:?}");
+  };
+  Ok(addr
+    .and_then(|addr| d.read::<Addr>(addr))
+    .map(|a| d.get_class_string(a)))
+}
+
+pub async fn get_fields(
+  env: &HttpEnv, session: &mut Session, req: GetFieldsRequest,
+) -> Result<GetFieldsResponse, CommandError> {
+  let FieldsQuery {type_, root, tree, version} = match req {
+    GetFieldsRequest::Type(type_) => FieldsQuery {type_, root: None, tree: None, version: None},
+    GetFieldsRequest::Query(query) => query,
+  };
+  let (type_, root, tree) = (type_.as_str(), root.as_deref(), tree.unwrap_or(false));
+  // the fields can be viewed without a running game if the version is specified
+  let (profile, ver) = match version {
+    Some(ver) => (None, ver),
+    None => {
+      let_dolphin!(d, env, session);
+      (Some(d.profile().name.clone()), d.ver())
+    },
+  };
+  let_obj_params!(obj_params, env, profile.as_deref(), ver);
+  Ok(match type_fields(obj_params, type_)? {
+    ObjectType::Primitive(_) => {
+      let type_ = Arc::<str>::from(type_);
+      let empty = Arc::<str>::from("");
+      match tree {
+        true => GetFieldsResponse::Tree(vec![FieldTreeNode::Field(FieldInfo {
+          id: empty.clone(), name: "value".into(), offset: "0".into(), notes: empty.clone(),
+          type_: type_.clone(), class: type_, file: empty, when: vec![], expr: None,
+        })]),
+        false => GetFieldsResponse::Rows(vec![FieldRow(
+          "0".into(), "value".into(), empty.clone(), type_.clone(), type_, empty.clone(), empty, vec![], None,
+        )]),
+      }
+    },
+    ObjectType::Class(class) => {
+      let nodes = field_nodes(class, root)?;
+      let expr = |r: &ClassField<SMSDolphin>| r.expr.as_ref().map(|c| c.expr.src.clone());
+      match tree {
+        true => GetFieldsResponse::Tree(fields_tree(class, nodes, &|r| FieldTreeNode::Field(FieldInfo {
+          id: r.id.clone(),
+          name: r.name.clone(),
+          offset: r.offset.to_string(),
+          notes: r.notes.clone(),
+          type_: r.type_.clone(),
+          class: r.class.clone(),
+          file: r.file.clone(),
+          when: conditions(class, r),
+          expr: expr(r),
+        }), &|node, children| {
+          let FieldNodeKind::Group {offset, type_, notes, file, ..} = &node.kind else {unreachable!()};
+          FieldTreeNode::Group(FieldGroupInfo {
+            id: node.id.clone(),
+            name: node.name.clone(),
+            offset: offset.to_string(),
+            notes: notes.clone(),
+            type_: type_.clone(),
+            file: file.clone(),
+            children,
+          })
+        })),
+        false => {
+          let mut indices = Vec::new();
+          nodes.iter().for_each(|e| e.field_indices(&mut indices));
+          GetFieldsResponse::Rows(indices.into_iter().map(|i| {
+            let r = &class.fields[i];
+            FieldRow(
+              r.offset.to_string(), r.name.clone(), r.notes.clone(), r.type_.clone(), r.class.clone(),
+              r.id.clone(), r.file.clone(), conditions(class, r), expr(r),
+            )
+          }).collect())
         },
-      })
+      }
     },
+  })
+}
 
-    "readString" => {
-      let_dolphin!(d);
-      let Ok(addr) = body.get("addr").ok_or(()).and_then(|o| d.resolve_addr(o)) else {
-        return_err!("Invalid body: {body:?}");
-      };
-      Ok(addr
-        .and_then(|addr| d.read_str(addr))
-        .map(|addr| json!(addr))
-        .unwrap_or_else(|| json!(null)))
-    },
+pub async fn get_version(env: &HttpEnv, session: &mut Session, _: ()) -> Result<SMSVersion, CommandError> {
+  let_dolphin!(d, env, session);
+  Ok(d.ver())
+}
 
-    "write" => {
-      let_dolphin!(d);
-      let (Ok(addr), Ok(payload)) = (
-        body.get("addr").ok_or(()).and_then(|o| d.resolve_addr(o)),
-        body.get("payload")
-          .and_then(|x| x.as_str()).ok_or(())
-          .and_then(|s| (0..s.len()).step_by(2)
-            .map(|i| u8::from_str_radix(&s[i..i+2], 16).map_err(|_| ()))
-            .collect::<Result<Vec<u8>, ()>>()
-          ),
-      ) else {
-        return_err!("Invalid body: {body:?}");
-      };
-      Ok(json!(
-        addr.and_then(|addr| d.write_bytes(addr, &payload)).is_some()
-      ))
-    },
+pub async fn get_profile(env: &HttpEnv, session: &mut Session, _: ()) -> Result<GameProfileInfo, CommandError> {
+  let_dolphin!(d, env, session);
+  Ok(d.profile().info())
+}
 
-    "getClass" => {
-      let_dolphin!(d);
-      let Ok(addr) = body.get("addr").ok_or(()).and_then(|o| d.resolve_addr(o)) else {
-        return_err!("Invalid body: {body:?}");
-      };
-      Ok(addr
-        .and_then(|addr| d.read::<Addr>(addr))
-        .map(|a| json!(d.get_class_string(a)))
-        .unwrap_or_else(|| json!(null)))
-    },
+pub async fn get_profiles(env: &HttpEnv, _: &mut Session, _: ()) -> Result<GameProfilesInfo, CommandError> {
+  Ok(env.profiles.info())
+}
 
-    "getFields" => {
-      let Some(type_) = body.as_str() else {
-        return_err!("body must be a string");
-      };
-      let_obj_params_fields!(fields, type_);
-      Ok(match fields {
-        // [offsets, name, value, notes, type, class]
-        ObjectType::Primitive(_) =>
-          json!([["0", "value", "", type_, type_]]),
-        ObjectType::Class(fields) => JsonValue::Array(fields.iter().map(|r| json!([
-          r.offset.to_string(), r.name, r.notes, r.type_, r.class,
-        ])).collect()),
-      })
-    },
+pub async fn import_dmw(_: &HttpEnv, _: &mut Session, req: ImportDmwRequest) -> Result<ImportedClasses, CommandError> {
+  let ImportDmwRequest {dmw, name} = req;
+  dmw::import_dmw(&dmw, name.as_deref().unwrap_or("Watches")).map_err(|e| json!(e).into())
+}
 
-    "getVersion" => {
-      let_dolphin!(d);
-      Ok(json!(d.ver().to_string()))
-    },
+pub async fn export_dmw(env: &HttpEnv, session: &mut Session, req: ExportDmwRequest) -> Result<DmwFile, CommandError> {
+  let ExportDmwRequest {addr, type_, root} = req;
+  let_dolphin!(d, env, session);
+  // the address is exported as is, without being resolved
+  let addr = match &addr {
+    None => Some(AddrOffsets(0, [].into())),
+    Some(addr) => addr_offsets(addr),
+  };
+  let Some(addr) = addr else {
+    return_err!("invalid addr: {addr:?}");
+  };
+  let (type_, root) = (type_.as_str(), root.as_deref());
+  let_obj_params!(obj_params, env, Some(&d.profile().name), d.ver());
+  let ObjectType::Class(class) = type_fields(obj_params, type_)? else {
+    return_err!("\"{type_}\" is not a class");
+  };
+  Ok(dmw::export_dmw(class, field_nodes(class, root)?, &addr))
+}
 
-    "reload" => {
-      let mut lock_obj_params = env.obj_params_result.lock().await;
-      load_obj_params(&env.obj_params_dir)
-        .map(|db| {
-          *lock_obj_params = Ok(db);
-          json!(null)
-        })
-        .map_err(|e| json!(e.to_string()))
+pub async fn export_types(env: &HttpEnv, session: &mut Session, req: ExportTypesRequest) -> Result<String, CommandError> {
+  let ExportTypesRequest {format, type_, version} = req;
+  let export = match format {
+    TypesFormat::C => export_c_header::<SMSDolphin>,
+    TypesFormat::Ghidra => export_ghidra_xml::<SMSDolphin>,
+  };
+  let (profile, ver) = match version {
+    Some(ver) => (None, ver),
+    None => {
+      let_dolphin!(d, env, session);
+      (Some(d.profile().name.clone()), d.ver())
     },
+  };
+  let_obj_params!(obj_params, env, profile.as_deref(), ver);
+  export(obj_params, type_.as_deref()).map_err(|e| json!(e).into())
+}
 
-    _ => {
-      return_err!("Unknown command: {command}")
-    },
+pub async fn import_c_header(_: &HttpEnv, _: &mut Session, src: String) -> Result<ImportedClasses, CommandError> {
+  c_header::import_c_header(&src).map_err(|e| json!(e).into())
+}
+
+pub async fn reload(env: &HttpEnv, _: &mut Session, _: ()) -> Result<Box<[ObjParamsDiagnostic]>, CommandError> {
+  env.reload_vtables();
+  env.reload_obj_params(false).await
+    .map(|(_, diagnostics)| diagnostics)
+    .map_err(|e| CommandError::Unavailable(json!(e.to_string())))
+}
+
+pub async fn reload_vtables(env: &HttpEnv, _: &mut Session, _: ()) -> Result<JsonValue, CommandError> {
+  Ok(env.reload_vtables())
+}
+
+pub async fn describe(_: &HttpEnv, _: &mut Session, _: ()) -> Result<Description, CommandError> {
+  Ok(commands::describe())
+}
+
+/// `{commands: [[command, body]], snapshot?: boolean}` => `[{result} | {error}]` in the same order.
+/// With `snapshot`, all commands read MEM1 from one copy taken before the first command,
+/// so the mutating commands (e.g. `write`), whose changes the copy would not see, are rejected
+pub async fn batch(env: &HttpEnv, conn: &mut Connection, req: BatchRequest) -> Result<Vec<JsonValue>, CommandError> {
+  let BatchRequest {commands, snapshot} = req;
+  if snapshot.unwrap_or(false) {
+    if let Some((command, _)) = commands.iter().find(|(o, _)| find_command(o).is_some_and(|o| o.mutating)) {
+      return_err!("{command} cannot be used in a batch with \"snapshot\"");
+    }
+    handle_command(env, &mut conn.session, "init", &JsonValue::Null).await?;
+    if conn.session.take_snapshot().await.is_none() {
+      return_unavailable!("Fail to take a snapshot of the memory");
+    }
+  }
+  let mut results = Vec::with_capacity(commands.len());
+  for (command, body) in commands.iter() {
+    let result = match command.as_str() {
+      "batch" => Err(json!("batch cannot be nested")),
+      command => handle_connection_command(env, conn, command, body).await,
+    };
+    results.push(match result {
+      Ok(result) => json!({"result": result}),
+      Err(error) => json!({"error": error}),
+    });
+  }
+  conn.session.clear_snapshot();
+  Ok(results)
+}
+
+pub async fn subscribe(env: &HttpEnv, conn: &mut Connection, req: SubscribeRequest) -> Result<u64, CommandError> {
+  Ok(conn.subscriptions.subscribe(env, &mut conn.session, req).await?)
+}
+
+pub async fn unsubscribe(_: &HttpEnv, conn: &mut Connection, id: u64) -> Result<(), CommandError> {
+  Ok(conn.subscriptions.unsubscribe(id)?)
+}
+
+/// body: whether to enable the binary framing (default: true)
+pub async fn binary(_: &HttpEnv, conn: &mut Connection, enable: Option<bool>) -> Result<JsonValue, CommandError> {
+  conn.binary = enable.unwrap_or(true);
+  Ok(binary_info())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run_batch(conn: &mut Connection, body: JsonValue) -> Result<JsonValue, JsonValue> {
+    let env = HttpEnv::for_test();
+    tokio::runtime::Builder::new_current_thread().build().unwrap()
+      .block_on(handle_connection_command(&env, conn, "batch", &body))
+  }
+
+  #[test]
+  fn fields_report_their_files() {
+    let env = HttpEnv::for_test();
+    *env.obj_params_result.try_lock().unwrap() = Ok(crate::obj_params::tests::load_str(&[
+      ("base.json", r#"{"A": {"offsets": [
+        {"offset": "0", "type": "u32", "name": "x"},
+        {"offset": "4", "type": "u32", "name": "y"}
+      ]}}"#),
+      ("mod.json", r#"{"A": {"patch": true, "offsets": [{"offset": "4", "notes": "patched"}]}}"#),
+    ]));
+    let mut session = Session::default();
+    let res = tokio::runtime::Builder::new_current_thread().build().unwrap()
+      .block_on(handle_command(&env, &mut session, "getFields", &json!({"type": "A", "version": "GMSJ01"})))
+      .unwrap();
+    // [offset, name, notes, type, class, id, file, when, expr]
+    let rows = res.as_array().unwrap().iter().map(|o| (&o[1], &o[2], &o[6])).collect::<Vec<_>>();
+    assert_eq!(rows, [(&json!("x"), &json!(""), &json!("base.json")), (&json!("y"), &json!("patched"), &json!("mod.json"))]);
+  }
+
+  #[test]
+  fn batch_results_are_in_order() {
+    let mut conn = Connection::default();
+    let res = run_batch(&mut conn, json!({"commands": [
+      ["binary", false],
+      ["foo", null],
+      ["batch", {"commands": []}],
+      ["binary", true],
+    ]})).unwrap();
+    assert_eq!(res[0], json!({"result": binary_info()}));
+    assert_eq!(res[1], json!({"error": "Unknown command: foo (see `describe` for the commands)"}));
+    assert_eq!(res[2], json!({"error": "batch cannot be nested"}));
+    assert_eq!(res[3], json!({"result": binary_info()}));
+    assert_eq!(res.as_array().unwrap().len(), 4);
+    // the commands run in order
+    assert!(conn.binary);
+  }
+
+  #[test]
+  fn snapshot_batches_reject_mutating_commands() {
+    let mut conn = Connection::default();
+    let body = json!({"commands": [["listFrozen", null], ["write", {"addr": 0x80000000u32, "value": "00"}]], "snapshot": true});
+    assert_eq!(run_batch(&mut conn, body), Err(json!("write cannot be used in a batch with \"snapshot\"")));
+    // rejected before attaching Dolphin
+    assert!(conn.session.dolphin().is_none());
+    for command in ["freeze", "unfreeze", "attach", "detach", "reload"] {
+      let body = json!({"commands": [[command, null]], "snapshot": true});
+      assert!(run_batch(&mut conn, body).is_err(), "{command}");
+    }
+    // without snapshot, the writes are run as the other commands
+    let res = run_batch(&mut conn, json!({"commands": [["detach", null]], "snapshot": false})).unwrap();
+    assert_eq!(res, json!([{"result": null}]));
   }
 }
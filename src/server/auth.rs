@@ -0,0 +1,169 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::net::{IpAddr, SocketAddr};
+use hyper::{Body, Request, header};
+use serde_json::{json, Value as JsonValue};
+use crate::server::commands::find_command;
+
+/// Name of the query parameter of the access token, e.g. `ws://localhost:35353/?token=...`
+pub const TOKEN_PARAM: &str = "token";
+
+/// What a client is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Role {
+  /// all commands
+  #[default]
+  Full,
+  /// all commands except those writing the memory of the game
+  ReadOnly,
+}
+
+impl Role {
+  /// Reject the command if it writes the memory of the game and the role is read-only
+  pub fn check_command(self, command: &str) -> Result<(), JsonValue> {
+    match self {
+      Role::ReadOnly if find_command(command).is_some_and(|o| o.writes) => {
+        Err(json!(format!("{command} is not allowed for read-only clients")))
+      },
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Access tokens and allowed origins of the API (websocket, `/rpc` and `/api/`).
+/// Static files are served without a token
+pub struct Auth {
+  /// token of [`Role::Full`], or `None` to allow full access without a token
+  pub token: Option<String>,
+  /// token of [`Role::ReadOnly`]
+  pub read_only_token: Option<String>,
+  /// origins allowed besides the origin of the server itself (`*` for any origin)
+  pub allowed_origins: Box<[String]>,
+  /// address the server listens on, which is the origin of the server itself
+  pub listen_addr: SocketAddr,
+}
+
+/// A hex string of 128 random bits from the OS
+pub fn generate_token() -> String {
+  let mut bytes = [0u8; 16];
+  getrandom::getrandom(&mut bytes).expect("Fail to get random bytes from the OS");
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Compare in constant time regarding the content
+fn token_eq(a: &str, b: &str) -> bool {
+  a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The token given by `Authorization: Bearer <token>`, or by the query parameter
+fn request_token(req: &Request<Body>) -> Option<String> {
+  let bearer = req.headers().get(header::AUTHORIZATION)
+    .and_then(|o| o.to_str().ok())
+    .and_then(|o| o.strip_prefix("Bearer "))
+    .map(|o| o.trim().to_string());
+  bearer.or_else(|| req.uri().query()?
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(key, _)| *key == TOKEN_PARAM)
+    .and_then(|(_, value)| urlencoding::decode(value).ok())
+    .map(|o| o.into_owned()))
+}
+
+impl Auth {
+  /// The role of the request by its token, or `None` if the token is missing or invalid
+  pub fn role(&self, req: &Request<Body>) -> Option<Role> {
+    let token = request_token(req);
+    let matches = |expected: &Option<String>| expected.as_deref()
+      .zip(token.as_deref())
+      .is_some_and(|(a, b)| token_eq(a, b));
+    if matches(&self.read_only_token) {
+      Some(Role::ReadOnly)
+    } else if self.token.is_none() || matches(&self.token) {
+      Some(Role::Full)
+    } else {
+      None
+    }
+  }
+
+  /// Whether the `Origin` of the request is the server itself or one of the allowed origins.
+  /// Requests without `Origin` (i.e. not from browsers) are allowed
+  pub fn origin_allowed(&self, req: &Request<Body>) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {return true};
+    let Ok(origin) = origin.to_str() else {return false};
+    self.is_server_origin(origin) ||
+      self.allowed_origins.iter().any(|o| o == "*" || o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+  }
+
+  /// Whether the origin is `http://` with the address the server listens on, where `localhost` is accepted
+  /// for loopback addresses, and any IP address for all interfaces (`0.0.0.0` or `::`).
+  /// The `Host` header is not compared, since it is the name of the attacker with DNS rebinding
+  fn is_server_origin(&self, origin: &str) -> bool {
+    let Some(authority) = origin.strip_prefix("http://") else {return false};
+    let (host, port) = match authority.rsplit_once(':') {
+      Some((host, port)) if !port.ends_with(']') => match port.parse::<u16>() {
+        Ok(port) => (host, port),
+        Err(_) => return false,
+      },
+      _ => (authority, 80),
+    };
+    let listen_ip = self.listen_addr.ip();
+    let host_matches = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+      Ok(ip) => ip == listen_ip || listen_ip.is_unspecified(),
+      Err(_) => host.eq_ignore_ascii_case("localhost") && (listen_ip.is_loopback() || listen_ip.is_unspecified()),
+    };
+    host_matches && port == self.listen_addr.port()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn auth(listen_addr: &str) -> Auth {
+    Auth {
+      token: Some(generate_token()),
+      read_only_token: None,
+      allowed_origins: ["https://example.com/".to_string()].into(),
+      listen_addr: listen_addr.parse().unwrap(),
+    }
+  }
+
+  fn allowed(auth: &Auth, origin: &str, host: &str) -> bool {
+    let req = Request::builder().uri("/rpc")
+      .header(header::ORIGIN, origin)
+      .header(header::HOST, host)
+      .body(Body::empty()).unwrap();
+    auth.origin_allowed(&req)
+  }
+
+  #[test]
+  fn tokens_are_random() {
+    let (a, b) = (generate_token(), generate_token());
+    assert_eq!(a.len(), 32);
+    assert!(a.bytes().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(a, b);
+  }
+
+  #[test]
+  fn origin_is_compared_with_the_listen_address() {
+    let local = auth("127.0.0.1:35353");
+    assert!(allowed(&local, "http://127.0.0.1:35353", "127.0.0.1:35353"));
+    assert!(allowed(&local, "http://localhost:35353", "localhost:35353"));
+    assert!(allowed(&local, "https://example.com", "127.0.0.1:35353"));
+    // DNS rebinding: the attacker's name resolves to the server, so Host matches Origin
+    assert!(!allowed(&local, "http://evil.example:35353", "evil.example:35353"));
+    assert!(!allowed(&local, "http://127.0.0.1:8080", "127.0.0.1:8080"));
+    assert!(!allowed(&local, "https://127.0.0.1:35353", "127.0.0.1:35353"));
+    assert!(!allowed(&local, "http://192.168.0.2:35353", "192.168.0.2:35353"));
+    assert!(!allowed(&local, "null", "127.0.0.1:35353"));
+
+    let any = auth("0.0.0.0:35353");
+    assert!(allowed(&any, "http://192.168.0.2:35353", "192.168.0.2:35353"));
+    assert!(!allowed(&any, "http://evil.example:35353", "evil.example:35353"));
+    let v6 = auth("[::1]:80");
+    assert!(allowed(&v6, "http://[::1]", "[::1]"));
+    assert!(allowed(&v6, "http://localhost", "localhost"));
+    assert!(!allowed(&v6, "http://[::2]", "[::2]"));
+  }
+}
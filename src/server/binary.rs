@@ -0,0 +1,168 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use serde_json::{json, Value as JsonValue};
+use crate::{
+  addr::Addr,
+  dolphin::{Dolphin, DolphinMemAddr, addr::MEM1_START_ADDR},
+  server::{http::HttpEnv, api::{Connection, handle_command}, auth::Role},
+};
+
+/// version of the binary framing
+pub const BINARY_VERSION: u32 = 1;
+/// size of the header of a request: id (i32), command (u8), addr (u32), len (u32)
+pub const REQUEST_HEADER_SIZE: usize = 13;
+
+/// `addr` and `len` bytes are read
+pub const CMD_READ: u8 = 1;
+/// the `len` bytes following the header are written to `addr`
+pub const CMD_WRITE: u8 = 2;
+/// like [`CMD_READ`] but within MEM1, where `addr` 0 means the start of MEM1,
+/// and `len` 0 means until the end of MEM1 (i.e. `[0, CMD_SNAPSHOT, 0, 0]` dumps the whole MEM1)
+pub const CMD_SNAPSHOT: u8 = 3;
+
+/// `{version, commands, requestHeaderSize}`, returned when the binary framing is enabled
+pub fn binary_info() -> JsonValue {
+  json!({
+    "version": BINARY_VERSION,
+    "commands": {"read": CMD_READ, "write": CMD_WRITE, "snapshot": CMD_SNAPSHOT},
+    "requestHeaderSize": REQUEST_HEADER_SIZE,
+  })
+}
+
+fn response(id: i32, payload: &[u8]) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(4 + payload.len());
+  buf.extend_from_slice(&id.to_be_bytes());
+  buf.extend_from_slice(payload);
+  buf
+}
+
+/// Handle a binary frame. All integers are big-endian.
+///
+/// Request: `id: i32, command: u8, addr: u32, len: u32, payload: [u8]`
+/// (`id` must be positive; `payload` is only for [`CMD_WRITE`]).
+/// Response: `id: i32` followed by the bytes read (empty for [`CMD_WRITE`]),
+/// or `-id: i32` followed by the UTF-8 error message
+pub async fn handle_binary_frame(env: &HttpEnv, conn: &mut Connection, frame: &[u8]) -> Option<Vec<u8>> {
+  let Some((header, payload)) = frame.split_first_chunk::<REQUEST_HEADER_SIZE>() else {
+    eprintln!("Invalid binary payload (too short): {} byte(s)", frame.len());
+    return None;
+  };
+  let id = i32::from_be_bytes(header[0..4].try_into().unwrap());
+  if id <= 0 {
+    eprintln!("Invalid binary payload (id must be positive): {id}");
+    return None;
+  }
+  Some(match handle_request(env, conn, header, payload).await {
+    Ok(bytes) => response(id, &bytes),
+    Err(msg) => response(-id, msg.as_bytes()),
+  })
+}
+
+async fn handle_request(
+  env: &HttpEnv,
+  conn: &mut Connection,
+  header: &[u8; REQUEST_HEADER_SIZE],
+  payload: &[u8],
+) -> Result<Vec<u8>, String> {
+  if !conn.binary {
+    return Err("binary framing is not enabled; send the \"binary\" command first".to_string());
+  }
+  let cmd = header[4];
+  let addr = u32::from_be_bytes(header[5..9].try_into().unwrap());
+  let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+  if !matches!(cmd, CMD_READ | CMD_WRITE | CMD_SNAPSHOT) {
+    return Err(format!("unknown binary command: {cmd}"));
+  }
+  if cmd == CMD_WRITE && conn.role == Role::ReadOnly {
+    return Err("write is not allowed for read-only clients".to_string());
+  }
+
+  if let Err(e) = handle_command(env, &mut conn.session, "init", &JsonValue::Null).await {
+    return Err(e.as_str().map_or_else(|| e.to_string(), String::from));
+  }
+  let Some(d) = conn.session.dolphin() else {
+    return Err("Dolphin is not attached".to_string());
+  };
+  let read = |addr: u32, len: usize| d
+    .read_memory(Addr(addr), len, |ptr| unsafe {std::slice::from_raw_parts(ptr, len)}.to_vec())
+    .ok_or_else(|| format!("fail to read {len} byte(s) at {addr:08X}"));
+
+  match cmd {
+    CMD_READ => read(addr, len),
+    CMD_WRITE => {
+      if payload.len() != len {
+        return Err(format!("len is {len} but {} byte(s) follow the header", payload.len()));
+      }
+      let res = d.write_bytes(Addr(addr), payload)
+        .map(|_| Vec::new())
+        .ok_or_else(|| format!("fail to write {len} byte(s) at {addr:08X}"));
+      conn.session.invalidate_cache();
+      res
+    },
+    CMD_SNAPSHOT => {
+      let addr = if addr == 0 {MEM1_START_ADDR.0} else {addr};
+      let space = match DolphinMemAddr::try_from(Addr(addr)) {
+        Ok(maddr @ DolphinMemAddr::MEM1(_)) => maddr.space(),
+        _ => return Err(format!("{addr:08X} is not in MEM1")),
+      };
+      read(addr, if len == 0 {space as usize} else {len})
+    },
+    _ => unreachable!(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(id: i32, cmd: u8, addr: u32, len: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.push(cmd);
+    buf.extend_from_slice(&addr.to_be_bytes());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+  }
+
+  /// `(id, payload)` of the response
+  fn handle(conn: &mut Connection, frame: &[u8]) -> Option<(i32, String)> {
+    let env = HttpEnv::for_test();
+    let res = tokio::runtime::Builder::new_current_thread().build().unwrap()
+      .block_on(handle_binary_frame(&env, conn, frame))?;
+    let (id, payload) = res.split_first_chunk::<4>().unwrap();
+    Some((i32::from_be_bytes(*id), String::from_utf8(payload.to_vec()).unwrap()))
+  }
+
+  #[test]
+  fn invalid_frames_are_ignored() {
+    let mut conn = Connection {binary: true, ..Connection::default()};
+    assert_eq!(handle(&mut conn, &[]), None);
+    assert_eq!(handle(&mut conn, &frame(1, CMD_READ, 0x80000000, 4, &[])[..REQUEST_HEADER_SIZE-1]), None);
+    assert_eq!(handle(&mut conn, &frame(0, CMD_READ, 0x80000000, 4, &[])), None);
+    assert_eq!(handle(&mut conn, &frame(-1, CMD_READ, 0x80000000, 4, &[])), None);
+  }
+
+  #[test]
+  fn errors_are_responded_with_negative_id() {
+    assert_eq!(response(5, b"ab"), [0, 0, 0, 5, b'a', b'b']);
+    assert_eq!(response(-5, b""), [0xff, 0xff, 0xff, 0xfb]);
+    let mut conn = Connection::default();
+    assert_eq!(handle(&mut conn, &frame(7, CMD_READ, 0x80000000, 4, &[])), Some((
+      -7, "binary framing is not enabled; send the \"binary\" command first".into(),
+    )));
+    conn.binary = true;
+    assert_eq!(handle(&mut conn, &frame(0x12345678, 0xff, 0x80000000, 4, &[])), Some((
+      -0x12345678, "unknown binary command: 255".into(),
+    )));
+  }
+
+  #[test]
+  fn read_only_clients_cannot_write() {
+    let mut conn = Connection {binary: true, role: Role::ReadOnly, ..Connection::default()};
+    assert_eq!(handle(&mut conn, &frame(3, CMD_WRITE, 0x80000000, 2, &[1, 2])), Some((
+      -3, "write is not allowed for read-only clients".into(),
+    )));
+  }
+}
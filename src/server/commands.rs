@@ -0,0 +1,441 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{collections::BTreeMap, sync::Arc};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value as JsonValue};
+use crate::{
+  json_schema::{JsonSchema, JSON_SCHEMA_DIALECT},
+  sms::{SMSVersion, profile::{GameProfileInfo, GameProfilesInfo}},
+  obj_params::{ImportedClasses, dmw::DmwFile, diagnostic::ObjParamsDiagnostic},
+  server::{
+    api::{self, CommandError, Connection, parse_body},
+    http::HttpEnv,
+    subscription::SubscribeRequest,
+    session::{AttachRequest, InstanceInfo, Session},
+    freeze::{FreezeRequest, FrozenInfo, UnfreezeRequest},
+  },
+};
+use sup_smsac_derive::JsonSchema;
+
+/// Address, or `[address, ...offsets]` to follow the pointers
+/// (i.e. `[a, b, c]` is `*(*(a)+b)+c`)
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ReqAddr {
+  Addr(u32),
+  Pointer(Vec<i64>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ReadRequest {
+  pub addr: ReqAddr,
+  /// number of bytes to read as hex (exclusive with `type`)
+  pub size: Option<usize>,
+  /// class or primitive type to read, or `auto` to resolve the class by the vtable
+  #[serde(rename = "type")]
+  pub type_: Option<String>,
+  /// id of the field (group) to read
+  pub root: Option<String>,
+  /// whether to return nested arrays of the field tree
+  pub tree: Option<bool>,
+  /// whether to read the inapplicable fields too, and return `{values, applicable}`
+  pub applicable: Option<bool>,
+}
+
+/// Value of a field (`null` if it cannot be read, or is inapplicable),
+/// or the values of the fields (nested arrays of the field groups with `tree`)
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FieldValue {
+  Value(Option<String>),
+  Group(Vec<FieldValue>),
+}
+
+/// Whether each field is applicable, in the same shape as [`FieldValue`]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FieldApplicable {
+  Value(bool),
+  Group(Vec<FieldApplicable>),
+}
+
+/// Values with the applicability (with `applicable`), or with the class of the object (with `type: "auto"`)
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TaggedValues {
+  pub values: FieldValue,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub applicable: Option<FieldApplicable>,
+  /// class of the object by its vtable
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub class: Option<Option<Arc<str>>>,
+  /// class whose ObjectParameters are used
+  #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+  pub type_: Option<Arc<str>>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ReadResponse {
+  Values(FieldValue),
+  Tagged(TaggedValues),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AddrRequest {
+  pub addr: ReqAddr,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct WriteRequest {
+  pub addr: ReqAddr,
+  /// bytes to write in hex
+  pub payload: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FieldsQuery {
+  #[serde(rename = "type")]
+  pub type_: String,
+  /// id of the field group whose fields are returned
+  pub root: Option<String>,
+  /// whether to return the field tree
+  pub tree: Option<bool>,
+  /// version of the ObjectParameters (default: the version of the attached game)
+  pub version: Option<SMSVersion>,
+}
+
+/// Type, or the query of the fields
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum GetFieldsRequest {
+  Type(String),
+  Query(FieldsQuery),
+}
+
+/// A condition of a field, where `field` is the id of the referenced field
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FieldConditionInfo {
+  pub field: Arc<str>,
+  pub equals: u32,
+}
+
+/// `[offsets, name, notes, type, class, id, file, when, expr]`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FieldRow(
+  pub String, pub Arc<str>, pub Arc<str>, pub Arc<str>, pub Arc<str>, pub Arc<str>, pub Arc<str>,
+  pub Vec<FieldConditionInfo>, pub Option<Arc<str>>,
+);
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FieldInfo {
+  pub id: Arc<str>,
+  pub name: Arc<str>,
+  pub offset: String,
+  pub notes: Arc<str>,
+  #[serde(rename = "type")]
+  pub type_: Arc<str>,
+  pub class: Arc<str>,
+  pub file: Arc<str>,
+  pub when: Vec<FieldConditionInfo>,
+  pub expr: Option<Arc<str>>,
+}
+
+/// An embedded class
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FieldGroupInfo {
+  pub id: Arc<str>,
+  pub name: Arc<str>,
+  pub offset: String,
+  pub notes: Arc<str>,
+  #[serde(rename = "type")]
+  pub type_: Arc<str>,
+  pub file: Arc<str>,
+  pub children: Vec<FieldTreeNode>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FieldTreeNode {
+  Field(FieldInfo),
+  Group(FieldGroupInfo),
+}
+
+/// The fields as rows, or the field tree with `tree`
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum GetFieldsResponse {
+  Rows(Vec<FieldRow>),
+  Tree(Vec<FieldTreeNode>),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ImportDmwRequest {
+  pub dmw: DmwFile,
+  /// name of the class containing the whole watch list (default: `Watches`)
+  pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExportDmwRequest {
+  /// address of the object (pointers are kept as pointer offsets; default: 0)
+  pub addr: Option<ReqAddr>,
+  #[serde(rename = "type")]
+  pub type_: String,
+  /// id of the field (group) to export
+  pub root: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TypesFormat {
+  C,
+  Ghidra,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExportTypesRequest {
+  pub format: TypesFormat,
+  /// class to export with its embedded classes (default: all classes)
+  #[serde(rename = "type")]
+  pub type_: Option<String>,
+  /// version of the ObjectParameters (default: the version of the attached game)
+  pub version: Option<SMSVersion>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BatchRequest {
+  /// `[command, body]` to run in order
+  pub commands: Vec<(String, JsonValue)>,
+  /// whether to read MEM1 from one copy taken before the first command
+  pub snapshot: Option<bool>,
+}
+
+/// Runs a command with its JSON body, and returns the JSON result
+#[derive(Clone, Copy)]
+pub enum CommandHandler {
+  Session(for<'a> fn(&'a HttpEnv, &'a mut Session, &'a JsonValue) -> BoxFuture<'a, Result<JsonValue, CommandError>>),
+  /// commands depending on the state of a websocket connection
+  Connection(for<'a> fn(&'a HttpEnv, &'a mut Connection, &'a JsonValue) -> BoxFuture<'a, Result<JsonValue, CommandError>>),
+}
+
+/// A command with the schemas of its body and result
+pub struct CommandSpec {
+  pub name: &'static str,
+  pub description: &'static str,
+  /// name of the body given as named params (e.g. JSON-RPC), for the commands whose body is not an object
+  pub param: Option<&'static str>,
+  pub handler: CommandHandler,
+  /// whether the command attaches to Dolphin, so that REST responds the failure with 503
+  pub needs_dolphin: bool,
+  /// whether the command modifies the state of the server or the game, so that REST requires `POST`
+  pub mutating: bool,
+  /// whether the command writes the memory of the game, which is rejected for read-only clients
+  pub writes: bool,
+  request: fn(&mut Map<String, JsonValue>) -> JsonValue,
+  response: fn(&mut Map<String, JsonValue>) -> JsonValue,
+}
+impl CommandSpec {
+  /// whether the command depends on the state of a websocket connection
+  pub fn connection(&self) -> bool {
+    matches!(self.handler, CommandHandler::Connection(_))
+  }
+}
+
+/// `[addr, class, name, count]`
+pub type ManagerRow = (u32, String, String, i32);
+/// `[addr, class, name]`
+pub type ManageeRow = (u32, Option<String>, String);
+
+/// `command!("name", Request => Response, Session(handler), "description", param: "name", flags...)`,
+/// where `handler` is the async fn in [`api`] taking the deserialized body, and the flags are
+/// `needs_dolphin`, `mutating` and `writes`
+macro_rules! command {
+  (@set $spec:ident param $param:literal) => {$spec.param = Some($param)};
+  (@set $spec:ident $flag:ident) => {$spec.$flag = true};
+  (
+    $name:literal, $req:ty => $res:ty, $kind:ident($handler:ident), $desc:literal
+    $(, $key:ident $(: $value:literal)?)*
+  ) => {{
+    // without any option, `spec` is not mutated
+    #[allow(unused_mut)]
+    let mut spec = CommandSpec {
+      name: $name,
+      description: $desc,
+      param: None,
+      handler: CommandHandler::$kind(|env, state, body| Box::pin(async move {
+        let res: $res = api::$handler(env, state, parse_body::<$req>($name, body)?).await?;
+        Ok(json!(res))
+      })),
+      needs_dolphin: false,
+      mutating: false,
+      writes: false,
+      request: <$req as JsonSchema>::json_schema,
+      response: <$res as JsonSchema>::json_schema,
+    };
+    $(command!(@set spec $key $($value)?);)*
+    spec
+  }};
+}
+
+/// All commands
+pub const COMMAND_SPECS: &[CommandSpec] = &[
+  command!("init", () => u32, Session(init), "Attach to Dolphin if not attached, and return its pid",
+    needs_dolphin),
+  command!("listInstances", () => Vec<InstanceInfo>, Session(list_instances),
+    "The running Dolphin instances, with the number of the sessions using each instance"),
+  command!("attach", Option<AttachRequest> => u32, Session(attach),
+    "Use the Dolphin instance of the pid in this session (shared with the other sessions using it), and return its pid",
+    mutating),
+  command!("detach", () => (), Session(detach),
+    "Stop using the Dolphin instance, which is closed when no session uses it", mutating),
+  command!("getManagers", () => Option<Vec<ManagerRow>>, Session(get_managers),
+    "The managers of the game as `[addr, class, name, count]`", needs_dolphin),
+  command!("getManagees", u32 => Option<Vec<ManageeRow>>, Session(get_managees),
+    "The managees of the manager at the address as `[addr, class, name]`", param: "addr", needs_dolphin),
+  command!("read", ReadRequest => Option<ReadResponse>, Session(read),
+    "Read the object as `type` (the values of the fields, or nested arrays with `tree`), \
+    or `size` bytes as hex. `null` if the pointers cannot be resolved", needs_dolphin),
+  command!("readString", AddrRequest => Option<String>, Session(read_string), "Read the Shift-JIS string",
+    needs_dolphin),
+  command!("write", WriteRequest => bool, Session(write), "Write the bytes, and return whether it succeeds",
+    needs_dolphin, mutating, writes),
+  command!("freeze", FreezeRequest => u64, Session(freeze),
+    "Keep writing the bytes, or the value as the type (or the field of the class) at a rate or every frame, \
+    and return the id", needs_dolphin, mutating, writes),
+  command!("unfreeze", UnfreezeRequest => (), Session(unfreeze),
+    "Stop writing the value frozen by this session, or the persistent one frozen by another session with `force`",
+    mutating, writes),
+  command!("listFrozen", () => Vec<FrozenInfo>, Session(list_frozen), "The frozen values of the Dolphin instance"),
+  command!("getClass", AddrRequest => Option<String>, Session(get_class), "The class of the object by its vtable",
+    needs_dolphin),
+  command!("getFields", GetFieldsRequest => GetFieldsResponse, Session(get_fields),
+    "The fields of the type as `[offsets, name, notes, type, class, id, file, when, expr]`, \
+    or the field tree with `tree` (of the attached game unless `version` is specified)"),
+  command!("getVersion", () => SMSVersion, Session(get_version), "The version of the attached game", needs_dolphin),
+  command!("getProfile", () => GameProfileInfo, Session(get_profile), "The game profile of the attached game",
+    needs_dolphin),
+  command!("getProfiles", () => GameProfilesInfo, Session(get_profiles), "All game profiles"),
+  command!("importDmw", ImportDmwRequest => ImportedClasses, Session(import_dmw),
+    "Convert a Dolphin Memory Engine watch list into ObjectParameters"),
+  command!("exportDmw", ExportDmwRequest => DmwFile, Session(export_dmw),
+    "Export the fields of an object as a Dolphin Memory Engine watch list", needs_dolphin),
+  command!("exportTypes", ExportTypesRequest => String, Session(export_types),
+    "Export classes as C structs or Ghidra XML data types"),
+  command!("importCHeader", String => ImportedClasses, Session(import_c_header),
+    "Convert C structs into ObjectParameters", param: "src"),
+  command!("reload", () => Box<[ObjParamsDiagnostic]>, Session(reload),
+    "Reload ObjectParameters and vtables, and return the diagnostics", mutating),
+  command!("reloadVTables", () => JsonValue, Session(reload_vtables),
+    "Reload the vtables and the class hierarchy, and return `{versions, hierarchy, diagnostics}`", mutating),
+  command!("describe", () => Description, Session(describe),
+    "The JSON Schema of the body and the result of every command"),
+  command!("batch", BatchRequest => Vec<JsonValue>, Connection(batch),
+    "Run the commands, and return `{result}` or `{error}` of each command in order"),
+  command!("subscribe", SubscribeRequest => u64, Connection(subscribe),
+    "Push the changed values of the reads as `subscription` events, and return the id"),
+  command!("unsubscribe", u64 => (), Connection(unsubscribe), "Remove the subscription", param: "id"),
+  command!("binary", Option<bool> => JsonValue, Connection(binary),
+    "Enable (default) or disable binary frames on the connection", param: "enable"),
+];
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+  COMMAND_SPECS.iter().find(|o| o.name == name)
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandDescription {
+  pub description: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub param: Option<String>,
+  pub connection: bool,
+  pub needs_dolphin: bool,
+  pub mutating: bool,
+  pub writes: bool,
+  /// JSON Schema of the body
+  pub request: JsonValue,
+  /// JSON Schema of the result
+  pub response: JsonValue,
+}
+
+/// The commands with the JSON Schemas of their bodies and results, whose named types are defined in `$defs`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Description {
+  #[serde(rename = "$schema")]
+  pub schema: String,
+  pub commands: BTreeMap<String, CommandDescription>,
+  #[serde(rename = "$defs")]
+  pub defs: Map<String, JsonValue>,
+}
+
+pub fn describe() -> Description {
+  let mut defs = Map::new();
+  let commands = COMMAND_SPECS.iter().map(|o| (o.name.to_string(), CommandDescription {
+    description: o.description.to_string(),
+    param: o.param.map(String::from),
+    connection: o.connection(),
+    needs_dolphin: o.needs_dolphin,
+    mutating: o.mutating,
+    writes: o.writes,
+    request: (o.request)(&mut defs),
+    response: (o.response)(&mut defs),
+  })).collect();
+  Description {
+    schema: JSON_SCHEMA_DIALECT.to_string(),
+    commands,
+    defs,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn refs(schema: &JsonValue, out: &mut Vec<String>) {
+    match schema {
+      JsonValue::Object(o) => o.iter().for_each(|(key, value)| match (key.as_str(), value) {
+        ("$ref", JsonValue::String(name)) => out.push(name.trim_start_matches("#/$defs/").into()),
+        _ => refs(value, out),
+      }),
+      JsonValue::Array(o) => o.iter().for_each(|value| refs(value, out)),
+      _ => {},
+    }
+  }
+
+  #[test]
+  fn describe_defines_all_refs() {
+    let description = json!(describe());
+    assert_eq!(description["commands"].as_object().unwrap().len(), COMMAND_SPECS.len());
+    let mut names = Vec::new();
+    refs(&description, &mut names);
+    assert!(names.contains(&"ReadResponse".to_string()));
+    for name in names {
+      assert!(description["$defs"].get(&name).is_some_and(|o| o.is_object()), "{name} is not defined");
+    }
+  }
+
+  #[test]
+  fn typed_results_keep_the_wire_format() {
+    let tagged = ReadResponse::Tagged(TaggedValues {
+      values: FieldValue::Group(vec![FieldValue::Value(Some("1".into())), FieldValue::Value(None)]),
+      applicable: None,
+      class: Some(None),
+      type_: Some("_default".into()),
+    });
+    assert_eq!(json!(tagged), json!({"values": ["1", null], "class": null, "type": "_default"}));
+    let row = FieldRow(
+      "4".into(), "HP".into(), "".into(), "s32".into(), "A".into(), "/HP".into(), "a.json".into(),
+      vec![FieldConditionInfo {field: "/kind".into(), equals: 2}], None,
+    );
+    assert_eq!(json!(row), json!(["4", "HP", "", "s32", "A", "/HP", "a.json", [{"field": "/kind", "equals": 2}], null]));
+  }
+
+  #[test]
+  fn empty_params_are_no_body() {
+    assert!(parse_body::<()>("detach", &json!({})).is_ok());
+    assert!(parse_body::<()>("detach", &json!({"x": 1})).is_err());
+    assert_eq!(parse_body::<Option<bool>>("binary", &json!({})).ok(), Some(None));
+  }
+}
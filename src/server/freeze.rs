@@ -0,0 +1,345 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::BTreeMap;
+use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use crate::{
+  addr::{Addr, AddrOffsets},
+  dolphin::Dolphin,
+  sms::SMSDolphin,
+  json_schema::JsonSchema,
+  server::{
+    commands::ReqAddr,
+    subscription::{Trigger, TriggerKind, AddrJson, FRAME_POLL_INTERVAL, read_frame},
+  },
+};
+use sup_smsac_derive::JsonSchema;
+
+/// default rate of a freeze (times per second)
+const DEFAULT_RATE: f64 = 60.0;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FreezeRequest {
+  pub addr: ReqAddr,
+  /// bytes to write in hex (exclusive with `type`)
+  pub payload: Option<String>,
+  /// primitive type of the value at `addr`, or class of the object at `addr` whose `field` is written
+  #[serde(rename = "type")]
+  pub type_: Option<String>,
+  /// id of the field of the class
+  pub field: Option<String>,
+  /// value to write as `type` (or the type of `field`)
+  pub value: Option<f64>,
+  /// times per second (default: 60)
+  pub rate: Option<f64>,
+  /// `frame`: write whenever the frame counter changes instead of at the rate
+  pub trigger: Option<TriggerKind>,
+  /// address of the frame counter (default: the `frame` global of the game profile, if defined)
+  pub frame_addr: Option<AddrJson>,
+  /// whether to keep writing after the session disconnects (until `unfreeze`, `detach` or Dolphin exits)
+  pub persistent: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct UnfreezeOptions {
+  pub id: u64,
+  /// whether to remove a persistent freeze created by another session
+  pub force: Option<bool>,
+}
+
+/// Id of the freeze, or `{id, force}`
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum UnfreezeRequest {
+  Id(u64),
+  Options(UnfreezeOptions),
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FrozenInfo {
+  pub id: u64,
+  pub addr: ReqAddr,
+  #[serde(rename = "type")]
+  pub type_: Option<String>,
+  pub field: Option<String>,
+  /// bytes written in hex
+  pub payload: String,
+  pub persistent: bool,
+  /// whether the freeze is created by this session
+  pub owned: bool,
+  /// error of the last write (e.g. the pointers cannot be resolved), or `null` if it succeeds
+  pub error: Option<String>,
+}
+
+/// The big-endian bytes of the value as the primitive type, or `None` if the type is not numeric
+/// or the value is out of its range
+pub fn encode_value(type_: &str, value: f64) -> Option<Box<[u8]>> {
+  let int = |min: f64, max: f64| (value.fract() == 0.0 && (min..=max).contains(&value)).then_some(value);
+  Some(match type_ {
+    "u8" => Box::new((int(0.0, u8::MAX.into())? as u8).to_be_bytes()),
+    "s8" => Box::new((int(i8::MIN.into(), i8::MAX.into())? as i8).to_be_bytes()),
+    "u16" => Box::new((int(0.0, u16::MAX.into())? as u16).to_be_bytes()),
+    "s16" => Box::new((int(i16::MIN.into(), i16::MAX.into())? as i16).to_be_bytes()),
+    "u32" => Box::new((int(0.0, u32::MAX.into())? as u32).to_be_bytes()),
+    "s32" => Box::new((int(i32::MIN.into(), i32::MAX.into())? as i32).to_be_bytes()),
+    "float" => Box::new((value as f32).to_be_bytes()),
+    "double" => Box::new(value.to_be_bytes()),
+    // pointers
+    t if t.ends_with('*') => Box::new((int(0.0, u32::MAX.into())? as u32).to_be_bytes()),
+    _ => return None,
+  })
+}
+
+/// A value written repeatedly by the server
+pub struct Freeze {
+  /// id of the session which created the freeze
+  owner: u64,
+  persistent: bool,
+  addr: ReqAddr,
+  type_: Option<String>,
+  field: Option<String>,
+  /// `addr` (and the offsets of the field) as the pointer chain, resolved on every write
+  target: AddrOffsets,
+  payload: Box<[u8]>,
+  trigger: Trigger,
+  next: Instant,
+  last_frame: Option<u32>,
+  error: Option<String>,
+}
+
+impl Freeze {
+  /// Write `payload` to `target` (`addr` of the request, with the offsets of the field if any)
+  /// of the game, which must be able to resolve the frame counter of the frame trigger
+  pub fn new(
+    d: &SMSDolphin, owner: u64, req: FreezeRequest, target: AddrOffsets, payload: Box<[u8]>,
+  ) -> Result<Freeze, JsonValue> {
+    let trigger = Trigger::new(req.rate, req.trigger.as_ref(), req.frame_addr.as_ref(), DEFAULT_RATE)?;
+    trigger.check(d.profile()).map_err(JsonValue::from)?;
+    Ok(Freeze {
+      owner,
+      persistent: req.persistent.unwrap_or(false),
+      addr: req.addr,
+      type_: req.type_,
+      field: req.field,
+      target,
+      payload,
+      trigger,
+      next: Instant::now(),
+      last_frame: None,
+      error: None,
+    })
+  }
+}
+
+/// A copy of a due freeze, which is written without holding the lock of the freezes (see [`Freezes::take_due`])
+pub struct DueFreeze {
+  id: u64,
+  target: AddrOffsets,
+  payload: Box<[u8]>,
+  trigger: Trigger,
+  last_frame: Option<u32>,
+  /// result of the write, or `None` if not written as the frame counter is unchanged
+  result: Option<Result<(), String>>,
+}
+
+impl DueFreeze {
+  /// Write the value, or only check the frame counter with the frame trigger until it changes
+  pub fn run(&mut self, d: &SMSDolphin) {
+    if let Trigger::Frame(addr) = self.trigger {
+      match read_frame(d, addr) {
+        Ok(frame) if self.last_frame == Some(frame) => return,
+        Ok(frame) => self.last_frame = Some(frame),
+        Err(e) => {
+          self.result = Some(Err(e));
+          return;
+        },
+      }
+    }
+    self.result = Some(self.write(d));
+  }
+
+  fn write(&self, d: &SMSDolphin) -> Result<(), String> {
+    let mut addr = Addr(self.target.0);
+    for &off in self.target.1.iter() {
+      addr = d.read::<Addr>(addr).ok_or_else(|| format!("fail to resolve the pointer at {addr}"))? + off;
+    }
+    d.write_bytes(addr, &self.payload)
+      .ok_or_else(|| format!("fail to write {} byte(s) at {addr}", self.payload.len()))
+  }
+}
+
+/// The freezes of a Dolphin instance
+#[derive(Default)]
+pub struct Freezes {
+  freezes: BTreeMap<u64, Freeze>,
+  next_id: u64,
+  /// whether the task writing the values is running
+  pub running: bool,
+}
+
+impl Freezes {
+  pub fn is_empty(&self) -> bool {
+    self.freezes.is_empty()
+  }
+
+  /// Add a freeze, and return its id
+  pub fn insert(&mut self, freeze: Freeze) -> u64 {
+    self.next_id += 1;
+    self.freezes.insert(self.next_id, freeze);
+    self.next_id
+  }
+
+  /// Remove the freeze created by the session, or the persistent freeze of another session with `force`.
+  /// The freezes of another session which are not persistent are removed only by that session
+  pub fn remove(&mut self, id: u64, session: u64, force: bool) -> Result<(), String> {
+    match self.freezes.get(&id) {
+      None => Err(format!("freeze {id} not found")),
+      Some(o) if o.owner == session || (o.persistent && force) => {
+        self.freezes.remove(&id);
+        Ok(())
+      },
+      Some(o) if o.persistent => Err(format!("freeze {id} is created by another session (use \"force\" to remove it)")),
+      Some(_) => Err(format!("freeze {id} is created by another session")),
+    }
+  }
+
+  /// Remove the freezes of the session, except the persistent ones if `keep_persistent`
+  pub fn release(&mut self, owner: u64, keep_persistent: bool) {
+    self.freezes.retain(|_, o| o.owner != owner || (keep_persistent && o.persistent));
+  }
+
+  pub fn clear(&mut self) {
+    self.freezes.clear();
+  }
+
+  /// The freezes in the view of the session
+  pub fn list(&self, session: u64) -> Vec<FrozenInfo> {
+    self.freezes.iter().map(|(&id, o)| FrozenInfo {
+      id,
+      addr: o.addr.clone(),
+      type_: o.type_.clone(),
+      field: o.field.clone(),
+      payload: o.payload.iter().map(|x| format!("{x:02X}")).collect(),
+      persistent: o.persistent,
+      owned: o.owner == session,
+      error: o.error.clone(),
+    }).collect()
+  }
+
+  /// When the next freeze is due
+  pub fn deadline(&self) -> Option<Instant> {
+    self.freezes.values().map(|o| o.next).min()
+  }
+
+  /// Schedule the next run of the due freezes, and return their copies to run (see [`DueFreeze::run`])
+  pub fn take_due(&mut self) -> Vec<DueFreeze> {
+    let now = Instant::now();
+    self.freezes.iter_mut().filter(|(_, o)| o.next <= now).map(|(&id, freeze)| {
+      freeze.next = match freeze.trigger {
+        Trigger::Interval(interval) => (freeze.next + interval).max(now),
+        Trigger::Frame(_) => now + FRAME_POLL_INTERVAL,
+      };
+      DueFreeze {
+        id,
+        target: freeze.target.clone(),
+        payload: freeze.payload.clone(),
+        trigger: freeze.trigger,
+        last_frame: freeze.last_frame,
+        result: None,
+      }
+    }).collect()
+  }
+
+  /// Store the results of the freezes run, except those removed meanwhile
+  pub fn update(&mut self, due: Vec<DueFreeze>) {
+    for o in due {
+      let Some(freeze) = self.freezes.get_mut(&o.id) else {continue};
+      freeze.last_frame = o.last_frame;
+      if let Some(result) = o.result {
+        freeze.error = result.err();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn freeze(owner: u64, persistent: bool) -> Freeze {
+    Freeze {
+      owner,
+      persistent,
+      addr: ReqAddr::Addr(0x80000000),
+      type_: None,
+      field: None,
+      target: AddrOffsets(0x80000000, Box::new([])),
+      payload: Box::new([0]),
+      trigger: Trigger::Interval(Duration::from_secs(1)),
+      next: Instant::now(),
+      last_frame: None,
+      error: None,
+    }
+  }
+
+  fn ids(freezes: &Freezes) -> Vec<u64> {
+    freezes.freezes.keys().copied().collect()
+  }
+
+  #[test]
+  fn detach_releases_persistent_freezes() {
+    let mut freezes = Freezes::default();
+    for (owner, persistent) in [(1, false), (1, true), (2, false), (2, true)] {
+      freezes.insert(freeze(owner, persistent));
+    }
+    // disconnecting keeps the persistent freezes of the session
+    freezes.release(1, true);
+    assert_eq!(ids(&freezes), [2, 3, 4]);
+    // detaching removes all freezes of the session
+    freezes.release(2, false);
+    assert_eq!(ids(&freezes), [2]);
+  }
+
+  #[test]
+  fn unfreeze_requires_owner_or_force() {
+    let mut freezes = Freezes::default();
+    freezes.insert(freeze(1, false));
+    freezes.insert(freeze(1, true));
+    assert!(freezes.remove(1, 2, true).is_err());
+    assert!(freezes.remove(2, 2, false).is_err());
+    assert!(freezes.remove(2, 2, true).is_ok());
+    assert!(freezes.remove(1, 1, false).is_ok());
+    assert!(freezes.remove(1, 1, false).is_err());
+  }
+
+  #[test]
+  fn results_of_removed_freezes_are_dropped() {
+    let mut freezes = Freezes::default();
+    freezes.insert(freeze(1, false));
+    freezes.insert(freeze(1, false));
+    let mut due = freezes.take_due();
+    assert_eq!(due.iter().map(|o| o.id).collect::<Vec<_>>(), [1, 2]);
+    // the next runs are scheduled when taken
+    assert!(freezes.take_due().is_empty());
+
+    // written without the lock, while the first freeze is removed
+    due[0].result = Some(Err("error 1".into()));
+    due[1].result = Some(Err("error 2".into()));
+    freezes.remove(1, 1, false).unwrap();
+    freezes.update(due);
+    assert_eq!(freezes.list(1).iter().map(|o| (o.id, o.error.as_deref())).collect::<Vec<_>>(), [(2, Some("error 2"))]);
+
+    // the error is kept if the freeze is not written (e.g. the frame counter is unchanged)
+    let due = vec![DueFreeze {
+      id: 2, target: AddrOffsets(0, Box::new([])), payload: Box::new([]),
+      trigger: Trigger::Frame(None), last_frame: Some(7), result: None,
+    }];
+    freezes.update(due);
+    assert_eq!(freezes.freezes[&2].last_frame, Some(7));
+    assert_eq!(freezes.freezes[&2].error.as_deref(), Some("error 2"));
+  }
+}
@@ -1,45 +1,160 @@
 /// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
 /// SPDX-License-Identifier: MIT OR Apache-2.0
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 use tokio::net::TcpListener;
-use tokio::fs::File;
-use tokio_util::codec::{BytesCodec, FramedRead};
 use hyper::{Body, Request, Response, StatusCode};
 use hyper_tungstenite::tungstenite;
-use urlencoding;
-use mime_guess;
+use serde_json::{json, Value as JsonValue};
 use crate::{
-  sms::SMSDolphin,
-  obj_params::{load_obj_params, ObjParamsLoadResult},
-  server::ws::serve_websocket,
+  sms::{SMSDolphin, vt::VTables, profile::GameProfiles},
+  obj_params::{ObjParamsLoader, ObjParamsLoadResult, diagnostic::ObjParamsDiagnostic},
+  server::{ws::serve_websocket, watch::watch_obj_params, api::Connection, rpc::{handle_rpc, RPC_SUBPROTOCOL}, rest::{handle_rest, REST_PREFIX}, static_files::StaticFiles, auth::{Auth, Role}, session::SessionManager},
 };
 
+/// An event pushed to all websocket clients
+#[derive(Debug, Clone)]
+pub struct ServerEvent {
+  pub name: &'static str,
+  pub body: JsonValue,
+}
+
 pub struct HttpEnv {
-  static_dir: Box<Path>,
-  pub obj_params_dir: Box<Path>,
+  static_files: StaticFiles,
+  auth: Auth,
+  pub obj_params_loader: Mutex<ObjParamsLoader>,
   pub obj_params_result: Mutex<ObjParamsLoadResult<SMSDolphin>>,
+  pub vtables: Arc<VTables>,
+  pub profiles: GameProfiles,
+  pub events: broadcast::Sender<ServerEvent>,
+  /// Dolphin instances shared by the sessions
+  pub sessions: SessionManager,
+  /// state of the HTTP requests to `/rpc` and `/api/`, shared by the requests with the same token
+  /// (i.e. of the same role), so that the clients of the read-only token cannot use the session of full access
+  /// (the sessions are never dropped, so their freezes are kept until `unfreeze`, `detach` or Dolphin exits).
+  /// Each request runs on a fork of the session (see [`HttpEnv::fork_http_conn`]), so that the requests
+  /// do not wait for each other
+  http_conns: HashMap<Role, Mutex<Connection>>,
 }
 
-pub async fn serve(listener: TcpListener, root_dir: Box<Path>) -> Result<(), tungstenite::Error> {
-  let obj_params_dir = {
-    let mut dir = root_dir.to_path_buf();
-    dir.push("res/ObjectParameters");
-    dir.into_boxed_path()
-  };
-  let obj_params_result = Mutex::new(load_obj_params(&obj_params_dir));
+impl HttpEnv {
+  /// A connection for an HTTP request with the token of the role, whose session is a fork of the session
+  /// of the role (see [`crate::server::session::Session::fork`]). The lock of the role is held only while forking,
+  /// so a slow request (e.g. attaching to Dolphin) does not block the other requests
+  pub async fn fork_http_conn(&self, role: Role) -> Connection {
+    let session = self.http_conns[&role].lock().await.session.fork();
+    Connection {session, role, ..Connection::default()}
+  }
 
-  let env = Arc::new(HttpEnv {
-    static_dir: {
-      let mut static_dir = root_dir.to_path_buf();
-      static_dir.push("www");
-      static_dir.into_boxed_path()
+  /// Apply the attach or detach of the request (see [`HttpEnv::fork_http_conn`]) to the session of the role
+  pub async fn join_http_conn(&self, conn: Connection) {
+    self.http_conns[&conn.role].lock().await.session.join(conn.session);
+  }
+
+  /// Reload ObjectParameters and notify all websocket clients with `objParamsReloaded`.
+  /// Only the files modified since the last load are parsed again, and only the classes depending on them
+  /// are resolved again (see [`ObjParamsLoader`]).
+  /// If `keep_on_error` is set, the current ObjectParameters are kept when the new ones have errors.
+  /// Returns whether the new ObjectParameters are applied, and their diagnostics
+  pub async fn reload_obj_params(
+    &self, keep_on_error: bool,
+  ) -> Result<(bool, Box<[ObjParamsDiagnostic]>), std::io::Error> {
+    let db = self.obj_params_loader.lock().await.load()?;
+    let diagnostics = db.diagnostics.clone();
+    let applied = !(keep_on_error && db.has_error());
+    if applied {
+      *self.obj_params_result.lock().await = Ok(db);
+    }
+    // no receiver is not an error
+    let _ = self.events.send(ServerEvent {
+      name: "objParamsReloaded",
+      body: json!({"applied": applied, "diagnostics": &diagnostics}),
+    });
+    Ok((applied, diagnostics))
+  }
+
+  /// Reload the vtables and the class hierarchy, which are applied to all sessions immediately.
+  /// Returns `{versions, hierarchy, diagnostics}`
+  pub fn reload_vtables(&self) -> JsonValue {
+    let db = self.vtables.reload();
+    for e in db.diagnostics.iter() {
+      eprintln!("{e}");
+    }
+    json!({"versions": db.summary_json(), "hierarchy": db.hierarchy_summary_json(), "diagnostics": db.diagnostics})
+  }
+}
+
+#[cfg(test)]
+impl HttpEnv {
+  /// An environment without ObjectParameters, vtable directories and game profile files
+  pub(crate) fn for_test() -> Self {
+    HttpEnv {
+      static_files: StaticFiles::new(Path::new(".")),
+      auth: Auth {
+        token: None,
+        read_only_token: None,
+        allowed_origins: Box::new([]),
+        listen_addr: ([127, 0, 0, 1], 0).into(),
+      },
+      obj_params_loader: Mutex::new(ObjParamsLoader::new::<&Path>(&[])),
+      obj_params_result: Mutex::new(Err(std::io::ErrorKind::NotFound.into())),
+      vtables: Arc::new(VTables::new(Box::new([]))),
+      profiles: GameProfiles::load(&[]),
+      events: broadcast::channel(16).0,
+      sessions: SessionManager::default(),
+      http_conns: [Role::Full, Role::ReadOnly].into_iter()
+        .map(|role| (role, Mutex::new(Connection {role, ..Connection::default()})))
+        .collect(),
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+  listener: TcpListener,
+  root_dir: Box<Path>,
+  obj_params_dirs: Box<[Box<Path>]>,
+  obj_params_cache: Option<PathBuf>,
+  vtables_dirs: Box<[Box<Path>]>,
+  profiles: GameProfiles,
+  auth: Auth,
+  watch: bool,
+) -> Result<(), tungstenite::Error> {
+  let mut obj_params_loader = ObjParamsLoader::new(&obj_params_dirs).with_profiles(profiles.obj_params_layers());
+  if let Some(path) = obj_params_cache {
+    obj_params_loader = obj_params_loader.with_cache(path);
+  }
+  let obj_params_result = obj_params_loader.load();
+  match &obj_params_result {
+    Ok(db) => for e in db.diagnostics.iter() {
+      eprintln!("{e}");
     },
-    obj_params_dir,
-    obj_params_result,
+    Err(e) => eprintln!("Fail to load ObjectParameters: {e}"),
+  }
+  let vtables = VTables::new(vtables_dirs);
+  for e in vtables.db().diagnostics.iter() {
+    eprintln!("{e}");
+  }
+
+  let env = Arc::new(HttpEnv {
+    static_files: StaticFiles::new(&root_dir),
+    auth,
+    obj_params_loader: Mutex::new(obj_params_loader),
+    obj_params_result: Mutex::new(obj_params_result),
+    vtables: Arc::new(vtables),
+    profiles,
+    events: broadcast::channel(16).0,
+    sessions: SessionManager::default(),
+    http_conns: [Role::Full, Role::ReadOnly].into_iter()
+      .map(|role| (role, Mutex::new(Connection {role, ..Connection::default()})))
+      .collect(),
   });
+  if watch {
+    tokio::spawn(watch_obj_params(env.clone()));
+  }
 
   let http = hyper::server::conn::Http::new();
   loop {
@@ -74,65 +189,81 @@ async fn handle_request(
   env: Arc<HttpEnv>,
 ) -> Result<Response<Body>, tungstenite::Error> {
   let is_upgrade = hyper_tungstenite::is_upgrade_request(&req);
+  let path = req.uri().path();
+  if !(is_upgrade || path.starts_with(REST_PREFIX) || path == "/rpc") {
+    return Ok(env.static_files.serve(&req).await);
+  }
 
-  let mut lock_obj_params = env.obj_params_result.lock().await;
-  if lock_obj_params.is_err() {
-    *lock_obj_params = load_obj_params(&env.obj_params_dir);
-    if let Err(e) = &*lock_obj_params {
-      return Ok(response_text(500, &format!(
-        "Fail to load ObjectParameters at {}: {e}",
-        env.obj_params_dir.to_string_lossy(),
-      )));
-    }
+  // the API requires the access token, and rejects the requests from other websites
+  if !env.auth.origin_allowed(&req) {
+    return Ok(response_text(403, &"Origin not allowed"));
   }
+  let Some(role) = env.auth.role(&req) else {
+    return Ok(response_text(401, &"Missing or invalid access token"));
+  };
 
   if is_upgrade {
-    let (res, ws) = hyper_tungstenite::upgrade(&mut req, None)?;
+    // JSON-RPC 2.0 if requested by the subprotocol
+    let rpc = req.headers().get_all(hyper::header::SEC_WEBSOCKET_PROTOCOL).iter()
+      .filter_map(|o| o.to_str().ok())
+      .flat_map(|o| o.split(','))
+      .any(|o| o.trim() == RPC_SUBPROTOCOL);
+    let (mut res, ws) = hyper_tungstenite::upgrade(&mut req, None)?;
+    if rpc {
+      res.headers_mut().insert(hyper::header::SEC_WEBSOCKET_PROTOCOL, hyper::header::HeaderValue::from_static(RPC_SUBPROTOCOL));
+    }
     let env = env.clone();
     tokio::spawn(async move {
-      if let Err(e) = serve_websocket(ws, env).await {
+      if let Err(e) = serve_websocket(ws, env, rpc, role).await {
         eprintln!("Error in websocket connection: {}", e);
       }
     });
     Ok(res)
+  } else if req.uri().path().starts_with(REST_PREFIX) {
+    Ok(handle_rest(req, &env, role).await)
   } else {
-    let url_path = match urlencoding::decode(req.uri().path()) {
-      Ok(p) if p.starts_with('/') => p,
-      _ => return Ok(Response::builder().status(400).body(Body::empty()).unwrap()),
+    if req.method() != hyper::Method::POST {
+      return Ok(response_text(405, &"JSON-RPC requests must be POST"));
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+      Ok(body) => body,
+      Err(e) => return Ok(response_text(400, &e)),
     };
-
-    let mut file_path = PathBuf::new();
-    file_path.push(&*env.static_dir);
-    file_path.push(if url_path.ends_with('/') {
-      "index.html"
-    } else {
-      &url_path[1..]
-    });
-    Ok(serve_file(&file_path).await)
+    let payload = String::from_utf8_lossy(&body);
+    let mut conn = env.fork_http_conn(role).await;
+    let res = handle_rpc(&env, &mut conn, &payload, false).await;
+    env.join_http_conn(conn).await;
+    Ok(match res {
+      Some(res) => Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap(),
+      // only notifications
+      None => Response::builder().status(204).body(Body::empty()).unwrap(),
+    })
   }
 }
 
-async fn serve_file(path: &Path) -> Response<Body> {
-  match File::open(path).await {
-    Err(err) => response_text(404, &match path.canonicalize() {
-      Ok(e) => format!("\"{}\" not found: {err}", e.to_string_lossy()),
-      Err(_) => {
-        match std::env::current_dir() {
-          Ok(mut cwd) => {
-            cwd.push(path);
-            format!("\"{}\" not found: {err}", cwd.to_string_lossy())
-          },
-          Err(_) => format!("\"{}\" not found: {err}", path.to_string_lossy()),
-        }
-      },
-    }),
-    Ok(file) => {
-      let mut res = Response::builder();
-      if let Some(mime) = mime_guess::from_path(path).first() {
-        res = res.header("Content-Type", format!("{mime}; charset=utf-8"));
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn http_requests_do_not_wait_for_each_other() {
+    let env = HttpEnv::for_test();
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+      let a = env.fork_http_conn(Role::Full).await;
+      // the session of the role is not locked while the request of `a` runs
+      let b = tokio::time::timeout(std::time::Duration::from_secs(1), env.fork_http_conn(Role::Full)).await
+        .expect("the session of the role is locked");
+      let c = env.fork_http_conn(Role::ReadOnly).await;
+      assert_eq!(a.session.id(), b.session.id());
+      assert_ne!(a.session.id(), c.session.id());
+      assert_eq!((a.role, c.role), (Role::Full, Role::ReadOnly));
+      for conn in [a, b, c] {
+        env.join_http_conn(conn).await;
       }
-      let stream = FramedRead::new(file, BytesCodec::new());
-      res.body(Body::wrap_stream(stream)).unwrap()
-    },
+      assert!(env.http_conns[&Role::Full].lock().await.session.dolphin().is_none());
+    });
   }
 }
@@ -4,3 +4,13 @@
 pub mod http;
 pub mod ws;
 pub mod api;
+pub mod watch;
+pub mod subscription;
+pub mod binary;
+pub mod rpc;
+pub mod rest;
+pub mod commands;
+pub mod static_files;
+pub mod auth;
+pub mod session;
+pub mod freeze;
@@ -0,0 +1,156 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value as JsonValue};
+use crate::server::{
+  http::HttpEnv,
+  api::{CommandError, handle_command, run_command},
+  commands::find_command,
+  auth::{Role, TOKEN_PARAM},
+};
+
+/// Prefix of the REST endpoints, i.e. `/api/<command>`
+pub const REST_PREFIX: &str = "/api/";
+
+fn response_json(status: StatusCode, body: &JsonValue) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+fn response_error(status: StatusCode, error: JsonValue) -> Response<Body> {
+  response_json(status, &json!({"error": error}))
+}
+
+/// `0x`-prefixed hex or decimal, optionally negative (e.g. `-0x10`) as the offsets in JSON
+fn parse_int(s: &str) -> Option<i64> {
+  let (sign, s) = match s.strip_prefix('-') {
+    Some(s) => (-1, s),
+    None => (1, s),
+  };
+  let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+    None => s.parse::<u64>().ok()?,
+  };
+  i64::try_from(n).ok().map(|n| sign * n)
+}
+
+/// The value of a query parameter. `addr` is a comma-separated list of the address and offsets
+/// (`0x`-prefixed hex or decimal, where the offsets can be negative);
+/// other values are parsed as JSON if valid, or used as strings
+fn query_value(key: &str, value: &str) -> Result<JsonValue, String> {
+  match key {
+    "addr" => {
+      let addr = value.split(',')
+        .map(|s| parse_int(s.trim()).map(|x| json!(x)))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| format!("invalid addr: {value}"))?;
+      Ok(match &addr[..] {
+        [addr] => addr.clone(),
+        _ => JsonValue::Array(addr),
+      })
+    },
+    _ => Ok(serde_json::from_str(value).unwrap_or_else(|_| json!(value))),
+  }
+}
+
+/// The body of the command from the query string, where repeated keys become arrays.
+/// The access token is not a part of the body
+fn query_body(param: Option<&str>, query: &str) -> Result<JsonValue, String> {
+  let mut params = Vec::<(String, Vec<JsonValue>)>::new();
+  for pair in query.split('&').filter(|s| !s.is_empty()) {
+    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+    let decode = |s: &str| urlencoding::decode(&s.replace('+', " "))
+      .map(|s| s.into_owned())
+      .map_err(|e| format!("invalid query string: {e}"));
+    let (key, value) = (decode(key)?, decode(value)?);
+    if key == TOKEN_PARAM {continue}
+    let value = query_value(&key, &value)?;
+    match params.iter_mut().find(|(k, _)| *k == key) {
+      Some((_, values)) => values.push(value),
+      None => params.push((key, vec![value])),
+    }
+  }
+  let mut body = params.into_iter().map(|(key, mut values)| (key, match values.len() {
+    1 => values.remove(0),
+    _ => JsonValue::Array(values),
+  })).collect::<serde_json::Map<_, _>>();
+  Ok(match param {
+    Some(name) => body.remove(name).unwrap_or_default(),
+    None if body.is_empty() => JsonValue::Null,
+    None => JsonValue::Object(body),
+  })
+}
+
+/// Handle `GET /api/<command>?<query>` and `POST /api/<command>` with the JSON body.
+/// The commands share the attached Dolphin with `/rpc`
+pub async fn handle_rest(req: Request<Body>, env: &HttpEnv, role: Role) -> Response<Body> {
+  let Some(command) = req.uri().path().strip_prefix(REST_PREFIX).map(String::from) else {
+    return response_error(StatusCode::NOT_FOUND, json!("not found"));
+  };
+  let Some(spec) = find_command(&command).filter(|o| !o.connection()) else {
+    return response_error(StatusCode::NOT_FOUND, json!(format!("Unknown command: {command}")));
+  };
+
+  if let Err(e) = role.check_command(&command) {
+    return response_error(StatusCode::FORBIDDEN, e);
+  }
+
+  let body = match *req.method() {
+    Method::GET if spec.mutating => {
+      let mut res = response_error(StatusCode::METHOD_NOT_ALLOWED, json!(format!("{command} must be POST")));
+      res.headers_mut().insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static("POST"));
+      return res;
+    },
+    Method::GET => query_body(spec.param, req.uri().query().unwrap_or_default()),
+    Method::POST => match hyper::body::to_bytes(req.into_body()).await {
+      Ok(body) if body.iter().all(|c| c.is_ascii_whitespace()) => Ok(JsonValue::Null),
+      Ok(body) => serde_json::from_slice(&body).map_err(|e| format!("invalid JSON body: {e}")),
+      Err(e) => Err(e.to_string()),
+    },
+    _ => {
+      let mut res = response_error(StatusCode::METHOD_NOT_ALLOWED, json!("method must be GET or POST"));
+      res.headers_mut().insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static("GET, POST"));
+      return res;
+    },
+  };
+  let body = match body {
+    Ok(body) => body,
+    Err(e) => return response_error(StatusCode::BAD_REQUEST, json!(e)),
+  };
+
+  let mut conn = env.fork_http_conn(role).await;
+  let init = match spec.needs_dolphin {
+    true => handle_command(env, &mut conn.session, "init", &JsonValue::Null).await,
+    false => Ok(JsonValue::Null),
+  };
+  let res = match init {
+    Ok(_) => run_command(env, &mut conn.session, &command, &body).await,
+    Err(e) => Err(CommandError::Unavailable(e)),
+  };
+  env.join_http_conn(conn).await;
+  match res {
+    Ok(result) => response_json(StatusCode::OK, &result),
+    Err(CommandError::Request(e)) => response_error(StatusCode::BAD_REQUEST, e),
+    Err(CommandError::Unavailable(e)) => response_error(StatusCode::SERVICE_UNAVAILABLE, e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_signed_offsets() {
+    assert_eq!(parse_int("0x8040A6E8"), Some(0x8040A6E8));
+    assert_eq!(parse_int("-0x10"), Some(-0x10));
+    assert_eq!(parse_int("-16"), Some(-16));
+    assert_eq!(parse_int("--16"), None);
+    assert_eq!(query_body(None, "addr=0x8040A6E8,0x14,-0x10&type=u32").unwrap(),
+      json!({"addr": [0x8040A6E8u32, 0x14, -0x10], "type": "u32"}));
+    assert!(query_body(None, "addr=0x8040A6E8,x").is_err());
+  }
+}
@@ -0,0 +1,181 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use serde_json::{json, Value as JsonValue};
+use crate::server::{
+  http::HttpEnv,
+  api::{CommandError, Connection, run_connection_command},
+  commands::find_command,
+};
+
+/// WebSocket subprotocol selecting JSON-RPC 2.0 instead of the `[id, command, body]` protocol
+pub const RPC_SUBPROTOCOL: &str = "jsonrpc-2.0";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// also used if the command rejects its params, e.g. an invalid body or an unknown type
+pub const INVALID_PARAMS: i64 = -32602;
+/// the command fails for a reason other than its params (e.g. Dolphin is not running, or the client is read-only).
+/// The error of the command is the `data` of the error object
+pub const COMMAND_ERROR: i64 = -32000;
+
+fn error(id: JsonValue, code: i64, message: &str, data: Option<JsonValue>) -> JsonValue {
+  let mut error = json!({"code": code, "message": message});
+  if let Some(data) = data {
+    error["data"] = data;
+  }
+  json!({"jsonrpc": "2.0", "error": error, "id": id})
+}
+
+/// A server event as a JSON-RPC notification
+pub fn notification(method: &str, params: &JsonValue) -> String {
+  json!({"jsonrpc": "2.0", "method": method, "params": params}).to_string()
+}
+
+/// Handle a JSON-RPC 2.0 request or batch, and return the response (`None` if there is nothing to respond,
+/// i.e. only notifications). The commands depending on the state of a WebSocket connection
+/// (e.g. `subscribe`) are available only if `websocket` is set
+pub async fn handle_rpc(env: &HttpEnv, conn: &mut Connection, payload: &str, websocket: bool) -> Option<String> {
+  let payload = match serde_json::from_str::<JsonValue>(payload) {
+    Ok(payload) => payload,
+    Err(e) => return Some(error(JsonValue::Null, PARSE_ERROR, "Parse error", Some(json!(e.to_string()))).to_string()),
+  };
+  match payload {
+    JsonValue::Array(calls) if calls.is_empty() =>
+      Some(error(JsonValue::Null, INVALID_REQUEST, "Invalid Request", Some(json!("empty batch")))),
+    JsonValue::Array(calls) => {
+      let mut responses = Vec::new();
+      for call in calls.iter() {
+        responses.extend(handle_call(env, conn, call, websocket).await);
+      }
+      (!responses.is_empty()).then_some(JsonValue::Array(responses))
+    },
+    call => handle_call(env, conn, &call, websocket).await,
+  }.map(|res| res.to_string())
+}
+
+async fn handle_call(env: &HttpEnv, conn: &mut Connection, call: &JsonValue, websocket: bool) -> Option<JsonValue> {
+  let Some(call) = call.as_object() else {
+    return Some(error(JsonValue::Null, INVALID_REQUEST, "Invalid Request", Some(json!("request must be an object"))));
+  };
+  // a request without id is a notification
+  let id = match call.get("id") {
+    None => None,
+    Some(id @ (JsonValue::Null | JsonValue::Number(_) | JsonValue::String(_))) => Some(id.clone()),
+    Some(_) => return Some(error(JsonValue::Null, INVALID_REQUEST, "Invalid Request", Some(json!("invalid id")))),
+  };
+  let invalid = |msg: &str| Some(error(id.clone().unwrap_or_default(), INVALID_REQUEST, "Invalid Request", Some(json!(msg))));
+  if call.get("jsonrpc").and_then(|o| o.as_str()) != Some("2.0") {
+    return invalid("\"jsonrpc\" must be \"2.0\"");
+  }
+  let Some(method) = call.get("method").and_then(|o| o.as_str()) else {
+    return invalid("\"method\" must be a string");
+  };
+
+  let spec = find_command(method).filter(|o| websocket || !o.connection());
+  let result = if let Some(spec) = spec {
+    // named params are the body of the command
+    let body = match (call.get("params"), spec.param) {
+      (None, _) => Ok(JsonValue::Null),
+      (Some(JsonValue::Object(params)), Some(name)) => Ok(params.get(name).cloned().unwrap_or_default()),
+      (Some(params @ JsonValue::Object(_)), None) => Ok(params.clone()),
+      (Some(_), _) => Err((INVALID_PARAMS, "Invalid params", Some(json!("params must be an object (named params)")))),
+    };
+    match (body, conn.role.check_command(method)) {
+      (Err(e), _) => Err(e),
+      (_, Err(e)) => Err((COMMAND_ERROR, "Command error", Some(e))),
+      (Ok(body), Ok(())) => run_connection_command(env, conn, method, &body).await.map_err(|e| match e {
+        CommandError::Request(e) => (INVALID_PARAMS, "Invalid params", Some(e)),
+        CommandError::Unavailable(e) => (COMMAND_ERROR, "Command error", Some(e)),
+      }),
+    }
+  } else {
+    Err((METHOD_NOT_FOUND, "Method not found", Some(json!(method))))
+  };
+
+  let id = id?;
+  Some(match result {
+    Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+    Err((code, message, data)) => error(id, code, message, data),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::auth::Role;
+
+  fn rpc(payload: &str, websocket: bool) -> Option<JsonValue> {
+    rpc_as(Role::Full, payload, websocket)
+  }
+
+  fn rpc_as(role: Role, payload: &str, websocket: bool) -> Option<JsonValue> {
+    let env = HttpEnv::for_test();
+    let mut conn = Connection {role, ..Connection::default()};
+    tokio::runtime::Builder::new_current_thread().build().unwrap()
+      .block_on(handle_rpc(&env, &mut conn, payload, websocket))
+      .map(|res| serde_json::from_str(&res).unwrap())
+  }
+
+  fn code(res: &JsonValue) -> Option<i64> {
+    res["error"]["code"].as_i64()
+  }
+
+  #[test]
+  fn error_codes() {
+    assert_eq!(rpc("{", false).as_ref().and_then(code), Some(PARSE_ERROR));
+    let res = rpc("1", false).unwrap();
+    assert_eq!((code(&res), &res["id"]), (Some(INVALID_REQUEST), &JsonValue::Null));
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "detach", "id": [1]}"#, false).unwrap();
+    assert_eq!((code(&res), &res["id"]), (Some(INVALID_REQUEST), &JsonValue::Null));
+    let res = rpc(r#"{"jsonrpc": "1.0", "method": "detach", "id": 1}"#, false).unwrap();
+    assert_eq!((code(&res), &res["id"]), (Some(INVALID_REQUEST), &json!(1)));
+    let res = rpc(r#"{"jsonrpc": "2.0", "id": "a"}"#, false).unwrap();
+    assert_eq!((code(&res), &res["id"]), (Some(INVALID_REQUEST), &json!("a")));
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "foo", "id": 1}"#, false).unwrap();
+    assert_eq!((code(&res), &res["error"]["data"]), (Some(METHOD_NOT_FOUND), &json!("foo")));
+    // the commands of WebSocket connections are not available for HTTP
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "unsubscribe", "params": {"id": 1}, "id": 1}"#, false).unwrap();
+    assert_eq!(code(&res), Some(METHOD_NOT_FOUND));
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "getManagees", "params": [1], "id": 1}"#, false).unwrap();
+    assert_eq!(code(&res), Some(INVALID_PARAMS));
+    // the body not matching the params of the command
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "getManagees", "params": {"addr": "x"}, "id": 1}"#, false).unwrap();
+    assert_eq!(code(&res), Some(INVALID_PARAMS));
+    assert_eq!(res["error"]["data"], json!("invalid body of \"getManagees\": invalid type: string \"x\", expected u32"));
+    let write = r#"{"jsonrpc": "2.0", "method": "write", "params": {"addr": 2147483648, "value": "00"}, "id": 1}"#;
+    let res = rpc_as(Role::ReadOnly, write, false).unwrap();
+    assert_eq!((code(&res), &res["error"]["data"]), (Some(COMMAND_ERROR), &json!("write is not allowed for read-only clients")));
+    // ObjectParameters are not available
+    let res = rpc(r#"{"jsonrpc": "2.0", "method": "getFields", "params": {"type": "TMario", "version": "GMSJ01"}, "id": 1}"#, false).unwrap();
+    assert_eq!(code(&res), Some(COMMAND_ERROR));
+    assert_eq!(rpc(r#"{"jsonrpc": "2.0", "method": "detach", "id": null}"#, false),
+      Some(json!({"jsonrpc": "2.0", "result": null, "id": null})));
+  }
+
+  #[test]
+  fn notifications_are_not_responded() {
+    assert_eq!(rpc(r#"{"jsonrpc": "2.0", "method": "detach"}"#, false), None);
+    // even if they fail
+    assert_eq!(rpc(r#"{"jsonrpc": "2.0", "method": "foo"}"#, false), None);
+    assert_eq!(rpc(r#"[{"jsonrpc": "2.0", "method": "detach"}, {"jsonrpc": "2.0", "method": "foo"}]"#, false), None);
+  }
+
+  #[test]
+  fn batches() {
+    let res = rpc("[]", false).unwrap();
+    assert_eq!((code(&res), &res["id"]), (Some(INVALID_REQUEST), &JsonValue::Null));
+    let res = rpc(r#"[
+      {"jsonrpc": "2.0", "method": "detach", "id": 1},
+      {"jsonrpc": "2.0", "method": "detach"},
+      1,
+      {"jsonrpc": "2.0", "method": "foo", "id": "b"}
+    ]"#, false).unwrap();
+    let res = res.as_array().unwrap();
+    assert_eq!(res.len(), 3);
+    assert_eq!(res[0], json!({"jsonrpc": "2.0", "result": null, "id": 1}));
+    assert_eq!((code(&res[1]), &res[1]["id"]), (Some(INVALID_REQUEST), &JsonValue::Null));
+    assert_eq!((code(&res[2]), &res[2]["id"]), (Some(METHOD_NOT_FOUND), &json!("b")));
+  }
+}
@@ -0,0 +1,369 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use crate::{
+  dolphin::{DolphinMemory, PidType},
+  sms::{SMSDolphin, SMSVersion},
+  json_schema::JsonSchema,
+  server::{http::HttpEnv, api::handle_command, freeze::{Freeze, Freezes, FrozenInfo}},
+};
+use sup_smsac_derive::JsonSchema;
+
+/// How long the values read by a session are reused by the other sessions (about one frame at 60 fps)
+const SHARED_CACHE_TTL: Duration = Duration::from_millis(16);
+/// interval to check whether Dolphin is still running while values are frozen
+const ALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct CacheEntry<T> {
+  time: Instant,
+  value: T,
+}
+
+impl<T: Clone> CacheEntry<T> {
+  fn get(&self) -> Option<T> {
+    (self.time.elapsed() < SHARED_CACHE_TTL).then(|| self.value.clone())
+  }
+}
+
+#[derive(Default)]
+struct SharedCache {
+  /// results of `read` by the body
+  reads: HashMap<String, CacheEntry<Result<JsonValue, JsonValue>>>,
+  /// copy of MEM1
+  snapshot: Option<CacheEntry<Arc<[u8]>>>,
+}
+
+/// A Dolphin instance shared by the sessions using it, which is closed when no session uses it
+pub struct Instance {
+  dolphin: SMSDolphin,
+  cache: Mutex<SharedCache>,
+  freezes: Mutex<Freezes>,
+  /// notified when the freezes are added
+  freezes_changed: Notify,
+}
+
+impl Instance {
+  /// Forget the cached values, e.g. after writing the memory
+  fn invalidate(&self) {
+    *self.cache.lock().unwrap() = SharedCache::default();
+  }
+}
+
+/// Write the frozen values until no freeze remains or Dolphin exits.
+/// The instance is kept attached while running, so that persistent freezes survive the sessions
+async fn run_freezes(instance: Arc<Instance>) {
+  let pid = instance.dolphin.pid();
+  let mut alive_check = tokio::time::Instant::now() + ALIVE_CHECK_INTERVAL;
+  loop {
+    // the processes are enumerated, and the memory is written, without holding the lock of the freezes
+    // so that `freeze`, `unfreeze` and `listFrozen` are not blocked meanwhile
+    let mut alive = true;
+    if alive_check <= tokio::time::Instant::now() {
+      alive_check += ALIVE_CHECK_INTERVAL;
+      alive = tokio::task::spawn_blocking(move || DolphinMemory::is_running(pid)).await.unwrap_or(true);
+    }
+    let mut due = {
+      let mut freezes = instance.freezes.lock().unwrap();
+      if !alive {
+        eprintln!("Dolphin (pid: {pid}) exited; the frozen values are released");
+        freezes.clear();
+      }
+      if freezes.is_empty() {
+        freezes.running = false;
+        return;
+      }
+      freezes.take_due()
+    };
+    for freeze in due.iter_mut() {
+      freeze.run(&instance.dolphin);
+    }
+    let deadline = {
+      let mut freezes = instance.freezes.lock().unwrap();
+      freezes.update(due);
+      freezes.deadline().map_or(alive_check, |o| o.min(alive_check))
+    };
+    tokio::select! {
+      _ = tokio::time::sleep_until(deadline) => {},
+      _ = instance.freezes_changed.notified() => {},
+    }
+  }
+}
+
+/// `{pid, profile, version, sessions, selected}` of a running Dolphin
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct InstanceInfo {
+  pub pid: PidType,
+  /// name of the game profile, or `null` if no known game is running
+  pub profile: Option<String>,
+  pub version: Option<SMSVersion>,
+  /// number of the sessions using the instance
+  pub sessions: usize,
+  /// whether the instance is used by this session
+  pub selected: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AttachRequest {
+  /// pid of the Dolphin (default: the instance used by other sessions, or the first Dolphin running SMS)
+  pub pid: Option<PidType>,
+}
+
+/// The Dolphin instances attached by the sessions (websocket connections, and the HTTP API),
+/// shared by the sessions using the same instance
+#[derive(Default)]
+pub struct SessionManager {
+  instances: Mutex<HashMap<PidType, Weak<Instance>>>,
+}
+
+impl SessionManager {
+  /// The instance of `pid` (or any instance if not specified) which is already attached,
+  /// or attach to it
+  fn attach(&self, env: &HttpEnv, pid: Option<PidType>) -> Result<Arc<Instance>, String> {
+    if let Some(instance) = self.attached(pid) {
+      return Ok(instance);
+    }
+    // opening the process may take a while, so the other sessions are not blocked meanwhile
+    let dolphin = SMSDolphin::find(&env.profiles, &env.vtables, pid).map_err(|e| match pid {
+      Some(pid) => format!("{e} (pid: {pid})"),
+      None => e.to_string(),
+    })?;
+    let mut instances = self.instances.lock().unwrap();
+    // another session may have attached to the same process meanwhile
+    if let Some(instance) = instances.get(&dolphin.pid()).and_then(Weak::upgrade) {
+      return Ok(instance);
+    }
+    let instance = Arc::new(Instance {
+      dolphin,
+      cache: Mutex::default(),
+      freezes: Mutex::default(),
+      freezes_changed: Notify::new(),
+    });
+    instances.insert(instance.dolphin.pid(), Arc::downgrade(&instance));
+    Ok(instance)
+  }
+
+  /// The attached instance of `pid`, or any attached instance if not specified
+  fn attached(&self, pid: Option<PidType>) -> Option<Arc<Instance>> {
+    let mut instances = self.instances.lock().unwrap();
+    instances.retain(|_, o| o.strong_count() > 0);
+    match pid {
+      Some(pid) => instances.get(&pid).and_then(Weak::upgrade),
+      None => instances.values().find_map(Weak::upgrade),
+    }
+  }
+
+  /// All running Dolphin instances
+  fn list(&self, env: &HttpEnv, selected: Option<PidType>) -> Vec<InstanceInfo> {
+    // the processes are enumerated and opened without holding the lock
+    let attached = self.instances.lock().unwrap().iter()
+      .filter_map(|(pid, o)| Some((*pid, o.upgrade()?)))
+      .collect::<HashMap<_, _>>();
+    DolphinMemory::list().map(|(pid, d)| {
+      let attached = attached.get(&pid);
+      // not attached by any session: open it only to recognize the game
+      let opened = match (attached, d) {
+        (None, Some(d)) => SMSDolphin::from_dolphin_memory(d, pid, &env.profiles, env.vtables.clone()).ok(),
+        _ => None,
+      };
+      let dolphin = attached.map(|o| &o.dolphin).or(opened.as_ref());
+      InstanceInfo {
+        pid,
+        profile: dolphin.map(|d| d.profile().name.to_string()),
+        version: dolphin.map(|d| d.ver()),
+        // excluding the reference held here
+        sessions: attached.map_or(0, |o| Arc::strong_count(o) - 1),
+        selected: selected == Some(pid),
+      }
+    }).collect()
+  }
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The Dolphin instance used by a session, with the snapshot of the session
+pub struct Session {
+  id: u64,
+  attached: Option<(Arc<Instance>, SMSDolphin)>,
+  /// `Some` if the session is a fork (see [`Session::fork`]), with the instance used when forked
+  /// (`Weak::new()` if none)
+  origin: Option<Weak<Instance>>,
+}
+
+impl Default for Session {
+  fn default() -> Self {
+    Session {id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed), attached: None, origin: None}
+  }
+}
+
+impl Drop for Session {
+  /// The persistent freezes of the session are kept after it disconnects.
+  /// The freezes of a fork belong to the session it is forked from, so they are kept too
+  fn drop(&mut self) {
+    if self.origin.is_none() {
+      self.release(true);
+    }
+  }
+}
+
+impl Session {
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  /// A session with the same id and instance, which runs a command without borrowing this session
+  /// (see [`Session::join`])
+  pub fn fork(&self) -> Session {
+    Session {
+      id: self.id,
+      attached: self.attached.clone(),
+      origin: Some(self.attached.as_ref().map_or_else(Weak::new, |(o, _)| Arc::downgrade(o))),
+    }
+  }
+
+  /// Use the instance of the fork if it attached or detached since forked from this session
+  pub fn join(&mut self, mut fork: Session) {
+    let Some(origin) = fork.origin.clone() else {return};
+    let is_origin = |instance: &Arc<Instance>| std::ptr::eq(origin.as_ptr(), Arc::as_ptr(instance));
+    let changed = match &fork.attached {
+      Some((instance, _)) => !is_origin(instance),
+      None => origin.strong_count() > 0,
+    };
+    if !changed {return}
+    let new_ptr = fork.attached.as_ref().map(|(o, _)| Arc::as_ptr(o));
+    let old = std::mem::replace(&mut self.attached, fork.attached.take());
+    // the origin is released by the fork, but not the instance attached by another fork meanwhile
+    if let Some((instance, _)) = old.filter(|(o, _)| !is_origin(o) && new_ptr != Some(Arc::as_ptr(o))) {
+      instance.freezes.lock().unwrap().release(self.id, false);
+    }
+  }
+
+  pub fn dolphin(&self) -> Option<&SMSDolphin> {
+    self.attached.as_ref().map(|(_, d)| d)
+  }
+
+  /// Use the instance of `pid` (see [`AttachRequest`]) instead of the current one
+  pub fn attach(&mut self, env: &HttpEnv, pid: Option<PidType>) -> Result<&SMSDolphin, String> {
+    let instance = env.sessions.attach(env, pid)?;
+    let d = instance.dolphin.clone();
+    if self.attached.as_ref().is_some_and(|(o, _)| !Arc::ptr_eq(o, &instance)) {
+      self.detach();
+    }
+    Ok(&self.attached.insert((instance, d)).1)
+  }
+
+  /// The instance used by the session, or attach to the default one if none
+  pub fn dolphin_or_attach(&mut self, env: &HttpEnv) -> Result<&SMSDolphin, String> {
+    if self.attached.is_none() {
+      self.attach(env, None)?;
+    }
+    self.dolphin().ok_or_else(|| "Dolphin is not attached".to_string())
+  }
+
+  /// Stop using the instance and unfreeze all values frozen by the session, including the persistent ones.
+  /// The instance is closed if no other session uses it
+  pub fn detach(&mut self) {
+    self.release(false);
+  }
+
+  fn release(&mut self, keep_persistent: bool) {
+    if let Some((instance, _)) = self.attached.take() {
+      instance.freezes.lock().unwrap().release(self.id, keep_persistent);
+    }
+  }
+
+  /// Keep writing the value, and return the id of the freeze
+  pub fn freeze(&self, freeze: Freeze) -> Result<u64, String> {
+    let Some((instance, _)) = &self.attached else {
+      return Err("Dolphin is not attached".to_string());
+    };
+    let mut freezes = instance.freezes.lock().unwrap();
+    let id = freezes.insert(freeze);
+    if !freezes.running {
+      freezes.running = true;
+      tokio::spawn(run_freezes(instance.clone()));
+    }
+    instance.freezes_changed.notify_one();
+    instance.invalidate();
+    Ok(id)
+  }
+
+  /// Remove the freeze of the id created by this session, or the persistent freeze of any session
+  /// using the instance with `force` (see [`Freezes::remove`])
+  pub fn unfreeze(&self, id: u64, force: bool) -> Result<(), String> {
+    match &self.attached {
+      Some((instance, _)) => instance.freezes.lock().unwrap().remove(id, self.id, force),
+      None => Err(format!("freeze {id} not found")),
+    }
+  }
+
+  /// The freezes of the instance
+  pub fn list_frozen(&self) -> Vec<FrozenInfo> {
+    match &self.attached {
+      Some((instance, _)) => instance.freezes.lock().unwrap().list(self.id),
+      None => Vec::new(),
+    }
+  }
+
+  /// `{pid, profile, version, sessions, selected}` of all running Dolphin instances
+  pub fn list_instances(&self, env: &HttpEnv) -> Vec<InstanceInfo> {
+    env.sessions.list(env, self.dolphin().map(|d| d.pid()))
+  }
+
+  /// Read MEM1 from one copy until [`Session::clear_snapshot`] (see [`SMSDolphin::set_snapshot`]).
+  /// The copy is shared with the other sessions taking a snapshot within [`SHARED_CACHE_TTL`]
+  pub async fn take_snapshot(&mut self) -> Option<()> {
+    let (instance, d) = self.attached.as_mut()?;
+    d.clear_snapshot();
+    let cached = instance.cache.lock().unwrap().snapshot.as_ref().and_then(CacheEntry::get);
+    let mem1 = match cached {
+      Some(mem1) => mem1,
+      None => {
+        // MEM1 is copied on a blocking thread without holding the lock of the cache,
+        // so that neither the other tasks nor the other sessions wait for the copy
+        let game = d.clone();
+        let mem1 = tokio::task::spawn_blocking(move || game.copy_mem1()).await.ok()??;
+        instance.cache.lock().unwrap().snapshot = Some(CacheEntry {time: Instant::now(), value: mem1.clone()});
+        mem1
+      },
+    };
+    d.set_snapshot(mem1);
+    Some(())
+  }
+
+  pub fn clear_snapshot(&mut self) {
+    if let Some((_, d)) = &mut self.attached {
+      d.clear_snapshot();
+    }
+  }
+
+  /// Forget the values cached for all sessions using the instance, e.g. after writing the memory
+  pub fn invalidate_cache(&self) {
+    if let Some((instance, _)) = &self.attached {
+      instance.invalidate();
+    }
+  }
+
+  /// `read`, reusing the result of the same read by any session using the instance within [`SHARED_CACHE_TTL`],
+  /// so that simultaneous subscriptions do not multiply the reads
+  pub async fn shared_read(&mut self, env: &HttpEnv, body: &JsonValue) -> Result<JsonValue, JsonValue> {
+    let key = body.to_string();
+    if let Some((instance, _)) = &self.attached {
+      if let Some(value) = instance.cache.lock().unwrap().reads.get(&key).and_then(CacheEntry::get) {
+        return value;
+      }
+    }
+    let res = handle_command(env, self, "read", body).await;
+    if let Some((instance, _)) = &self.attached {
+      let mut cache = instance.cache.lock().unwrap();
+      cache.reads.retain(|_, o| o.time.elapsed() < SHARED_CACHE_TTL);
+      cache.reads.insert(key, CacheEntry {time: Instant::now(), value: res.clone()});
+    }
+    res
+  }
+}
@@ -0,0 +1,203 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use hyper::{Body, Request, Response, StatusCode, header};
+
+/// The directory of the static files, relative to the root directory
+pub const STATIC_DIR: &str = "www";
+
+/// `(path, content, ETag)` of the files in `www/` compiled into the binary
+#[cfg(feature = "embed-www")]
+const EMBEDDED_FILES: &[(&str, &[u8], &str)] = sup_smsac_derive::embed_dir!("www");
+
+/// Where the static files are served from
+pub enum StaticFiles {
+  /// canonicalized directory
+  Dir(Box<Path>),
+  /// the files compiled into the binary
+  #[cfg(feature = "embed-www")]
+  Embedded,
+}
+
+/// The relative path of the file to serve from the URL path (`/` separated), or `None` if it is not allowed.
+/// `.` and empty segments are ignored, and `index.html` is served for directories.
+/// `..`, and segments which could be interpreted as absolute paths or other separators on some platforms are rejected
+pub fn normalize_url_path(url_path: &str) -> Option<String> {
+  let decoded = urlencoding::decode(url_path).ok()?;
+  let rest = decoded.strip_prefix('/')?;
+  let mut segments = Vec::new();
+  for segment in rest.split('/') {
+    match segment {
+      "" | "." => {},
+      ".." => return None,
+      s if s.contains(['\\', ':', '\0']) => return None,
+      s => segments.push(s),
+    }
+  }
+  if rest.is_empty() || rest.ends_with('/') {
+    segments.push("index.html");
+  }
+  Some(segments.join("/"))
+}
+
+/// Whether `If-None-Match` matches the ETag (weak comparison)
+fn etag_matches(req: &Request<Body>, etag: &str) -> bool {
+  let strip = |s: &str| s.trim().trim_start_matches("W/").to_string();
+  req.headers().get_all(header::IF_NONE_MATCH).iter()
+    .filter_map(|o| o.to_str().ok())
+    .flat_map(|o| o.split(','))
+    .any(|o| o.trim() == "*" || strip(o) == strip(etag))
+}
+
+fn response_text(status: StatusCode, msg: &str) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+    .body(Body::from(msg.to_string()))
+    .unwrap()
+}
+
+/// `200` with the body, or `304` if the ETag matches
+fn response_file(req: &Request<Body>, path: &str, etag: &str, body: impl FnOnce() -> Body) -> Response<Body> {
+  let res = Response::builder()
+    .header(header::ETAG, etag)
+    // always revalidate since the files may be edited while the server is running
+    .header(header::CACHE_CONTROL, "no-cache");
+  if etag_matches(req, etag) {
+    return res.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+  }
+  let res = match mime_guess::from_path(path).first() {
+    Some(mime) => res.header(header::CONTENT_TYPE, format!("{mime}; charset=utf-8")),
+    None => res,
+  };
+  res.body(body()).unwrap()
+}
+
+impl StaticFiles {
+  /// `www/` in the root directory, or the embedded files if it does not exist and `embed-www` is enabled
+  pub fn new(root_dir: &Path) -> Self {
+    let dir = root_dir.join(STATIC_DIR);
+    match dir.canonicalize() {
+      Ok(dir) => StaticFiles::Dir(dir.into_boxed_path()),
+      #[cfg(feature = "embed-www")]
+      Err(_) => StaticFiles::Embedded,
+      #[cfg(not(feature = "embed-www"))]
+      Err(e) => {
+        eprintln!("Fail to open {}: {e}", dir.to_string_lossy());
+        StaticFiles::Dir(dir.into_boxed_path())
+      },
+    }
+  }
+
+  /// Serve the file of the request. Error responses contain only the URL path, but not the file system path
+  pub async fn serve(&self, req: &Request<Body>) -> Response<Body> {
+    let url_path = req.uri().path();
+    let Some(path) = normalize_url_path(url_path) else {
+      return response_text(StatusCode::BAD_REQUEST, &format!("Invalid path: {url_path}"));
+    };
+    let not_found = || response_text(StatusCode::NOT_FOUND, &format!("Not found: {url_path}"));
+    match self {
+      StaticFiles::Dir(dir) => {
+        let Some(file_path) = contained_path(dir, &path) else {return not_found()};
+        let Ok(file) = File::open(&file_path).await else {return not_found()};
+        let Ok(meta) = file.metadata().await else {return not_found()};
+        if !meta.is_file() {
+          return not_found();
+        }
+        let mtime = meta.modified().ok()
+          .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+          .map_or(0, |t| t.as_nanos());
+        let etag = format!("\"{:x}-{mtime:x}\"", meta.len());
+        response_file(req, &path, &etag, || Body::wrap_stream(FramedRead::new(file, BytesCodec::new())))
+      },
+      #[cfg(feature = "embed-www")]
+      StaticFiles::Embedded => match EMBEDDED_FILES.iter().find(|(name, _, _)| *name == path) {
+        Some((_, content, etag)) => response_file(req, &path, etag, || Body::from(*content)),
+        None => not_found(),
+      },
+    }
+  }
+}
+
+/// The canonicalized path of the file in `dir`, or `None` if it does not exist or is outside of `dir`
+/// (e.g. through symbolic links)
+fn contained_path(dir: &Path, path: &str) -> Option<PathBuf> {
+  let file_path = path.split('/').fold(dir.to_path_buf(), |p, s| p.join(s)).canonicalize().ok()?;
+  file_path.starts_with(dir).then_some(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(unix)]
+  use std::os::unix::fs::symlink;
+  #[cfg(windows)]
+  use std::os::windows::fs::symlink_file as symlink;
+
+  #[test]
+  fn normalizes_url_paths() {
+    assert_eq!(normalize_url_path("/").as_deref(), Some("index.html"));
+    assert_eq!(normalize_url_path("/a/./b//c.js").as_deref(), Some("a/b/c.js"));
+    assert_eq!(normalize_url_path("/dir/").as_deref(), Some("dir/index.html"));
+    assert_eq!(normalize_url_path("/a%20b.js").as_deref(), Some("a b.js"));
+    // absolute paths stay relative to the directory
+    assert_eq!(normalize_url_path("//etc/passwd").as_deref(), Some("etc/passwd"));
+    assert_eq!(normalize_url_path("/%2Fetc%2Fpasswd").as_deref(), Some("etc/passwd"));
+  }
+
+  #[test]
+  fn rejects_escaping_url_paths() {
+    for path in [
+      "/../secret", "/a/../../secret", "/%2e%2e/secret", "/%2E%2E%2Fsecret", "/a%2F..%2F..%2Fsecret",
+      "/..\\secret", "/a%5c..%5csecret", "/C:/Windows/win.ini", "/c%3a%5cWindows", "/a%00.html",
+      "relative", "",
+    ] {
+      assert_eq!(normalize_url_path(path), None, "{path}");
+    }
+  }
+
+  #[test]
+  fn serves_only_files_in_the_directory() {
+    let tmp = std::env::temp_dir().join(format!("sup-smsac-test-www-{}", std::process::id()));
+    let www = tmp.join("www");
+    std::fs::create_dir_all(www.join("dir")).unwrap();
+    std::fs::write(www.join("index.html"), "index").unwrap();
+    std::fs::write(www.join("dir/a.js"), "a").unwrap();
+    std::fs::write(tmp.join("secret.txt"), "secret").unwrap();
+    let linked = symlink(tmp.join("secret.txt"), www.join("link.txt")).is_ok();
+    let www = www.canonicalize().unwrap();
+
+    assert_eq!(contained_path(&www, "dir/a.js"), Some(www.join("dir/a.js")));
+    assert_eq!(contained_path(&www, "index.html"), Some(www.join("index.html")));
+    assert_eq!(contained_path(&www, "missing.js"), None);
+    assert_eq!(contained_path(&www, "../secret.txt"), None);
+    let absolute = tmp.join("secret.txt").canonicalize().unwrap();
+    assert_eq!(contained_path(&www, &absolute.to_string_lossy()), None);
+    if linked {
+      assert_eq!(contained_path(&www, "link.txt"), None);
+    }
+
+    let files = StaticFiles::Dir(www.clone().into_boxed_path());
+    let serve = |path: &str| {
+      let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+      tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+        let res = files.serve(&req).await;
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+      })
+    };
+    assert_eq!(serve("/"), "index");
+    assert_eq!(serve("/dir/a.js"), "a");
+    // error responses contain neither the file system path nor the content outside of the directory
+    let dirs = [tmp.to_string_lossy().into_owned(), www.to_string_lossy().into_owned()];
+    for path in ["/missing.js", "/link.txt", "/dir", "/%2e%2e/secret.txt", "/a%5cb"] {
+      let body = serve(path);
+      assert!(body != "secret" && dirs.iter().all(|dir| !body.contains(dir)), "{path}: {body}");
+    }
+    let _ = std::fs::remove_dir_all(&tmp);
+  }
+}
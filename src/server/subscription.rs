@@ -0,0 +1,328 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use crate::{
+  addr::Addr,
+  dolphin::Dolphin,
+  sms::{SMSDolphin, profile::GameProfile},
+  json_schema::JsonSchema,
+  server::{http::HttpEnv, api::handle_command, commands::ReadRequest, session::Session},
+};
+use sup_smsac_derive::JsonSchema;
+
+/// default rate of a subscription (times per second)
+const DEFAULT_RATE: f64 = 30.0;
+/// maximum rate of a subscription (times per second)
+const MAX_RATE: f64 = 1000.0;
+/// interval to check whether the game frame changes
+pub const FRAME_POLL_INTERVAL: Duration = Duration::from_millis(4);
+const NO_FRAME_ADDR: &str = "the address of the frame counter is not specified by \"frameAddr\" \
+  nor defined as \"frame\" in the game profile";
+
+/// When a subscription (or a freeze) runs
+#[derive(Clone, Copy)]
+pub enum Trigger {
+  Interval(Duration),
+  /// when the frame counter at the address (or the `frame` global of the game profile) changes
+  Frame(Option<Addr>),
+}
+
+struct Subscription {
+  /// body of each `read`
+  reads: Box<[JsonValue]>,
+  trigger: Trigger,
+  next: Instant,
+  last_frame: Option<u32>,
+  /// last result of each read
+  last: Vec<Option<Result<JsonValue, JsonValue>>>,
+  /// last error of the subscription itself
+  last_error: Option<String>,
+}
+
+/// The subscriptions of a connection
+#[derive(Default)]
+pub struct Subscriptions {
+  subs: HashMap<u64, Subscription>,
+  next_id: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerKind {
+  Frame,
+}
+
+/// Address as a number or a hex string
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AddrJson {
+  Number(u32),
+  Hex(String),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRequest {
+  /// bodies of `read`
+  pub reads: Vec<ReadRequest>,
+  /// times per second (default: 30)
+  pub rate: Option<f64>,
+  /// `frame`: read whenever the frame counter changes instead of at the rate
+  pub trigger: Option<TriggerKind>,
+  /// address of the frame counter (default: the `frame` global of the game profile, if defined)
+  pub frame_addr: Option<AddrJson>,
+}
+
+fn parse_addr(value: &AddrJson) -> Option<Addr> {
+  match value {
+    AddrJson::Number(n) => Some(Addr(*n)),
+    AddrJson::Hex(s) => u32::from_str_radix(s.trim_start_matches("0x"), 16).ok().map(Addr),
+  }
+}
+
+impl Trigger {
+  /// On frame change with `trigger: "frame"` (and `frameAddr`), or at `rate` times per second
+  pub fn new(
+    rate: Option<f64>, trigger: Option<&TriggerKind>, frame_addr: Option<&AddrJson>, default_rate: f64,
+  ) -> Result<Trigger, JsonValue> {
+    Ok(match trigger {
+      None => {
+        let rate = match rate {
+          None => default_rate,
+          Some(rate) if rate > 0.0 && rate <= MAX_RATE => rate,
+          Some(_) => return Err(json!(format!("\"rate\" must be a number in (0, {MAX_RATE}]"))),
+        };
+        Trigger::Interval(Duration::from_secs_f64(1.0 / rate))
+      },
+      Some(TriggerKind::Frame) => Trigger::Frame(match frame_addr {
+        None => None,
+        Some(addr) => match parse_addr(addr) {
+          Some(addr) => Some(addr),
+          None => return Err(json!("\"frameAddr\" must be an address")),
+        },
+      }),
+    })
+  }
+
+  /// Check that the frame counter can be resolved for the game profile, as every poll fails otherwise
+  pub fn check(&self, profile: &GameProfile) -> Result<(), String> {
+    match self {
+      Trigger::Frame(None) if !profile.has_global("frame") => Err(NO_FRAME_ADDR.to_string()),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// The frame counter at `addr`, or at the `frame` global of the game profile
+pub fn read_frame(d: &SMSDolphin, addr: Option<Addr>) -> Result<u32, String> {
+  frame_counter(d, d.profile(), addr)
+}
+fn frame_counter(d: &impl Dolphin, profile: &GameProfile, addr: Option<Addr>) -> Result<u32, String> {
+  let addr = match addr.map(Some).or_else(|| profile.global(d, "frame")) {
+    Some(Some(addr)) => addr,
+    Some(None) => return Err("fail to resolve the pointer to the frame counter".to_string()),
+    None => return Err(NO_FRAME_ADDR.to_string()),
+  };
+  d.read::<u32>(addr).ok_or_else(|| format!("fail to read the frame counter at {addr}"))
+}
+
+/// Push `[read, path, value]` of the changed values in `new`, where `path` is the keys/indices from the read value
+fn diff(index: usize, path: &mut Vec<JsonValue>, old: &JsonValue, new: &JsonValue, changes: &mut Vec<JsonValue>) {
+  match (old, new) {
+    (JsonValue::Array(a), JsonValue::Array(b)) if a.len() == b.len() => {
+      for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+        path.push(json!(i));
+        diff(index, path, a, b, changes);
+        path.pop();
+      }
+    },
+    (JsonValue::Object(a), JsonValue::Object(b)) if a.len() == b.len() && a.keys().all(|k| b.contains_key(k)) => {
+      for (k, a) in a.iter() {
+        path.push(json!(k));
+        diff(index, path, a, &b[k], changes);
+        path.pop();
+      }
+    },
+    (old, new) => if old != new {
+      changes.push(json!([index, path, new]));
+    },
+  }
+}
+
+impl Subscriptions {
+  /// Add a subscription, and return its id.
+  /// The frame trigger without `frameAddr` requires the attached game to define the `frame` global
+  pub async fn subscribe(
+    &mut self, env: &HttpEnv, session: &mut Session, req: SubscribeRequest,
+  ) -> Result<u64, JsonValue> {
+    let trigger = Trigger::new(req.rate, req.trigger.as_ref(), req.frame_addr.as_ref(), DEFAULT_RATE)?;
+    if let Trigger::Frame(None) = trigger {
+      handle_command(env, session, "init", &JsonValue::Null).await?;
+      match session.dolphin() {
+        Some(d) => trigger.check(d.profile()).map_err(|e| json!(e))?,
+        None => return Err(json!("Dolphin is not attached")),
+      }
+    }
+    let reads = req.reads.iter().map(|read| json!(read)).collect::<Box<[_]>>();
+    self.next_id += 1;
+    let id = self.next_id;
+    self.subs.insert(id, Subscription {
+      reads,
+      trigger,
+      next: Instant::now(),
+      last_frame: None,
+      last: vec![None; req.reads.len()],
+      last_error: None,
+    });
+    Ok(id)
+  }
+
+  pub fn unsubscribe(&mut self, id: u64) -> Result<(), JsonValue> {
+    match self.subs.remove(&id) {
+      Some(_) => Ok(()),
+      None => Err(json!(format!("subscription {id} not found"))),
+    }
+  }
+
+  /// When the next subscription is due
+  pub fn deadline(&self) -> Option<Instant> {
+    self.subs.values().map(|o| o.next).min()
+  }
+
+  /// Run the due subscriptions, and return the updates to push as
+  /// `{id, changes: [[read, path, value]], errors: [[read, message]], error?}`,
+  /// where `changes` contains only the values changed since the last update,
+  /// `errors` contains the reads whose errors changed, and `error` is the error of the subscription itself
+  /// (`null` when it is resolved).
+  /// The reads are shared with the subscriptions of other sessions using the same Dolphin instance
+  pub async fn poll(&mut self, env: &HttpEnv, session: &mut Session) -> Vec<JsonValue> {
+    let now = Instant::now();
+    let mut updates = Vec::new();
+    for (&id, sub) in self.subs.iter_mut() {
+      if sub.next > now {continue}
+      let error = match sub.trigger {
+        Trigger::Interval(interval) => {
+          sub.next = (sub.next + interval).max(now);
+          None
+        },
+        Trigger::Frame(addr) => {
+          sub.next = now + FRAME_POLL_INTERVAL;
+          let frame = match handle_command(env, session, "init", &JsonValue::Null).await {
+            Err(e) => Err(e.as_str().unwrap_or_default().to_string()),
+            Ok(_) => match session.dolphin() {
+              Some(d) => read_frame(d, addr),
+              None => Err("Dolphin is not attached".to_string()),
+            },
+          };
+          match frame {
+            Ok(frame) if sub.last_frame == Some(frame) => continue,
+            Ok(frame) => {
+              sub.last_frame = Some(frame);
+              None
+            },
+            Err(e) => Some(e),
+          }
+        },
+      };
+      let mut update = json!({"id": id});
+      if error != sub.last_error {
+        update["error"] = json!(error);
+        sub.last_error = error;
+      }
+      if sub.last_error.is_none() {
+        let mut changes = Vec::new();
+        let mut errors = Vec::new();
+        for (i, body) in sub.reads.iter().enumerate() {
+          let res = session.shared_read(env, body).await;
+          match (&sub.last[i], &res) {
+            (Some(Ok(old)), Ok(new)) => diff(i, &mut Vec::new(), old, new, &mut changes),
+            (_, Ok(new)) => changes.push(json!([i, [], new])),
+            (Some(Err(old)), Err(e)) if old == e => {},
+            (_, Err(e)) => errors.push(json!([i, e])),
+          }
+          sub.last[i] = Some(res);
+        }
+        if !changes.is_empty() {update["changes"] = json!(changes)}
+        if !errors.is_empty() {update["errors"] = json!(errors)}
+      }
+      if update.as_object().is_some_and(|o| o.len() > 1) {
+        updates.push(update);
+      }
+    }
+    updates
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Path;
+  use crate::{
+    addr::AddrOffsets,
+    dolphin::{DolphinMemAddr, addr::{MEM1_SIZE, MEM1_START_ADDR}},
+    sms::{SMSVersion, profile::{GameProfiles, GAME_PROFILES_FILE}},
+  };
+
+  /// MEM1 of a fake game
+  struct Mem1(Vec<u8>);
+  impl Dolphin for Mem1 {
+    unsafe fn read_memory_unchecked<T, F>(&self, maddr: DolphinMemAddr, _: usize, operator: F) -> Option<T>
+      where F: FnOnce(*const u8) -> T
+    {
+      match maddr {
+        DolphinMemAddr::MEM1(offset) => Some(operator(self.0.as_ptr().add(offset as usize))),
+        DolphinMemAddr::MEM2(_) => None,
+      }
+    }
+    unsafe fn write_memory_unchecked(&self, _: DolphinMemAddr, _: &[u8]) -> Option<()> {
+      None
+    }
+  }
+  impl Mem1 {
+    fn write_u32(&mut self, addr: u32, value: u32) {
+      let offset = (addr - MEM1_START_ADDR.0) as usize;
+      self.0[offset..offset+4].copy_from_slice(&value.to_be_bytes());
+    }
+  }
+
+  #[test]
+  fn shipped_profiles_resolve_frame_trigger() {
+    let profiles = GameProfiles::load(&[Box::from(Path::new(GAME_PROFILES_FILE))]);
+    assert!(profiles.diagnostics.is_empty(), "{:?}", profiles.diagnostics);
+    for (game_id, revision, ver) in [
+      (b"GMSJ01", 0, SMSVersion::GMSJ01),
+      (b"GMSE01", 48, SMSVersion::GMSE01),
+      (b"GMSP01", 0, SMSVersion::GMSP01),
+      (b"GMSJ01", 1, SMSVersion::GMSJ0A),
+    ] {
+      let mut header = [0; 8];
+      header[..6].copy_from_slice(game_id);
+      header[7] = revision;
+      let profile = profiles.find(&header).unwrap();
+      assert_eq!(profile.base, ver);
+
+      // `trigger: "frame"` without `frameAddr`
+      let trigger = Trigger::new(None, Some(&TriggerKind::Frame), None, DEFAULT_RATE).unwrap();
+      assert_eq!(trigger.check(&profile), Ok(()), "{ver}");
+      let Trigger::Frame(addr) = trigger else {panic!("not a frame trigger")};
+      assert!(addr.is_none());
+
+      // the frame counter is read through the pointer of the global
+      let frame: AddrOffsets = profile.info().globals["frame"].parse().unwrap();
+      let mut mem1 = Mem1(vec![0; MEM1_SIZE as usize]);
+      assert!(frame_counter(&mem1, &profile, addr).is_err(), "{ver}: null pointer is read");
+      let object = 0x8050_0000;
+      mem1.write_u32(frame.0, object);
+      mem1.write_u32(object + frame.1.iter().sum::<u32>(), 1234);
+      assert_eq!(frame_counter(&mem1, &profile, addr), Ok(1234), "{ver}");
+      // `frameAddr` takes precedence
+      mem1.write_u32(0x8000_1000, 42);
+      assert_eq!(frame_counter(&mem1, &profile, Some(Addr(0x8000_1000))), Ok(42), "{ver}");
+    }
+  }
+}
@@ -0,0 +1,87 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use notify::{Watcher, RecursiveMode, EventKind};
+use crate::server::http::HttpEnv;
+
+/// Wait until no change is made to the files for this duration before reloading,
+/// since editors usually write a file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watch the directories again, since the directories created, removed or re-created
+/// since the last time are not watched correctly.
+/// A missing directory is watched by its nearest existing ancestor until it is created
+fn arm(watcher: &mut impl Watcher, dirs: &[Box<Path>], watched: &mut Vec<PathBuf>) {
+  for path in watched.drain(..) {
+    let _ = watcher.unwatch(&path);
+  }
+  for dir in dirs.iter() {
+    let Some(target) = dir.ancestors().find(|o| o.is_dir()) else {
+      eprintln!("Fail to watch {}: no such directory", dir.to_string_lossy());
+      continue;
+    };
+    if watched.iter().any(|o| o == target) {continue}
+    match watcher.watch(target, RecursiveMode::NonRecursive) {
+      Ok(()) => watched.push(target.to_path_buf()),
+      Err(e) => eprintln!("Fail to watch {}: {e}", target.to_string_lossy()),
+    }
+  }
+}
+
+/// Watch the ObjectParameters directories (including those of the game profiles),
+/// and reload ObjectParameters when they are modified.
+/// The reloaded ObjectParameters are applied only if they have no error
+pub async fn watch_obj_params(env: Arc<HttpEnv>) {
+  let dirs = {
+    let loader = env.obj_params_loader.lock().await;
+    loader.dirs().iter().chain(loader.profile_dirs()).cloned().collect::<Vec<_>>()
+  };
+  let (tx, mut rx) = mpsc::unbounded_channel();
+  let event_dirs = dirs.clone();
+  let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+    match res {
+      // the ancestors of the missing directories are watched too
+      Ok(event) => if !matches!(event.kind, EventKind::Access(_)) && (
+        event.paths.is_empty() ||
+        event.paths.iter().any(|path| event_dirs.iter().any(|dir| path.starts_with(dir) || dir.starts_with(path)))
+      ) {
+        let _ = tx.send(());
+      },
+      Err(e) => eprintln!("Error watching ObjectParameters: {e}"),
+    }
+  }) {
+    Ok(watcher) => watcher,
+    Err(e) => {
+      eprintln!("Fail to watch ObjectParameters: {e}");
+      return;
+    },
+  };
+  let mut watched = Vec::new();
+  arm(&mut watcher, &dirs, &mut watched);
+
+  while rx.recv().await.is_some() {
+    loop {
+      match tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+        Ok(Some(())) => continue,
+        Ok(None) => return,
+        Err(_) => break,
+      }
+    }
+    arm(&mut watcher, &dirs, &mut watched);
+    match env.reload_obj_params(true).await {
+      Ok((applied, diagnostics)) => {
+        let count = diagnostics.len();
+        if applied {
+          eprintln!("ObjectParameters reloaded with {count} diagnostic(s)");
+        } else {
+          eprintln!("ObjectParameters not reloaded due to errors ({count} diagnostic(s))");
+        }
+      },
+      Err(e) => eprintln!("Fail to reload ObjectParameters: {e}"),
+    }
+  }
+}
@@ -1,21 +1,23 @@
 /// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
 /// SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::{
-  sms::SMSDolphin,
-  server::{http::HttpEnv, api::handle_command},
-};
+use crate::server::{http::HttpEnv, api::{Connection, handle_connection_command}, binary::handle_binary_frame, rpc::{handle_rpc, notification}, auth::Role};
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 use futures_util::{SinkExt, StreamExt};
 use hyper_tungstenite::{tungstenite::{self, Message}, HyperWebsocket};
 use serde_json::{self, json, Value as JsonValue};
 
+/// Serve a websocket connection, speaking JSON-RPC 2.0 instead of `[id, command, body]` if `rpc` is set
 pub async fn serve_websocket(
   ws: HyperWebsocket,
   env: Arc<HttpEnv>,
+  rpc: bool,
+  role: Role,
 ) -> Result<(), tungstenite::Error> {
   let mut ws = ws.await?;
-  let mut dolphin: Option<SMSDolphin> = None;
+  let mut conn = Connection {role, ..Connection::default()};
+  let mut events = env.events.subscribe();
   macro_rules! return_err {
     ($($msg:expr),+) => {
       eprintln!($($msg),+);
@@ -23,9 +25,43 @@ pub async fn serve_websocket(
     }
   }
 
-  while let Some(msg) = ws.next().await {
+  loop {
+    let deadline = conn.subscriptions.deadline();
+    let msg = tokio::select! {
+      msg = ws.next() => match msg {
+        Some(msg) => msg,
+        None => break,
+      },
+      event = events.recv() => {
+        match event {
+          // events are sent as [0, name, body] (or notifications in JSON-RPC)
+          Ok(event) => if let Err(e) = ws.send(Message::Text(match rpc {
+            true => notification(event.name, &event.body),
+            false => json!([0, event.name, event.body]).to_string(),
+          })).await {
+            eprintln!("Fail to send event: {e}");
+          },
+          Err(RecvError::Lagged(n)) => eprintln!("{n} event(s) skipped"),
+          Err(RecvError::Closed) => {},
+        }
+        continue;
+      },
+      _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+        // subscription updates are sent as [0, "subscription", body]
+        for update in conn.subscriptions.poll(&env, &mut conn.session).await {
+          if let Err(e) = ws.send(Message::Text(match rpc {
+            true => notification("subscription", &update),
+            false => json!([0, "subscription", update]).to_string(),
+          })).await {
+            eprintln!("Fail to send subscription update: {e}");
+          }
+        }
+        continue;
+      },
+    };
     let Ok(msg) = msg else {continue};
     if let Some(res) = (|| async {match msg {
+      Message::Text(payload) if rpc => handle_rpc(&env, &mut conn, &payload, true).await.map(Message::Text),
       Message::Text(payload) => {
         let Ok(payload) = serde_json::from_str::<JsonValue>(&payload) else {
           eprintln!("Invalid payload (failed to deserialize): {payload}");
@@ -43,17 +79,17 @@ pub async fn serve_websocket(
           return_err!("Invalid payload (invalid format): {payload}");
         };
 
-        match handle_command(&env, &mut dolphin, command, body).await {
-          Ok(body) => Some(json!([id, body])),
-          Err(msg) => Some(json!([-id, msg])),
-        }
-      },
-      Message::Binary(payload) => {
-        Some(json!(format!("{}", payload.len())))
+        let res = match handle_connection_command(&env, &mut conn, command, body).await {
+          Ok(body) => json!([id, body]),
+          Err(msg) => json!([-id, msg]),
+        };
+        Some(Message::Text(res.to_string()))
       },
+      // binary frames are responded with binary frames (see `handle_binary_frame`)
+      Message::Binary(payload) => handle_binary_frame(&env, &mut conn, &payload).await.map(Message::Binary),
       _ => None,
     }})().await {
-      if let Err(e) = ws.send(Message::Text(res.to_string())).await {
+      if let Err(e) = ws.send(res).await {
         eprintln!("Fail to send message: {e}");
       }
     }
@@ -1,29 +1,31 @@
 /// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
 /// SPDX-License-Identifier: MIT OR Apache-2.0
 
+use std::sync::Arc;
 use crate::addr::Addr;
-use crate::dolphin::{DolphinMemory, Dolphin, DolphinMemAddr, PidType};
-
-#[derive(Debug, Clone, Copy)]
-pub enum SMSVersion {
-  GMSJ01, GMSE01, GMSP01, GMSJ0A,
-}
-impl std::fmt::Display for SMSVersion {
-  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-    std::fmt::Debug::fmt(self, fmt)
-  }
-}
+use crate::dolphin::{DolphinMemory, Dolphin, DolphinMemAddr, PidType, addr::MEM1_SIZE};
+/// The versions of the game, shared with the ObjectParameters format
+pub use sup_smsac_obj_params::SMSVersion;
 
+/// A handle of the game in Dolphin. Clones share the process handle, but each has its own snapshot
+#[derive(Clone)]
 pub struct SMSDolphin {
-  d: DolphinMemory,
+  d: Arc<DolphinMemory>,
   pid: PidType,
   ver: SMSVersion,
+  profile: Arc<GameProfile>,
+  vtables: Arc<vt::VTables>,
+  /// copy of MEM1 from which MEM1 is read instead while set
+  snapshot: Option<Arc<[u8]>>,
 }
 impl Dolphin for SMSDolphin {
   unsafe fn read_memory_unchecked<T, F>(&self, maddr: DolphinMemAddr, size: usize, operator: F) -> Option<T>
     where F: FnOnce(*const u8) -> T
   {
-    self.d.read_memory_unchecked(maddr, size, operator)
+    match (&self.snapshot, maddr) {
+      (Some(mem1), DolphinMemAddr::MEM1(offset)) => Some(operator(mem1.as_ptr().add(offset as usize))),
+      (_, maddr) => self.d.read_memory_unchecked(maddr, size, operator),
+    }
   }
   unsafe fn write_memory_unchecked(&self, maddr: DolphinMemAddr, payload: &[u8]) -> Option<()> {
     self.d.write_memory_unchecked(maddr, payload)
@@ -31,31 +33,69 @@ impl Dolphin for SMSDolphin {
 }
 
 pub mod vt;
+/// Games recognized by their game IDs, including mods based on one of the versions
+pub mod profile;
+use profile::{GameProfile, GameProfiles};
+/// Structs of the classes generated from ObjectParameters, e.g. `d.read::<types::TMario>(addr)`
+pub mod types;
+
 impl SMSDolphin {
   #[inline]
   pub fn ver(&self) -> SMSVersion {
     self.ver
   }
 
-  pub fn from_dolphin_memory(d: DolphinMemory, pid: PidType) -> Result<SMSDolphin, Option<[u8; 8]>> {
+  #[inline]
+  pub fn profile(&self) -> &GameProfile {
+    &self.profile
+  }
+
+  /// Recognize the game by the game ID and the revision with the game profiles
+  pub fn from_dolphin_memory(
+    d: DolphinMemory, pid: PidType,
+    profiles: &GameProfiles, vtables: Arc<vt::VTables>,
+  ) -> Result<SMSDolphin, Option<[u8; 8]>> {
     unsafe {
       d.read_memory_unchecked(DolphinMemAddr::MEM1(0), 8, |ptr| {
-        match &*(ptr as *const [u8; 8]) {
-          b"GMSJ01\x00\x00" => Ok(SMSVersion::GMSJ01),
-          b"GMSE01\x00\x30" => Ok(SMSVersion::GMSE01),
-          b"GMSP01\x00\x00" => Ok(SMSVersion::GMSP01),
-          b"GMSJ01\x00\x01" => Ok(SMSVersion::GMSJ0A),
-          rver => Err(Some(rver.to_owned())),
-        }
+        let header = &*(ptr as *const [u8; 8]);
+        profiles.find(header).ok_or_else(|| Some(header.to_owned()))
       })
     } .unwrap_or(Err(None))
-      .map(|ver| SMSDolphin {d, ver, pid})
+      .map(|profile| SMSDolphin {d: Arc::new(d), ver: profile.base, profile, pid, vtables, snapshot: None})
   }
-  pub fn get_class(&self, addr: Addr) -> Option<&'static str> {
-    vt::get_class(self.ver, addr)
+  /// Copy MEM1 at once from the game (regardless of the snapshot).
+  /// Returns `None` if MEM1 cannot be read
+  pub fn copy_mem1(&self) -> Option<Arc<[u8]>> {
+    let size = MEM1_SIZE as usize;
+    unsafe {
+      self.d.read_memory_unchecked(DolphinMemAddr::MEM1(0), size, |ptr| {
+        Arc::from(std::slice::from_raw_parts(ptr, size))
+      })
+    }
+  }
+  /// Read MEM1 from the copy (see [`SMSDolphin::copy_mem1`]) until [`SMSDolphin::clear_snapshot`]
+  /// so that the values read are consistent. Writes still go to the game
+  pub fn set_snapshot(&mut self, mem1: Arc<[u8]>) {
+    self.snapshot = Some(mem1);
+  }
+  pub fn clear_snapshot(&mut self) {
+    self.snapshot = None;
+  }
+
+  /// The class of the vtable, added by the profile or of the base version
+  pub fn get_class(&self, addr: Addr) -> Option<Arc<str>> {
+    self.profile.vtables.get(&addr.0).cloned()
+      .or_else(|| self.vtables.get_class(self.ver, addr))
+  }
+  /// The base class of the class, if known (see [`vt::VTABLES_DIR`])
+  pub fn base_class(&self, name: &str) -> Option<Arc<str>> {
+    self.vtables.base_class(name)
   }
   pub fn get_class_string(&self, addr: Addr) -> String {
-    vt::get_class_string(self.ver, addr)
+    match self.get_class(addr) {
+      Some(s) => s.to_string(),
+      None => format!("({addr})"),
+    }
   }
 }
 
@@ -80,18 +120,26 @@ impl SMSDolphin {
     self.pid
   }
 
-  pub fn find_one() -> Result<SMSDolphin, SMSDolphinFindOneError> {
+  pub fn find_one(profiles: &GameProfiles, vtables: &Arc<vt::VTables>) -> Result<SMSDolphin, SMSDolphinFindOneError> {
+    SMSDolphin::find(profiles, vtables, None)
+  }
+
+  /// The first Dolphin running SMS, or only the Dolphin of `pid` if specified
+  pub fn find(
+    profiles: &GameProfiles, vtables: &Arc<vt::VTables>, pid: Option<PidType>,
+  ) -> Result<SMSDolphin, SMSDolphinFindOneError> {
     let mut dolphin_running = false;
     let mut game_running = false;
-    for (pid, d) in DolphinMemory::list() {
+    for (pid, d) in DolphinMemory::list().filter(|(o, _)| pid.is_none_or(|pid| pid == *o)) {
       match d {
         Some(d) => {
-          match SMSDolphin::from_dolphin_memory(d, pid) {
+          match SMSDolphin::from_dolphin_memory(d, pid, profiles, vtables.clone()) {
             Ok(o) => return Ok(o),
             Err(e) => {
               game_running = true;
               match e {
-                Some(e) => eprintln!("Unknown game (pid: {pid}): {}",
+                Some(e) => eprintln!("Unknown game (pid: {pid}): {} \
+                  (define a game profile or specify --force-version to use it)",
                   e.map(|c| format!("{c:02X}")).join("")),
                 None => eprintln!("Unknown game (pid: {pid}): fail to get version"),
               }
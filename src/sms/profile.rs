@@ -0,0 +1,246 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use sup_smsac_derive::JsonSchema;
+use crate::addr::{Addr, AddrOffsets};
+use crate::dolphin::Dolphin;
+use crate::json_schema::JsonSchema;
+use crate::obj_params::ObjParamsProfileLayer;
+use crate::sms::{SMSVersion, vt::{VTable, parse_entries}};
+
+/// Game profiles shipped with the tool, relative to the root directory
+pub const GAME_PROFILES_FILE: &str = "res/GameProfiles.json";
+/// Used if [`GAME_PROFILES_FILE`] is not found
+const BUILTIN_GAME_PROFILES: &str = include_str!("../../res/GameProfiles.json");
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameProfileJson {
+  name: Arc<str>,
+  /// pattern of the game ID, where `?` matches any character and `*` matches any sequence
+  game_id: Box<str>,
+  /// revision of the disc (any revision if omitted)
+  revision: Option<u8>,
+  /// version whose vtables, globals and ObjectParameters are inherited
+  base: SMSVersion,
+  /// name => hex address of the global variables, overriding the ones of the base version.
+  /// The address can be followed by comma-separated pointer offsets (e.g. `8040A2A8,5c` is `*(8040A2A8)+5c`)
+  #[serde(default)]
+  globals: HashMap<Box<str>, String>,
+  /// hex vtable address => class name, added to the vtables of the base version
+  #[serde(default)]
+  vtables: HashMap<String, String>,
+  /// ObjectParameters directory (relative to the profiles file) loaded on top of those of the base version,
+  /// which can add, replace or patch classes like the other layers
+  object_parameters: Option<PathBuf>,
+}
+
+/// A game profile as returned by `getProfile`
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GameProfileInfo {
+  pub name: Arc<str>,
+  pub game_id: Box<str>,
+  pub revision: Option<u8>,
+  pub base: SMSVersion,
+  /// name => hex address of the global variables, with the pointer offsets if any
+  pub globals: BTreeMap<String, String>,
+  /// number of the vtables
+  pub vtables: usize,
+  /// ObjectParameters directory of the profile, if any
+  pub object_parameters: Option<String>,
+}
+
+/// All game profiles as returned by `getProfiles`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct GameProfilesInfo {
+  pub profiles: Vec<GameProfileInfo>,
+  /// name of the profile used regardless of the game ID
+  pub forced: Option<Arc<str>>,
+}
+
+/// A game recognized by its game ID and revision, based on one of the versions of SMS
+#[derive(Debug)]
+pub struct GameProfile {
+  pub name: Arc<str>,
+  game_id: Box<str>,
+  revision: Option<u8>,
+  pub base: SMSVersion,
+  globals: HashMap<Box<str>, AddrOffsets>,
+  pub vtables: VTable,
+  obj_params_dir: Option<Box<Path>>,
+}
+impl GameProfile {
+  /// Address of the global variable (e.g. `managers`), following its pointer offsets.
+  /// `None` if the global is not defined, or `Some(None)` if a pointer cannot be read
+  pub fn global(&self, d: &impl Dolphin, name: &str) -> Option<Option<Addr>> {
+    let AddrOffsets(addr, offsets) = self.globals.get(name)?;
+    let mut addr = Addr(*addr);
+    for &off in offsets.iter() {
+      match d.read::<Addr>(addr) {
+        Some(ptr) => addr = Addr(ptr.0.wrapping_add(off)),
+        None => return Some(None),
+      }
+    }
+    Some(Some(addr))
+  }
+  pub fn has_global(&self, name: &str) -> bool {
+    self.globals.contains_key(name)
+  }
+  fn matches(&self, header: &[u8; 8]) -> bool {
+    glob_match(self.game_id.as_bytes(), &header[..6]) &&
+      self.revision.is_none_or(|rev| rev == header[7])
+  }
+  pub fn info(&self) -> GameProfileInfo {
+    GameProfileInfo {
+      name: self.name.clone(),
+      game_id: self.game_id.clone(),
+      revision: self.revision,
+      base: self.base,
+      globals: self.globals.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+      vtables: self.vtables.len(),
+      object_parameters: self.obj_params_dir.as_ref().map(|dir| dir.to_string_lossy().into_owned()),
+    }
+  }
+}
+
+/// `?` matches any character and `*` matches any sequence
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+  match pattern.split_first() {
+    None => s.is_empty(),
+    Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+    Some((&c, rest)) => s.split_first()
+      .is_some_and(|(&x, s)| (c == b'?' || c == x) && glob_match(rest, s)),
+  }
+}
+
+fn parse_profiles(src: &str) -> Result<Vec<GameProfileJson>, String> {
+  serde_json::from_str(src).map_err(|e| e.to_string())
+}
+
+/// The game profiles in the order of precedence
+pub struct GameProfiles {
+  profiles: Box<[Arc<GameProfile>]>,
+  /// profile used regardless of the game ID
+  forced: Option<Arc<GameProfile>>,
+  pub diagnostics: Box<[String]>,
+}
+impl GameProfiles {
+  /// Load the profiles from `files` in order, where later profiles take precedence.
+  /// The built-in profiles are used instead of the first file if it is not found
+  pub fn load(files: &[Box<Path>]) -> Self {
+    let mut diagnostics = Vec::new();
+    // (file name, profile, directory of the file)
+    let mut jsons = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+      let file_name = file.to_string_lossy();
+      let (file_name, result) = match std::fs::read_to_string(file) {
+        Ok(src) => (file_name, parse_profiles(&src)),
+        Err(e) if i == 0 && e.kind() == std::io::ErrorKind::NotFound =>
+          ("(built-in)".into(), parse_profiles(BUILTIN_GAME_PROFILES)),
+        Err(e) => (file_name, Err(e.to_string())),
+      };
+      // the built-in profiles are relative to the directory of the file not found
+      let dir = file.parent().unwrap_or(Path::new(""));
+      match result {
+        Ok(profiles) => jsons.extend(profiles.into_iter().map(|o| (file_name.clone(), o, dir))),
+        Err(e) => diagnostics.push(format!("{file_name}: {e}")),
+      }
+    }
+
+    let globals = jsons.iter()
+      .map(|(file, o, _)| parse_globals(file, o, &mut diagnostics))
+      .collect::<Vec<_>>();
+    // the globals of the base version are those of the last profile named after it
+    let base_globals = |ver: SMSVersion| jsons.iter().rposition(|(_, o, _)| *o.name == *ver.to_string())
+      .map(|i| globals[i].clone())
+      .unwrap_or_default();
+    let mut profiles = Vec::new();
+    for (i, (file, o, dir)) in jsons.iter().enumerate().rev() {
+      let globals = match *o.name == *o.base.to_string() {
+        true => globals[i].clone(),
+        false => {
+          let mut base = base_globals(o.base);
+          base.extend(globals[i].clone());
+          base
+        },
+      };
+      let vtables = match parse_entries(o.vtables.iter().map(|(k, v)| (k.as_str(), v.as_str()))) {
+        Ok(entries) => entries.into_iter().collect(),
+        Err(e) => {
+          diagnostics.push(format!("{file}: profile \"{}\": {e}", o.name));
+          VTable::new()
+        },
+      };
+      profiles.push(Arc::new(GameProfile {
+        name: o.name.clone(),
+        game_id: o.game_id.clone(),
+        revision: o.revision,
+        base: o.base,
+        globals,
+        vtables,
+        obj_params_dir: o.object_parameters.as_ref().map(|path| dir.join(path).into_boxed_path()),
+      }));
+    }
+    Self {profiles: profiles.into(), forced: None, diagnostics: diagnostics.into()}
+  }
+
+  /// Use the profile of the version (or a bare profile if not defined) regardless of the game ID
+  pub fn force_version(mut self, ver: SMSVersion) -> Self {
+    let profile = self.profiles.iter()
+      .find(|o| *o.name == *ver.to_string())
+      .cloned()
+      .unwrap_or_else(|| Arc::new(GameProfile {
+        name: Arc::from(ver.to_string()),
+        game_id: Box::from("*"),
+        revision: None,
+        base: ver,
+        globals: HashMap::new(),
+        vtables: VTable::new(),
+        obj_params_dir: None,
+      }));
+    self.forced = Some(profile);
+    self
+  }
+
+  /// The profile of the game whose first 8 bytes of the memory are `header`
+  pub fn find(&self, header: &[u8; 8]) -> Option<Arc<GameProfile>> {
+    self.forced.clone()
+      .or_else(|| self.profiles.iter().find(|o| o.matches(header)).cloned())
+  }
+
+  /// The ObjectParameters layers of the profiles which define `objectParameters`
+  pub fn obj_params_layers(&self) -> Box<[ObjParamsProfileLayer]> {
+    let mut seen = std::collections::HashSet::new();
+    // only the profile taking precedence is used among those of the same name
+    self.profiles.iter().chain(self.forced.iter())
+      .filter(|o| seen.insert(o.name.clone()))
+      .filter_map(|o| o.obj_params_dir.as_ref().map(|dir| ObjParamsProfileLayer {
+        profile: o.name.clone(),
+        base: o.base,
+        dir: dir.clone(),
+      }))
+      .collect()
+  }
+
+  pub fn info(&self) -> GameProfilesInfo {
+    GameProfilesInfo {
+      profiles: self.profiles.iter().map(|o| o.info()).collect(),
+      forced: self.forced.as_ref().map(|o| o.name.clone()),
+    }
+  }
+}
+
+fn parse_globals(file: &str, o: &GameProfileJson, diagnostics: &mut Vec<String>) -> HashMap<Box<str>, AddrOffsets> {
+  o.globals.iter().filter_map(|(name, addr)| match addr.parse() {
+    Ok(addr) => Some((name.clone(), addr)),
+    Err(e) => {
+      diagnostics.push(format!("{file}: profile \"{}\": invalid address of global \"{name}\": {e}", o.name));
+      None
+    },
+  }).collect()
+}
@@ -0,0 +1,35 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::addr::Addr;
+use crate::big_endian::DecodeBE;
+use sup_smsac_derive::{DecodeBE, obj_params_structs};
+
+obj_params_structs!("res/ObjectParameters", "TMario", "TMarDirector", "TApplication");
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decode_known_bytes() {
+    let mut buf = [0u8; TApplication::PACKED_SIZE];
+    assert_eq!(buf.len(), 80);
+    buf[4..8].copy_from_slice(&0x8040_1234u32.to_be_bytes());
+    buf[8] = 5;
+    buf[0xa..0xc].copy_from_slice(&0x0102u16.to_be_bytes());
+    buf[0xe..0x10].copy_from_slice(&0x0304u16.to_be_bytes());
+    let app = unsafe {TApplication::decode_be(buf.as_ptr())};
+    assert!(app.current_director == Addr(0x8040_1234));
+    assert_eq!(app.director_type, 5);
+    assert_eq!((app.previous_area, app.current_area), (0x0102, 0x0304));
+
+    let mut buf = vec![0u8; TMario::PACKED_SIZE];
+    buf[0xa4..0xa8].copy_from_slice(&1.5f32.to_be_bytes());
+    buf[0xac..0xb0].copy_from_slice(&(-2.0f32).to_be_bytes());
+    buf[0xb0..0xb4].copy_from_slice(&30.0f32.to_be_bytes());
+    let mario = unsafe {TMario::decode_be(buf.as_ptr())};
+    assert_eq!((mario.speed.x, mario.speed.y, mario.speed.z), (1.5, 0.0, -2.0));
+    assert_eq!(mario.forward_speed, 30.0);
+  }
+}
@@ -1,21 +1,302 @@
 /// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
 /// SPDX-License-Identifier: MIT OR Apache-2.0
 
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use serde_json::{json, Value as JsonValue};
 use crate::addr::Addr;
 use crate::sms::SMSVersion;
-use sup_smsac_derive::match_class_from_json;
+use sup_smsac_derive::entries_from_json;
 
-pub fn get_class(ver: SMSVersion, addr: Addr) -> Option<&'static str> {
+/// Default vtable directory, in which `<GameID>.json` maps the vtable addresses (hex) to the class names,
+/// and `hierarchy.json` maps the class names to their base classes
+pub const VTABLES_DIR: &str = "res/VTables";
+/// name of the class hierarchy file without `.json`
+const HIERARCHY_STEM: &str = "hierarchy";
+
+/// The tables compiled into the binary, used if `<GameID>.json` is not found in any directory
+fn compiled_entries(ver: SMSVersion) -> &'static [(&'static str, &'static str)] {
   match ver {
-    SMSVersion::GMSJ01 => match_class_from_json!("src/sms/vt/GMSJ01.json")(addr.0),
-    SMSVersion::GMSE01 => match_class_from_json!("src/sms/vt/GMSE01.json")(addr.0),
-    SMSVersion::GMSP01 => match_class_from_json!("src/sms/vt/GMSP01.json")(addr.0),
-    SMSVersion::GMSJ0A => match_class_from_json!("src/sms/vt/GMSJ0A.json")(addr.0),
+    SMSVersion::GMSJ01 => entries_from_json!("res/VTables/GMSJ01.json"),
+    SMSVersion::GMSE01 => entries_from_json!("res/VTables/GMSE01.json"),
+    SMSVersion::GMSP01 => entries_from_json!("res/VTables/GMSP01.json"),
+    SMSVersion::GMSJ0A => entries_from_json!("res/VTables/GMSJ0A.json"),
+  }
+}
+
+/// The class hierarchy compiled into the binary, used if `hierarchy.json` is not found in any directory
+fn compiled_hierarchy() -> &'static [(&'static str, &'static str)] {
+  entries_from_json!("res/VTables/hierarchy.json")
+}
+
+/// vtable address => class name
+pub type VTable = HashMap<u32, Arc<str>>;
+/// class name => base class name
+pub type Hierarchy = HashMap<Arc<str>, Arc<str>>;
+
+/// Parse the entries of `hex vtable address => class name`
+pub fn parse_entries<'a>(
+  entries: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Result<Vec<(u32, Arc<str>)>, String> {
+  entries.map(|(addr, name)| {
+    let addr = u32::from_str_radix(addr.trim_start_matches("0x"), 16)
+      .map_err(|e| format!("invalid vtable address \"{addr}\": {e}"))?;
+    Ok((addr, Arc::from(name)))
+  }).collect()
+}
+
+fn read_map(path: &Path) -> Result<HashMap<String, String>, String> {
+  let src = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+  serde_json::from_str(&src).map_err(|e| e.to_string())
+}
+
+fn read_entries(path: &Path) -> Result<Vec<(u32, Arc<str>)>, String> {
+  let map = read_map(path)?;
+  parse_entries(map.iter().map(|(addr, name)| (addr.as_str(), name.as_str())))
+}
+
+fn read_hierarchy(path: &Path) -> Result<Hierarchy, String> {
+  Ok(read_map(path)?.into_iter().map(|(name, base)| (Arc::from(name), Arc::from(base))).collect())
+}
+
+/// The vtables of all versions
+pub struct VTableDB {
+  tables: HashMap<SMSVersion, VTable>,
+  /// files loaded for each version in order
+  files: HashMap<SMSVersion, Vec<Box<Path>>>,
+  /// versions using the compiled table
+  compiled: Vec<SMSVersion>,
+  /// shared by all versions
+  hierarchy: Hierarchy,
+  hierarchy_files: Vec<Box<Path>>,
+  hierarchy_compiled: bool,
+  pub diagnostics: Box<[String]>,
+}
+impl VTableDB {
+  pub fn get(&self, ver: SMSVersion, addr: Addr) -> Option<&Arc<str>> {
+    self.tables.get(&ver)?.get(&addr.0)
+  }
+  /// The base class of the class, if known
+  pub fn base_class(&self, name: &str) -> Option<&Arc<str>> {
+    self.hierarchy.get(name)
+  }
+  /// `{[version]: {count, compiled, files}}`
+  pub fn summary_json(&self) -> JsonValue {
+    JsonValue::Object(SMSVersion::ALL.iter().map(|ver| (ver.to_string(), json!({
+      "count": self.tables.get(ver).map_or(0, |t| t.len()),
+      "compiled": self.compiled.contains(ver),
+      "files": self.files.get(ver).into_iter().flatten()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>(),
+    }))).collect())
+  }
+  /// `{count, compiled, files}` of the class hierarchy
+  pub fn hierarchy_summary_json(&self) -> JsonValue {
+    json!({
+      "count": self.hierarchy.len(),
+      "compiled": self.hierarchy_compiled,
+      "files": self.hierarchy_files.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+    })
+  }
+}
+
+/// Whether the file is `<stem>.json` (`Some(true)`) or an addition `<stem>.<name>.json` (`Some(false)`)
+fn vtable_file_kind(file_name: &str, stem: &str) -> Option<bool> {
+  let rest = file_name.strip_suffix(".json")?.strip_prefix(stem)?;
+  match rest {
+    "" => Some(true),
+    rest => rest.strip_prefix('.').filter(|name| !name.is_empty()).map(|_| false),
+  }
+}
+
+/// `(path, is_base, entries)` of `<stem>.json` and then the additions `<stem>.<name>.json` of each directory
+fn load_files<T>(
+  dir_files: &[(&Path, Vec<String>)],
+  stem: &str,
+  read: fn(&Path) -> Result<T, String>,
+  diagnostics: &mut Vec<String>,
+) -> Vec<(Box<Path>, bool, T)> {
+  let mut loaded = Vec::new();
+  for (dir, names) in dir_files.iter() {
+    let mut kinds = names.iter()
+      .filter_map(|name| Some((name, vtable_file_kind(name, stem)?)))
+      .collect::<Vec<_>>();
+    // the base file first
+    kinds.sort_by_key(|(_, is_base)| !is_base);
+    for (name, is_base) in kinds.into_iter() {
+      let path = dir.join(name);
+      match read(&path) {
+        Ok(entries) => loaded.push((path.into_boxed_path(), is_base, entries)),
+        Err(e) => diagnostics.push(format!("{}: {e}", path.to_string_lossy())),
+      }
+    }
   }
+  loaded
 }
-pub fn get_class_string(ver: SMSVersion, addr: Addr) -> String {
-  match get_class(ver, addr) {
-    Some(s) => s.to_string(),
-    None => format!("({addr})"),
+
+/// Load the vtables and the class hierarchy from `dirs` in order. In each directory, `<GameID>.json`
+/// (or `hierarchy.json`) and then the additions `<GameID>.<name>.json` (or `hierarchy.<name>.json`,
+/// e.g. shipped by mods) are loaded in the order of their names.
+/// Later entries take precedence. The compiled table is used as the base
+/// if `<GameID>.json` (or `hierarchy.json`) is not loaded from any directory
+pub fn load_vtables(dirs: &[Box<Path>]) -> VTableDB {
+  let mut diagnostics = Vec::new();
+  let mut dir_files = Vec::new();
+  for dir in dirs.iter() {
+    match std::fs::read_dir(dir) {
+      Ok(entries) => {
+        let mut names = entries
+          .filter_map(|e| e.ok()?.file_name().into_string().ok())
+          .collect::<Vec<_>>();
+        names.sort();
+        dir_files.push((&**dir, names));
+      },
+      Err(e) => diagnostics.push(format!("{}: {e}", dir.to_string_lossy())),
+    }
+  }
+
+  let mut tables = HashMap::new();
+  let mut files = HashMap::new();
+  let mut compiled = Vec::new();
+  for ver in SMSVersion::ALL {
+    let loaded = load_files(&dir_files, &ver.to_string(), read_entries, &mut diagnostics);
+    let mut table = VTable::new();
+    if !loaded.iter().any(|(_, is_base, _)| *is_base) {
+      // the compiled JSON files are valid
+      table.extend(parse_entries(compiled_entries(ver).iter().copied()).unwrap_or_default());
+      compiled.push(ver);
+    }
+    files.insert(ver, loaded.iter().map(|(path, _, _)| path.clone()).collect());
+    for (_, _, entries) in loaded.into_iter() {
+      table.extend(entries);
+    }
+    tables.insert(ver, table);
+  }
+
+  let loaded = load_files(&dir_files, HIERARCHY_STEM, read_hierarchy, &mut diagnostics);
+  let hierarchy_compiled = !loaded.iter().any(|(_, is_base, _)| *is_base);
+  let mut hierarchy = match hierarchy_compiled {
+    true => compiled_hierarchy().iter().map(|(name, base)| (Arc::from(*name), Arc::from(*base))).collect(),
+    false => Hierarchy::new(),
+  };
+  let hierarchy_files = loaded.iter().map(|(path, _, _)| path.clone()).collect();
+  for (_, _, entries) in loaded.into_iter() {
+    hierarchy.extend(entries);
+  }
+  VTableDB {
+    tables, files, compiled,
+    hierarchy, hierarchy_files, hierarchy_compiled,
+    diagnostics: diagnostics.into(),
+  }
+}
+
+/// The vtables shared by all sessions, which can be reloaded while in use
+pub struct VTables {
+  dirs: Box<[Box<Path>]>,
+  db: RwLock<Arc<VTableDB>>,
+}
+impl VTables {
+  pub fn new(dirs: Box<[Box<Path>]>) -> Self {
+    let db = Arc::new(load_vtables(&dirs));
+    Self {dirs, db: RwLock::new(db)}
+  }
+  pub fn dirs(&self) -> &[Box<Path>] {
+    &self.dirs
+  }
+  pub fn db(&self) -> Arc<VTableDB> {
+    self.db.read().unwrap().clone()
+  }
+  /// Reload the vtables from the directories
+  pub fn reload(&self) -> Arc<VTableDB> {
+    let db = Arc::new(load_vtables(&self.dirs));
+    *self.db.write().unwrap() = db.clone();
+    db
+  }
+  pub fn get_class(&self, ver: SMSVersion, addr: Addr) -> Option<Arc<str>> {
+    self.db.read().unwrap().get(ver, addr).cloned()
+  }
+  pub fn base_class(&self, name: &str) -> Option<Arc<str>> {
+    self.db.read().unwrap().base_class(name).cloned()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn file_kinds() {
+    let kind = |name| vtable_file_kind(name, "GMSJ01");
+    assert_eq!(kind("GMSJ01.json"), Some(true));
+    assert_eq!(kind("GMSJ01.mod.json"), Some(false));
+    assert_eq!(kind("GMSJ01.a.b.json"), Some(false));
+    assert_eq!(kind("GMSJ01..json"), None);
+    assert_eq!(kind("GMSJ01mod.json"), None);
+    assert_eq!(kind("GMSJ01.json.bak"), None);
+    assert_eq!(kind("GMSJ0A.json"), None);
+    assert_eq!(kind("GMSE01.json"), None);
+  }
+
+  #[test]
+  fn load_precedence() {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-test-vtables-{}", std::process::id()));
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    std::fs::create_dir_all(&a).unwrap();
+    std::fs::create_dir_all(&b).unwrap();
+    // the addition sorted before the base file is still loaded after it
+    std::fs::write(a.join("GMSJ01.json"), r#"{"80001000": "Base", "80002000": "Base"}"#).unwrap();
+    std::fs::write(a.join("GMSJ01.a.json"), r#"{"80002000": "A", "80003000": "A"}"#).unwrap();
+    std::fs::write(b.join("GMSJ01.b.json"), r#"{"80003000": "B"}"#).unwrap();
+    // an addition without the base file extends the compiled table
+    std::fs::write(b.join("GMSE01.b.json"), r#"{"803AB684": "B"}"#).unwrap();
+    std::fs::write(b.join("GMSP01.json"), r#"{"x": "B"}"#).unwrap();
+    let db = load_vtables(&[a.clone().into_boxed_path(), b.clone().into_boxed_path()]);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let get = |ver, addr| db.get(ver, Addr(addr)).map(|o| &**o);
+    assert_eq!(get(SMSVersion::GMSJ01, 0x80001000), Some("Base"));
+    assert_eq!(get(SMSVersion::GMSJ01, 0x80002000), Some("A"));
+    assert_eq!(get(SMSVersion::GMSJ01, 0x80003000), Some("B"));
+    assert_eq!(get(SMSVersion::GMSJ01, 0x803AB684), None);
+    assert_eq!(db.files[&SMSVersion::GMSJ01], [
+      a.join("GMSJ01.json").into_boxed_path(),
+      a.join("GMSJ01.a.json").into_boxed_path(),
+      b.join("GMSJ01.b.json").into_boxed_path(),
+    ]);
+    assert_eq!(get(SMSVersion::GMSE01, 0x803AB684), Some("B"));
+    assert_eq!(get(SMSVersion::GMSE01, 0x803AB6FC), Some("TNerveWaitForever<TLiveActor>"));
+    // the invalid base file is reported, and the compiled table is used instead
+    assert_eq!(db.diagnostics.len(), 1);
+    assert!(db.diagnostics[0].contains("GMSP01.json"));
+    assert_eq!(db.compiled, [SMSVersion::GMSE01, SMSVersion::GMSP01, SMSVersion::GMSJ0A]);
+  }
+
+  #[test]
+  fn load_hierarchy() {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-test-hierarchy-{}", std::process::id()));
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    std::fs::create_dir_all(&a).unwrap();
+    std::fs::create_dir_all(&b).unwrap();
+    // an addition without the base file extends the compiled hierarchy
+    std::fs::write(a.join("hierarchy.mod.json"), r#"{"TMyEnemy": "TSmallEnemy", "JDrama::TViewObj": "TFoo"}"#).unwrap();
+    let db = load_vtables(&[a.clone().into_boxed_path()]);
+    assert!(db.hierarchy_compiled);
+    assert_eq!(db.base_class("TMyEnemy").map(|o| &**o), Some("TSmallEnemy"));
+    assert_eq!(db.base_class("JDrama::TViewObj").map(|o| &**o), Some("TFoo"));
+    assert_eq!(db.base_class("JDrama::TActor").map(|o| &**o), Some("JDrama::TPlacement"));
+
+    std::fs::write(b.join("hierarchy.json"), r#"{"TMyEnemy": "TEnemy"}"#).unwrap();
+    let db = load_vtables(&[a.clone().into_boxed_path(), b.clone().into_boxed_path()]);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(!db.hierarchy_compiled);
+    assert_eq!(db.hierarchy_files, [
+      a.join("hierarchy.mod.json").into_boxed_path(),
+      b.join("hierarchy.json").into_boxed_path(),
+    ]);
+    // the later directory takes precedence even over the additions of the earlier ones
+    assert_eq!(db.base_class("TMyEnemy").map(|o| &**o), Some("TEnemy"));
+    assert_eq!(db.base_class("JDrama::TActor"), None);
+    assert!(db.diagnostics.is_empty());
   }
 }
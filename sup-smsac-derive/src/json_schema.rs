@@ -0,0 +1,208 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use proc_macro2::TokenStream;
+use quote::quote;
+
+/// `#[serde(...)]` attributes understood by the derive
+#[derive(Default)]
+struct SerdeAttrs {
+  rename: Option<String>,
+  rename_all: Option<String>,
+  default: bool,
+  skip: bool,
+  untagged: bool,
+}
+
+fn serde_attrs(attrs: &[syn::Attribute]) -> syn::Result<SerdeAttrs> {
+  let mut o = SerdeAttrs::default();
+  for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+    let syn::Meta::List(list) = attr.parse_meta()? else {continue};
+    for meta in list.nested.iter() {
+      match meta {
+        syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) => {
+          let syn::Lit::Str(s) = &nv.lit else {continue};
+          if nv.path.is_ident("rename") {
+            o.rename = Some(s.value());
+          } else if nv.path.is_ident("rename_all") {
+            o.rename_all = Some(s.value());
+          } else if nv.path.is_ident("default") {
+            o.default = true;
+          }
+        },
+        syn::NestedMeta::Meta(syn::Meta::Path(path)) => {
+          if path.is_ident("default") {
+            o.default = true;
+          } else if path.is_ident("skip") || path.is_ident("skip_deserializing") {
+            o.skip = true;
+          } else if path.is_ident("untagged") {
+            o.untagged = true;
+          } else if path.is_ident("flatten") || path.is_ident("tag") || path.is_ident("content") {
+            return Err(syn::Error::new_spanned(path, "unsupported by JsonSchema"));
+          }
+        },
+        _ => {},
+      }
+    }
+  }
+  Ok(o)
+}
+
+/// The doc comments joined by newlines
+fn doc(attrs: &[syn::Attribute]) -> Option<String> {
+  let lines = attrs.iter()
+    .filter(|a| a.path.is_ident("doc"))
+    .filter_map(|a| match a.parse_meta() {
+      Ok(syn::Meta::NameValue(syn::MetaNameValue {lit: syn::Lit::Str(s), ..})) => Some(s.value().trim().to_string()),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Rename a field (snake_case) or a variant (PascalCase) by `rename_all`
+fn rename(name: &str, rule: Option<&str>, is_variant: bool, span: &impl quote::ToTokens) -> syn::Result<String> {
+  Ok(match rule {
+    None => name.to_string(),
+    Some("lowercase") => name.to_lowercase(),
+    Some("UPPERCASE") => name.to_uppercase(),
+    Some("camelCase") if is_variant => name[..1].to_lowercase() + &name[1..],
+    Some("camelCase") => {
+      let mut words = name.split('_');
+      let first = words.next().unwrap_or_default().to_string();
+      words.fold(first, |s, w| s + &w[..w.len().min(1)].to_uppercase() + &w[w.len().min(1)..])
+    },
+    Some("snake_case") if is_variant => name.chars().enumerate().fold(String::new(), |mut s, (i, c)| {
+      if c.is_uppercase() && i > 0 {s.push('_')}
+      s.extend(c.to_lowercase());
+      s
+    }),
+    Some("snake_case") => name.to_string(),
+    Some(rule) => return Err(syn::Error::new_spanned(span, format!("unsupported rename_all: {rule}"))),
+  })
+}
+
+fn is_option(ty: &syn::Type) -> bool {
+  match ty {
+    syn::Type::Path(p) => p.path.segments.last().is_some_and(|s| s.ident == "Option"),
+    _ => false,
+  }
+}
+
+/// Code evaluating to a JSON object of the entries
+fn object(entries: &[(&str, TokenStream)]) -> TokenStream {
+  let entries = entries.iter().map(|(k, v)| quote! {(#k.to_string(), #v)});
+  quote! {serde_json::Value::Object(serde_json::Map::from_iter([#(#entries),*]))}
+}
+
+/// Code evaluating to a JSON array of the items
+fn array(items: impl Iterator<Item = TokenStream>) -> TokenStream {
+  quote! {serde_json::Value::Array(vec![#(#items),*])}
+}
+
+/// Code evaluating to the schema of `ty` with the description
+fn field_schema(ty: &syn::Type, doc: Option<String>) -> TokenStream {
+  let q_doc = doc.map(|doc| quote! {
+    if let Some(o) = schema.as_object_mut() {
+      o.insert("description".into(), serde_json::json!(#doc));
+    }
+  });
+  quote! {{
+    let mut schema = <#ty as JsonSchema>::json_schema(defs);
+    #q_doc
+    schema
+  }}
+}
+
+/// Code evaluating to the schema of the fields of a struct or a variant
+fn fields_schema(fields: &syn::Fields, container: &SerdeAttrs) -> syn::Result<TokenStream> {
+  Ok(match fields {
+    syn::Fields::Named(fields) => {
+      let mut props = Vec::new();
+      let mut required = Vec::new();
+      for field in fields.named.iter() {
+        let attrs = serde_attrs(&field.attrs)?;
+        if attrs.skip {continue}
+        let ident = field.ident.as_ref().unwrap().to_string();
+        let ident = ident.strip_prefix("r#").unwrap_or(&ident);
+        let name = match attrs.rename {
+          Some(name) => name,
+          None => rename(ident, container.rename_all.as_deref(), false, field)?,
+        };
+        if !(attrs.default || container.default || is_option(&field.ty)) {
+          required.push(name.clone());
+        }
+        let schema = field_schema(&field.ty, doc(&field.attrs));
+        props.push(quote! {(#name.to_string(), #schema)});
+      }
+      object(&[
+        ("type", quote! {serde_json::json!("object")}),
+        ("properties", quote! {serde_json::Value::Object(serde_json::Map::from_iter([#(#props),*]))}),
+        ("required", quote! {serde_json::json!([#(#required),*])}),
+      ])
+    },
+    syn::Fields::Unnamed(fields) if fields.unnamed.len() == 1 => field_schema(&fields.unnamed[0].ty, None),
+    syn::Fields::Unnamed(fields) => {
+      object(&[
+        ("type", quote! {serde_json::json!("array")}),
+        ("prefixItems", array(fields.unnamed.iter().map(|f| field_schema(&f.ty, None)))),
+        ("items", quote! {serde_json::json!(false)}),
+      ])
+    },
+    syn::Fields::Unit => quote! {serde_json::json!({"type": "null"})},
+  })
+}
+
+pub fn json_schema_derive(input: TokenStream) -> syn::Result<TokenStream> {
+  let ast: syn::DeriveInput = syn::parse2(input)?;
+  if !ast.generics.params.is_empty() {
+    return Err(syn::Error::new_spanned(&ast.generics, "generic types cannot derive JsonSchema"));
+  }
+  let attrs = serde_attrs(&ast.attrs)?;
+  let type_name = &ast.ident;
+  let name = type_name.to_string();
+
+  let q_schema = match &ast.data {
+    syn::Data::Struct(data) => fields_schema(&data.fields, &attrs)?,
+    syn::Data::Enum(data) if attrs.untagged => {
+      let variants = data.variants.iter()
+        .map(|v| fields_schema(&v.fields, &serde_attrs(&v.attrs)?))
+        .collect::<syn::Result<Vec<_>>>()?;
+      object(&[("anyOf", array(variants.into_iter()))])
+    },
+    syn::Data::Enum(data) => {
+      let variants = data.variants.iter().map(|v| {
+        if !matches!(v.fields, syn::Fields::Unit) {
+          return Err(syn::Error::new_spanned(v, "only unit variants are supported unless untagged"));
+        }
+        match serde_attrs(&v.attrs)?.rename {
+          Some(name) => Ok(name),
+          None => rename(&v.ident.to_string(), attrs.rename_all.as_deref(), true, v),
+        }
+      }).collect::<syn::Result<Vec<_>>>()?;
+      quote! {serde_json::json!({"enum": [#(#variants),*]})}
+    },
+    syn::Data::Union(_) => return Err(syn::Error::new_spanned(&ast, "union cannot derive JsonSchema")),
+  };
+  let q_doc = doc(&ast.attrs).map(|doc| quote! {
+    if let Some(o) = schema.as_object_mut() {
+      o.insert("description".into(), serde_json::json!(#doc));
+    }
+  });
+
+  Ok(quote! {
+    impl JsonSchema for #type_name {
+      fn json_schema(defs: &mut serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+        if !defs.contains_key(#name) {
+          // placeholder for recursive types
+          defs.insert(#name.into(), serde_json::Value::Null);
+          #[allow(unused_mut)]
+          let mut schema = #q_schema;
+          #q_doc
+          defs.insert(#name.into(), schema);
+        }
+        serde_json::json!({"$ref": concat!("#/$defs/", #name)})
+      }
+    }
+  })
+}
@@ -40,7 +40,7 @@ pub fn decode_be_derive(input: TokenStream) -> TokenStream {
         let ty_last = ty0;
         ty0 = Some(ty);
         if let Some(ty0) = ty_last {
-          quote! {#name: {ptr = ptr.add(#ty0::PACKED_SIZE); <#ty>::decode_be(ptr)}}
+          quote! {#name: {ptr = ptr.add(<#ty0>::PACKED_SIZE); <#ty>::decode_be(ptr)}}
         } else {
           quote! {#name: <#ty>::decode_be(ptr)}
         }
@@ -55,7 +55,7 @@ pub fn decode_be_derive(input: TokenStream) -> TokenStream {
         let ty_last = ty0;
         ty0 = Some(ty);
         if let Some(ty0) = ty_last {
-          quote! {{ptr = ptr.add(#ty0::PACKED_SIZE); <#ty>::decode_be(ptr)}}
+          quote! {{ptr = ptr.add(<#ty0>::PACKED_SIZE); <#ty>::decode_be(ptr)}}
         } else {
           quote! {<#ty>::decode_be(ptr)}
         }
@@ -81,8 +81,9 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 
-#[proc_macro]
-pub fn match_class_from_json(input: TokenStream) -> TokenStream {
+/// Read a JSON file in the form of `HashMap<String, String>`
+/// whose path (relative to the crate root) is given as a string literal
+fn read_json_map(input: TokenStream) -> HashMap<String, String> {
   let file_name = input.to_string();
   let file_name = if file_name.starts_with('\"') && file_name.ends_with('\"') {
     &file_name[1..file_name.len()-1]
@@ -93,17 +94,88 @@ pub fn match_class_from_json(input: TokenStream) -> TokenStream {
   // let cwd = std::env::current_dir().unwrap();
   let file = File::open(&file_name).expect(&file_name);
   let reader = BufReader::new(file);
-  let vt: HashMap<String, String> = serde_json::from_reader(reader)
-    .expect("The JSON file is not in the form of HashMap<String, String>");
-  let entries = vt.iter().map(|e| {
-    let (addr, name) = e;
-    let addr = u32::from_str_radix(addr, 16).unwrap();
-    quote! {#addr => Some(#name)}
-  });
+  serde_json::from_reader(reader)
+    .expect("The JSON file is not in the form of HashMap<String, String>")
+}
+
+/// `&'static [(&'static str, &'static str)]` of the entries of the JSON file
+#[proc_macro]
+pub fn entries_from_json(input: TokenStream) -> TokenStream {
+  let map = read_json_map(input);
+  let entries = map.iter().map(|(key, value)| quote! {(#key, #value)});
   quote! {
-    |x| match x {
-      #(#entries),*,
-      _ => None,
+    &[#(#entries),*]
+  }.into()
+}
+
+mod obj_params;
+
+/// Generate the structs (deriving `DecodeBE`) of the given ObjectParameters classes and their embedded classes
+/// in the form of `obj_params_structs!("path", "Class", ...)`,
+/// where the path (relative to the crate root) is a JSON file or a directory of them.
+/// The classes are loaded in the same way as the loader of the main crate, whose errors in the generated classes
+/// fail the build.
+/// The structs are shared by all versions, so the fields which differ between the versions are left in the padding,
+/// as well as the hidden and conditional fields, and the members of a union except the first one.
+/// `Addr` and `DecodeBE` must be in scope
+#[proc_macro]
+pub fn obj_params_structs(input: TokenStream) -> TokenStream {
+  obj_params::obj_params_structs(input.into())
+    .unwrap_or_else(|e| e.to_compile_error())
+    .into()
+}
+
+mod json_schema;
+
+/// Derive `JsonSchema` (which must be in scope) from the type and its `#[serde]` attributes
+/// (`rename`, `rename_all`, `default`, `skip` and `untagged`), with the doc comments as descriptions.
+/// The schema of the type is defined in `$defs`, and referred by `$ref`
+#[proc_macro_derive(JsonSchema, attributes(serde))]
+pub fn json_schema_derive(input: TokenStream) -> TokenStream {
+  json_schema::json_schema_derive(input.into())
+    .unwrap_or_else(|e| e.to_compile_error())
+    .into()
+}
+
+/// FNV-1a
+fn hash_bytes(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn embed_files(dir: &std::path::Path, prefix: &str, out: &mut Vec<proc_macro2::TokenStream>) -> Result<(), String> {
+  let err = |path: &std::path::Path, e: std::io::Error| format!("{}: {e}", path.display());
+  let mut entries = std::fs::read_dir(dir).map_err(|e| err(dir, e))?
+    .map(|e| e.map(|e| e.path()).map_err(|e| err(dir, e)))
+    .collect::<Result<Vec<_>, _>>()?;
+  entries.sort();
+  for path in entries.iter() {
+    let Some(file_name) = path.file_name() else {continue};
+    let name = format!("{prefix}{}", file_name.to_string_lossy());
+    if path.is_dir() {
+      embed_files(path, &format!("{name}/"), out)?;
+    } else {
+      let bytes = std::fs::read(path).map_err(|e| err(path, e))?;
+      let etag = format!("\"{:016x}\"", hash_bytes(&bytes));
+      let abs = path.canonicalize().map_err(|e| err(path, e))?.to_string_lossy().to_string();
+      out.push(quote! {(#name, include_bytes!(#abs) as &[u8], #etag)});
     }
+  }
+  Ok(())
+}
+
+/// `&'static [(&'static str, &'static [u8], &'static str)]` of `(path, content, ETag)` of the files
+/// in the directory (relative to the crate root) given as a string literal, where `path` is relative to the directory
+/// with `/` as the separator.
+/// Files added to or removed from the directory are picked up only when the crate is rebuilt,
+/// so the calling crate should rerun its build script on changes of the directory (`cargo:rerun-if-changed`)
+#[proc_macro]
+pub fn embed_dir(input: TokenStream) -> TokenStream {
+  let dir = syn::parse_macro_input!(input as syn::LitStr);
+  let mut files = Vec::new();
+  match embed_files(std::path::Path::new(&dir.value()), "", &mut files) {
+    Ok(()) => quote! {
+      &[#(#files),*]
+    },
+    Err(e) => syn::Error::new(dir.span(), e).to_compile_error(),
   }.into()
 }
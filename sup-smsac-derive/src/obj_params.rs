@@ -0,0 +1,309 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+
+// The classes are parsed, merged and validated by the same crate as the loader of the main crate,
+// so that the generated structs are the same as the classes loaded at runtime
+use sup_smsac_obj_params::{
+  AddrOffsets, SMSVersion,
+  definition::{ObjParamsClasses, ObjParamsField, classes_of_version, collect_classes, parse_file_content},
+  diagnostic::class_path,
+  validate::{class_size, validate},
+};
+
+type Result<T> = std::result::Result<T, String>;
+
+/// The JSON files at `path` (a file, or a directory whose files are loaded in the order of their names)
+fn json_files(path: &Path) -> Result<Vec<PathBuf>> {
+  if !path.is_dir() {
+    return Ok(vec![path.to_path_buf()]);
+  }
+  let mut files = std::fs::read_dir(path).map_err(|e| format!("{}: {e}", path.display()))?
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|e| e.extension().is_some_and(|ext| ext == "json"))
+    .collect::<Vec<_>>();
+  files.sort();
+  Ok(files)
+}
+
+/// Offset of a field decoded in the struct in the given version, or `None` if the field is hidden,
+/// conditional (the value is meaningful only if the condition holds), behind pointers, or not in the version
+fn decoded_offset(field: &ObjParamsField, ver: SMSVersion) -> Option<u32> {
+  if field.hidden || field.when.is_some() {return None}
+  match field.offset.get(ver)? {
+    AddrOffsets(offset, ptrs) if ptrs.is_empty() => Some(*offset),
+    _ => None,
+  }
+}
+
+/// `JGeometry::TVec3<float>` => `JGeometry_TVec3_float`
+fn type_ident(name: &str) -> syn::Ident {
+  let mut ident = String::new();
+  for c in name.chars() {
+    if c.is_ascii_alphanumeric() || c == '_' {
+      ident.push(c);
+    } else if !ident.ends_with('_') {
+      ident.push('_');
+    }
+  }
+  let ident = ident.trim_end_matches('_');
+  format_ident!("{}", if ident.starts_with(|c: char| c.is_ascii_digit()) {format!("_{ident}")} else {ident.into()})
+}
+
+/// `Y angle (yaw)` => `y_angle_yaw`, or `field_{offset}` if the name is empty
+fn field_ident(name: &str, offset: u32) -> String {
+  let mut ident = String::new();
+  for c in name.chars() {
+    if c.is_ascii_alphanumeric() {
+      ident.push(c.to_ascii_lowercase());
+    } else if !ident.is_empty() && !ident.ends_with('_') {
+      ident.push('_');
+    }
+  }
+  let ident = ident.trim_end_matches('_');
+  if ident.is_empty() {
+    format!("field_{offset:x}")
+  } else if ident.starts_with(|c: char| c.is_ascii_digit()) {
+    format!("_{ident}")
+  } else if syn::parse_str::<syn::Ident>(ident).is_err() {
+    // keyword
+    format!("{ident}_")
+  } else {
+    ident.into()
+  }
+}
+
+/// Rust type and size of a primitive type
+fn primitive_type(type_: &str) -> Option<(TokenStream, u32)> {
+  Some(match type_ {
+    "u8" => (quote! {u8}, 1),
+    "u16" => (quote! {u16}, 2),
+    "u32" => (quote! {u32}, 4),
+    "s8" => (quote! {i8}, 1),
+    "s16" => (quote! {i16}, 2),
+    "s32" => (quote! {i32}, 4),
+    "float" => (quote! {f32}, 4),
+    "double" => (quote! {f64}, 8),
+    "string" => (quote! {Addr}, 4),
+    t if t.ends_with('*') => (quote! {Addr}, 4),
+    t => {
+      let (elem, len) = t.strip_suffix(']')?.split_once('[')?;
+      let len: u32 = len.parse().ok()?;
+      if !matches!(elem, "char" | "u8") {return None}
+      let n = len as usize;
+      (quote! {[u8; #n]}, len)
+    },
+  })
+}
+
+fn padding(offset: u32, len: u32) -> TokenStream {
+  let ident = format_ident!("_pad_{:x}", offset);
+  let len = len as usize;
+  quote! {pub #ident: [u8; #len]}
+}
+
+struct Generator {
+  /// the classes of each version
+  dbs: Vec<(SMSVersion, ObjParamsClasses)>,
+  /// size of the generated structs
+  sizes: HashMap<String, u32>,
+  /// classes being generated, to detect recursive embedding
+  visiting: HashSet<String>,
+  structs: Vec<TokenStream>,
+}
+impl Generator {
+  /// Generate the struct of the class (and the embedded classes) if not yet, and return its size.
+  /// The struct is shared by all versions, so the fields which are not at the same offset
+  /// with the same type and name in every version are left in the padding
+  fn class(&mut self, name: &str) -> Result<u32> {
+    if let Some(&size) = self.sizes.get(name) {
+      return Ok(size);
+    }
+    let classes = self.dbs.iter().map(|(ver, db)| match db.get(name) {
+      Some(class) => Ok((*ver, class.clone())),
+      None => Err(format!("class \"{name}\" is not defined in {ver}")),
+    }).collect::<Result<Vec<_>>>()?;
+    if !self.visiting.insert(name.into()) {
+      return Err(format!("class \"{name}\" embeds itself"));
+    }
+    let is_common = |field: &ObjParamsField, offset: u32| classes.iter().all(|(ver, class)| {
+      class.fields.iter().any(|f| decoded_offset(f, *ver) == Some(offset) && f.type_ == field.type_ && f.name == field.name)
+    });
+    let (ver0, class0) = &classes[0];
+    let mut fields = class0.fields.iter()
+      .filter_map(|f| decoded_offset(f, *ver0).filter(|&offset| is_common(f, offset)).map(|offset| (offset, f)))
+      .collect::<Vec<_>>();
+    fields.sort_by_key(|f| f.0);
+    let specific = classes.iter().flat_map(|(ver, class)| class.fields.iter().filter_map(|f| {
+      let offset = decoded_offset(f, *ver)?;
+      (!is_common(f, offset)).then(|| if f.name.is_empty() {f.type_.to_string()} else {f.name.to_string()})
+    })).collect::<BTreeSet<_>>();
+    // the smallest size in the versions
+    let class_size = classes.iter()
+      .filter_map(|(_, class)| class_size(name, class, &mut Vec::new()))
+      .min();
+
+    let mut cursor = 0;
+    let mut idents = HashSet::new();
+    let mut q_fields = Vec::new();
+    for (offset, field) in fields.into_iter() {
+      let (ty, size) = match primitive_type(&field.type_) {
+        Some(e) => e,
+        // fields of unknown types are left in the padding
+        None if !self.dbs.iter().all(|(_, db)| db.contains_key(&field.type_)) => continue,
+        None => {
+          let size = self.class(&field.type_)?;
+          let ident = type_ident(&field.type_);
+          (quote! {#ident}, size)
+        },
+      };
+      // the members of a union (and the fields overlapping with others) except the first one
+      if offset < cursor {continue}
+      if offset > cursor {
+        q_fields.push(padding(cursor, offset - cursor));
+      }
+      let mut ident = field_ident(&field.name, offset);
+      if !idents.insert(ident.clone()) {
+        ident = format!("{ident}_{:x}", offset);
+        idents.insert(ident.clone());
+      }
+      let ident = format_ident!("{}", ident);
+      let mut docs = vec![format!(" `{}` at 0x{:x}", if field.name.is_empty() {&field.type_} else {&field.name}, offset)];
+      if !field.notes.is_empty() {
+        docs.push(String::new());
+        docs.extend(field.notes.lines().map(|e| format!(" {e}")));
+      }
+      q_fields.push(quote! {
+        #(#[doc = #docs])*
+        pub #ident: #ty
+      });
+      cursor = offset + size;
+    }
+    if let Some(size) = class_size {
+      if cursor > size {
+        return Err(format!("fields of class \"{name}\" exceed its size (0x{cursor:x} > 0x{size:x})"));
+      }
+      if cursor < size {
+        q_fields.push(padding(cursor, size - cursor));
+        cursor = size;
+      }
+    }
+    if q_fields.is_empty() {
+      q_fields.push(padding(0, 0));
+    }
+
+    let ident = type_ident(name);
+    let mut docs = vec![format!(" `{name}` (0x{cursor:x} bytes) defined in `{}`", class0.file)];
+    if !specific.is_empty() {
+      let names = specific.iter().map(|e| format!("`{e}`")).collect::<Vec<_>>();
+      docs.push(String::new());
+      docs.push(format!(" Fields specific to some versions (left in the padding): {}", names.join(", ")));
+    }
+    self.structs.push(quote! {
+      #(#[doc = #docs])*
+      #[allow(non_camel_case_types, dead_code)]
+      #[derive(Debug, Clone, DecodeBE)]
+      pub struct #ident {
+        #(#q_fields),*
+      }
+    });
+    self.visiting.remove(name);
+    self.sizes.insert(name.into(), cursor);
+    Ok(cursor)
+  }
+}
+
+/// Parse `"path", "Class", ...` and generate the structs
+pub fn obj_params_structs(input: TokenStream) -> syn::Result<TokenStream> {
+  use syn::parse::Parser;
+  let args = syn::punctuated::Punctuated::<syn::LitStr, syn::Token![,]>::parse_terminated.parse2(input)?;
+  let mut args = args.iter();
+  let Some(path) = args.next() else {
+    return Err(syn::Error::new(Span::call_site(), "expected \"path\", \"Class\", ..."));
+  };
+  // relative to the crate root
+  let path_abs = std::env::current_dir().map(|e| e.join(path.value())).unwrap_or_else(|_| path.value().into());
+  let files = json_files(&path_abs).map_err(|e| syn::Error::new(path.span(), e))?;
+  let mut parsed = Vec::new();
+  for file in files.iter() {
+    let src = std::fs::read(file).map_err(|e| syn::Error::new(path.span(), format!("{}: {e}", file.display())))?;
+    let file_name: Arc<str> = Arc::from(file.to_string_lossy());
+    let classes = parse_file_content(&src, &file_name).map_err(|e| syn::Error::new(path.span(), e))?;
+    // the hash of the content is only used to reuse the resolved classes
+    parsed.push(((file_name, 0), classes));
+  }
+
+  let mut diagnostics = Vec::new();
+  let (db_raw, patches) = collect_classes(&parsed, &mut diagnostics);
+  let dbs = SMSVersion::ALL.into_iter().map(|ver| {
+    let db = classes_of_version(&db_raw, &patches, ver, &mut diagnostics);
+    validate(&db, ver, &mut diagnostics);
+    (ver, db)
+  }).collect();
+  let mut gen = Generator {dbs, sizes: HashMap::new(), visiting: HashSet::new(), structs: Vec::new()};
+  for class in args {
+    gen.class(&class.value()).map_err(|e| syn::Error::new(class.span(), e))?;
+  }
+  // the generated classes must be valid, while the errors of the other classes are left to the loader
+  let generated = gen.sizes.keys().map(|name| class_path(name, format_args!(""))).collect::<Vec<_>>();
+  if let Some(e) = diagnostics.iter().find(|e| e.is_error() && generated.iter().any(|prefix| e.path.starts_with(prefix))) {
+    return Err(syn::Error::new(path.span(), format!("{}: {}: {}", e.file, e.path, e.message)));
+  }
+
+  // rebuild when the files change
+  let files = files.iter().map(|e| e.to_string_lossy());
+  let structs = gen.structs;
+  Ok(quote! {
+    #(const _: &[u8] = include_bytes!(#files);)*
+    #(#structs)*
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Generate the structs of `classes` from an ObjectParameters file with the given content
+  fn generate(test: &str, content: &str, classes: &[&str]) -> syn::Result<String> {
+    let dir = std::env::temp_dir().join(format!("sup-smsac-derive-{}-{test}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.json"), content).unwrap();
+    let path = dir.to_string_lossy().to_string();
+    let res = obj_params_structs(quote! {#path, #(#classes),*});
+    std::fs::remove_dir_all(&dir).unwrap();
+    res.map(|e| e.to_string())
+  }
+
+  #[test]
+  fn fields_not_shared_by_versions() {
+    let out = generate("shared", r#"{"A": {"size": 16, "offsets": [
+      {"offset": "0", "type": "u32", "name": "X"},
+      {"offset": {"GMSJ01": "4", "GMSE01": "8", "GMSP01": "4", "GMSJ0A": "4"}, "type": "u32", "name": "Y"},
+      {"offset": "c", "type": "u32", "name": "H", "hidden": true},
+      {"offset": "c", "type": "u16", "name": "C", "when": {"field": "X", "equals": 1}},
+      {"name": "S", "expr": "{X} * 2"}
+    ]}}"#, &["A"]).unwrap();
+    assert!(out.contains("pub x : u32"), "{out}");
+    for field in ["pub y :", "pub h :", "pub c :", "pub s :"] {
+      assert!(!out.contains(field), "{field}: {out}");
+    }
+    assert!(out.contains("pub _pad_4 : [u8 ; 12usize]"), "{out}");
+    assert!(out.contains("left in the padding): `Y`"), "{out}");
+  }
+
+  #[test]
+  fn errors_of_generated_classes() {
+    let content = r#"{
+      "A": {"size": 4, "offsets": [{"offset": "0", "type": "u32", "name": "X"}]},
+      "B": {"size": 4, "offsets": [{"offset": "0", "type": "Unknown", "name": "X"}]}
+    }"#;
+    assert!(generate("valid", content, &["A"]).is_ok());
+    let e = generate("invalid", content, &["B"]).unwrap_err().to_string();
+    assert!(e.contains("unknown type \"Unknown\""), "{e}");
+  }
+}
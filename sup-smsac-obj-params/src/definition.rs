@@ -0,0 +1,553 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde::{Deserialize, Deserializer, Serialize, de::{self, Visitor}};
+use crate::{AddrOffsets, SMSVersion, expr::Expr, diagnostic::{ObjParamsDiagnostic, class_path}};
+
+/**** original json ****/
+#[derive(Debug, Deserialize)]
+pub struct ObjParamsJson {
+  /// size of the class in bytes (`-1` or omitted if unknown)
+  pub size: Option<serde_json::Value>,
+  /// versions this class applies to (all versions if omitted)
+  pub versions: Option<Box<[SMSVersion]>>,
+  /// whether to patch the class defined in the previous layers instead of replacing it
+  #[serde(default)]
+  pub patch: bool,
+  pub offsets: Box<[ObjParamsOffsetEntry]>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ObjParamsOffsetEntry {
+  /// required unless `expr` is specified
+  #[serde(default, deserialize_with = "deserialize_obj_params_offset_entry")]
+  pub offset: Option<ObjParamsOffset>,
+  /// required unless patching an existing field
+  #[serde(rename = "type")]
+  pub type_: Option<Arc<str>>,
+  pub name: Option<Arc<str>>,
+  pub notes: Option<Arc<str>>,
+  #[serde(
+    default = "ObjParamsOffsetEntryFormat::none",
+    deserialize_with = "deserialize_obj_params_offset_entry_format",
+  )]
+  pub format: Option<ObjParamsOffsetEntryFormat>,
+  pub hidden: Option<bool>,
+  /// the field is applicable only if the condition holds
+  pub when: Option<Arc<ObjParamsCondition>>,
+  /// name of the union group, whose members share the same memory
+  pub union: Option<Arc<str>>,
+  /// expression of a computed field (see [`Expr`])
+  pub expr: Option<Arc<str>>,
+}
+
+/// `"when": {"field": "Current state", "equals": "0x10"}`
+#[derive(Debug, Deserialize)]
+pub struct ObjParamsCondition {
+  /// name of a field of the same class, or the id of a nested field (e.g. `/Inherited fields/State`)
+  pub field: Arc<str>,
+  /// a number, or a decimal or hex (`0x` prefixed) string
+  pub equals: serde_json::Value,
+}
+
+/**** merged ****/
+/// Name of a file and the hash of its content
+pub type FileSource = (Arc<str>, u64);
+
+/// A class definition, possibly patched by the later layers
+#[derive(Debug, Clone)]
+pub struct ObjParamsClass {
+  /// file in which the class is defined
+  pub file: Arc<str>,
+  /// the file defining the class, followed by the files of the applied patches
+  pub sources: Vec<FileSource>,
+  /// order in which the definitions are loaded
+  pub seq: usize,
+  pub size: Option<serde_json::Value>,
+  pub versions: Option<Box<[SMSVersion]>>,
+  pub fields: Vec<ObjParamsField>,
+  pub computed: Vec<ObjParamsComputed>,
+}
+impl ObjParamsClass {
+  #[inline]
+  pub fn applies_to(&self, ver: SMSVersion) -> bool {
+    applies_to(&self.versions, ver)
+  }
+  /// whether the two definitions apply to the same version
+  pub fn overlaps(&self, other: &Self) -> bool {
+    match (&self.versions, &other.versions) {
+      (None, None) => true,
+      (Some(vers), Some(_)) => vers.iter().any(|&ver| other.applies_to(ver)),
+      // version-tagged classes are meant to override untagged ones
+      _ => false,
+    }
+  }
+}
+
+#[inline]
+fn applies_to(versions: &Option<Box<[SMSVersion]>>, ver: SMSVersion) -> bool {
+  match versions {
+    Some(vers) => vers.contains(&ver),
+    None => true,
+  }
+}
+
+/// A patch to a class defined in the previous layers
+#[derive(Debug)]
+pub struct ObjParamsPatch {
+  pub file: Arc<str>,
+  pub hash: u64,
+  pub seq: usize,
+  pub json: Arc<ObjParamsJson>,
+}
+
+/// A field and where it is (last) defined
+#[derive(Debug, Clone)]
+pub struct ObjParamsField {
+  pub file: Arc<str>,
+  /// index in `offsets` of the class in `file`
+  pub index: usize,
+  pub offset: ObjParamsOffset,
+  pub type_: Arc<str>,
+  pub name: Arc<str>,
+  pub notes: Arc<str>,
+  pub format: Option<ObjParamsOffsetEntryFormat>,
+  pub hidden: bool,
+  pub when: Option<Arc<ObjParamsCondition>>,
+  pub union: Option<Arc<str>>,
+}
+impl ObjParamsField {
+  /// Apply the keys specified in the patch
+  pub fn patch(&mut self, file: &Arc<str>, index: usize, entry: &ObjParamsOffsetEntry) {
+    self.file = file.clone();
+    self.index = index;
+    if let Some(type_) = &entry.type_ {self.type_ = type_.clone()}
+    if let Some(name) = &entry.name {self.name = name.clone()}
+    if let Some(notes) = &entry.notes {self.notes = notes.clone()}
+    if entry.format.is_some() {self.format = entry.format}
+    if let Some(hidden) = entry.hidden {self.hidden = hidden}
+    if let Some(when) = &entry.when {self.when = Some(when.clone())}
+    if let Some(union) = &entry.union {self.union = Some(union.clone())}
+  }
+}
+
+/// A field computed from the other fields of the class
+#[derive(Debug, Clone)]
+pub struct ObjParamsComputed {
+  pub file: Arc<str>,
+  /// index in `offsets` of the class in `file`
+  pub index: usize,
+  pub name: Arc<str>,
+  pub notes: Arc<str>,
+  pub expr: Arc<Expr>,
+  pub hidden: bool,
+}
+
+/// Parse the expression of a computed field, reporting the error
+fn parse_expr(
+  file: &Arc<str>, path: String, src: &str,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) -> Option<Arc<Expr>> {
+  match Expr::parse(src) {
+    Ok(expr) => Some(Arc::new(expr)),
+    Err(e) => {
+      diagnostics.push(ObjParamsDiagnostic::error(file, path, format!("invalid expression \"{src}\": {e}")));
+      None
+    },
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ObjParamsOffsetEntryFormat {
+  Hex,
+}
+impl ObjParamsOffsetEntryFormat {
+  #[inline]
+  pub fn none() -> Option<ObjParamsOffsetEntryFormat> {
+    None
+  }
+  /// whether the format can be used for the given type
+  pub fn supports(&self, type_: &str) -> bool {
+    match self {
+      Self::Hex => matches!(type_, "u8" | "u16" | "u32"),
+    }
+  }
+}
+
+impl std::fmt::Display for ObjParamsOffsetEntryFormat {
+  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    match self {
+      Self::Hex => write!(fmt, "hex"),
+    }
+  }
+}
+
+/// Offset of a field, either shared by all versions or specified per version
+#[derive(Debug, Clone)]
+pub enum ObjParamsOffset {
+  Common(AddrOffsets),
+  PerVersion(HashMap<SMSVersion, AddrOffsets>),
+}
+impl ObjParamsOffset {
+  /// `None` if the field does not exist in the given version
+  pub fn get(&self, ver: SMSVersion) -> Option<&AddrOffsets> {
+    match self {
+      Self::Common(offset) => Some(offset),
+      Self::PerVersion(offsets) => offsets.get(&ver),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct HexAddrOffsets(
+  #[serde(deserialize_with = "deserialize_hex_addr_offsets")]
+  AddrOffsets,
+);
+
+fn deserialize_hex_addr_offsets<'de, D>(deserializer: D) -> Result<AddrOffsets, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  struct ValueVisitor;
+  impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = AddrOffsets;
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+      formatter.write_str("a hex string or a non-empty array of hex string")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+      E: de::Error,
+    {
+      u32::from_str_radix(value, 16)
+        .map_err(|e| E::custom(e))
+        .map(|x| AddrOffsets(x, Box::from([])))
+    }
+    fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
+    where
+      S: de::SeqAccess<'de>,
+    {
+      let mut arr = match seq.size_hint() {
+        Some(size) => Vec::<u32>::with_capacity(size),
+        None => Vec::<u32>::new(),
+      };
+      while let Some(value) = seq.next_element::<Cow<'de, str>>()? {
+        arr.push(u32::from_str_radix(&value, 16).map_err(de::Error::custom)?);
+      }
+      arr.split_first()
+        .map(|p| AddrOffsets(*p.0, p.1.into()))
+        .ok_or_else(|| de::Error::custom("Offset array must not be empty"))
+    }
+  }
+
+  deserializer.deserialize_any(ValueVisitor)
+}
+
+fn deserialize_obj_params_offset_entry<'de, D>(deserializer: D) -> Result<Option<ObjParamsOffset>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  struct ValueVisitor;
+  impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = ObjParamsOffset;
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+      formatter.write_str("a hex string, a non-empty array of hex string, or a map from version to them")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+      E: de::Error,
+    {
+      deserialize_hex_addr_offsets(de::value::StrDeserializer::new(value))
+        .map(ObjParamsOffset::Common)
+    }
+    fn visit_seq<S>(self, seq: S) -> Result<Self::Value, S::Error>
+    where
+      S: de::SeqAccess<'de>,
+    {
+      deserialize_hex_addr_offsets(de::value::SeqAccessDeserializer::new(seq))
+        .map(ObjParamsOffset::Common)
+    }
+    fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+    where
+      M: de::MapAccess<'de>,
+    {
+      let mut offsets = HashMap::<SMSVersion, AddrOffsets>::new();
+      while let Some((ver, offset)) = map.next_entry::<SMSVersion, HexAddrOffsets>()? {
+        offsets.insert(ver, offset.0);
+      }
+      Ok(ObjParamsOffset::PerVersion(offsets))
+    }
+  }
+
+  deserializer.deserialize_any(ValueVisitor).map(Some)
+}
+
+fn deserialize_obj_params_offset_entry_format<'de, D>(deserializer: D)
+  -> Result<Option<ObjParamsOffsetEntryFormat>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  struct ValueVisitor;
+  impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Option<ObjParamsOffsetEntryFormat>;
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+      formatter.write_str("\"hex\" or omitted")
+    }
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+      Ok(None)
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+      E: de::Error,
+    {
+      match value {
+        "hex" => Ok(Some(ObjParamsOffsetEntryFormat::Hex)),
+        _ => Err(E::unknown_variant(value, &["hex"])),
+      }
+    }
+  }
+
+  deserializer.deserialize_any(ValueVisitor)
+}
+
+/// Classes in a file sorted by name
+pub type ObjParamsFileClasses = Arc<[(Arc<str>, Arc<ObjParamsJson>)]>;
+/// Classes in a file, or the parse error
+pub type ObjParamsFileContent = Result<ObjParamsFileClasses, Arc<str>>;
+
+/// Parse an ObjectParameters file into its classes sorted by name
+pub fn parse_file_content(src: &[u8], file_name: &str) -> ObjParamsFileContent {
+  match serde_json::from_slice::<HashMap<Arc<str>, ObjParamsJson>>(src) {
+    Ok(o) => {
+      let mut o = o.into_iter().map(|(name, json)| (name, Arc::new(json))).collect::<Vec<_>>();
+      o.sort_by(|a, b| a.0.cmp(&b.0));
+      Ok(o.into())
+    },
+    Err(e) => Err(Arc::from(format!("Fail to parse {file_name}: {e}"))),
+  }
+}
+
+/// Definitions of each class in the order of loading
+pub type ObjParamsDefs = HashMap<Arc<str>, Vec<Arc<ObjParamsClass>>>;
+/// Patches of each class in the order of loading
+pub type ObjParamsPatches = HashMap<Arc<str>, Vec<ObjParamsPatch>>;
+/// The class of each name selected for a version
+pub type ObjParamsClasses = HashMap<Arc<str>, Arc<ObjParamsClass>>;
+
+/// Collect the definitions and the patches of the classes of the parsed files (in the order of loading)
+pub fn collect_classes(
+  files: &[(FileSource, ObjParamsFileClasses)],
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) -> (ObjParamsDefs, ObjParamsPatches) {
+  let mut db_raw = ObjParamsDefs::new();
+  let mut patches = ObjParamsPatches::new();
+  let mut seq = 0usize;
+  for ((file_name, hash), o) in files.iter() {
+    for (name, json) in o.iter() {
+      seq += 1;
+      if json.patch {
+        patches.entry(name.clone()).or_default()
+          .push(ObjParamsPatch {file: file_name.clone(), hash: *hash, seq, json: json.clone()});
+        continue;
+      }
+      let mut fields = Vec::new();
+      let mut computed = Vec::new();
+      for (i, entry) in json.offsets.iter().enumerate() {
+        let path = || class_path(name, format_args!(".offsets[{i}]"));
+        let offset = match (&entry.offset, &entry.expr) {
+          (Some(offset), None) => offset,
+          (None, Some(src)) => {
+            let Some(expr) = parse_expr(file_name, path()+".expr", src, diagnostics) else {continue};
+            let Some(name) = entry.name.clone() else {
+              diagnostics.push(ObjParamsDiagnostic::error(file_name, path(),
+                "missing \"name\" of the computed field".into()));
+              continue;
+            };
+            computed.push(ObjParamsComputed {
+              file: file_name.clone(),
+              index: i,
+              name,
+              notes: entry.notes.clone().unwrap_or_else(|| Arc::from("")),
+              expr,
+              hidden: entry.hidden.unwrap_or(false),
+            });
+            continue;
+          },
+          (Some(_), Some(_)) => {
+            diagnostics.push(ObjParamsDiagnostic::error(file_name, path(),
+              "\"offset\" and \"expr\" cannot be specified at the same time".into()));
+            continue;
+          },
+          (None, None) => {
+            diagnostics.push(ObjParamsDiagnostic::error(file_name, path(),
+              "missing \"offset\"".into()));
+            continue;
+          },
+        };
+        let Some(type_) = entry.type_.clone() else {
+          diagnostics.push(ObjParamsDiagnostic::error(file_name, path(),
+            "missing \"type\"".into()));
+          continue;
+        };
+        fields.push(ObjParamsField {
+          file: file_name.clone(),
+          index: i,
+          offset: offset.clone(),
+          type_,
+          name: entry.name.clone().unwrap_or_else(|| Arc::from("")),
+          notes: entry.notes.clone().unwrap_or_else(|| Arc::from("")),
+          format: entry.format,
+          hidden: entry.hidden.unwrap_or(false),
+          when: entry.when.clone(),
+          union: entry.union.clone(),
+        });
+      }
+      let class = ObjParamsClass {
+        file: file_name.clone(),
+        sources: vec![(file_name.clone(), *hash)],
+        seq,
+        fields,
+        computed,
+        size: json.size.clone(),
+        versions: json.versions.clone(),
+      };
+      let defs = db_raw.entry(name.clone()).or_default();
+      if let Some(def0) = defs.iter().find(|def0| def0.overlaps(&class)) {
+        diagnostics.push(ObjParamsDiagnostic::warning(file_name,
+          class_path(name, format_args!("")),
+          format!("class \"{name}\" is also defined in \"{}\", which is overridden", def0.file)));
+      }
+      defs.push(Arc::new(class));
+    }
+  }
+  (db_raw, patches)
+}
+
+/// The classes of `ver` (later definitions take precedence), with the patches applied
+pub fn classes_of_version(
+  db_raw: &ObjParamsDefs,
+  patches: &ObjParamsPatches,
+  ver: SMSVersion,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) -> ObjParamsClasses {
+  // version-tagged classes take precedence over untagged ones
+  let mut db = db_raw.iter().filter_map(|(name, defs)| {
+    defs.iter().rev().find(|o| o.versions.is_some() && o.applies_to(ver))
+      .or_else(|| defs.iter().rev().find(|o| o.versions.is_none()))
+      .map(|o| (name.clone(), o.clone()))
+  }).collect::<HashMap<_, _>>();
+  apply_patches(&mut db, patches, ver, diagnostics);
+  db
+}
+
+/// Apply the patches loaded after the selected definition of each class.
+/// Fields are matched by their offsets in the given version
+fn apply_patches(
+  db: &mut ObjParamsClasses,
+  patches: &ObjParamsPatches,
+  ver: SMSVersion,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) {
+  for (name, patches) in patches.iter() {
+    let Some(class) = db.get_mut(name) else {
+      for patch in patches.iter().filter(|e| applies_to(&e.json.versions, ver)) {
+        diagnostics.push(ObjParamsDiagnostic::error(&patch.file, class_path(name, format_args!("")),
+          format!("class \"{name}\" to patch is not defined in the previous layers")));
+      }
+      continue;
+    };
+    for ObjParamsPatch {file, hash, seq, json} in patches.iter() {
+      if *seq < class.seq || !applies_to(&json.versions, ver) {continue}
+      let class = Arc::make_mut(class);
+      class.sources.push((file.clone(), *hash));
+      if json.size.is_some() {
+        class.size = json.size.clone();
+      }
+      for (i, entry) in json.offsets.iter().enumerate() {
+        let path = || class_path(name, format_args!(".offsets[{i}]"));
+        let offset = match (&entry.offset, &entry.expr) {
+          (Some(offset), None) => offset,
+          (Some(_), Some(_)) => {
+            diagnostics.push(ObjParamsDiagnostic::error(file, path(),
+              "\"offset\" and \"expr\" cannot be specified at the same time".into()));
+            continue;
+          },
+          // computed fields are matched by name
+          (None, expr) => {
+            let Some(field_name) = &entry.name else {
+              diagnostics.push(ObjParamsDiagnostic::error(file, path(),
+                "either \"offset\" or \"name\" of the computed field to patch must be specified".into()));
+              continue;
+            };
+            let expr = match expr {
+              Some(src) => match parse_expr(file, path()+".expr", src, diagnostics) {
+                Some(expr) => Some(expr),
+                None => continue,
+              },
+              None => None,
+            };
+            match (class.computed.iter_mut().find(|c| c.name == *field_name), expr) {
+              (Some(c), expr) => {
+                c.file = file.clone();
+                c.index = i;
+                if let Some(expr) = expr {c.expr = expr}
+                if let Some(notes) = &entry.notes {c.notes = notes.clone()}
+                if let Some(hidden) = entry.hidden {c.hidden = hidden}
+              },
+              (None, Some(expr)) => class.computed.push(ObjParamsComputed {
+                file: file.clone(),
+                index: i,
+                name: field_name.clone(),
+                notes: entry.notes.clone().unwrap_or_else(|| Arc::from("")),
+                expr,
+                hidden: entry.hidden.unwrap_or(false),
+              }),
+              (None, None) => diagnostics.push(ObjParamsDiagnostic::error(file, path(),
+                format!("computed field \"{field_name}\" to patch is not found, and \"expr\" is not specified"))),
+            }
+            continue;
+          },
+        };
+        let Some(offset) = offset.get(ver) else {continue};
+        // the union members and the conditional fields sharing the offset are told apart by `union` and `name`,
+        // where a name matching none of them with `type` adds a field
+        let mut matched = class.fields.iter().enumerate()
+          .filter(|(_, f)| f.offset.get(ver) == Some(offset))
+          .map(|(j, _)| j)
+          .collect::<Vec<_>>();
+        if let (true, Some(union)) = (matched.len() > 1, &entry.union) {
+          matched.retain(|&j| class.fields[j].union.as_ref() == Some(union));
+        }
+        if let (true, Some(name)) = (matched.len() > 1, &entry.name) {
+          let named = matched.iter().copied().filter(|&j| class.fields[j].name == *name).collect::<Vec<_>>();
+          if !named.is_empty() || entry.type_.is_some() {matched = named}
+        }
+        match matched[..] {
+          [j] => class.fields[j].patch(file, i, entry),
+          [_, _, ..] => diagnostics.push(ObjParamsDiagnostic::error(file, path(), format!(
+            "{} fields at offset {offset} match the patch; specify \"name\" (or \"union\") of the field to patch",
+            matched.len(),
+          ))),
+          [] => match &entry.type_ {
+            Some(type_) => class.fields.push(ObjParamsField {
+              file: file.clone(),
+              index: i,
+              offset: ObjParamsOffset::Common(offset.clone()),
+              type_: type_.clone(),
+              name: entry.name.clone().unwrap_or_else(|| Arc::from("")),
+              notes: entry.notes.clone().unwrap_or_else(|| Arc::from("")),
+              format: entry.format,
+              hidden: entry.hidden.unwrap_or(false),
+              when: entry.when.clone(),
+              union: entry.union.clone(),
+            }),
+            None => diagnostics.push(ObjParamsDiagnostic::error(file, path(),
+              format!("field at offset {offset} to patch is not found, and \"type\" is not specified"))),
+          },
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,53 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Error,
+  Warning,
+}
+impl std::fmt::Display for Severity {
+  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    match self {
+      Self::Error => write!(fmt, "error"),
+      Self::Warning => write!(fmt, "warning"),
+    }
+  }
+}
+
+/// A problem found while loading ObjectParameters
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ObjParamsDiagnostic {
+  pub severity: Severity,
+  /// path of the file in which the problem is found
+  pub file: Arc<str>,
+  /// JSON path to the offending value (e.g. `$["TMario"].offsets[3].type`)
+  pub path: String,
+  pub message: String,
+}
+impl ObjParamsDiagnostic {
+  pub fn error(file: &Arc<str>, path: String, message: String) -> Self {
+    Self {severity: Severity::Error, file: file.clone(), path, message}
+  }
+  pub fn warning(file: &Arc<str>, path: String, message: String) -> Self {
+    Self {severity: Severity::Warning, file: file.clone(), path, message}
+  }
+  #[inline]
+  pub fn is_error(&self) -> bool {
+    self.severity == Severity::Error
+  }
+}
+impl std::fmt::Display for ObjParamsDiagnostic {
+  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    write!(fmt, "{}: {}: {}: {}", self.file, self.path, self.severity, self.message)
+  }
+}
+
+/// JSON path of a class, followed by `rest` (e.g. `.offsets[3]`)
+pub fn class_path(class: &str, rest: std::fmt::Arguments) -> String {
+  format!("$[{}]{rest}", serde_json::Value::from(class))
+}
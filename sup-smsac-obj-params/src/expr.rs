@@ -0,0 +1,454 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::sync::Arc;
+
+/// maximum nesting depth of an expression
+const MAX_DEPTH: usize = 64;
+
+/// operators sorted so that the longer ones are matched first
+const OPS: &[&str] = &[
+  "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+  "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "<", ">", "?", ":", "(", ")", ",",
+];
+/// binary operators from the lowest precedence (`**` is handled with the unary operators)
+const BINARY_OPS: &[&[&str]] = &[
+  &["||"],
+  &["&&"],
+  &["|"],
+  &["^"],
+  &["&"],
+  &["==", "!="],
+  &["<", "<=", ">", ">="],
+  &["<<", ">>"],
+  &["+", "-"],
+  &["*", "/", "%"],
+];
+
+type Func = fn(&[f64]) -> f64;
+/// (name, number of arguments, function)
+const FUNCS: &[(&str, usize, Func)] = &[
+  ("sqrt", 1, |x| x[0].sqrt()),
+  ("abs", 1, |x| x[0].abs()),
+  ("floor", 1, |x| x[0].floor()),
+  ("ceil", 1, |x| x[0].ceil()),
+  ("round", 1, |x| x[0].round()),
+  ("trunc", 1, |x| x[0].trunc()),
+  ("sign", 1, |x| if x[0] == 0. {0.} else {x[0].signum()}),
+  ("sin", 1, |x| x[0].sin()),
+  ("cos", 1, |x| x[0].cos()),
+  ("tan", 1, |x| x[0].tan()),
+  ("asin", 1, |x| x[0].asin()),
+  ("acos", 1, |x| x[0].acos()),
+  ("atan", 1, |x| x[0].atan()),
+  ("exp", 1, |x| x[0].exp()),
+  ("ln", 1, |x| x[0].ln()),
+  ("log10", 1, |x| x[0].log10()),
+  ("log2", 1, |x| x[0].log2()),
+  ("deg", 1, |x| x[0].to_degrees()),
+  ("rad", 1, |x| x[0].to_radians()),
+  ("atan2", 2, |x| x[0].atan2(x[1])),
+  ("pow", 2, |x| x[0].powf(x[1])),
+  ("hypot", 2, |x| x[0].hypot(x[1])),
+  ("min", 2, |x| x[0].min(x[1])),
+  ("max", 2, |x| x[0].max(x[1])),
+];
+const CONSTS: &[(&str, f64)] = &[
+  ("pi", std::f64::consts::PI),
+  ("e", std::f64::consts::E),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+  Number(f64),
+  /// `{name}`
+  Field(&'a str),
+  Ident(&'a str),
+  Op(&'static str),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token<'_>>, String> {
+  let mut tokens = Vec::new();
+  let mut rest = src.trim_start();
+  while let Some(c) = rest.chars().next() {
+    let pos = src.len() - rest.len();
+    let (token, len) = if c == '{' {
+      let Some(end) = rest.find('}') else {
+        return Err(format!("unclosed '{{' at {pos}"));
+      };
+      (Token::Field(rest[1..end].trim()), end+1)
+    } else if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+      match rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+        Some(hex) => {
+          let len = hex.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(hex.len());
+          let x = u64::from_str_radix(&hex[..len], 16)
+            .map_err(|e| format!("invalid number at {pos}: {e}"))?;
+          (Token::Number(x as f64), len+2)
+        },
+        None => {
+          let bytes = rest.as_bytes();
+          let mut len = 0;
+          while len < bytes.len() && (bytes[len].is_ascii_digit() || bytes[len] == b'.' ||
+            matches!(bytes[len], b'e' | b'E') ||
+            (matches!(bytes[len], b'+' | b'-') && len > 0 && matches!(bytes[len-1], b'e' | b'E'))) {
+            len += 1;
+          }
+          let x = rest[..len].parse::<f64>()
+            .map_err(|e| format!("invalid number \"{}\" at {pos}: {e}", &rest[..len]))?;
+          (Token::Number(x), len)
+        },
+      }
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+      (Token::Ident(&rest[..len]), len)
+    } else {
+      match OPS.iter().find(|op| rest.starts_with(**op)) {
+        Some(op) => (Token::Op(op), op.len()),
+        None => return Err(format!("unexpected character '{c}' at {pos}")),
+      }
+    };
+    tokens.push(token);
+    rest = rest[len..].trim_start();
+  }
+  Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+  Number(f64),
+  /// index in [`Expr::fields`]
+  Field(usize),
+  Unary(&'static str, Box<Node>),
+  Binary(&'static str, Box<Node>, Box<Node>),
+  Cond(Box<Node>, Box<Node>, Box<Node>),
+  Call(Func, Box<[Node]>),
+}
+
+struct Parser<'a> {
+  tokens: Vec<Token<'a>>,
+  pos: usize,
+  depth: usize,
+  fields: Vec<Arc<str>>,
+}
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<Token<'a>> {
+    self.tokens.get(self.pos).copied()
+  }
+  fn eat(&mut self, op: &str) -> bool {
+    let found = matches!(self.peek(), Some(Token::Op(e)) if e == op);
+    if found {
+      self.pos += 1;
+    }
+    found
+  }
+  fn expect(&mut self, op: &str) -> Result<(), String> {
+    match self.eat(op) {
+      true => Ok(()),
+      false => Err(format!("expected '{op}', got {}", self.describe())),
+    }
+  }
+  fn describe(&self) -> String {
+    match self.peek() {
+      None => "end of expression".into(),
+      Some(Token::Number(x)) => format!("number {x}"),
+      Some(Token::Field(name)) => format!("field {{{name}}}"),
+      Some(Token::Ident(name)) => format!("\"{name}\""),
+      Some(Token::Op(op)) => format!("'{op}'"),
+    }
+  }
+  fn enter(&mut self) -> Result<(), String> {
+    self.depth += 1;
+    match self.depth > MAX_DEPTH {
+      true => Err(format!("expression is nested too deeply (> {MAX_DEPTH})")),
+      false => Ok(()),
+    }
+  }
+
+  fn cond(&mut self) -> Result<Node, String> {
+    self.enter()?;
+    let cond = self.binary(0)?;
+    let node = match self.eat("?") {
+      true => {
+        let a = self.cond()?;
+        self.expect(":")?;
+        let b = self.cond()?;
+        Node::Cond(Box::new(cond), Box::new(a), Box::new(b))
+      },
+      false => cond,
+    };
+    self.depth -= 1;
+    Ok(node)
+  }
+
+  fn binary(&mut self, level: usize) -> Result<Node, String> {
+    let Some(ops) = BINARY_OPS.get(level) else {
+      return self.unary();
+    };
+    let mut lhs = self.binary(level+1)?;
+    while let Some(Token::Op(op)) = self.peek() {
+      if !ops.contains(&op) {break}
+      self.pos += 1;
+      let rhs = self.binary(level+1)?;
+      lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+  }
+
+  fn unary(&mut self) -> Result<Node, String> {
+    match self.peek() {
+      Some(Token::Op(op @ ("-" | "+" | "!" | "~"))) => {
+        self.pos += 1;
+        self.enter()?;
+        let node = Node::Unary(op, Box::new(self.unary()?));
+        self.depth -= 1;
+        Ok(node)
+      },
+      _ => self.power(),
+    }
+  }
+
+  /// `**` is right-associative and binds tighter than the unary operator on its left
+  fn power(&mut self) -> Result<Node, String> {
+    let base = self.primary()?;
+    if !self.eat("**") {
+      return Ok(base);
+    }
+    self.enter()?;
+    let node = Node::Binary("**", Box::new(base), Box::new(self.unary()?));
+    self.depth -= 1;
+    Ok(node)
+  }
+
+  fn primary(&mut self) -> Result<Node, String> {
+    let token = self.peek();
+    self.pos += 1;
+    match token {
+      Some(Token::Number(x)) => Ok(Node::Number(x)),
+      Some(Token::Field(name)) => Ok(Node::Field(
+        match self.fields.iter().position(|e| **e == *name) {
+          Some(i) => i,
+          None => {
+            self.fields.push(Arc::from(name));
+            self.fields.len()-1
+          },
+        }
+      )),
+      Some(Token::Ident(name)) => {
+        if !self.eat("(") {
+          return match CONSTS.iter().find(|e| e.0 == name) {
+            Some((_, x)) => Ok(Node::Number(*x)),
+            None => Err(format!("unknown constant \"{name}\" (fields must be enclosed in {{}})")),
+          };
+        }
+        let Some(&(_, argc, func)) = FUNCS.iter().find(|e| e.0 == name) else {
+          return Err(format!("unknown function \"{name}\""));
+        };
+        let mut args = Vec::new();
+        if !self.eat(")") {
+          loop {
+            args.push(self.cond()?);
+            if self.eat(")") {break}
+            self.expect(",")?;
+          }
+        }
+        if args.len() != argc {
+          return Err(format!("{name}() takes {argc} argument(s), got {}", args.len()));
+        }
+        Ok(Node::Call(func, args.into()))
+      },
+      Some(Token::Op("(")) => {
+        let node = self.cond()?;
+        self.expect(")")?;
+        Ok(node)
+      },
+      _ => {
+        self.pos -= 1;
+        Err(format!("unexpected {}", self.describe()))
+      },
+    }
+  }
+}
+
+#[inline]
+fn int(x: f64) -> i64 {
+  x as i64
+}
+#[inline]
+fn bool(x: bool) -> f64 {
+  if x {1.} else {0.}
+}
+
+fn eval(node: &Node, values: &[f64]) -> f64 {
+  match node {
+    Node::Number(x) => *x,
+    Node::Field(i) => values[*i],
+    Node::Unary(op, a) => {
+      let a = eval(a, values);
+      match *op {
+        "-" => -a,
+        "!" => bool(a == 0.),
+        "~" => !int(a) as f64,
+        _ => a,
+      }
+    },
+    Node::Binary("&&", a, b) => bool(eval(a, values) != 0. && eval(b, values) != 0.),
+    Node::Binary("||", a, b) => bool(eval(a, values) != 0. || eval(b, values) != 0.),
+    Node::Binary(op, a, b) => {
+      let (a, b) = (eval(a, values), eval(b, values));
+      match *op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "%" => a % b,
+        "**" => a.powf(b),
+        "&" => (int(a) & int(b)) as f64,
+        "|" => (int(a) | int(b)) as f64,
+        "^" => (int(a) ^ int(b)) as f64,
+        "<<" => int(a).wrapping_shl(int(b) as u32) as f64,
+        ">>" => int(a).wrapping_shr(int(b) as u32) as f64,
+        "==" => bool(a == b),
+        "!=" => bool(a != b),
+        "<" => bool(a < b),
+        "<=" => bool(a <= b),
+        ">" => bool(a > b),
+        ">=" => bool(a >= b),
+        _ => unreachable!(),
+      }
+    },
+    Node::Cond(cond, a, b) => match eval(cond, values) != 0. {
+      true => eval(a, values),
+      false => eval(b, values),
+    },
+    Node::Call(func, args) => func(&args.iter().map(|e| eval(e, values)).collect::<Vec<_>>()),
+  }
+}
+
+/// An expression of a computed field, e.g. `sqrt({Velocity X}**2 + {Velocity Z}**2)`.
+///
+/// Fields are referenced as `{name}` (or `{/id}` for nested fields). Numbers are `f64`;
+/// bit operations truncate their operands into integers, and comparisons return 1 or 0
+#[derive(Debug)]
+pub struct Expr {
+  pub src: Arc<str>,
+  /// names (or ids) of the referenced fields in order of appearance
+  pub fields: Box<[Arc<str>]>,
+  root: Node,
+}
+impl Expr {
+  pub fn parse(src: &str) -> Result<Self, String> {
+    let mut parser = Parser {
+      tokens: tokenize(src)?,
+      pos: 0,
+      depth: 0,
+      fields: Vec::new(),
+    };
+    let root = parser.cond()?;
+    if parser.pos < parser.tokens.len() {
+      return Err(format!("unexpected {}", parser.describe()));
+    }
+    Ok(Self {
+      src: Arc::from(src),
+      fields: parser.fields.into(),
+      root,
+    })
+  }
+
+  /// Evaluate with the values of the referenced fields (in the order of [`Self::fields`])
+  pub fn eval(&self, values: &[f64]) -> f64 {
+    eval(&self.root, values)
+  }
+}
+
+/// Types which can be referenced by expressions (pointers are read as addresses)
+pub fn is_numeric_type(type_: &str) -> bool {
+  matches!(type_, "u8" | "s8" | "u16" | "s16" | "u32" | "s32" | "float" | "double") || type_.ends_with('*')
+}
+
+/// The expression of a computed field and the fields it references
+#[derive(Clone)]
+pub struct ComputedExpr {
+  pub expr: Arc<Expr>,
+  /// indices of the referenced fields in the flattened field list
+  pub fields: Box<[usize]>,
+}
+impl ComputedExpr {
+  /// Copy into the class embedding the class of the field
+  pub fn embed(&self, base: usize) -> Self {
+    Self {
+      expr: self.expr.clone(),
+      fields: self.fields.iter().map(|i| base + i).collect(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn eval_str(src: &str, values: &[f64]) -> f64 {
+    Expr::parse(src).unwrap().eval(values)
+  }
+
+  fn parse_err(src: &str) -> String {
+    Expr::parse(src).unwrap_err()
+  }
+
+  #[test]
+  fn precedence() {
+    assert_eq!(eval_str("1 + 2 * 3", &[]), 7.);
+    assert_eq!(eval_str("(1 + 2) * 3", &[]), 9.);
+    assert_eq!(eval_str("10 - 4 - 3", &[]), 3.);
+    assert_eq!(eval_str("2 ** 3 ** 2", &[]), 512.);
+    assert_eq!(eval_str("1 << 2 + 1", &[]), 8.);
+    assert_eq!(eval_str("1 | 6 & 3", &[]), 3.);
+    assert_eq!(eval_str("1 + 1 == 2 && 3 < 2 || 1", &[]), 1.);
+    assert_eq!(eval_str("0 ? 1 : 2 ? 3 : 4", &[]), 3.);
+    assert_eq!(eval_str("{a} * {b} + {a}", &[2., 5.]), 12.);
+  }
+
+  #[test]
+  fn unary_minus() {
+    assert_eq!(eval_str("-3 + 5", &[]), 2.);
+    assert_eq!(eval_str("- -3", &[]), 3.);
+    assert_eq!(eval_str("2 * -{x}", &[4.]), -8.);
+    // `**` binds tighter than the unary operator on its left
+    assert_eq!(eval_str("-2 ** 2", &[]), -4.);
+    assert_eq!(eval_str("2 ** -1", &[]), 0.5);
+  }
+
+  #[test]
+  fn division_by_zero() {
+    assert_eq!(eval_str("1 / 0", &[]), f64::INFINITY);
+    assert_eq!(eval_str("-1 / {x}", &[0.]), f64::NEG_INFINITY);
+    assert!(eval_str("0 / 0", &[]).is_nan());
+    assert!(eval_str("5 % 0", &[]).is_nan());
+  }
+
+  #[test]
+  fn depth_overflow() {
+    let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+    assert_eq!(eval_str(&nested(MAX_DEPTH-1), &[]), 1.);
+    assert_eq!(parse_err(&nested(MAX_DEPTH)), format!("expression is nested too deeply (> {MAX_DEPTH})"));
+    assert_eq!(parse_err(&format!("{}1", "-".repeat(MAX_DEPTH+1))),
+      format!("expression is nested too deeply (> {MAX_DEPTH})"));
+  }
+
+  #[test]
+  fn unknown_identifiers() {
+    assert_eq!(parse_err("speed * 2"), "unknown constant \"speed\" (fields must be enclosed in {})");
+    assert_eq!(parse_err("len({x})"), "unknown function \"len\"");
+    assert_eq!(parse_err("atan2({x})"), "atan2() takes 2 argument(s), got 1");
+    assert_eq!(eval_str("max(pi, e)", &[]), std::f64::consts::PI);
+  }
+
+  #[test]
+  fn trailing_garbage() {
+    assert_eq!(parse_err("1 2"), "unexpected number 2");
+    assert_eq!(parse_err("{x} + 1)"), "unexpected ')'");
+    assert_eq!(parse_err("{x} {y}"), "unexpected field {y}");
+    assert_eq!(parse_err("1 + 1 $"), "unexpected character '$' at 6");
+    assert_eq!(parse_err("(1 + 1"), "expected ')', got end of expression");
+    assert_eq!(parse_err("{x"), "unclosed '{' at 0");
+  }
+}
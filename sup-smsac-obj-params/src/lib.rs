@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The format of ObjectParameters, shared by the loader of `sup-smsac` and `obj_params_structs!` of
+//! `sup-smsac-derive`, so that the structs generated at compile time match the classes loaded at runtime
+
+use serde::{Deserialize, Serialize};
+
+/// The JSON format of ObjectParameters files, and the merge of the definitions and patches
+/// into the classes of each version
+pub mod definition;
+/// Validation of the classes of a version
+pub mod validate;
+pub mod expr;
+pub mod diagnostic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SMSVersion {
+  GMSJ01, GMSE01, GMSP01, GMSJ0A,
+}
+impl SMSVersion {
+  pub const ALL: [SMSVersion; 4] = [
+    SMSVersion::GMSJ01, SMSVersion::GMSE01, SMSVersion::GMSP01, SMSVersion::GMSJ0A,
+  ];
+}
+impl std::fmt::Display for SMSVersion {
+  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    std::fmt::Debug::fmt(self, fmt)
+  }
+}
+impl std::str::FromStr for SMSVersion {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    SMSVersion::ALL.into_iter()
+      .find(|ver| ver.to_string() == s)
+      .ok_or_else(|| format!("unknown version: {s}"))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddrOffsets<T=u32>(pub T, pub Box<[T]>);
+impl std::ops::Add<&AddrOffsets> for &AddrOffsets {
+  type Output = AddrOffsets;
+  fn add(self, other: &AddrOffsets) -> AddrOffsets {
+    match self.1.split_last() {
+      Some((last, init)) => AddrOffsets(
+        self.0,
+        [init, &[last+other.0], &other.1].concat().into(),
+      ),
+      None => AddrOffsets(self.0+other.0, other.1.clone()),
+    }
+  }
+}
+impl std::fmt::Display for AddrOffsets {
+  fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    write!(fmt, "{:X}", self.0)?;
+    for off in self.1.iter() {
+      write!(fmt, ",{:X}", off)?;
+    }
+    Ok(())
+  }
+}
+impl std::str::FromStr for AddrOffsets {
+  type Err = String;
+  /// Parse comma-separated hex offsets, e.g. `8040E108,0`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut offsets = s.split(',').map(|off| {
+      let off = off.trim();
+      let off = off.strip_prefix("0x").unwrap_or(off);
+      u32::from_str_radix(off, 16).map_err(|e| format!("invalid offset \"{off}\": {e}"))
+    });
+    let first = offsets.next().unwrap_or(Ok(0))?;
+    Ok(AddrOffsets(first, offsets.collect::<Result<_, _>>()?))
+  }
+}
@@ -0,0 +1,143 @@
+/// SPDX-FileCopyrightText: 2023 sup39 <sms@sup39.dev>
+/// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde_json::Value as JsonValue;
+use crate::{SMSVersion, definition::{ObjParamsClass, ObjParamsOffset}, diagnostic::{ObjParamsDiagnostic, class_path}};
+
+/// size of the types defined in `load_obj_params()`
+pub fn primitive_size(type_: &str) -> Option<u32> {
+  match type_ {
+    "u8" | "s8" => Some(1),
+    "u16" | "s16" => Some(2),
+    "u32" | "s32" | "float" | "string" => Some(4),
+    "double" => Some(8),
+    t if t.ends_with('*') => Some(4),
+    t => match array_type(t) {
+      Some(("char" | "u8", len)) => Some(len as u32),
+      _ => None,
+    },
+  }
+}
+
+/// Parse `T[N]` into `(T, N)`
+pub fn array_type(type_: &str) -> Option<(&str, usize)> {
+  let (elem, len) = type_.strip_suffix(']')?.split_once('[')?;
+  Some((elem, len.parse().ok()?))
+}
+
+/// Parse the `size` of a class. `-1` or omitted means unknown
+pub fn class_size(
+  name: &str, o: &ObjParamsClass,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) -> Option<u32> {
+  let path = || class_path(name, format_args!(".size"));
+  match &o.size {
+    None => None,
+    Some(JsonValue::Number(n)) => match (n.as_u64(), n.as_i64()) {
+      (Some(n), _) if n <= u32::MAX as u64 => Some(n as u32),
+      (_, Some(-1)) => None,
+      _ => {
+        diagnostics.push(ObjParamsDiagnostic::error(&o.file, path(),
+          format!("invalid size: {n}")));
+        None
+      },
+    },
+    Some(JsonValue::String(s)) => match s.parse::<u32>() {
+      Ok(n) => {
+        diagnostics.push(ObjParamsDiagnostic::warning(&o.file, path(),
+          format!("size should be a number instead of a string: \"{s}\"")));
+        Some(n)
+      },
+      Err(e) => {
+        diagnostics.push(ObjParamsDiagnostic::error(&o.file, path(),
+          format!("invalid size \"{s}\": {e}")));
+        None
+      },
+    },
+    Some(v) => {
+      diagnostics.push(ObjParamsDiagnostic::error(&o.file, path(),
+        format!("size must be a number, got {v}")));
+      None
+    },
+  }
+}
+
+/// Validate the classes selected for `ver`.
+/// Inheritance cycles are reported while resolving the classes
+pub fn validate(
+  db: &HashMap<Arc<str>, Arc<ObjParamsClass>>,
+  ver: SMSVersion,
+  diagnostics: &mut Vec<ObjParamsDiagnostic>,
+) {
+  let sizes: HashMap<&str, Option<u32>> = db.iter()
+    .map(|(name, o)| (&**name, class_size(name, o, diagnostics)))
+    .collect();
+  let type_size = |type_: &str| primitive_size(type_)
+    .or_else(|| sizes.get(type_).copied().flatten());
+
+  let mut names = db.keys().collect::<Vec<_>>();
+  names.sort();
+  for name in names {
+    let o = &db[name];
+    let size = sizes.get(&**name).copied().flatten();
+    // (begin, end, index) of the fields with a direct offset
+    let mut ranges = Vec::<(u32, u32, usize)>::new();
+    for (i, field) in o.fields.iter().enumerate() {
+      let path = |key: &str| class_path(name, format_args!(".offsets[{}]{key}", field.index));
+      let Some(offset) = field.offset.get(ver) else {continue};
+      let ver_note = match field.offset {
+        ObjParamsOffset::Common(_) => String::new(),
+        ObjParamsOffset::PerVersion(_) => format!(" (in {ver})"),
+      };
+      // type
+      if primitive_size(&field.type_).is_none() && !db.contains_key(&field.type_) {
+        diagnostics.push(ObjParamsDiagnostic::error(&field.file, path(".type"),
+          format!("unknown type \"{}\"", field.type_)));
+      }
+      // format
+      if let Some(format) = field.format {
+        if !format.supports(&field.type_) {
+          diagnostics.push(ObjParamsDiagnostic::error(&field.file, path(".format"),
+            format!("format \"{format}\" cannot be used for type \"{}\"", field.type_)));
+        }
+      }
+      // size
+      if !offset.1.is_empty() {continue}
+      let Some(field_size) = type_size(&field.type_) else {continue};
+      let (begin, end) = (offset.0, offset.0.saturating_add(field_size));
+      if let Some(size) = size {
+        if end > size {
+          diagnostics.push(ObjParamsDiagnostic::error(&field.file, path(".offset"),
+            format!(
+              "field \"{}\" [0x{begin:X}, 0x{end:X}) exceeds the size of the class (0x{size:X}){ver_note}",
+              field.name,
+            )));
+        }
+      }
+      ranges.push((begin, end, i));
+    }
+    // overlap
+    ranges.sort();
+    let mut last: Option<(u32, usize)> = None;
+    for &(begin, end, i) in ranges.iter() {
+      if let Some((last_end, j)) = last {
+        let (field, field0) = (&o.fields[i], &o.fields[j]);
+        // union members and conditional fields are meant to share the memory
+        let intended = field.when.is_some() || field0.when.is_some() ||
+          (field.union.is_some() && field.union == field0.union);
+        if begin < last_end && !intended {
+          diagnostics.push(ObjParamsDiagnostic::warning(&field.file,
+            class_path(name, format_args!(".offsets[{}].offset", field.index)),
+            format!(
+              "field \"{}\" overlaps with \"{}\" (offsets[{}] in \"{}\")",
+              field.name, field0.name, field0.index, field0.file,
+            )));
+        }
+        if end <= last_end {continue}
+      }
+      last = Some((end, i));
+    }
+  }
+}
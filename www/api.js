@@ -5,6 +5,14 @@
 /**
  * @typedef {number|number[]} ReqAddr
  * @typedef {'GMSJ01'|'GMSE01'|'GMSP01'|'GMSJ0A'} SMSVersion
+ * @typedef {{
+ *   severity: 'error'|'warning',
+ *   file: string,
+ *   path: string,
+ *   message: string,
+ * }} ObjParamsDiagnostic
+ * @typedef {{field: string, equals: number}} FieldCondition
+ *   the field is applicable only if the field with id `field` equals `equals`
  */
 
 /** @param {string} s */
@@ -13,17 +21,53 @@ const hex2dv = s => new DataView(Uint8Array.from(
     .map(s => parseInt(s, 16))).buffer
 );
 
+/**
+ * Set the value at `path` of `root`, and return the new root
+ * @param {any} root
+ * @param {(string|number)[]} path
+ * @param {any} value
+ */
+function setAt(root, path, value) {
+  if (path.length === 0) return value;
+  const parent = path.slice(0, -1).reduce((o, k) => o[k], root);
+  parent[path[path.length-1]] = value;
+  return root;
+}
+
+/**
+ * @typedef {(values: any[], errors: (any|null)[], error: string|null) => void} SubscriptionHandler
+ *   `values`: the latest value of each read; `errors`: the error of each read (`null` if none);
+ *   `error`: the error of the subscription itself
+ */
+
 /**
  * @param {{
  *   onClose?: null | ((this: WebSocket, ev: CloseEvent)=>void)
+ *   onEvent?: null | ((name: string, body: any)=>void)
  * }} options
+ *   `onEvent`: called when the server pushes an event,
+ *   e.g. `objParamsReloaded` with `{applied: boolean, diagnostics: ObjParamsDiagnostic[]}`
  */
-function Client({onClose = null}={}) {
+function Client({onClose = null, onEvent = null}={}) {
   /** @type {Map<number, {rsv: (res: any)=>void, rjt: (res: any)=>void}>} */
   const reqs = new Map();
   /** @type {WebSocket|null} */
   let ws = null;
   let nextId = 1;
+  /** @type {Map<number, {values: any[], errors: (any|null)[], error: string|null, onUpdate: SubscriptionHandler}>} */
+  const subs = new Map();
+  /** @param {{id: number, changes?: [number, (string|number)[], any][], errors?: [number, any][], error?: string|null}} update */
+  function onSubscription({id, changes=[], errors=[], error}) {
+    const sub = subs.get(id);
+    if (sub == null) return;
+    changes.forEach(([i, path, value]) => {
+      sub.values[i] = setAt(sub.values[i], path, value);
+      if (path.length === 0) sub.errors[i] = null;
+    });
+    errors.forEach(([i, e]) => sub.errors[i] = e);
+    if (error !== undefined) sub.error = error;
+    sub.onUpdate(sub.values, sub.errors, sub.error);
+  }
 
   /**
    * @template T
@@ -38,13 +82,57 @@ function Client({onClose = null}={}) {
     ws.send(JSON.stringify([id, action, payload]));
   });
 
+  /**
+   * Send a binary frame `[id: i32, command: u8, addr: u32, len: u32, payload]` (big-endian).
+   * The binary framing must be enabled by `api.enableBinary()` first
+   * @param {number} command 1: read, 2: write, 3: snapshot
+   * @param {number} addr
+   * @param {number} len
+   * @param {Uint8Array} [payload]
+   * @returns {Promise<DataView>} the bytes of the response
+   */
+  const requestBinary = (command, addr, len, payload=new Uint8Array()) => new Promise((rsv, rjt) => {
+    if (ws == null) throw Error('Client is not connected to server. Use `client.connect()` first.');
+    const id = nextId++;
+    reqs.set(id, {rsv, rjt});
+    const frame = new Uint8Array(13 + payload.length);
+    const dv = new DataView(frame.buffer);
+    dv.setInt32(0, id);
+    dv.setUint8(4, command);
+    dv.setUint32(5, addr);
+    dv.setUint32(9, len);
+    frame.set(payload, 13);
+    ws.send(frame);
+  });
+
+  // the access token in the URL of the page (`?token=...`) is passed to the server
+  const token = new URLSearchParams(window.location.search).get('token');
+  const defaultUrl = `ws://${window.location.host}/` + (token == null ? '' : `?token=${encodeURIComponent(token)}`);
+
   return {
-    connect: (url=`ws://${window.location.host}/`, protocol=undefined) => new Promise((rsv, rjt) => {
+    connect: (url=defaultUrl, protocol=undefined) => new Promise((rsv, rjt) => {
       const ws1 = new WebSocket(url, protocol);
+      ws1.binaryType = 'arraybuffer';
       ws1.onmessage = ({data}) => {
-        if (typeof data !== 'string') return; // TODO
-        const [id, body] = JSON.parse(data);
-        if (id > 0) {
+        if (data instanceof ArrayBuffer) {
+          // binary: [id: i32, bytes] or [-id: i32, error message]
+          const id = new DataView(data).getInt32(0);
+          if (id > 0) {
+            reqs.get(id)?.rsv(new DataView(data, 4));
+            reqs.delete(id);
+          } else {
+            reqs.get(-id)?.rjt(new TextDecoder().decode(new Uint8Array(data, 4)));
+            reqs.delete(-id);
+          }
+          return;
+        }
+        const [id, ...args] = JSON.parse(data);
+        const [body] = args;
+        if (id === 0) {
+          // event: [0, name, body]
+          if (args[0] === 'subscription') onSubscription(args[1]);
+          else onEvent?.(args[0], args[1]);
+        } else if (id > 0) {
           reqs.get(id)?.rsv(body);
           reqs.delete(id);
         } else {
@@ -59,20 +147,46 @@ function Client({onClose = null}={}) {
     }),
     get ws() {return ws},
     request,
+    requestBinary,
     api: {
       /**
        * @returns {Promise<number|null>}
        */
       init: () => request('init'),
 
+      /**
+       * The running Dolphin instances
+       * @returns {Promise<{pid: number, profile: string|null, version: SMSVersion|null, sessions: number, selected: boolean}[]>}
+       */
+      listInstances: () => request('listInstances'),
+
+      /**
+       * Use the Dolphin instance of `pid` (default: the one used by other sessions, or the first Dolphin running SMS)
+       * @param {number} [pid]
+       * @returns {Promise<number>} pid
+       */
+      attach: pid => request('attach', {pid}),
+
+      /** @returns {Promise<void>} */
+      detach: () => request('detach'),
+
       /**
        * @param {ReqAddr} addr
        * @param {string} type
+       * @param {{root?: string, tree?: boolean, applicable?: boolean}} [options]
+       *   `root`: id of the field (group) to read; `tree`: return nested arrays;
+       *   `applicable`: read the fields whose `when` condition does not hold too,
+       *   and return `{values, applicable}` where `applicable` has the same shape as `values`.
+       *   Otherwise such fields are read as `null`.
+       *   If `type` is `auto`, the class is resolved by the vtable of the object,
+       *   and `{class, type, values}` is returned, where `class` is the class of the object (`null` if unknown)
+       *   and `type` is the nearest class in its class hierarchy which has ObjectParameters
        */
-      read: (addr, type) => request('read', {
+      read: (addr, type, options={}) => request('read', {
         addr: addr instanceof Array ? addr : [addr],
         type,
-      }).then((/**@type{string[]|string|null}*/s) => s),
+        ...options,
+      }).then((/**@type{any[]|string|null|{values: any, applicable?: any, class?: string|null, type?: string}}*/s) => s),
 
       /**
        * @param {ReqAddr} addr
@@ -83,6 +197,34 @@ function Client({onClose = null}={}) {
         size,
       }).then((/**@type{string|null}*/s) => s == null ? null : hex2dv(s)),
 
+      /**
+       * Accept binary frames on this connection, used by `readBinary`, `writeBinary` and `snapshotBinary`
+       * @returns {Promise<{version: number, commands: Record<string, number>, requestHeaderSize: number}>}
+       */
+      enableBinary: () => request('binary', true),
+
+      /**
+       * Read raw bytes through a binary frame (no pointer chain)
+       * @param {number} addr
+       * @param {number} size
+       */
+      readBinary: (addr, size) => requestBinary(1, addr, size),
+
+      /**
+       * Write raw bytes through a binary frame (no pointer chain)
+       * @param {number} addr
+       * @param {Uint8Array} payload
+       */
+      writeBinary: (addr, payload) => requestBinary(2, addr, payload.length, payload).then(() => {}),
+
+      /**
+       * Read MEM1 through a binary frame, from `addr` (default: start of MEM1)
+       * with `size` bytes (default: until the end of MEM1)
+       * @param {number} [addr]
+       * @param {number} [size]
+       */
+      snapshotBinary: (addr=0, size=0) => requestBinary(3, addr, size),
+
       /**
        * @param {ReqAddr} addr
        * @returns {Promise<string|null>}
@@ -104,6 +246,33 @@ function Client({onClose = null}={}) {
         ).join(''),
       }),
 
+      /**
+       * Keep writing the value from the server
+       * @param {ReqAddr} addr
+       * @param {{payload: string} | {type: string, field?: string, value: number}} value
+       *   bytes in hex, or a number as the primitive `type` or as the type of the `field` (id) of the class `type`
+       * @param {{rate?: number, trigger?: 'frame', frameAddr?: number|string, persistent?: boolean}} [options]
+       *   `rate`: times per second (default: 60); `trigger: 'frame'`: write when the frame counter
+       *   (at `frameAddr`, or the `frame` global of the game profile) changes;
+       *   `persistent`: keep writing after this connection is closed
+       * @returns {Promise<number>} id of the freeze
+       */
+      freeze: (addr, value, options={}) => request('freeze', {addr, ...value, ...options}),
+
+      /**
+       * Stop writing the value frozen by this connection
+       * @param {number} id
+       * @param {boolean} [force] remove the persistent freeze of another connection too
+       * @returns {Promise<void>}
+       */
+      unfreeze: (id, force=false) => request('unfreeze', force ? {id, force} : id),
+
+      /**
+       * The frozen values of the Dolphin instance (including those of other connections)
+       * @returns {Promise<{id: number, addr: ReqAddr, type: string|null, field: string|null, payload: string, persistent: boolean, owned: boolean, error: string|null}[]>}
+       */
+      listFrozen: () => request('listFrozen'),
+
       /**
        * @param {ReqAddr} addr
        * @returns {Promise<string|null>}
@@ -114,15 +283,28 @@ function Client({onClose = null}={}) {
 
       /**
        * @param {string} type
+       * @param {string} [version] version of the ObjectParameters (default: the version of the attached game)
        * @returns {Promise<[
        *   offsets: string,
        *   name: string,
        *   notes: string,
        *   type: string,
        *   class_: string,
+       *   id: string,
+       *   file: string,
+       *   when: FieldCondition[],
+       *   expr: string|null,
        * ][]>}
        */
-      getFields: type => request('getFields', type),
+      getFields: (type, version) => request('getFields', version ? {type, version} : type),
+
+      /**
+       * @param {string} type
+       * @param {string} [root] id of the field (group) to get
+       * @param {string} [version] version of the ObjectParameters (default: the version of the attached game)
+       * @returns {Promise<any[]>} nested fields (see `FieldNode` in index.js)
+       */
+      getFieldsTree: (type, root, version) => request('getFields', {type, root, tree: true, version}),
 
       getManagers: () => request('getManagers')
         .then((/**@type{[addr: number, type: string, name: string, count: number][]|null}*/rows) =>
@@ -138,7 +320,83 @@ function Client({onClose = null}={}) {
       /** @returns {Promise<SMSVersion>} */
       getVersion: ()  => request('getVersion'),
 
+      /** @returns {Promise<{name: string, gameId: string, revision: number|null, base: SMSVersion, globals: Record<string, string>, vtables: number, objectParameters: string|null}>} */
+      getProfile: () => request('getProfile'),
+
+      /** @returns {Promise<ObjParamsDiagnostic[]>} */
       reload: () => request('reload', null),
+
+      /**
+       * JSON Schema of the body and the result of every command
+       * @returns {Promise<{commands: Record<string, {description: string, connection: boolean, writes: boolean, param?: string, request: any, response: any}>, $defs: Record<string, any>}>}
+       */
+      describe: () => request('describe'),
+
+      /**
+       * Let the server push the values of the reads when they change
+       * @param {{addr: ReqAddr, type: string, root?: string, tree?: boolean, applicable?: boolean}[]} reads bodies of `read`
+       * @param {{rate?: number, trigger?: 'frame', frameAddr?: number|string}} options
+       *   `rate`: times per second (default: 30);
+       *   `trigger: 'frame'`: read when the frame counter (at `frameAddr`, or the `frame` global of the game profile if defined) changes
+       * @param {SubscriptionHandler} onUpdate
+       * @returns {Promise<{id: number, unsubscribe: () => Promise<void>}>}
+       */
+      subscribe: async (reads, options, onUpdate) => {
+        const id = await request('subscribe', {
+          reads: reads.map(o => ({...o, addr: o.addr instanceof Array ? o.addr : [o.addr]})),
+          ...options,
+        });
+        subs.set(id, {values: reads.map(() => null), errors: reads.map(() => null), error: null, onUpdate});
+        return {
+          id,
+          unsubscribe: async () => {
+            subs.delete(id);
+            await request('unsubscribe', id);
+          },
+        };
+      },
+
+      /**
+       * Run commands in one request, and return the result or error of each command in order
+       * @param {[command: string, body: any][]} commands
+       * @param {{snapshot?: boolean}} [options]
+       *   `snapshot`: read the memory from one copy taken before the first command
+       * @returns {Promise<({result: any}|{error: any})[]>}
+       */
+      batch: (commands, {snapshot=false}={}) => request('batch', {commands, snapshot}),
+
+      /**
+       * Convert a Dolphin Memory Engine watch list into ObjectParameters
+       * @param {any} dmw parsed .dmw file
+       * @param {string} [name] name of the class containing the whole watch list (default: `Watches`)
+       * @returns {Promise<Record<string, any>>}
+       */
+      importDmw: (dmw, name) => request('importDmw', {dmw, name}),
+
+      /**
+       * Export the fields of an object as a Dolphin Memory Engine watch list
+       * @param {ReqAddr} addr address of the object (pointers are kept as pointer offsets)
+       * @param {string} type
+       * @param {string} [root] id of the field (group) to export
+       * @returns {Promise<{watchList: any[]}>}
+       */
+      exportDmw: (addr, type, root) => request('exportDmw', {addr, type, root}),
+
+      /**
+       * Export classes as C structs or Ghidra XML data types
+       * @param {'c'|'ghidra'} format
+       * @param {string} [type] class to export with its embedded classes (default: all classes)
+       * @param {SMSVersion} [version] (default: version of the running game)
+       * @returns {Promise<string>}
+       */
+      exportTypes: (format, type, version) => request('exportTypes', {format, type, version}),
+
+      /**
+       * Convert the structs in a C header into ObjectParameters
+       * @param {string} source
+       * @returns {Promise<Record<string, any>>}
+       */
+      importCHeader: source => request('importCHeader', source),
     },
   };
 }
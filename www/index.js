@@ -3,13 +3,17 @@
 
 // @ts-check
 /**
- * @typedef {{addr: number, type: string, name: string, count: number}} Manager
- * @typedef {{addr: number, type: string, name: string}} Managee
+ * @typedef {{addr: number, type: string, name: string, count: number, auto?: boolean}} Manager
+ * @typedef {{addr: number, type: string, name: string, auto?: boolean}} Managee
+ *   `auto`: resolve the class with ObjectParameters by the vtable
  * @typedef {(td: HTMLTableCellElement) => void} CellFactory
  * @typedef {CellFactory[]} RowFactory
  * @typedef {{name: string, notes: string, offset: string|string[], type: string}} Field
  * @typedef {(Omit<Field, 'offset'> & {offset: number[], srcType: string})} FieldView
  * @typedef {{offsets: Field[]}} ObjParamsDBEntry
+ * @typedef {{id: string, name: string, offset: string, notes: string, type: string, class: string, file: string, when: FieldCondition[], expr: string|null}} FieldLeaf
+ * @typedef {{id: string, name: string, offset: string, notes: string, type: string, file: string, children: FieldNode[]}} FieldGroup
+ * @typedef {FieldLeaf|FieldGroup} FieldNode
  * @typedef {Record<string, null|ObjParamsDBEntry>} ObjParamsDB
  */
 
@@ -46,6 +50,9 @@ document.addEventListener('DOMContentLoaded', async () => {
   }
   const client = Client({
     onClose: () => showError(`Disconnected from server. Please reload the page.`),
+    onEvent: (name, body) => {
+      if (name === 'objParamsReloaded') fieldsViewer.onReloaded(body);
+    },
   });
   const {api} = client;
   Object.assign(window, {client, api}); // TODO
@@ -107,7 +114,7 @@ document.addEventListener('DOMContentLoaded', async () => {
         fieldsViewer.reset();
         initTable(elmTable, [
           ...vars.map(o => makeManageesRowFactory(o)),
-          ...managers.map(makeManagersRowFactory),
+          ...managers.map(o => makeManagersRowFactory({...o, auto: true})),
         ]);
       },
     };
@@ -130,56 +137,130 @@ document.addEventListener('DOMContentLoaded', async () => {
       return e;
     })();
     // states
-    const tdidxVal = 2;
-    let hAnm = NaN;
-    let t0 = 0;
+    /** @type {Promise<{unsubscribe: () => Promise<void>}>|null} */
+    let subscription = null;
     /** @type {Managee|null} */
     let target = null;
-    async function readValues() {
-      if (target == null) return [];
-      const values = await api.read([target.addr], target.type);
-      return values instanceof Array ? values : [values];
+    /** @type {Managee|null} the object being viewed, whose type may be resolved into `target.type` */
+    let viewed = null;
+    /** @type {HTMLTableCellElement[]} */
+    let valueCells = [];
+    /** @param {any} res result of `read` with `{tree: true, applicable: true}` */
+    function render(res) {
+      // the leaves of the tree are in the same order as the rows
+      const {values, applicable} = res == null || typeof res !== 'object' || res instanceof Array ?
+        {values: [res], applicable: [true]} :
+        {values: res.values.flat(Infinity), applicable: res.applicable.flat(Infinity)};
+      values.forEach((s, i) => {
+        if (valueCells[i] == null) return;
+        valueCells[i].textContent = s;
+        valueCells[i].parentElement?.classList.toggle('inapplicable', !applicable[i]);
+      });
     }
-    /** @param {DOMHighResTimeStamp} t */
-    async function render(t) {
-      if (t-t0 >= 33) { // TODO configurable fps
-        (await readValues())
-          .forEach((s, i) => elmTable.rows[i].cells[tdidxVal].textContent = s);
-        t0 = t;
-      }
-      hAnm = requestAnimationFrame(render);
+    function unsubscribe() {
+      subscription?.then(o => o.unsubscribe()).catch(err => console.warn(err));
+      subscription = null;
     }
     const methods = {
       get classList() {
         return elm.classList;
       },
       reload() {
-        api.reload().then(() => {
-          target != null && methods.view(target);
-        }, err => {
+        // the result is handled by onReloaded() via the `objParamsReloaded` event
+        api.reload().catch(err => {
           elmMsg.textContent = err;
         });
       },
+      /** @param {{applied: boolean, diagnostics: ObjParamsDiagnostic[]}} result */
+      onReloaded({applied, diagnostics}) {
+        diagnostics.forEach(e => console.warn(`${e.file}: ${e.path}: ${e.severity}: ${e.message}`));
+        const nError = diagnostics.filter(e => e.severity === 'error').length;
+        elmMsg.textContent = (applied ? '' : 'ObjectParameters not reloaded due to errors. ') +
+          (diagnostics.length ?
+            `ObjectParameters: ${nError} error(s), ${diagnostics.length-nError} warning(s) (see console)` : '');
+        applied && viewed != null && methods.view(viewed);
+      },
       reset() {
+        unsubscribe();
         elm.classList.add('hidden');
       },
       /** @param {Manager|Managee} o */
       async view(o) {
-        cancelAnimationFrame(hAnm);
-        target = o;
-        elmTitle.textContent = `${o.name} (${o.type}) [${fmt.hex(o.addr)}]`;
-        const fields = await api.getFields(o.type);
-        const values = readValues();
-        initTable(elmTable, fields.map((r, i) => [
-          td => td.textContent = r[0],
-          td => td.textContent = r[1],
-          td => td.textContent = values[i],
-          td => td.textContent = r[2],
-          td => td.textContent = r[3],
-          td => td.textContent = r[4],
-        ]));
+        unsubscribe();
+        viewed = o;
+        let {type} = o;
+        if (o.auto) {
+          const res = await api.read([o.addr], 'auto');
+          if (res != null && typeof res === 'object' && 'type' in res) type = res.type;
+        }
+        target = {...o, type};
+        elmTitle.textContent = `${o.name} (${o.type}${type === o.type ? '' : ` → ${type}`}) [${fmt.hex(o.addr)}]`;
+        const nodes = await api.getFieldsTree(type);
+        valueCells = [];
+        /** @type {RowFactory[]} */
+        const rows = [];
+        /** @type {{rows: HTMLTableRowElement[], open: boolean}[]} */
+        const groups = [];
+        /**
+         * @param {FieldNode[]} nodes
+         * @param {typeof groups} ancestors
+         */
+        const walk = (nodes, ancestors) => nodes.forEach(node => {
+          const indent = td => td.style.paddingLeft = `${ancestors.length}em`;
+          /** @type {CellFactory} */
+          const register = td => ancestors.forEach(g => td.parentElement && g.rows.push(td.parentElement));
+          if ('children' in node) {
+            const group = {rows: /**@type{HTMLTableRowElement[]}*/([]), open: true};
+            rows.push([
+              td => {register(td); td.textContent = node.offset},
+              td => {
+                indent(td);
+                td.title = node.file;
+                const btn = document.createElement('button');
+                btn.textContent = `v ${node.name} (${node.type})`;
+                btn.addEventListener('click', () => {
+                  group.open = !group.open;
+                  btn.textContent = `${group.open ? 'v' : '>'} ${node.name} (${node.type})`;
+                  // show the rows of which all ancestor groups are open
+                  groups.forEach(g => g.rows.forEach(tr => tr.classList.remove('hidden')));
+                  groups.filter(g => !g.open).forEach(g => g.rows.forEach(tr => tr.classList.add('hidden')));
+                });
+                td.appendChild(btn);
+              },
+              _ => {},
+              td => td.textContent = node.notes,
+              td => td.textContent = node.type,
+              _ => {},
+            ]);
+            groups.push(group);
+            walk(node.children, [...ancestors, group]);
+          } else {
+            rows.push([
+              // computed fields are not in memory
+              td => {register(td); td.textContent = node.expr == null ? node.offset : ''},
+              td => {
+                indent(td);
+                td.title = [node.file, ...(node.expr == null ? [] : [`= ${node.expr}`]), ...node.when.map(c => `when ${c.field} == 0x${fmt.hex(c.equals)}`)].join('\n');
+                td.textContent = node.name;
+              },
+              td => valueCells.push(td),
+              td => td.textContent = node.notes,
+              td => td.textContent = node.type,
+              td => td.textContent = node.class,
+            ]);
+          }
+        });
+        walk(nodes, []);
+        initTable(elmTable, rows);
         elm.classList.remove('hidden');
-        if (fields.length) hAnm = requestAnimationFrame(render);
+        if (valueCells.length) {
+          // TODO configurable rate
+          subscription = api.subscribe([{addr: [o.addr], type, tree: true, applicable: true}], {rate: 30},
+            ([res], [err], error) => {
+              if (error != null || err != null) elmMsg.textContent = `${error ?? err}`;
+              else render(res);
+            });
+        }
       },
     }
     return methods;
@@ -226,7 +307,7 @@ document.addEventListener('DOMContentLoaded', async () => {
               const tr = document.createElement('tr');
               tr.classList.add('managee');
               trP?.insertBefore(tr, tr1);
-              makeManageesRowFactory(o, i).forEach(f => f(tr.insertCell()));
+              makeManageesRowFactory({...o, auto: true}, i).forEach(f => f(tr.insertCell()));
               return tr;
             });
           } else {